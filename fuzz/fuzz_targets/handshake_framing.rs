@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use safequanta_tls::handshake::HandshakeMessage;
+
+fuzz_target!(|data: &[u8]| {
+    // Malformed input must be rejected with a FramingError, never a panic
+    if let Ok(msg) = HandshakeMessage::decode(data) {
+        // Anything that decodes must re-encode to the same bytes
+        assert_eq!(msg.encode().unwrap(), data);
+    }
+});
@@ -0,0 +1,214 @@
+//! systemd socket activation (`sd_listen_fds(3)`) and readiness
+//! notification (`sd_notify(3)`)
+
+use crate::error::{Result, SafeQuantaError};
+
+/// First file descriptor passed by systemd
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// `FileDescriptorName=` of the socket that serves the metrics endpoint
+pub const METRICS_SOCKET_NAME: &str = "metrics";
+
+/// Socket activation variables, taken out of the environment
+#[derive(Debug, Default)]
+pub struct ActivationEnv {
+    pid: Option<String>,
+    fds: Option<String>,
+    names: Option<String>,
+}
+
+/// Sockets passed by systemd
+#[derive(Debug, Default)]
+pub struct InheritedSockets {
+    /// Proxy listeners, in the order of the socket unit's `ListenStream=` lines
+    pub listeners: Vec<std::net::TcpListener>,
+    /// The socket named `metrics`, for the metrics endpoint
+    pub metrics: Option<std::net::TcpListener>,
+}
+
+impl ActivationEnv {
+    /// Read the activation variables and remove them, so child processes
+    /// don't adopt the same sockets
+    ///
+    /// Changing the environment is only sound while no other thread may be
+    /// reading it, so this must run before the Tokio runtime starts.
+    pub fn take() -> Self {
+        let take = |name: &str| {
+            let value = std::env::var(name).ok();
+            std::env::remove_var(name);
+            value
+        };
+        Self {
+            pid: take("LISTEN_PID"),
+            fds: take("LISTEN_FDS"),
+            names: take("LISTEN_FDNAMES"),
+        }
+    }
+
+    /// Take ownership of the passed sockets
+    ///
+    /// Empty when the process was not socket-activated.
+    #[cfg(unix)]
+    pub fn adopt(self) -> Result<InheritedSockets> {
+        let listeners =
+            adopt_listeners(self.pid.as_deref(), self.fds.as_deref(), SD_LISTEN_FDS_START)?;
+        Ok(split_sockets(listeners.unwrap_or_default(), self.names.as_deref()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn adopt(self) -> Result<InheritedSockets> {
+        Ok(InheritedSockets::default())
+    }
+}
+
+/// Set the socket named `metrics` in `LISTEN_FDNAMES` aside from the proxy listeners
+#[cfg(unix)]
+fn split_sockets(listeners: Vec<std::net::TcpListener>, names: Option<&str>) -> InheritedSockets {
+    let mut names = names.map(|names| names.split(':')).into_iter().flatten();
+    let mut sockets = InheritedSockets::default();
+    for listener in listeners {
+        if names.next() == Some(METRICS_SOCKET_NAME) && sockets.metrics.is_none() {
+            sockets.metrics = Some(listener);
+        } else {
+            sockets.listeners.push(listener);
+        }
+    }
+    sockets
+}
+
+/// Tell systemd the service is ready (`READY=1`)
+///
+/// A no-op unless started by a `Type=notify` unit, i.e. without
+/// `NOTIFY_SOCKET`.
+#[cfg(unix)]
+pub fn notify_ready() -> Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send_notification(&path, "READY=1"),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify_ready() -> Result<()> {
+    Ok(())
+}
+
+/// Send `state` to the notification socket at `path`
+///
+/// A leading `@` names a socket in the Linux abstract namespace.
+#[cfg(unix)]
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes() {
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Take ownership of `fds` descriptors starting at `first_fd` if `pid` is
+/// this process
+#[cfg(unix)]
+fn adopt_listeners(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    first_fd: std::os::fd::RawFd,
+) -> Result<Option<Vec<std::net::TcpListener>>> {
+    use std::os::fd::FromRawFd;
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    // The variables may have been inherited from a socket-activated parent
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count: std::os::fd::RawFd = fds
+        .parse()
+        .map_err(|e| SafeQuantaError::InvalidConfig(format!("Invalid LISTEN_FDS {:?}: {}", fds, e)))?;
+
+    (first_fd..first_fd + count)
+        .map(|fd| {
+            // SAFETY: systemd passes these descriptors to this process, which
+            // owns them from here on
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            log::info!("Adopted inherited listener {:?} (fd {})", listener.local_addr()?, fd);
+            Ok(listener)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn test_not_activated() {
+        assert!(adopt_listeners(None, None, SD_LISTEN_FDS_START).unwrap().is_none());
+        // Variables meant for another process
+        assert!(adopt_listeners(Some("0"), Some("1"), SD_LISTEN_FDS_START).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_adopts_passed_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd();
+
+        let pid = std::process::id().to_string();
+        let adopted = adopt_listeners(Some(&pid), Some("1"), fd).unwrap().unwrap();
+        assert_eq!(adopted.len(), 1);
+        assert_eq!(adopted[0].local_addr().unwrap(), addr);
+
+        let listener = tokio::net::TcpListener::from_std(adopted.into_iter().next().unwrap()).unwrap();
+        let (connected, accepted) = tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+        assert_eq!(accepted.unwrap().1, connected.unwrap().local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_metrics_socket_set_aside_by_name() {
+        let bind = || std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (first, metrics, second) = (bind(), bind(), bind());
+        let addrs = [&first, &metrics, &second].map(|listener| listener.local_addr().unwrap());
+
+        let sockets = split_sockets(vec![first, metrics, second], Some("proxy:metrics:proxy"));
+        assert_eq!(sockets.metrics.unwrap().local_addr().unwrap(), addrs[1]);
+        let listeners: Vec<_> =
+            sockets.listeners.iter().map(|listener| listener.local_addr().unwrap()).collect();
+        assert_eq!(listeners, [addrs[0], addrs[2]]);
+
+        // Unnamed sockets are all proxy listeners
+        let sockets = split_sockets(vec![bind()], None);
+        assert!(sockets.metrics.is_none());
+        assert_eq!(sockets.listeners.len(), 1);
+    }
+
+    #[test]
+    fn test_notification_reaches_socket() {
+        let dir = std::env::temp_dir().join(format!("safequanta-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+        send_notification(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How `UpstreamSet::select` spreads new connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BalancePolicy {
+    /// Each available upstream in turn
+    #[default]
+    RoundRobin,
+    /// In proportion to each upstream's weight, using smooth weighted
+    /// round-robin so heavier upstreams are interleaved rather than picked in
+    /// bursts. Upstreams weighted zero are never selected
+    Weighted,
+}
+
+struct Upstream {
+    addr: String,
+    weight: u32,
+    draining: AtomicBool,
+    /// Set while the upstream is failing its health check
+    ejected: AtomicBool,
+    /// Slots for connections to the upstream, if capped
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl Upstream {
+    fn available(&self) -> bool {
+        !self.draining.load(Ordering::Relaxed) && !self.ejected.load(Ordering::Relaxed)
+    }
+
+    /// Whether `UpstreamSet::select` may pick the upstream under `policy`
+    fn selectable(&self, policy: BalancePolicy) -> bool {
+        self.available() && (policy == BalancePolicy::RoundRobin || self.weight > 0)
+    }
+}
+
+/// One of an upstream's connection slots, released on drop
+pub struct UpstreamPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Round-robin or weighted selection over a set of upstreams
+///
+/// A draining or ejected upstream receives no new connections; connections
+/// already established to it are left to finish.
+pub struct UpstreamSet {
+    upstreams: Vec<Upstream>,
+    policy: BalancePolicy,
+    next: AtomicUsize,
+    /// Smooth weighted round-robin running weight of each upstream
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl UpstreamSet {
+    pub fn new(upstreams: impl IntoIterator<Item = String>) -> Self {
+        Self::with_policy(
+            BalancePolicy::RoundRobin,
+            upstreams.into_iter().map(|addr| (addr, 1)),
+        )
+    }
+
+    /// Weighted selection over `(address, weight)` pairs
+    pub fn weighted(upstreams: impl IntoIterator<Item = (String, u32)>) -> Self {
+        Self::with_policy(BalancePolicy::Weighted, upstreams)
+    }
+
+    /// Selection over `(address, weight)` pairs; weights only matter with
+    /// `BalancePolicy::Weighted`
+    pub fn with_policy(
+        policy: BalancePolicy,
+        upstreams: impl IntoIterator<Item = (String, u32)>,
+    ) -> Self {
+        let upstreams: Vec<Upstream> = upstreams
+            .into_iter()
+            .map(|(addr, weight)| {
+                crate::metrics::record_upstream_draining(&addr, false);
+                Upstream {
+                    addr,
+                    weight,
+                    draining: AtomicBool::new(false),
+                    ejected: AtomicBool::new(false),
+                    connections: None,
+                }
+            })
+            .collect();
+        Self {
+            current_weights: Mutex::new(vec![0; upstreams.len()]),
+            upstreams,
+            policy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allow at most `max` connections to each upstream at once
+    pub fn with_connection_limit(mut self, max: Option<usize>) -> Self {
+        for upstream in &mut self.upstreams {
+            upstream.connections = max.map(|max| Arc::new(Semaphore::new(max)));
+        }
+        self
+    }
+
+    /// Take a connection slot on `addr`
+    ///
+    /// `None` if `addr` is at its connection limit. Upstreams outside the set
+    /// are never limited.
+    pub fn try_acquire(&self, addr: &str) -> Option<UpstreamPermit> {
+        let connections = self
+            .upstreams
+            .iter()
+            .find(|upstream| upstream.addr == addr)
+            .and_then(|upstream| upstream.connections.clone());
+        match connections {
+            Some(connections) => {
+                let slot = connections.try_acquire_owned().ok()?;
+                Some(UpstreamPermit { _slot: Some(slot) })
+            }
+            None => Some(UpstreamPermit { _slot: None }),
+        }
+    }
+
+    /// Next available upstream below its connection limit, with its slot
+    ///
+    /// Tries `select`'s pick first, then every other upstream it could have
+    /// picked.
+    pub fn select_unsaturated(&self) -> Option<(&str, UpstreamPermit)> {
+        if let Some(addr) = self.select() {
+            if let Some(permit) = self.try_acquire(addr) {
+                return Some((addr, permit));
+            }
+        }
+        self.upstreams
+            .iter()
+            .filter(|upstream| upstream.selectable(self.policy))
+            .find_map(|upstream| {
+                let permit = self.try_acquire(&upstream.addr)?;
+                Some((upstream.addr.as_str(), permit))
+            })
+    }
+
+    /// Next available upstream other than `skip`, for retrying elsewhere
+    pub fn select_excluding(&self, skip: &str) -> Option<&str> {
+        if let Some(addr) = self.select().filter(|addr| *addr != skip) {
+            return Some(addr);
+        }
+        self.upstreams
+            .iter()
+            .find(|upstream| upstream.selectable(self.policy) && upstream.addr != skip)
+            .map(|upstream| upstream.addr.as_str())
+    }
+
+    /// Next upstream for a new connection, skipping draining and ejected ones
+    ///
+    /// `None` if no upstream is available.
+    pub fn select(&self) -> Option<&str> {
+        match self.policy {
+            BalancePolicy::RoundRobin => self.select_round_robin(),
+            BalancePolicy::Weighted => self.select_weighted(),
+        }
+    }
+
+    fn select_round_robin(&self) -> Option<&str> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.upstreams.len())
+            .map(|i| &self.upstreams[(start + i) % self.upstreams.len()])
+            .find(|upstream| upstream.available())
+            .map(|upstream| upstream.addr.as_str())
+    }
+
+    /// Smooth weighted round-robin (as in nginx): every candidate's running
+    /// weight grows by its weight, the largest is picked and knocked back by
+    /// the candidates' total
+    fn select_weighted(&self) -> Option<&str> {
+        let mut current = self.current_weights.lock();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            if !upstream.selectable(BalancePolicy::Weighted) {
+                continue;
+            }
+            current[i] += i64::from(upstream.weight);
+            total += i64::from(upstream.weight);
+            if best.map_or(true, |b| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        current[best] -= total;
+        Some(self.upstreams[best].addr.as_str())
+    }
+
+    /// Stop sending new connections to `addr`
+    ///
+    /// Returns false if `addr` is not in the set.
+    pub fn drain_upstream(&self, addr: &str) -> bool {
+        self.set_draining(addr, true)
+    }
+
+    /// Send new connections to `addr` again
+    ///
+    /// Returns false if `addr` is not in the set.
+    pub fn undrain_upstream(&self, addr: &str) -> bool {
+        self.set_draining(addr, false)
+    }
+
+    pub fn is_draining(&self, addr: &str) -> bool {
+        self.upstreams
+            .iter()
+            .any(|upstream| upstream.addr == addr && upstream.draining.load(Ordering::Relaxed))
+    }
+
+    /// Take `addr` out of selection after failed health checks, or put it back
+    ///
+    /// Returns false if `addr` is not in the set.
+    pub fn set_ejected(&self, addr: &str, ejected: bool) -> bool {
+        let Some(upstream) = self.upstreams.iter().find(|upstream| upstream.addr == addr) else {
+            return false;
+        };
+        if upstream.ejected.swap(ejected, Ordering::Relaxed) != ejected {
+            if ejected {
+                log::warn!("Upstream {} ejected after failing health checks", addr);
+            } else {
+                log::info!("Upstream {} passing health checks again", addr);
+            }
+            crate::metrics::record_upstream_ejected(addr, ejected);
+        }
+        true
+    }
+
+    /// Whether `addr` may receive new connections
+    ///
+    /// Upstreams outside the set are always available.
+    pub fn is_available(&self, addr: &str) -> bool {
+        self.upstreams
+            .iter()
+            .find(|upstream| upstream.addr == addr)
+            .map_or(true, Upstream::available)
+    }
+
+    /// Upstreams currently ejected by their health checks
+    pub fn ejected(&self) -> Vec<String> {
+        self.upstreams
+            .iter()
+            .filter(|upstream| upstream.ejected.load(Ordering::Relaxed))
+            .map(|upstream| upstream.addr.clone())
+            .collect()
+    }
+
+    fn set_draining(&self, addr: &str, draining: bool) -> bool {
+        let Some(upstream) = self.upstreams.iter().find(|upstream| upstream.addr == addr) else {
+            return false;
+        };
+        if upstream.draining.swap(draining, Ordering::Relaxed) != draining {
+            log::info!("Upstream {} {}", addr, if draining { "draining" } else { "active" });
+            crate::metrics::record_upstream_draining(addr, draining);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn selections(set: &UpstreamSet, n: usize) -> HashSet<String> {
+        (0..n).filter_map(|_| set.select().map(str::to_owned)).collect()
+    }
+
+    #[test]
+    fn test_draining_upstream_skipped() {
+        let set = UpstreamSet::new(["a:80", "b:80", "c:80"].map(String::from));
+        assert_eq!(selections(&set, 6).len(), 3);
+
+        assert!(set.drain_upstream("b:80"));
+        assert!(set.is_draining("b:80"));
+        let chosen = selections(&set, 6);
+        assert_eq!(chosen, HashSet::from(["a:80".to_string(), "c:80".to_string()]));
+
+        assert!(set.undrain_upstream("b:80"));
+        assert!(selections(&set, 6).contains("b:80"));
+        assert!(!set.drain_upstream("unknown:80"));
+    }
+
+    #[test]
+    fn test_ejected_upstream_skipped() {
+        let set = UpstreamSet::new(["a:80", "b:80"].map(String::from));
+        assert!(set.set_ejected("a:80", true));
+        assert!(!set.is_available("a:80"));
+        assert_eq!(selections(&set, 4), HashSet::from(["b:80".to_string()]));
+
+        set.set_ejected("a:80", false);
+        assert!(set.is_available("a:80"));
+        assert!(set.is_available("unknown:80"));
+    }
+
+    #[test]
+    fn test_weighted_selection_follows_weights() {
+        let set = UpstreamSet::weighted([
+            ("big:80".to_string(), 5),
+            ("small:80".to_string(), 1),
+            ("mid:80".to_string(), 2),
+            ("off:80".to_string(), 0),
+        ]);
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..8000 {
+            *counts.entry(set.select().unwrap().to_string()).or_insert(0) += 1;
+        }
+        assert_eq!(counts["big:80"], 5000);
+        assert_eq!(counts["small:80"], 1000);
+        assert_eq!(counts["mid:80"], 2000);
+        assert!(!counts.contains_key("off:80"));
+
+        // Smooth: the heavy upstream is interleaved, not picked five times running
+        let first: Vec<_> = (0..8).map(|_| set.select().unwrap()).collect();
+        assert!(first.windows(4).all(|w| w.iter().any(|addr| *addr != "big:80")));
+
+        set.drain_upstream("big:80");
+        let chosen = selections(&set, 6);
+        assert_eq!(chosen, HashSet::from(["small:80".to_string(), "mid:80".to_string()]));
+    }
+
+    #[test]
+    fn test_connection_limit_per_upstream() {
+        let set =
+            UpstreamSet::new(["a:80", "b:80"].map(String::from)).with_connection_limit(Some(2));
+        let held: Vec<_> = (0..2).map(|_| set.try_acquire("a:80").unwrap()).collect();
+        assert!(set.try_acquire("a:80").is_none());
+        // The other upstream keeps its own slots
+        assert!(set.try_acquire("b:80").is_some());
+        for _ in 0..4 {
+            assert_eq!(set.select_unsaturated().unwrap().0, "b:80");
+        }
+
+        drop(held);
+        assert!(set.try_acquire("a:80").is_some());
+        assert!(set.try_acquire("unknown:80").is_some());
+    }
+
+    #[test]
+    fn test_unsaturated_selection_skips_unweighted_upstreams() {
+        let set = UpstreamSet::weighted([
+            ("big:80".to_string(), 5),
+            ("small:80".to_string(), 1),
+            ("off:80".to_string(), 0),
+        ])
+        .with_connection_limit(Some(1));
+        let _big = set.try_acquire("big:80").unwrap();
+        let (addr, _small) = set.select_unsaturated().unwrap();
+        assert_eq!(addr, "small:80");
+        // Only the weight 0 upstream has a slot left
+        assert!(set.select_unsaturated().is_none());
+    }
+
+    #[test]
+    fn test_selection_excluding_failed_upstream() {
+        let set = UpstreamSet::weighted([
+            ("big:80".to_string(), 5),
+            ("small:80".to_string(), 1),
+            ("off:80".to_string(), 0),
+        ]);
+        for _ in 0..6 {
+            assert_eq!(set.select_excluding("big:80"), Some("small:80"));
+        }
+        set.drain_upstream("small:80");
+        assert_eq!(set.select_excluding("big:80"), None);
+    }
+
+    #[test]
+    fn test_all_draining_selects_none() {
+        let set = UpstreamSet::new(["a:80".to_string()]);
+        set.drain_upstream("a:80");
+        assert_eq!(set.select(), None);
+    }
+}
@@ -1,86 +1,1924 @@
+use crate::error::SafeQuantaError;
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct Config {
+    /// Strict mode: PQC key exchange on every client and upstream handshake,
+    /// no classic fallback, and startup fails on upstreams that don't comply,
+    /// on plaintext upstreams and on a certificate that is not Dilithium3
+    pub require_pqc: bool,
     pub server: ServerConfig,
     pub tls: TlsConfig,
     pub metrics: MetricsConfig,
     pub proxy: ProxyConfig,
+    /// Additional listeners served by the same process
+    pub listeners: Vec<ListenerConfig>,
+    /// Plaintexts of the `enc:` values decrypted at load time, hidden by
+    /// `redacted` wherever they end up
+    #[serde(skip)]
+    pub decrypted_values: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Extra listen address with its own proxy and, optionally, TLS settings
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ListenerConfig {
+    /// Proxy settings, including the `listen_addr`
+    pub proxy: ProxyConfig,
+    /// TLS settings; the top-level `tls` section when unset
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub workers: usize,
+    /// File the log is written to instead of stderr
+    pub log_file: Option<PathBuf>,
+    /// When `log_file` is rotated
+    pub log_rotation: LogRotation,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 443,
+            workers: 4,
+            log_file: None,
+            log_rotation: LogRotation::default(),
+        }
+    }
+}
+
+/// Rotation of the log file: `daily` or `size:<N>mb`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum LogRotation {
+    /// At midnight UTC
+    #[default]
+    Daily,
+    /// Before a write would take the file past this many bytes
+    Size(u64),
+}
+
+impl TryFrom<String> for LogRotation {
+    type Error = String;
+
+    fn try_from(rotation: String) -> std::result::Result<Self, String> {
+        if rotation.eq_ignore_ascii_case("daily") {
+            return Ok(LogRotation::Daily);
+        }
+        rotation
+            .strip_prefix("size:")
+            .and_then(|size| size.strip_suffix("mb").or_else(|| size.strip_suffix("MB")))
+            .and_then(|mb| mb.trim().parse::<u64>().ok())
+            .filter(|mb| *mb > 0)
+            .map(|mb| LogRotation::Size(mb * 1024 * 1024))
+            .ok_or_else(|| format!("invalid log rotation {:?}, expected daily or size:<N>mb", rotation))
+    }
+}
+
+impl From<LogRotation> for String {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Daily => "daily".to_string(),
+            LogRotation::Size(bytes) => format!("size:{}mb", bytes / (1024 * 1024)),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct TlsConfig {
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
+    /// Upstream address used for outbound TLS connections
+    pub server_addr: SocketAddr,
     pub kem_algorithm: KemAlgorithm,
     pub signature_algorithm: SignatureAlgorithm,
     pub fallback_config: FallbackConfig,
+    /// Allow TLS 1.3 session resumption via tickets
+    pub session_resumption: bool,
+    /// Maximum number of sessions kept in the in-memory session store
+    pub session_cache_size: usize,
+    /// ALPN protocols offered to clients, in preference order
+    pub alpn_protocols: Vec<String>,
+    /// Pad proxied data to multiples of this many bytes to hide its length.
+    /// Only works between two SafeQuanta proxies, which agree on it via ALPN;
+    /// upstream connections then offer only the padding protocol
+    pub record_padding: Option<usize>,
+    /// Enabled cipher suites in preference order, e.g. `TLS13_AES_256_GCM_SHA384`;
+    /// empty means the rustls safe defaults
+    pub cipher_suites: Vec<String>,
+    /// Request client certificates after the handshake; unsupported by rustls,
+    /// so it must stay disabled
+    pub post_handshake_auth: bool,
+    /// Maximum TLS 1.3 early (0-RTT) data accepted per connection; 0 disables
+    /// early data. Requires `session_resumption`.
+    pub max_early_data_size: u32,
+    /// Algorithms refused for incident response, e.g. `Kyber768`, `Dilithium3`
+    /// or a TLS key exchange group such as `X25519Kyber768Draft00`
+    pub disabled_algorithms: Vec<String>,
+    /// Application-layer PQC client authentication after the TLS handshake
+    pub client_auth: ClientAuthConfig,
+    /// Refuse to start when the KEM and signature algorithm target different
+    /// NIST security levels
+    pub enforce_matched_security_level: bool,
+    /// DER CA certificates trusted for upstream TLS, in addition to the system
+    /// roots when `native_roots` is set
+    pub upstream_ca_certs: Vec<PathBuf>,
+    /// Trust the operating system's root store for upstream TLS; if it cannot
+    /// be read, only `upstream_ca_certs` are trusted
+    pub native_roots: bool,
+    /// Most certificates (leaf plus intermediates) an upstream may present;
+    /// longer chains are rejected before path building
+    pub max_chain_depth: usize,
+    /// Largest first handshake message (normally the ClientHello) a client may
+    /// announce; larger ones are refused before rustls buffers them
+    pub max_handshake_bytes: usize,
+    /// Second certificate presented to clients that cannot verify signatures
+    /// from the primary key, e.g. a classic certificate next to a PQC one
+    pub alternate_identity: Option<CertIdentity>,
+    /// YAML file of per-connection algorithm rules by client SNI and address,
+    /// reloaded when it changes. A client matching a rule is offered only the
+    /// rule's key exchange groups and certificates; clients matching none get
+    /// the server's usual settings
+    pub policy_path: Option<PathBuf>,
+    /// Set from the top-level `require_pqc`: offer only quantum-safe key
+    /// exchange groups, to clients and upstreams alike
+    #[serde(skip)]
+    pub require_pqc: bool,
+}
+
+/// PEM certificate chain (leaf first) and its private key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CertIdentity {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClientAuthConfig {
+    /// Require clients to sign a challenge with an allowed PQC key
+    pub enabled: bool,
+    /// Files holding the raw PQC public keys clients may authenticate with
+    pub allowed_client_keys: Vec<PathBuf>,
+    /// Seconds a client has to answer the challenge
+    pub timeout: u64,
+    /// PEM CA certificates; when set, clients must present a TLS certificate
+    /// issued by one of them
+    pub ca_certs: Vec<PathBuf>,
+    /// PEM CRL file, or a directory of them, checked for revoked client certificates
+    pub crls: Option<PathBuf>,
+}
+
+impl Default for ClientAuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allowed_client_keys: vec![],
+            timeout: 10,
+            ca_certs: vec![],
+            crls: None,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: PathBuf::from("certs/server.crt"),
+            key_path: PathBuf::from("certs/server.key"),
+            server_addr: SocketAddr::from(([127, 0, 0, 1], 443)),
+            kem_algorithm: KemAlgorithm::default(),
+            signature_algorithm: SignatureAlgorithm::default(),
+            fallback_config: FallbackConfig::default(),
+            session_resumption: true,
+            session_cache_size: 256,
+            alpn_protocols: vec![],
+            record_padding: None,
+            cipher_suites: vec![],
+            post_handshake_auth: false,
+            max_early_data_size: 0,
+            disabled_algorithms: vec![],
+            client_auth: ClientAuthConfig::default(),
+            enforce_matched_security_level: false,
+            upstream_ca_certs: vec![],
+            native_roots: true,
+            max_chain_depth: 8,
+            max_handshake_bytes: 32 * 1024,
+            alternate_identity: None,
+            policy_path: None,
+            require_pqc: false,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl TlsConfig {
+    /// Whether `name` matches a `disabled_algorithms` entry; hybrid groups are
+    /// disabled along with the algorithms they contain
+    pub fn algorithm_disabled(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.disabled_algorithms
+            .iter()
+            .any(|disabled| name.contains(&disabled.to_ascii_lowercase()))
+    }
+
+    /// Refuse a configured KEM or signature algorithm that has been disabled
+    pub fn ensure_algorithms_enabled(&self) -> Result<(), SafeQuantaError> {
+        let kem = format!("{:?}", self.kem_algorithm);
+        let signature = format!("{:?}", self.signature_algorithm);
+        for name in [kem, signature] {
+            if self.algorithm_disabled(&name) {
+                crate::metrics::record_disabled_algorithm_rejection(&name);
+                return Err(SafeQuantaError::AlgorithmDisabled(name));
+            }
+        }
+        Ok(())
+    }
+
+    /// With `enforce_matched_security_level`, refuse a KEM and signature
+    /// algorithm at different NIST security levels
+    pub fn ensure_matched_security_level(&self) -> Result<(), SafeQuantaError> {
+        let kem_level = self.kem_algorithm.nist_level();
+        let signature_level = self.signature_algorithm.nist_level();
+        if self.enforce_matched_security_level && kem_level != signature_level {
+            return Err(SafeQuantaError::InvalidConfig(format!(
+                "{:?} targets NIST level {} but {:?} targets level {}",
+                self.kem_algorithm, kem_level, self.signature_algorithm, signature_level
+            )));
+        }
+        Ok(())
+    }
+
+    /// With `require_pqc`, refuse certificates that are not post-quantum
+    pub fn ensure_pqc_certificate(&self) -> Result<(), SafeQuantaError> {
+        if !self.require_pqc {
+            return Ok(());
+        }
+        if self.signature_algorithm == SignatureAlgorithm::Rsa3072 {
+            return Err(SafeQuantaError::InvalidConfig(format!(
+                "require_pqc is set but the certificate uses classical {:?}",
+                self.signature_algorithm
+            )));
+        }
+        if self.alternate_identity.is_some() {
+            return Err(SafeQuantaError::InvalidConfig(
+                "require_pqc is set but alternate_identity presents a second certificate".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
 pub enum KemAlgorithm {
+    #[default]
     Kyber768,
     Kyber1024,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl KemAlgorithm {
+    /// Every KEM this build supports
+    pub const ALL: [KemAlgorithm; 2] = [KemAlgorithm::Kyber768, KemAlgorithm::Kyber1024];
+
+    /// NIST post-quantum security category
+    pub fn nist_level(&self) -> u8 {
+        match self {
+            KemAlgorithm::Kyber768 => 3,
+            KemAlgorithm::Kyber1024 => 5,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
 pub enum SignatureAlgorithm {
+    #[default]
     Dilithium3,
     Rsa3072,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl SignatureAlgorithm {
+    /// Every signature algorithm this build supports
+    pub const ALL: [SignatureAlgorithm; 2] =
+        [SignatureAlgorithm::Dilithium3, SignatureAlgorithm::Rsa3072];
+
+    /// NIST post-quantum security category
+    ///
+    /// RSA-3072 is rated at the classical 128-bit level (category 1) even
+    /// though it offers no protection against a quantum attacker.
+    pub fn nist_level(&self) -> u8 {
+        match self {
+            SignatureAlgorithm::Dilithium3 => 3,
+            SignatureAlgorithm::Rsa3072 => 1,
+        }
+    }
+}
+
+/// Classic TLS fallback; disabled by default so clients must negotiate PQC
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct FallbackConfig {
     pub enabled: bool,
     pub strategy: FallbackStrategy,
     pub non_pqc_port: Option<u16>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
 pub enum FallbackStrategy {
+    #[default]
     Reject,
     Redirect,
     ClassicTls,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct MetricsConfig {
     pub enabled: bool,
     pub host: String,
     pub port: u16,
+    pub exporter: MetricsExporter,
+    /// Metric name prefix, used by the StatsD exporter
+    pub prefix: Option<String>,
+    /// Credentials required to scrape the Prometheus endpoint
+    pub auth: Option<MetricsAuth>,
+    /// Serve the signed PQC public-key bundle as JSON on `/pqc-keys`
+    pub serve_pqc_keys: bool,
+}
+
+/// Credentials checked against the `Authorization` header of metrics requests
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum MetricsAuth {
+    /// `Authorization: Bearer <token>`
+    Bearer { token: String },
+    /// `Authorization: Basic <base64(username:password)>`
+    Basic { username: String, password: String },
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port: 9090,
+            exporter: MetricsExporter::default(),
+            prefix: None,
+            auth: None,
+            serve_pqc_keys: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetricsExporter {
+    /// Serve `/metrics` over HTTP on `host:port`
+    #[default]
+    Prometheus,
+    /// Push to a StatsD agent over UDP at `host:port`
+    Statsd,
+    /// Record nothing
+    Noop,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct ProxyConfig {
     pub mode: ProxyMode,
+    /// Default upstream for L7 requests that match no route
     pub upstream: String,
+    /// Timeout in seconds
     pub timeout: u64,
+    /// Milliseconds allowed for the TCP connect to an upstream, kept short so a
+    /// blackholed upstream fails fast
+    pub connect_timeout_ms: u64,
+    pub listen_addr: SocketAddr,
+    /// `IPV6_V6ONLY` for an IPv6 `listen_addr`: false accepts IPv4 clients as
+    /// v4-mapped addresses too. Unset keeps the OS default
+    pub ipv6_only: Option<bool>,
+    /// Default upstream for L4 connections that match no route
+    pub target_addr: SocketAddr,
+    pub target_host: String,
+    /// Upstreams sharing the connections (L7: requests) that match no route in
+    /// proportion to their weights, in place of `target_addr` (L7:
+    /// `upstream`). Weight 0 takes none
+    pub weighted_targets: Vec<WeightedTarget>,
+    /// Whether L4 upstreams are spoken to over TLS. `Auto` probes each
+    /// upstream once and caches the result; a network attacker present at
+    /// that moment could force plaintext, so it is never the default
+    pub upstream_tls: UpstreamTls,
+    pub max_connections: usize,
+    /// How long a connection beyond `max_connections` waits for a free slot
+    /// before it is rejected. Unset, L4 connections wait indefinitely and L7
+    /// connections are rejected straight away
+    pub queue_timeout_ms: Option<u64>,
+    /// Connections that may wait for a slot at once; further ones are
+    /// rejected without waiting
+    pub max_queue_depth: Option<usize>,
+    /// Connections relayed to any one upstream at once. Refused in L7 mode,
+    /// where requests share pooled upstream connections
+    pub max_connections_per_upstream: Option<usize>,
+    /// What happens to a connection whose upstream is at
+    /// `max_connections_per_upstream`
+    pub upstream_saturated_action: SaturatedAction,
+    /// Global cap on new connections accepted per second
+    pub max_accepts_per_sec: Option<u32>,
+    /// What happens to connections beyond `max_accepts_per_sec`
+    pub accept_rate_mode: AcceptRateMode,
+    /// TLS handshakes (including client authentication) in progress at once;
+    /// further handshakes wait for a slot, and their handshake timeout
+    /// starts once they get one
+    pub max_concurrent_handshakes: Option<usize>,
+    /// Close a connection once this many bytes have been transferred in total
+    pub max_total_bytes: Option<u64>,
+    /// Rotate a client connection's TLS traffic keys each time this many bytes
+    /// have passed through it, for AEAD usage limits on long-lived connections
+    pub rekey_after_bytes: Option<u64>,
+    /// What happens when `rekey_after_bytes` is reached
+    pub rekey_action: RekeyAction,
+    /// Close a client connection that sends more than this many TLS 1.3
+    /// `KeyUpdate`s. rustls on its own only refuses 32 in a row without
+    /// application data in between
+    pub max_key_updates: Option<u64>,
+    /// Bytes of relay copy buffers a connection may hold; a connection that
+    /// would need more is closed. TLS, socket and HTTP buffers are not
+    /// counted, so this does not bound a connection's total memory
+    pub max_relay_buffer_bytes: Option<usize>,
+    /// How relayed connections copy data between client and upstream
+    pub relay_strategy: RelayStrategy,
+    /// Local address outbound upstream connections are bound to
+    pub bind_addr: Option<SocketAddr>,
+    /// DSCP code point (0-63) marked on upstream connections, and on client
+    /// connections in L4 mode
+    pub dscp: Option<u8>,
+    /// Send the client's SNI instead of `target_host` in the handshake with
+    /// the default L4 upstream; routes set their own `forward_sni`
+    pub forward_sni: bool,
+    /// Close a connection this many seconds after it was accepted, even if active
+    pub max_connection_lifetime_secs: Option<u64>,
+    pub routes: Vec<RouteConfig>,
+    /// What happens to a connection or L7 request whose SNI or `Host` matches
+    /// no route
+    pub unmatched_route: UnmatchedRoute,
+    /// Accept HTTP `CONNECT` in L7 mode and tunnel to the requested target
+    pub forward_proxy: bool,
+    /// `CONNECT` targets permitted in forward proxy mode: `host`, `host:port`
+    /// or `*.domain` entries
+    pub connect_allow_list: Vec<String>,
+    /// Upstream that receives a copy of every L7 request; its responses are discarded
+    pub shadow_upstream: Option<String>,
+    /// Streams an HTTP/2 client may have open at once on one connection;
+    /// further streams are refused with `REFUSED_STREAM`
+    pub max_concurrent_streams: u32,
+    /// HTTP/1.1 requests served on one keep-alive client connection; the last
+    /// response carries `Connection: close` and the connection is then closed
+    pub max_requests_per_connection: Option<usize>,
+    /// Resend an idempotent, bodiless HTTP/1.1 request once, to another
+    /// weighted target, when its upstream resets or closes before any
+    /// response bytes. Requests that matched a route, whose route has no
+    /// other upstream, get `upstream_error` instead, as does a reset with no
+    /// other target available. A reset after the response started is not
+    /// retried
+    pub retry_on_upstream_reset: bool,
+    /// Hold back each HTTP/1.1 response until its headers are in, retrying
+    /// requests the upstream fails before then; takes precedence over
+    /// `retry_on_upstream_reset`
+    pub warm_upstream: Option<WarmUpstreamConfig>,
+    /// Request header in which L7 clients send their deadline in milliseconds;
+    /// `grpc-timeout` is always honored. The upstream response is awaited for
+    /// the time remaining, capped at the idle timeout.
+    pub deadline_header: Option<String>,
+    /// gRPC methods, e.g. `/helloworld.Greeter/SayHello`, labeled by name in
+    /// `grpc_requests_total`; calls to any other method count as `other`, so
+    /// clients cannot add series at will
+    pub grpc_methods: Vec<String>,
+    /// Request id header, e.g. `X-Request-Id`: an inbound id is passed upstream
+    /// and echoed, otherwise one is generated. Responses then also carry
+    /// `X-Connection-Id` and each request is written to the access log.
+    pub request_id_header: Option<String>,
+    /// Idle keep-alive connections kept per L7 upstream
+    pub pool_max_idle: usize,
+    /// Seconds an idle pooled upstream connection is kept before being dropped
+    pub pool_idle_timeout: u64,
+    /// Responses sent to L7 clients when a request cannot be proxied
+    pub error_responses: ErrorResponses,
+    /// `X-Forwarded-*` / `Forwarded` handling in L7 mode
+    pub forwarded: ForwardedConfig,
+    /// Handshake with every TLS upstream at startup to check it negotiates a
+    /// quantum-safe key exchange group
+    pub probe_upstreams_on_start: bool,
+    /// What happens when an upstream fails the startup probe
+    pub upstream_probe_action: ProbeAction,
+    /// Set from the top-level `require_pqc`: refuse upstreams reached
+    /// without TLS
+    #[serde(skip)]
+    pub require_pqc: bool,
+    /// Add `X-SafeQuanta-KEM` / `X-SafeQuanta-Sig` headers naming the client
+    /// connection's negotiated algorithms to L7 responses
+    pub expose_negotiated_algorithms: bool,
+    /// Write a JSON lines trace of connections from selected client addresses
+    pub connection_trace: Option<ConnectionTraceConfig>,
+    /// File L7 requests are recorded to for later replay, with
+    /// `Authorization`, `Cookie` and `Proxy-Authorization` values redacted
+    pub record_requests: Option<String>,
+    /// Record request bodies, up to 64 KiB each, as well as their SHA-256
+    pub record_request_bodies: bool,
+    /// Connection error lines logged per second at most; the rest are counted
+    /// and reported in a summary line
+    pub max_error_logs_per_sec: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConnectionTraceConfig {
+    /// Trace from startup; otherwise only once enabled with
+    /// `POST /trace/enable` on the metrics listener
+    pub enabled: bool,
+    /// File trace records are appended to
+    pub path: PathBuf,
+    /// Client addresses whose connections are traced
+    pub source_ips: Vec<IpAddr>,
+    /// Stop writing once the file has grown to this many bytes
+    pub max_bytes: u64,
+}
+
+impl Default for ConnectionTraceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: PathBuf::from("connection-trace.jsonl"),
+            source_ips: vec![],
+            max_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// L7 "warm upstream" mode
+///
+/// Nothing reaches the client until the upstream's response headers have
+/// arrived in full. Until then an idempotent bodiless request whose upstream
+/// refuses the connection, resets or closes it, or sends a malformed response
+/// is resent on a new connection: to another weighted target if there is one,
+/// otherwise to the same upstream.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct WarmUpstreamConfig {
+    /// Largest upstream response header block buffered; a larger one fails
+    /// with `upstream_error` and is not retried, as the upstream would send
+    /// it again. Values below 8 KiB are raised to 8 KiB
+    pub max_header_bytes: usize,
+    /// Further attempts for a request failed before its response headers
+    pub retries: u32,
+}
+
+impl Default for WarmUpstreamConfig {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 64 * 1024,
+            retries: 2,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ForwardedConfig {
+    /// Add the client address to `X-Forwarded-For` and `Forwarded`;
+    /// `X-Forwarded-Proto` is set either way
+    pub enabled: bool,
+    /// Number of proxies in front of this one whose inbound forwarding headers
+    /// are trusted; with 0 inbound values are stripped
+    pub trusted_hops: usize,
+}
+
+impl Default for ForwardedConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trusted_hops: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ErrorResponses {
+    /// Sent when the proxy is at its connection limit
+    pub capacity: ErrorResponse,
+    /// Sent when the upstream cannot be reached or fails
+    pub upstream_error: ErrorResponse,
+    /// Sent when waiting on the upstream times out
+    pub timeout: ErrorResponse,
+    /// Sent when `Host` matches no route and `unmatched_route` is `Reject`
+    pub no_route: ErrorResponse,
+}
+
+impl Default for ErrorResponses {
+    fn default() -> Self {
+        Self {
+            capacity: ErrorResponse {
+                status: 503,
+                retry_after: Some(5),
+                body: "Service temporarily over capacity\n".to_string(),
+            },
+            upstream_error: ErrorResponse {
+                status: 502,
+                retry_after: None,
+                body: "Bad gateway\n".to_string(),
+            },
+            timeout: ErrorResponse {
+                status: 504,
+                retry_after: None,
+                body: "Gateway timeout\n".to_string(),
+            },
+            no_route: ErrorResponse {
+                status: 421,
+                retry_after: None,
+                body: "Misdirected request\n".to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ErrorResponse {
+    pub status: u16,
+    /// `Retry-After` value in seconds
+    pub retry_after: Option<u64>,
+    pub body: String,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            mode: ProxyMode::default(),
+            upstream: "http://127.0.0.1:8080".to_string(),
+            timeout: 30,
+            connect_timeout_ms: 5000,
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 8443)),
+            ipv6_only: None,
+            target_addr: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            target_host: "localhost".to_string(),
+            weighted_targets: Vec::new(),
+            upstream_tls: UpstreamTls::default(),
+            max_connections: 1000,
+            queue_timeout_ms: None,
+            max_queue_depth: None,
+            max_connections_per_upstream: None,
+            upstream_saturated_action: SaturatedAction::default(),
+            max_accepts_per_sec: None,
+            accept_rate_mode: AcceptRateMode::default(),
+            max_concurrent_handshakes: None,
+            max_total_bytes: None,
+            rekey_after_bytes: None,
+            rekey_action: RekeyAction::default(),
+            max_key_updates: None,
+            max_relay_buffer_bytes: None,
+            relay_strategy: RelayStrategy::default(),
+            bind_addr: None,
+            dscp: None,
+            forward_sni: false,
+            max_connection_lifetime_secs: None,
+            routes: vec![],
+            unmatched_route: UnmatchedRoute::default(),
+            forward_proxy: false,
+            connect_allow_list: vec![],
+            shadow_upstream: None,
+            max_concurrent_streams: 100,
+            max_requests_per_connection: None,
+            retry_on_upstream_reset: false,
+            warm_upstream: None,
+            deadline_header: None,
+            grpc_methods: vec![],
+            request_id_header: None,
+            pool_max_idle: 8,
+            pool_idle_timeout: 90,
+            error_responses: ErrorResponses::default(),
+            forwarded: ForwardedConfig::default(),
+            probe_upstreams_on_start: false,
+            upstream_probe_action: ProbeAction::default(),
+            require_pqc: false,
+            expose_negotiated_algorithms: false,
+            connection_trace: None,
+            record_requests: None,
+            record_request_bodies: false,
+            max_error_logs_per_sec: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RekeyAction {
+    /// Send a TLS 1.3 `KeyUpdate`; connections that cannot (TLS 1.2) are
+    /// closed instead
+    #[default]
+    KeyUpdate,
+    /// Close the connection with a `close_notify`
+    Close,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnmatchedRoute {
+    /// Send it to the catch-all upstream: `target_addr` in L4 and passthrough
+    /// mode, `upstream` in L7 mode
+    #[default]
+    Default,
+    /// Refuse it: L4 and passthrough clients get an `unrecognized_name` alert,
+    /// L7 clients the `no_route` error response. With no routes configured,
+    /// everything is refused.
+    Reject,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProbeAction {
+    /// Log a warning and keep starting
+    #[default]
+    Warn,
+    /// Refuse to start
+    Fail,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AcceptRateMode {
+    /// Leave excess connections in the listen backlog until a token is free
+    #[default]
+    Delay,
+    /// Accept and immediately close excess connections
+    Reject,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpstreamTls {
+    #[default]
+    On,
+    /// Plaintext TCP to the upstream
+    Off,
+    /// TLS if the upstream completes a handshake, plaintext otherwise
+    Auto,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaturatedAction {
+    /// Close the connection
+    #[default]
+    Reject,
+    /// Relay an unrouted connection to another weighted target below its
+    /// limit; routed connections, and those with nowhere to go, are closed
+    Failover,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelayStrategy {
+    /// A future per direction; the connection ends when either finishes, and
+    /// each direction has its own idle timeout
+    #[default]
+    Split,
+    /// One loop selecting over both reads; the connection is idle only when
+    /// neither side sends, and an EOF is passed on to the other peer
+    SingleTask,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ProxyMode {
+    #[default]
     Layer4,
     Layer7,
+    /// Route on the ClientHello's SNI and relay the TLS stream without
+    /// terminating it
+    Passthrough,
+}
+
+/// One of `weighted_targets`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct WeightedTarget {
+    /// `host:port` of the upstream, or in L7 mode a URL as for `upstream`
+    pub addr: String,
+    pub weight: u32,
+}
+
+/// Upstream selected by the client's server name
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteConfig {
+    /// SNI server name this route matches
+    pub server_name: String,
+    pub upstream: String,
+    #[serde(default)]
+    pub timeouts: TimeoutOverrides,
+    /// Forward idempotent L7 requests received as TLS early data
+    #[serde(default)]
+    pub allow_early_data: bool,
+    /// Local address connections to this upstream are bound to, overriding
+    /// the global `bind_addr`
+    #[serde(default)]
+    pub bind_addr: Option<SocketAddr>,
+    /// DSCP code point for this route's connections, overriding the global `dscp`
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// HTML page served with `503` in L7 mode while the upstream is unreachable
+    #[serde(default)]
+    pub maintenance_page: Option<PathBuf>,
+    /// Active check deciding whether the upstream receives connections
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    /// Request header changes applied before forwarding in L7 mode
+    #[serde(default)]
+    pub header_rules: HeaderRules,
+    /// Send the client's SNI in the upstream handshake instead of `server_name`
+    #[serde(default)]
+    pub forward_sni: bool,
+}
+
+/// Per-route request header rewriting
+///
+/// Applied in a fixed order: `remove`, then `rename`, then `set`. Names are
+/// case-insensitive.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HeaderRules {
+    /// Headers dropped from the request
+    pub remove: Vec<String>,
+    /// Headers moved to a new name, replacing any values already there, in
+    /// list order
+    pub rename: Vec<HeaderRename>,
+    /// Headers set to a value, replacing any existing values
+    pub set: Vec<HeaderValueRule>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HeaderRename {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HeaderValueRule {
+    pub name: String,
+    pub value: String,
+}
+
+/// HAProxy style send/expect health check
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// Seconds between checks, also the time allowed for one check
+    pub interval: u64,
+    /// Written after connecting, e.g. `"GET /health HTTP/1.0\r\n\r\n"`;
+    /// empty sends nothing
+    pub send: String,
+    /// Substring the response must contain; empty only requires the connect
+    /// to succeed
+    pub expect: String,
+    /// Consecutive failed checks before the upstream is ejected
+    pub fall: u32,
+    /// Consecutive passed checks before an ejected upstream is used again
+    pub rise: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            send: String::new(),
+            expect: String::new(),
+            fall: 3,
+            rise: 2,
+        }
+    }
+}
+
+/// Per-route timeout overrides, in seconds
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TimeoutOverrides {
+    pub connect: Option<u64>,
+    pub idle: Option<u64>,
+    pub total: Option<u64>,
+}
+
+/// Resolved timeouts for a single connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// TCP connect to the upstream
+    pub connect: std::time::Duration,
+    /// TLS handshake with the upstream
+    pub handshake: std::time::Duration,
+    pub idle: std::time::Duration,
+    pub total: Option<std::time::Duration>,
+}
+
+impl ProxyConfig {
+    /// Find the route matching `server_name`, if any
+    pub fn route_for(&self, server_name: Option<&str>) -> Option<&RouteConfig> {
+        let server_name = server_name?;
+        self.routes
+            .iter()
+            .find(|route| route.server_name.eq_ignore_ascii_case(server_name))
+    }
+
+    /// Whether a connection or request that matched `route` is refused
+    /// because it matched none and `unmatched_route` is `Reject`
+    pub fn rejects_unmatched(&self, route: Option<&RouteConfig>) -> bool {
+        route.is_none() && self.unmatched_route == UnmatchedRoute::Reject
+    }
+
+    /// Local address for connections to the upstream of `route`
+    pub fn bind_addr_for(&self, route: Option<&RouteConfig>) -> Option<SocketAddr> {
+        route.and_then(|route| route.bind_addr).or(self.bind_addr)
+    }
+
+    /// DSCP code point for connections of `route`
+    pub fn dscp_for(&self, route: Option<&RouteConfig>) -> Option<u8> {
+        route.and_then(|route| route.dscp).or(self.dscp)
+    }
+
+    /// Whether the upstream handshake for `route` carries the client's SNI
+    pub fn forward_sni_for(&self, route: Option<&RouteConfig>) -> bool {
+        route.map_or(self.forward_sni, |route| route.forward_sni)
+    }
+
+    /// Refuse `dscp` values that do not fit the 6-bit DSCP field
+    pub fn ensure_valid_dscp(&self) -> Result<(), SafeQuantaError> {
+        let routes = self.routes.iter().map(|route| (route.dscp, route.server_name.as_str()));
+        for (dscp, scope) in std::iter::once((self.dscp, "proxy")).chain(routes) {
+            if let Some(dscp) = dscp.filter(|&dscp| dscp > 63) {
+                return Err(SafeQuantaError::InvalidConfig(format!(
+                    "dscp {} for {} is outside 0-63",
+                    dscp, scope
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse `max_connections_per_upstream` in L7 mode, where pooled
+    /// connections are shared between requests and the cap is never checked
+    pub fn ensure_upstream_limit_supported(&self) -> Result<(), SafeQuantaError> {
+        match (self.mode, self.max_connections_per_upstream) {
+            (ProxyMode::Layer7, Some(_)) => Err(SafeQuantaError::InvalidConfig(
+                "max_connections_per_upstream is not supported in Layer7 mode".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse `header_rules` naming an invalid header or setting an invalid value
+    pub fn ensure_valid_header_rules(&self) -> Result<(), SafeQuantaError> {
+        for route in &self.routes {
+            let invalid = |what: String| {
+                SafeQuantaError::InvalidConfig(format!(
+                    "header_rules for {}: {}",
+                    route.server_name, what
+                ))
+            };
+            let rules = &route.header_rules;
+            let names = rules
+                .remove
+                .iter()
+                .chain(rules.rename.iter().flat_map(|rename| [&rename.from, &rename.to]))
+                .chain(rules.set.iter().map(|set| &set.name));
+            for name in names {
+                if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    return Err(invalid(format!("invalid header name {:?}", name)));
+                }
+            }
+            for set in &rules.set {
+                if http::HeaderValue::from_str(&set.value).is_err() {
+                    return Err(invalid(format!("invalid {} value {:?}", set.name, set.value)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `(address, server name)` of every upstream reached over TLS
+    ///
+    /// In L4 and passthrough mode these are the default target and every
+    /// route; in L7 mode only `https://` upstreams.
+    pub fn tls_upstreams(&self) -> Vec<(String, String)> {
+        match self.mode {
+            ProxyMode::Layer4 | ProxyMode::Passthrough => self
+                .default_targets()
+                .into_iter()
+                .map(|target| (target, self.target_host.clone()))
+                .chain(self.routes.iter().map(|r| (r.upstream.clone(), r.server_name.clone())))
+                .collect(),
+            ProxyMode::Layer7 => self
+                .default_targets()
+                .into_iter()
+                .chain(self.routes.iter().map(|r| r.upstream.clone()))
+                .filter_map(|upstream| {
+                    let uri: http::Uri = upstream.parse().ok()?;
+                    if uri.scheme_str() != Some("https") {
+                        return None;
+                    }
+                    let host = uri.host()?.to_string();
+                    Some((format!("{}:{}", host, uri.port_u16().unwrap_or(443)), host))
+                })
+                .collect(),
+        }
+    }
+
+    /// With `require_pqc`, refuse upstreams that are not spoken to over TLS
+    ///
+    /// `tls_upstreams` leaves these out, so the startup probe would never
+    /// see them.
+    pub fn ensure_pqc_upstreams(&self) -> Result<(), SafeQuantaError> {
+        if !self.require_pqc {
+            return Ok(());
+        }
+        let plaintext = match self.mode {
+            ProxyMode::Layer4 => (self.upstream_tls == UpstreamTls::Off).then(|| self.target_addr.to_string()),
+            // The client's own TLS session reaches the upstream untouched
+            ProxyMode::Passthrough => None,
+            ProxyMode::Layer7 => self.upstreams().into_iter().find(|upstream| {
+                upstream.parse::<http::Uri>().map_or(true, |uri| uri.scheme_str() != Some("https"))
+            }),
+        };
+        match plaintext {
+            Some(upstream) => Err(SafeQuantaError::InvalidConfig(format!(
+                "require_pqc is set but upstream {} is not reached over TLS",
+                upstream
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Upstreams for connections (L7: requests) that match no route: the
+    /// weighted targets if any, otherwise `target_addr` (L7: `upstream`)
+    pub fn default_targets(&self) -> Vec<String> {
+        if !self.weighted_targets.is_empty() {
+            return self.weighted_targets.iter().map(|target| target.addr.clone()).collect();
+        }
+        match self.mode {
+            ProxyMode::Layer4 | ProxyMode::Passthrough => vec![self.target_addr.to_string()],
+            ProxyMode::Layer7 => vec![self.upstream.clone()],
+        }
+    }
+
+    /// Every upstream connections may go to: the default ones, then each
+    /// route's, without duplicates
+    pub fn upstreams(&self) -> Vec<String> {
+        let mut upstreams = self.default_targets();
+        for route in &self.routes {
+            if !upstreams.contains(&route.upstream) {
+                upstreams.push(route.upstream.clone());
+            }
+        }
+        upstreams
+    }
+
+    /// Whether a `CONNECT` to `authority` (`host:port`) is on the allow-list
+    pub fn connect_allowed(&self, authority: &str) -> bool {
+        let host = authority
+            .rsplit_once(':')
+            .map_or(authority, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+
+        self.connect_allow_list.iter().any(|entry| {
+            if let Some(domain) = entry.strip_prefix("*.") {
+                host.len() > domain.len()
+                    && host.to_ascii_lowercase().ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+            } else {
+                entry.eq_ignore_ascii_case(authority) || entry.eq_ignore_ascii_case(host)
+            }
+        })
+    }
+
+    /// Resolve the timeouts for `route`
+    ///
+    /// The connect timeout falls back to `connect_timeout_ms` and the idle
+    /// timeout to the global `timeout`; the total connection lifetime is
+    /// unbounded unless the route sets it. Routes cannot override the upstream
+    /// handshake timeout, which is always `timeout`.
+    pub fn timeouts_for(&self, route: Option<&RouteConfig>) -> Timeouts {
+        let overrides = route.map(|r| r.timeouts.clone()).unwrap_or_default();
+        let secs = std::time::Duration::from_secs;
+        Timeouts {
+            connect: overrides
+                .connect
+                .map_or(std::time::Duration::from_millis(self.connect_timeout_ms), secs),
+            handshake: secs(self.timeout),
+            idle: secs(overrides.idle.unwrap_or(self.timeout)),
+            total: overrides.total.map(secs),
+        }
+    }
 }
 
 impl Config {
     pub fn load() -> anyhow::Result<Self> {
+        Self::load_with_decryptor(&NoopDecryptor)
+    }
+
+    /// Proxy and TLS settings of every listener, the top-level one first
+    pub fn listener_configs(&self) -> Vec<(ProxyConfig, TlsConfig)> {
+        std::iter::once((self.proxy.clone(), self.tls.clone()))
+            .chain(self.listeners.iter().map(|listener| {
+                let tls = listener.tls.clone().unwrap_or_else(|| self.tls.clone());
+                (listener.proxy.clone(), tls)
+            }))
+            .collect()
+    }
+
+    /// The default configuration as YAML, with a comment above each documented field
+    pub fn annotated_default() -> anyhow::Result<String> {
+        let yaml = serde_yaml::to_string(&Config::default())?;
+        let mut annotated = String::from("# SafeQuanta TLS configuration\n");
+        let mut path: Vec<(usize, &str)> = Vec::new();
+
+        for line in yaml.lines() {
+            let indent = line.len() - line.trim_start().len();
+            if let Some((key, _)) = line.trim_start().split_once(':') {
+                if !key.starts_with('-') {
+                    path.retain(|&(depth, _)| depth < indent);
+                    path.push((indent, key));
+                    let field = path.iter().map(|&(_, key)| key).collect::<Vec<_>>().join(".");
+
+                    if indent == 0 {
+                        annotated.push('\n');
+                    }
+                    if let Some((_, doc)) = FIELD_DOCS.iter().find(|(name, _)| *name == field) {
+                        annotated.push_str(&format!("{}# {}\n", &line[..indent], doc));
+                    }
+                }
+            }
+            annotated.push_str(line);
+            annotated.push('\n');
+        }
+
+        Ok(annotated)
+    }
+
+    /// Load the configuration, decrypting any `enc:` values with `decryptor`
+    pub fn load_with_decryptor(decryptor: &dyn ConfigDecryptor) -> anyhow::Result<Self> {
         let config_path = std::env::var("CONFIG_PATH")
             .unwrap_or_else(|_| "config/default.yaml".to_string());
 
-        let config = config::Config::builder()
-            .add_source(config::File::with_name(&config_path))
-            .add_source(config::Environment::with_prefix("SAFEQUANTA"))
+        Self::load_from_path(&config_path, decryptor)
+    }
+
+    /// Load the configuration from `path`; missing fields take their defaults
+    ///
+    /// If `path` is a directory, every `*.yaml`/`*.yml`/`*.toml` file in it is
+    /// merged in file name order, later files overriding earlier ones.
+    pub fn load_from_path(path: &str, decryptor: &dyn ConfigDecryptor) -> anyhow::Result<Self> {
+        Self::load_from_path_with_env(path, decryptor, None)
+    }
+
+    /// Like `load_from_path`, reading `SAFEQUANTA__*` overrides from `env`
+    /// instead of the process environment when it is given
+    pub fn load_from_path_with_env(
+        path: &str,
+        decryptor: &dyn ConfigDecryptor,
+        env: Option<config::Map<String, String>>,
+    ) -> anyhow::Result<Self> {
+        let mut builder = config::Config::builder();
+        if std::path::Path::new(path).is_dir() {
+            for fragment in config_fragments(path)? {
+                builder = builder.add_source(config::File::from(fragment));
+            }
+        } else {
+            builder = builder.add_source(config::File::with_name(path));
+        }
+        let config = builder
+            .add_source(env_overrides(env))
             .build()?;
 
-        Ok(config.try_deserialize()?)
+        // Decrypted in the config crate's own value tree, which still
+        // coerces strings such as environment overrides into numbers
+        let mut value: config::Value = config.try_deserialize()?;
+        let mut decrypted_values = Vec::new();
+        decrypt_values(&mut value, decryptor, &mut decrypted_values)?;
+
+        let mut config: Config = value.try_deserialize()?;
+        config.decrypted_values = decrypted_values;
+        config.apply_require_pqc();
+        for (proxy, _) in config.listener_configs() {
+            proxy.ensure_valid_dscp()?;
+            proxy.ensure_valid_header_rules()?;
+            proxy.ensure_upstream_limit_supported()?;
+            proxy.ensure_pqc_upstreams()?;
+        }
+        Ok(config)
+    }
+
+    /// Push the top-level `require_pqc` switch down into every listener
+    ///
+    /// TLS sections offer only quantum-safe groups and lose classic fallback;
+    /// proxy sections probe their TLS upstreams at startup and fail on any
+    /// that doesn't negotiate PQC.
+    pub fn apply_require_pqc(&mut self) {
+        if !self.require_pqc {
+            return;
+        }
+        let tls_sections = std::iter::once(&mut self.tls)
+            .chain(self.listeners.iter_mut().filter_map(|listener| listener.tls.as_mut()));
+        for tls in tls_sections {
+            if tls.fallback_config.enabled {
+                log::warn!("require_pqc overrides tls.fallback_config.enabled");
+            }
+            tls.fallback_config.enabled = false;
+            tls.require_pqc = true;
+        }
+        let proxy_sections = std::iter::once(&mut self.proxy)
+            .chain(self.listeners.iter_mut().map(|listener| &mut listener.proxy));
+        for proxy in proxy_sections {
+            if proxy.upstream_tls == UpstreamTls::Auto {
+                log::warn!("require_pqc overrides proxy.upstream_tls: Auto with On");
+                proxy.upstream_tls = UpstreamTls::On;
+            }
+            proxy.probe_upstreams_on_start = true;
+            proxy.upstream_probe_action = ProbeAction::Fail;
+            proxy.require_pqc = true;
+        }
+    }
+
+    /// The configuration as JSON with secrets replaced by `"<redacted>"`
+    ///
+    /// Any field whose name has a `passphrase`, `password`, `token`, `secret`
+    /// or `pin` part is redacted, wherever it is nested, and so is any value
+    /// that was decrypted from an `enc:` value.
+    pub fn redacted(&self) -> anyhow::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        redact_values(&mut value, &self.decrypted_values);
+        Ok(value)
+    }
+}
+
+/// Field name parts marking a value `Config::redacted` hides
+const SENSITIVE_FIELD_PARTS: &[&str] = &["passphrase", "password", "token", "secret", "pin", "pins"];
+
+fn redact_values(value: &mut serde_json::Value, decrypted: &[String]) {
+    let redacted = || serde_json::Value::String("<redacted>".to_string());
+    match value {
+        serde_json::Value::String(s) if decrypted.contains(s) => *value = redacted(),
+        // A decrypted value may have been deserialized into a number
+        serde_json::Value::Number(n) if decrypted.contains(&n.to_string()) => *value = redacted(),
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(|item| redact_values(item, decrypted))
+        }
+        serde_json::Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                let sensitive = key
+                    .to_ascii_lowercase()
+                    .split('_')
+                    .any(|part| SENSITIVE_FIELD_PARTS.contains(&part));
+                if sensitive && !item.is_null() {
+                    *item = redacted();
+                } else {
+                    redact_values(item, decrypted);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Comments written above each field by `Config::annotated_default`
+const FIELD_DOCS: &[(&str, &str)] = &[
+    ("require_pqc", "Strict mode: PQC end to end, no fallback, fail on classic or plaintext upstreams"),
+    ("server", "General server settings"),
+    ("server.log_file", "Write the log to this file instead of stderr"),
+    ("server.log_rotation", "Rotate the log file: daily (midnight UTC) or size:<N>mb"),
+    ("tls", "TLS and post-quantum cryptography"),
+    ("tls.cert_path", "PEM certificate chain presented to clients (leaf first)"),
+    ("tls.key_path", "PEM private key for the certificate"),
+    ("tls.server_addr", "Upstream address used for outbound TLS connections"),
+    ("tls.kem_algorithm", "Post-quantum KEM: Kyber768 or Kyber1024"),
+    ("tls.signature_algorithm", "Signature algorithm: Dilithium3 or Rsa3072"),
+    ("tls.fallback_config", "Classic TLS fallback for clients without PQC support"),
+    ("tls.fallback_config.strategy", "Reject, Redirect or ClassicTls"),
+    ("tls.session_resumption", "Allow TLS 1.3 session resumption"),
+    ("tls.session_cache_size", "Maximum number of sessions kept in memory"),
+    ("tls.alpn_protocols", "ALPN protocols offered to clients, in preference order"),
+    ("tls.record_padding", "Pad proxied data to multiples of this size (proxy-to-proxy only)"),
+    ("tls.cipher_suites", "Enabled cipher suites in preference order; empty = safe defaults"),
+    ("tls.post_handshake_auth", "Unsupported; must stay false"),
+    ("tls.disabled_algorithms", "Algorithms refused at startup and during handshakes"),
+    ("tls.client_auth", "Require clients to sign a challenge with an allowed PQC key"),
+    ("tls.client_auth.allowed_client_keys", "Files holding raw PQC public keys of allowed clients"),
+    ("tls.client_auth.ca_certs", "PEM CAs client certificates must be issued by (mTLS)"),
+    ("tls.client_auth.crls", "PEM CRL file or directory of CRLs for client certificates"),
+    ("tls.max_early_data_size", "Bytes of 0-RTT early data accepted; 0 disables early data"),
+    (
+        "tls.enforce_matched_security_level",
+        "Refuse a KEM and signature algorithm at different NIST levels",
+    ),
+    ("tls.upstream_ca_certs", "DER CA certificates trusted for upstream TLS"),
+    ("tls.native_roots", "Also trust the OS root store for upstream TLS (skipped if unreadable)"),
+    ("tls.max_chain_depth", "Most certificates an upstream chain may contain (leaf included)"),
+    ("tls.max_handshake_bytes", "Largest ClientHello accepted; larger handshakes are closed"),
+    (
+        "tls.alternate_identity",
+        "Certificate and key for clients that cannot verify the primary key's signatures",
+    ),
+    ("tls.policy_path", "Hot-reloaded YAML rules choosing algorithms by client SNI and address"),
+    ("metrics", "Metrics export"),
+    ("metrics.exporter", "Prometheus, Statsd or Noop"),
+    ("metrics.prefix", "Metric name prefix (StatsD only)"),
+    ("metrics.auth", "Bearer token or Basic credentials for /metrics; /config and /upstreams need them set"),
+    ("metrics.serve_pqc_keys", "Serve the signed PQC public-key bundle on /pqc-keys"),
+    ("proxy", "Proxying"),
+    ("proxy.mode", "Layer4 (raw TLS relay), Layer7 (HTTP aware) or Passthrough (SNI routed, no TLS)"),
+    ("proxy.upstream", "Default upstream for L7 requests that match no route"),
+    ("proxy.timeout", "Idle and upstream TLS handshake timeout in seconds"),
+    ("proxy.connect_timeout_ms", "Upstream TCP connect timeout in milliseconds"),
+    ("proxy.listen_addr", "Address clients connect to"),
+    ("proxy.ipv6_only", "Set IPV6_V6ONLY on an IPv6 listen_addr; false also accepts IPv4"),
+    ("proxy.target_addr", "Default upstream for L4 connections that match no route"),
+    ("proxy.target_host", "Server name used when connecting to target_addr"),
+    (
+        "proxy.weighted_targets",
+        "addr/weight pairs sharing unrouted traffic instead of target_addr (L7: upstream)",
+    ),
+    ("proxy.upstream_tls", "On, Off or Auto (probe each L4 upstream once and cache the result)"),
+    ("proxy.max_connections", "Maximum concurrent client connections"),
+    ("proxy.queue_timeout_ms", "How long a connection over max_connections waits for a slot"),
+    ("proxy.max_queue_depth", "Connections that may wait for a slot at once"),
+    (
+        "proxy.max_connections_per_upstream",
+        "Maximum concurrent connections to any one L4 or passthrough upstream",
+    ),
+    ("proxy.upstream_saturated_action", "Reject or Failover when an upstream is at its limit"),
+    ("proxy.bind_addr", "Local address outbound upstream connections originate from"),
+    ("proxy.dscp", "DSCP (0-63) set on upstream sockets, and client sockets in L4 mode"),
+    ("proxy.forward_sni", "Send the client's SNI, not target_host, to the default L4 upstream"),
+    ("proxy.max_accepts_per_sec", "Global cap on new connections accepted per second"),
+    ("proxy.accept_rate_mode", "Delay or Reject connections beyond max_accepts_per_sec"),
+    ("proxy.max_concurrent_handshakes", "TLS handshakes in progress at once; extras queue"),
+    ("proxy.max_total_bytes", "Close a connection after this many bytes in total"),
+    ("proxy.rekey_after_bytes", "Rotate client TLS keys after this many bytes"),
+    ("proxy.rekey_action", "KeyUpdate (close if unsupported) or Close at rekey_after_bytes"),
+    ("proxy.max_key_updates", "Close a client connection after it sends more TLS 1.3 KeyUpdates than this"),
+    ("proxy.max_relay_buffer_bytes", "Relay copy buffer bytes a connection may hold, not counting TLS or socket buffers"),
+    ("proxy.relay_strategy", "Split (a future per direction) or SingleTask (one select loop)"),
+    (
+        "proxy.max_connection_lifetime_secs",
+        "Close connections after this many seconds, even if active",
+    ),
+    ("proxy.routes", "Per server name upstreams and timeout overrides"),
+    (
+        "proxy.unmatched_route",
+        "Default (target_addr / upstream) or Reject for SNI or Host matching no route",
+    ),
+    ("proxy.routes.health_check", "Send/expect check; failing upstreams get no connections"),
+    ("proxy.routes.dscp", "DSCP for this route, overriding proxy.dscp"),
+    ("proxy.routes.forward_sni", "Send the client's SNI, not server_name, to this upstream"),
+    ("proxy.routes.header_rules", "L7 request headers to remove, then rename, then set"),
+    ("proxy.forward_proxy", "Accept HTTP CONNECT in L7 mode"),
+    ("proxy.connect_allow_list", "CONNECT targets allowed: host, host:port or *.domain"),
+    ("proxy.shadow_upstream", "Upstream that receives a copy of every L7 request"),
+    ("proxy.pool_max_idle", "Idle keep-alive connections kept per L7 upstream"),
+    ("proxy.pool_idle_timeout", "Seconds an idle pooled connection is kept"),
+    (
+        "proxy.max_concurrent_streams",
+        "Open HTTP/2 streams allowed per connection; extra streams are refused",
+    ),
+    (
+        "proxy.max_requests_per_connection",
+        "HTTP/1.1 requests per keep-alive client connection before it is closed",
+    ),
+    (
+        "proxy.retry_on_upstream_reset",
+        "Resend idempotent L7 requests reset before responding to another weighted target",
+    ),
+    (
+        "proxy.warm_upstream",
+        "Buffer L7 upstream headers (max_header_bytes) and retry failures before them (retries)",
+    ),
+    (
+        "proxy.deadline_header",
+        "Header with the client's deadline in ms (grpc-timeout is always honored)",
+    ),
+    (
+        "proxy.grpc_methods",
+        "gRPC methods labeled by name in grpc_requests_total; others count as `other`",
+    ),
+    (
+        "proxy.request_id_header",
+        "Request id header echoed or generated per request, with X-Connection-Id",
+    ),
+    ("proxy.error_responses", "Responses sent when an L7 request cannot be proxied"),
+    ("proxy.forwarded", "X-Forwarded-For / Forwarded handling in L7 mode"),
+    (
+        "proxy.forwarded.trusted_hops",
+        "Proxies in front whose inbound headers are kept; 0 strips them",
+    ),
+    ("proxy.probe_upstreams_on_start", "Check TLS upstreams negotiate a quantum-safe group"),
+    ("proxy.upstream_probe_action", "Warn or Fail when an upstream fails the probe"),
+    (
+        "proxy.expose_negotiated_algorithms",
+        "Add X-SafeQuanta-KEM / X-SafeQuanta-Sig headers to L7 responses",
+    ),
+    ("proxy.connection_trace", "JSON lines trace of connections from source_ips, for debugging"),
+    ("proxy.connection_trace.max_bytes", "Trace file size after which records are dropped"),
+    ("proxy.connection_trace.enabled", "Trace from startup; false waits for POST /trace/enable"),
+    ("proxy.record_requests", "JSON lines file recording L7 requests for replay"),
+    ("proxy.record_request_bodies", "Record request bodies (first 64 KiB), not only their SHA-256"),
+    ("proxy.max_error_logs_per_sec", "Connection error log lines per second; extras are counted"),
+    ("listeners", "Extra listen addresses, each with a proxy and optional tls section"),
+];
+
+/// Fields an environment override sets as a comma separated list
+const ENV_LIST_FIELDS: &[&str] = &[
+    "tls.alpn_protocols",
+    "tls.cipher_suites",
+    "tls.disabled_algorithms",
+    "tls.upstream_ca_certs",
+    "proxy.connect_allow_list",
+];
+
+/// `SAFEQUANTA__<SECTION>__<FIELD>` environment overrides
+///
+/// `__` separates nesting levels so field names keep their single
+/// underscores, e.g. `SAFEQUANTA__TLS__KEM_ALGORITHM=Kyber1024`. Numbers and
+/// booleans are parsed, and the fields in `ENV_LIST_FIELDS` are split on commas.
+fn env_overrides(source: Option<config::Map<String, String>>) -> config::Environment {
+    ENV_LIST_FIELDS.iter().fold(
+        config::Environment::with_prefix("SAFEQUANTA")
+            .separator("__")
+            .try_parsing(true)
+            .list_separator(",")
+            .source(source),
+        |env, field| env.with_list_parse_key(field),
+    )
+}
+
+/// Config files in `dir`, sorted by file name
+fn config_fragments(dir: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut fragments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_config = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("yaml" | "yml" | "toml")
+        );
+        if is_config && path.is_file() {
+            fragments.push(path);
+        }
+    }
+    if fragments.is_empty() {
+        anyhow::bail!("No *.yaml or *.toml config files found in {}", dir);
+    }
+    fragments.sort();
+    Ok(fragments)
+}
+
+/// Prefix marking an encrypted configuration value
+pub const ENCRYPTED_VALUE_PREFIX: &str = "enc:";
+
+/// Hook used by `Config::load_with_decryptor` to decrypt `enc:` values
+pub trait ConfigDecryptor {
+    fn decrypt(&self, ciphertext: &str) -> anyhow::Result<String>;
+}
+
+/// Decryptor that leaves every value untouched
+pub struct NoopDecryptor;
+
+impl ConfigDecryptor for NoopDecryptor {
+    fn decrypt(&self, ciphertext: &str) -> anyhow::Result<String> {
+        Ok(format!("{}{}", ENCRYPTED_VALUE_PREFIX, ciphertext))
+    }
+}
+
+/// Decryptor for ASCII-armored age payloads
+///
+/// The identity is read from `SAFEQUANTA_AGE_KEY`, or from the file named by
+/// `SAFEQUANTA_AGE_KEY_FILE`.
+pub struct AgeDecryptor {
+    identity: Option<age::x25519::Identity>,
+}
+
+impl AgeDecryptor {
+    pub fn new(identity: age::x25519::Identity) -> Self {
+        Self { identity: Some(identity) }
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let key = match std::env::var("SAFEQUANTA_AGE_KEY") {
+            Ok(key) => Some(key),
+            Err(_) => match std::env::var("SAFEQUANTA_AGE_KEY_FILE") {
+                Ok(path) => Some(std::fs::read_to_string(path)?),
+                Err(_) => None,
+            },
+        };
+
+        let identity = key
+            .map(|key| {
+                key.lines()
+                    .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+                    .ok_or_else(|| anyhow::anyhow!("No age identity found in key material"))?
+                    .parse::<age::x25519::Identity>()
+                    .map_err(|e| anyhow::anyhow!("Invalid age identity: {}", e))
+            })
+            .transpose()?;
+
+        Ok(Self { identity })
+    }
+}
+
+impl ConfigDecryptor for AgeDecryptor {
+    fn decrypt(&self, ciphertext: &str) -> anyhow::Result<String> {
+        use std::io::Read;
+
+        let identity = self.identity.as_ref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Encrypted config value found but no age key is set (SAFEQUANTA_AGE_KEY or SAFEQUANTA_AGE_KEY_FILE)"
+            )
+        })?;
+
+        let decryptor = age::Decryptor::new(age::armor::ArmoredReader::new(ciphertext.trim().as_bytes()))?;
+        if decryptor.is_scrypt() {
+            anyhow::bail!("Encrypted config value is not recipient-encrypted");
+        }
+
+        let mut plaintext = String::new();
+        decryptor
+            .decrypt(std::iter::once(identity as &dyn age::Identity))?
+            .read_to_string(&mut plaintext)?;
+
+        Ok(plaintext)
+    }
+}
+
+fn decrypt_values(
+    value: &mut config::Value,
+    decryptor: &dyn ConfigDecryptor,
+    decrypted: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    match &mut value.kind {
+        config::ValueKind::String(s) => {
+            if let Some(ciphertext) = s.strip_prefix(ENCRYPTED_VALUE_PREFIX) {
+                *s = decryptor.decrypt(ciphertext)?;
+                decrypted.push(s.clone());
+            }
+        }
+        config::ValueKind::Array(items) => {
+            for item in items {
+                decrypt_values(item, decryptor, decrypted)?;
+            }
+        }
+        config::ValueKind::Table(map) => {
+            for item in map.values_mut() {
+                decrypt_values(item, decryptor, decrypted)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn yaml_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".yaml").tempfile().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let file = yaml_file("");
+        let config = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap();
+        let defaults = Config::default();
+
+        assert_eq!(config.tls.kem_algorithm, defaults.tls.kem_algorithm);
+        assert_eq!(config.proxy.timeout, defaults.proxy.timeout);
+        assert_eq!(config.proxy.listen_addr, defaults.proxy.listen_addr);
+        assert!(!config.tls.fallback_config.enabled);
+    }
+
+    #[test]
+    fn test_redacted_hides_secrets_only() {
+        let mut config = Config::default();
+        config.metrics.auth = Some(MetricsAuth::Basic {
+            username: "scraper".to_string(),
+            password: "open sesame".to_string(),
+        });
+        config.listeners.push(ListenerConfig {
+            proxy: ProxyConfig {
+                upstream: "http://10.0.0.2:8080".to_string(),
+                ..Default::default()
+            },
+            tls: None,
+        });
+
+        let redacted = config.redacted().unwrap();
+        assert_eq!(redacted["metrics"]["auth"]["Basic"]["password"], "<redacted>");
+        assert_eq!(redacted["metrics"]["auth"]["Basic"]["username"], "scraper");
+        assert_eq!(redacted["metrics"]["port"], 9090);
+        assert_eq!(redacted["tls"]["kem_algorithm"], "Kyber768");
+        assert_eq!(redacted["listeners"][0]["proxy"]["upstream"], "http://10.0.0.2:8080");
+        assert!(!redacted.to_string().contains("open sesame"));
+
+        // Values decrypted from enc: are hidden whatever their field is called
+        config.listeners[0].proxy.upstream = "http://internal.example:8080".to_string();
+        config.decrypted_values = vec!["http://internal.example:8080".to_string()];
+        let redacted = config.redacted().unwrap();
+        assert_eq!(redacted["listeners"][0]["proxy"]["upstream"], "<redacted>");
+        assert!(!redacted.to_string().contains("internal.example"));
+    }
+
+    #[test]
+    fn test_env_overrides_nested_fields() {
+        let file = yaml_file("tls:\n  kem_algorithm: Kyber768\nproxy:\n  timeout: 5\n");
+        let env = [
+            ("SAFEQUANTA__TLS__KEM_ALGORITHM", "Kyber1024"),
+            ("SAFEQUANTA__TLS__FALLBACK_CONFIG__ENABLED", "true"),
+            ("SAFEQUANTA__TLS__ALPN_PROTOCOLS", "h2,http/1.1"),
+            ("SAFEQUANTA__PROXY__TIMEOUT", "12"),
+            ("SAFEQUANTA__PROXY__MODE", "Layer4"),
+            ("SAFEQUANTA__METRICS__PORT", "9191"),
+            // Parsed as a number, but still accepted by a string field
+            ("SAFEQUANTA__METRICS__PREFIX", "2024"),
+            ("SAFEQUANTA_AGE_KEY", "not a config field"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+        let path = file.path().to_str().unwrap();
+        let config = Config::load_from_path_with_env(path, &NoopDecryptor, Some(env)).unwrap();
+        assert_eq!(config.tls.kem_algorithm, KemAlgorithm::Kyber1024);
+        assert!(config.tls.fallback_config.enabled);
+        assert_eq!(config.tls.alpn_protocols, vec!["h2".to_string(), "http/1.1".to_string()]);
+        assert_eq!(config.proxy.timeout, 12);
+        assert!(matches!(config.proxy.mode, ProxyMode::Layer4));
+        assert_eq!(config.metrics.port, 9191);
+        assert_eq!(config.metrics.prefix.as_deref(), Some("2024"));
+        assert_eq!(config.tls.signature_algorithm, SignatureAlgorithm::Dilithium3);
+    }
+
+    #[test]
+    fn test_connect_allow_list_matching() {
+        let config = ProxyConfig {
+            connect_allow_list: vec!["api.example.com:443".into(), "*.internal".into(), "10.0.0.1".into()],
+            ..Default::default()
+        };
+
+        assert!(config.connect_allowed("api.example.com:443"));
+        assert!(!config.connect_allowed("api.example.com:8443"));
+        assert!(config.connect_allowed("db.internal:5432"));
+        assert!(!config.connect_allowed("internal:5432"));
+        assert!(config.connect_allowed("10.0.0.1:22"));
+        assert!(!config.connect_allowed("evil.example:443"));
+    }
+
+    #[test]
+    fn test_partial_config_fills_defaults() {
+        let file = yaml_file("tls:\n  kem_algorithm: Kyber1024\nproxy:\n  timeout: 5\n");
+        let config = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap();
+
+        assert_eq!(config.tls.kem_algorithm, KemAlgorithm::Kyber1024);
+        assert_eq!(config.proxy.timeout, 5);
+        assert_eq!(config.tls.signature_algorithm, SignatureAlgorithm::Dilithium3);
+        assert_eq!(config.proxy.max_connections, 1000);
+        assert_eq!(config.metrics.port, 9090);
+    }
+
+    #[test]
+    fn test_directory_fragments_are_merged_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.path().join(name), contents).unwrap();
+        write("tls.yaml", "tls:\n  kem_algorithm: Kyber1024\n  session_cache_size: 64\n");
+        write("proxy.toml", "[proxy]\ntimeout = 5\nmax_connections = 10\n");
+        write("zz-override.yaml", "proxy:\n  max_connections: 20\n");
+        write("notes.txt", "not: [valid");
+
+        let config = Config::load_from_path(dir.path().to_str().unwrap(), &NoopDecryptor).unwrap();
+
+        assert_eq!(config.tls.kem_algorithm, KemAlgorithm::Kyber1024);
+        assert_eq!(config.tls.session_cache_size, 64);
+        assert_eq!(config.proxy.timeout, 5);
+        assert_eq!(config.proxy.max_connections, 20);
+        assert_eq!(config.metrics.port, 9090);
+    }
+
+    #[test]
+    fn test_empty_config_directory_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Config::load_from_path(dir.path().to_str().unwrap(), &NoopDecryptor).is_err());
+    }
+
+    #[test]
+    fn test_annotated_default_round_trips() {
+        let generated = Config::annotated_default().unwrap();
+        assert!(generated.contains("  # Post-quantum KEM: Kyber768 or Kyber1024\n  kem_algorithm: Kyber768"));
+
+        let file = yaml_file(&generated);
+        let config = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap();
+        let defaults = Config::default();
+
+        assert_eq!(config.tls.kem_algorithm, defaults.tls.kem_algorithm);
+        assert_eq!(config.proxy.listen_addr, defaults.proxy.listen_addr);
+        assert_eq!(config.proxy.error_responses.capacity.status, 503);
+        assert_eq!(config.metrics.host, defaults.metrics.host);
+    }
+
+    fn encrypt(recipient: age::x25519::Recipient, plaintext: &str) -> String {
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient)).unwrap();
+        let mut armored = vec![];
+        let armor = age::armor::ArmoredWriter::wrap_output(&mut armored, age::armor::Format::AsciiArmor).unwrap();
+        let mut writer = encryptor.wrap_output(armor).unwrap();
+        writer.write_all(plaintext.as_bytes()).unwrap();
+        writer.finish().unwrap().finish().unwrap();
+        String::from_utf8(armored).unwrap()
+    }
+
+    /// Environment overrides setting `metrics.prefix` to an encrypted value
+    fn encrypted_prefix(identity: &age::x25519::Identity, plaintext: &str) -> config::Map<String, String> {
+        let value = format!("enc:{}", encrypt(identity.to_public(), plaintext));
+        [("SAFEQUANTA__METRICS__PREFIX".to_string(), value)].into_iter().collect()
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_encrypted_value_is_decrypted() {
+        let identity = age::x25519::Identity::generate();
+        let env = encrypted_prefix(&identity, "hunter2");
+
+        let file = yaml_file("");
+        let path = file.path().to_str().unwrap();
+        let config = Config::load_from_path_with_env(path, &AgeDecryptor::new(identity), Some(env)).unwrap();
+
+        assert_eq!(config.metrics.prefix.as_deref(), Some("hunter2"));
+        assert_eq!(config.decrypted_values, ["hunter2"]);
+    }
+
+    #[test]
+    fn test_encrypted_value_without_key_errors() {
+        let identity = age::x25519::Identity::generate();
+        let env = encrypted_prefix(&identity, "hunter2");
+
+        let file = yaml_file("");
+        let path = file.path().to_str().unwrap();
+        let err = Config::load_from_path_with_env(path, &AgeDecryptor { identity: None }, Some(env))
+            .unwrap_err();
+
+        assert!(err.to_string().contains("no age key is set"));
+    }
+
+    #[test]
+    fn test_matched_security_level_enforced() {
+        let matched = TlsConfig {
+            kem_algorithm: KemAlgorithm::Kyber768,
+            signature_algorithm: SignatureAlgorithm::Dilithium3,
+            enforce_matched_security_level: true,
+            ..Default::default()
+        };
+        assert!(matched.ensure_matched_security_level().is_ok());
+
+        let mismatched = TlsConfig {
+            kem_algorithm: KemAlgorithm::Kyber1024,
+            ..matched.clone()
+        };
+        let err = mismatched.ensure_matched_security_level().unwrap_err();
+        assert!(matches!(err, SafeQuantaError::InvalidConfig(ref msg)
+            if msg.contains("level 5") && msg.contains("level 3")));
+
+        // Without the flag the same pairing is allowed
+        let relaxed = TlsConfig {
+            enforce_matched_security_level: false,
+            ..mismatched
+        };
+        assert!(relaxed.ensure_matched_security_level().is_ok());
+    }
+
+    #[test]
+    fn test_require_pqc_applied_to_every_listener() {
+        let mut config = Config {
+            require_pqc: true,
+            listeners: vec![ListenerConfig {
+                tls: Some(TlsConfig::default()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        config.tls.fallback_config.enabled = true;
+        config.apply_require_pqc();
+
+        for (proxy, tls) in config.listener_configs() {
+            assert!(tls.require_pqc);
+            assert!(!tls.fallback_config.enabled);
+            assert!(proxy.probe_upstreams_on_start);
+            assert_eq!(proxy.upstream_probe_action, ProbeAction::Fail);
+        }
+
+        let classic = TlsConfig {
+            signature_algorithm: SignatureAlgorithm::Rsa3072,
+            ..config.tls.clone()
+        };
+        assert!(classic.ensure_pqc_certificate().is_err());
+        assert!(config.tls.ensure_pqc_certificate().is_ok());
+    }
+
+    #[test]
+    fn test_require_pqc_refuses_plaintext_upstreams() {
+        let load = |yaml: &str| {
+            let file = yaml_file(&format!("require_pqc: true\n{}", yaml));
+            Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor)
+        };
+
+        let config = load("proxy:\n  upstream_tls: Auto\n").unwrap();
+        assert_eq!(config.proxy.upstream_tls, UpstreamTls::On);
+        assert!(load("proxy:\n  upstream_tls: Off\n").is_err());
+
+        let l7 = "proxy:\n  mode: Layer7\n  upstream: https://app:8443\n";
+        assert!(load(l7).is_ok());
+        let err = load(&format!(
+            "{}  routes:\n    - server_name: legacy.test\n      upstream: http://legacy:8080\n",
+            l7
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("http://legacy:8080"));
+    }
+
+    #[test]
+    fn test_out_of_range_dscp_rejected() {
+        let with_route_dscp = |dscp: u8| {
+            yaml_file(&format!(
+                concat!(
+                    "proxy:\n  dscp: 46\n  routes:\n",
+                    "    - server_name: app.test\n      upstream: app:443\n      dscp: {}\n",
+                ),
+                dscp
+            ))
+        };
+
+        let file = with_route_dscp(10);
+        let config = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap();
+        let route = config.proxy.route_for(Some("app.test"));
+        assert_eq!(config.proxy.dscp_for(route), Some(10));
+        assert_eq!(config.proxy.dscp_for(None), Some(46));
+
+        let file = with_route_dscp(64);
+        let err = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap_err();
+        assert!(err.to_string().contains("dscp 64 for app.test"));
+    }
+
+    #[test]
+    fn test_upstream_limit_refused_in_layer7() {
+        let with_mode = |mode: &str| {
+            yaml_file(&format!("proxy:\n  mode: {}\n  max_connections_per_upstream: 4\n", mode))
+        };
+
+        let file = with_mode("Layer4");
+        let config = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap();
+        assert_eq!(config.proxy.max_connections_per_upstream, Some(4));
+
+        let file = with_mode("Layer7");
+        let err = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap_err();
+        assert!(err.to_string().contains("max_connections_per_upstream"));
+    }
+
+    #[test]
+    fn test_route_connect_timeout_leaves_handshake_timeout() {
+        let file = yaml_file(concat!(
+            "proxy:\n  timeout: 30\n  connect_timeout_ms: 500\n  routes:\n",
+            "    - server_name: app.test\n      upstream: app:443\n",
+            "      timeouts: { connect: 2 }\n",
+        ));
+        let config = Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor).unwrap();
+
+        let defaults = config.proxy.timeouts_for(None);
+        assert_eq!(defaults.connect, Duration::from_millis(500));
+        assert_eq!(defaults.handshake, Duration::from_secs(30));
+        let route = config.proxy.timeouts_for(config.proxy.route_for(Some("app.test")));
+        assert_eq!(route.connect, Duration::from_secs(2));
+        assert_eq!(route.handshake, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_invalid_header_rules_rejected_at_load() {
+        let with_rules = |rules: &str| {
+            yaml_file(&format!(
+                concat!(
+                    "proxy:\n  routes:\n",
+                    "    - server_name: app.test\n      upstream: app:443\n",
+                    "      header_rules: {}\n",
+                ),
+                rules
+            ))
+        };
+        let load = |rules: &str| {
+            let file = with_rules(rules);
+            Config::load_from_path(file.path().to_str().unwrap(), &NoopDecryptor)
+        };
+
+        assert!(load("{ set: [{ name: x-route, value: app }] }").is_ok());
+        let err = load("{ rename: [{ from: x-user, to: \"bad name\" }] }").unwrap_err();
+        assert!(err.to_string().contains("header_rules for app.test: invalid header name"));
+        let err = load("{ set: [{ name: x-route, value: \"a\\nb\" }] }").unwrap_err();
+        assert!(err.to_string().contains("invalid x-route value"));
+    }
+}
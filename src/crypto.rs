@@ -1,13 +1,22 @@
-use crate::config::{KemAlgorithm, SignatureAlgorithm};
+use crate::config::{KemAlgorithm, SignatureAlgorithm, TlsConfig};
 use crate::error::{Result, SafeQuantaError};
+use openssl::hash::MessageDigest;
 use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
 use openssl::x509::X509;
+use parking_lot::RwLock;
 use pqcrypto::kyber::{kyber768, kyber1024};
 use pqcrypto::dilithium::dilithium3;
-use pqcrypto_traits::kem::{SharedSecret, PublicKey as KemPublicKey, SecretKey as KemSecretKey};
-use pqcrypto_traits::sign::{PublicKey as SignPublicKey, SecretKey as SignSecretKey};
-use rand_core::{CryptoRng, RngCore};
+use pqcrypto_traits::kem::{
+    Ciphertext as KemCiphertext, PublicKey as KemPublicKey, SecretKey as KemSecretKey, SharedSecret,
+};
+use pqcrypto_traits::sign::{
+    DetachedSignature as SignDetachedSignature, PublicKey as SignPublicKey, SecretKey as SignSecretKey,
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
 
 /// Quantum-safe cryptography provider
 pub struct CryptoProvider {
@@ -16,10 +25,9 @@ pub struct CryptoProvider {
     private_key: Arc<PKey<Private>>,
     public_key: Arc<PKey<Public>>,
     certificate: Arc<X509>,
-    kem_secret_key: Option<Arc<dyn KemSecretKey>>,
-    kem_public_key: Option<Arc<dyn KemPublicKey>>,
-    sign_secret_key: Option<Arc<dyn SignSecretKey>>,
-    sign_public_key: Option<Arc<dyn SignPublicKey>>,
+    intermediates: Vec<X509>,
+    /// Replaced as a whole by `rotate_and_sign_bundle`
+    keys: RwLock<Keypairs>,
 }
 
 impl CryptoProvider {
@@ -27,33 +35,27 @@ impl CryptoProvider {
     pub fn new(
         kem_algorithm: KemAlgorithm,
         signature_algorithm: SignatureAlgorithm,
-        cert_path: &str,
-        key_path: &str,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
     ) -> Result<Self> {
-        // Load certificate and private key
-        let certificate = X509::from_pem(&std::fs::read(cert_path)?)?;
+        // Load certificate chain (leaf first) and private key
+        let cert_path = cert_path.as_ref();
+        let mut chain = X509::stack_from_pem(&std::fs::read(cert_path)?)?.into_iter();
+        let certificate = chain
+            .next()
+            .ok_or_else(|| SafeQuantaError::Crypto(format!("No certificate found in {}", cert_path.display())))?;
+        let intermediates: Vec<X509> = chain.collect();
         let private_key = PKey::private_key_from_pem(&std::fs::read(key_path)?)?;
         let public_key = PKey::public_key_from_pem(&certificate.public_key()?.public_key_to_pem()?)?;
+        // Caught here rather than as an opaque failure in the first handshake
+        if !public_key.public_eq(&private_key) {
+            return Err(SafeQuantaError::Crypto(
+                "certificate and private key do not match".to_string(),
+            ));
+        }
 
         // Generate quantum-safe key pairs
-        let (kem_secret_key, kem_public_key) = match kem_algorithm {
-            KemAlgorithm::Kyber768 => {
-                let (sk, pk) = kyber768::keypair();
-                (Some(Arc::new(sk) as Arc<dyn KemSecretKey>), Some(Arc::new(pk) as Arc<dyn KemPublicKey>))
-            }
-            KemAlgorithm::Kyber1024 => {
-                let (sk, pk) = kyber1024::keypair();
-                (Some(Arc::new(sk) as Arc<dyn KemSecretKey>), Some(Arc::new(pk) as Arc<dyn KemPublicKey>))
-            }
-        };
-
-        let (sign_secret_key, sign_public_key) = match signature_algorithm {
-            SignatureAlgorithm::Dilithium3 => {
-                let (sk, pk) = dilithium3::keypair();
-                (Some(Arc::new(sk) as Arc<dyn SignSecretKey>), Some(Arc::new(pk) as Arc<dyn SignPublicKey>))
-            }
-            SignatureAlgorithm::Rsa3072 => (None, None),
-        };
+        let keys = generate_keypairs(kem_algorithm, signature_algorithm);
 
         Ok(Self {
             kem_algorithm,
@@ -61,13 +63,137 @@ impl CryptoProvider {
             private_key: Arc::new(private_key),
             public_key: Arc::new(public_key),
             certificate: Arc::new(certificate),
-            kem_secret_key,
-            kem_public_key,
-            sign_secret_key,
-            sign_public_key,
+            intermediates,
+            keys: RwLock::new(keys),
         })
     }
 
+    /// Create a crypto provider for `config`, refusing disabled algorithms and,
+    /// if enforced, mismatched security levels
+    pub fn from_config(config: &TlsConfig) -> Result<Self> {
+        config.ensure_algorithms_enabled()?;
+        config.ensure_matched_security_level()?;
+        Self::new(
+            config.kem_algorithm,
+            config.signature_algorithm,
+            &config.cert_path,
+            &config.key_path,
+        )
+    }
+
+    /// Export the certificate chain (leaf followed by intermediates) as PEM
+    pub fn certificate_chain_pem(&self) -> Result<String> {
+        let mut pem = self.certificate.to_pem()?;
+        for cert in &self.intermediates {
+            pem.extend(cert.to_pem()?);
+        }
+        String::from_utf8(pem).map_err(|e| SafeQuantaError::Crypto(format!("Invalid PEM encoding: {}", e)))
+    }
+
+    /// Replace the PQC keypairs and return the new public keys signed by the
+    /// long-term certificate key
+    ///
+    /// Clients that pin the certificate can check the bundle with
+    /// `SignedKeyBundle::verify`. A provider shared by running listeners can
+    /// rotate; operations in flight keep the keys they started with.
+    pub fn rotate_and_sign_bundle(&self) -> Result<SignedKeyBundle> {
+        let keys = generate_keypairs(self.kem_algorithm, self.signature_algorithm);
+        let bundle = self.sign_bundle(&keys)?;
+        *self.keys.write() = keys;
+        Ok(bundle)
+    }
+
+    /// The current PQC public keys signed by the long-term certificate key
+    pub fn signed_bundle(&self) -> Result<SignedKeyBundle> {
+        self.sign_bundle(&self.keys())
+    }
+
+    /// The current PQC keypairs
+    fn keys(&self) -> Keypairs {
+        self.keys.read().clone()
+    }
+
+    fn sign_bundle(&self, keys: &Keypairs) -> Result<SignedKeyBundle> {
+        let mut bundle = SignedKeyBundle {
+            kem_algorithm: self.kem_algorithm,
+            signature_algorithm: self.signature_algorithm,
+            kem_public_key: keys
+                .kem_public_key
+                .as_ref()
+                .map(|pk| pk.to_bytes().to_vec())
+                .unwrap_or_default(),
+            sign_public_key: keys.sign_public_key.as_ref().map(|pk| pk.to_bytes().to_vec()),
+            signature: Vec::new(),
+        };
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
+        bundle.signature = signer.sign_oneshot_to_vec(&bundle.signed_payload())?;
+
+        Ok(bundle)
+    }
+
+    /// Description of the active algorithms, for version and self-test
+    /// output, metrics and embedders
+    pub fn algorithm_info(&self) -> AlgorithmInfo {
+        let kem = self.kem_algorithm;
+        let (kem_public_key_bytes, kem_ciphertext_bytes, kem_shared_secret_bytes) = match kem {
+            KemAlgorithm::Kyber768 => (
+                kyber768::public_key_bytes(),
+                kyber768::ciphertext_bytes(),
+                kyber768::shared_secret_bytes(),
+            ),
+            KemAlgorithm::Kyber1024 => (
+                kyber1024::public_key_bytes(),
+                kyber1024::ciphertext_bytes(),
+                kyber1024::shared_secret_bytes(),
+            ),
+        };
+        let (signature_public_key_bytes, signature_bytes) = match self.signature_algorithm {
+            SignatureAlgorithm::Dilithium3 => {
+                (dilithium3::public_key_bytes(), dilithium3::signature_bytes())
+            }
+            // Signed with the certificate key
+            SignatureAlgorithm::Rsa3072 => (self.public_key.bits() as usize / 8, self.public_key.size()),
+        };
+
+        AlgorithmInfo {
+            kem,
+            kem_nist_level: kem.nist_level(),
+            kem_public_key_bytes,
+            kem_ciphertext_bytes,
+            kem_shared_secret_bytes,
+            signature: self.signature_algorithm,
+            signature_nist_level: self.signature_algorithm.nist_level(),
+            signature_public_key_bytes,
+            signature_bytes,
+            hybrid: self.keys().sign_public_key.is_some(),
+        }
+    }
+
+    /// Public half of the PQC signing keypair, if the signature algorithm has one
+    pub fn sign_public_key(&self) -> Option<Vec<u8>> {
+        self.keys().sign_public_key.as_ref().map(|pk| pk.as_bytes().to_vec())
+    }
+
+    /// Verify a detached signature made by another party's PQC signing key
+    ///
+    /// A signature that does not match `data` and `public_key` is `Ok(false)`;
+    /// malformed keys and signatures are errors.
+    pub async fn verify_with_key(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool> {
+        match self.signature_algorithm {
+            SignatureAlgorithm::Dilithium3 => {
+                let pk = dilithium3::PublicKey::from_bytes(public_key)
+                    .map_err(|e| SafeQuantaError::Crypto(format!("Invalid public key: {}", e)))?;
+                let sig = dilithium3::DetachedSignature::from_bytes(signature)
+                    .map_err(|e| SafeQuantaError::Crypto(format!("Invalid signature: {}", e)))?;
+
+                Ok(dilithium3::verify_detached_signature(&sig, data, &pk).is_ok())
+            }
+            SignatureAlgorithm::Rsa3072 => Err(SafeQuantaError::Crypto(
+                "Verifying with a peer key requires a PQC signature algorithm".into(),
+            )),
+        }
+    }
+
     /// Perform a quantum-safe key exchange
     pub async fn key_exchange(&self, peer_public_key: &[u8]) -> Result<Vec<u8>> {
         match self.kem_algorithm {
@@ -76,6 +202,76 @@ impl CryptoProvider {
         }
     }
 
+    /// Recover the shared secret from a KEM ciphertext made for our public key
+    ///
+    /// The ciphertext length is checked against the negotiated KEM first so a
+    /// truncated or mismatched peer message gets a precise error.
+    pub async fn decapsulate(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let expected = match self.kem_algorithm {
+            KemAlgorithm::Kyber768 => kyber768::ciphertext_bytes(),
+            KemAlgorithm::Kyber1024 => kyber1024::ciphertext_bytes(),
+        };
+        if ciphertext.len() != expected {
+            let kem = format!("{:?}", self.kem_algorithm).to_ascii_lowercase();
+            return Err(SafeQuantaError::Crypto(format!(
+                "ciphertext length {}, expected {} for {}",
+                ciphertext.len(),
+                expected,
+                kem
+            )));
+        }
+        match self.kem_algorithm {
+            KemAlgorithm::Kyber768 => self.kyber768_decapsulate(ciphertext).await,
+            KemAlgorithm::Kyber1024 => self.kyber1024_decapsulate(ciphertext).await,
+        }
+    }
+
+    /// Encapsulate a fresh shared secret to `peer_public_key`
+    ///
+    /// Returns the ciphertext to send to the peer and the shared secret.
+    pub async fn encapsulate(&self, peer_public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let invalid = |e| SafeQuantaError::Crypto(format!("Invalid peer public key: {}", e));
+        match self.kem_algorithm {
+            KemAlgorithm::Kyber768 => {
+                let peer_pk = kyber768::PublicKey::from_bytes(peer_public_key).map_err(invalid)?;
+                let (shared_secret, ciphertext) = kyber768::encapsulate(&peer_pk);
+                Ok((ciphertext.to_bytes().to_vec(), shared_secret.to_bytes().to_vec()))
+            }
+            KemAlgorithm::Kyber1024 => {
+                let peer_pk = kyber1024::PublicKey::from_bytes(peer_public_key).map_err(invalid)?;
+                let (shared_secret, ciphertext) = kyber1024::encapsulate(&peer_pk);
+                Ok((ciphertext.to_bytes().to_vec(), shared_secret.to_bytes().to_vec()))
+            }
+        }
+    }
+
+    /// Encapsulate to `peer_public_key` and derive session keys bound to
+    /// `transcript`, returning the ciphertext for the peer with the keys
+    ///
+    /// The raw shared secret never leaves this call.
+    pub async fn encapsulate_and_derive(
+        &self,
+        peer_public_key: &[u8],
+        transcript: &[u8],
+    ) -> Result<(Vec<u8>, DirectionalKeys)> {
+        let (ciphertext, shared_secret) = self.encapsulate(peer_public_key).await?;
+        Ok((ciphertext, derive_session_keys(&shared_secret, transcript)))
+    }
+
+    /// Decapsulate the peer's `ciphertext` and derive session keys bound to
+    /// `transcript`
+    ///
+    /// The counterpart of `encapsulate_and_derive`; the raw shared secret
+    /// never leaves this call.
+    pub async fn decapsulate_and_derive(
+        &self,
+        ciphertext: &[u8],
+        transcript: &[u8],
+    ) -> Result<DirectionalKeys> {
+        let shared_secret = self.decapsulate(ciphertext).await?;
+        Ok(derive_session_keys(&shared_secret, transcript))
+    }
+
     /// Sign data using the configured signature algorithm
     pub async fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
         match self.signature_algorithm {
@@ -97,8 +293,7 @@ impl CryptoProvider {
         let peer_pk = kyber768::PublicKey::from_bytes(peer_public_key)
             .map_err(|e| SafeQuantaError::Crypto(format!("Invalid peer public key: {}", e)))?;
         
-        let shared_secret = kyber768::encapsulate(&peer_pk)
-            .map_err(|e| SafeQuantaError::Crypto(format!("Encapsulation failed: {}", e)))?;
+        let (shared_secret, _ciphertext) = kyber768::encapsulate(&peer_pk);
         
         Ok(shared_secret.to_bytes().to_vec())
     }
@@ -108,47 +303,253 @@ impl CryptoProvider {
         let peer_pk = kyber1024::PublicKey::from_bytes(peer_public_key)
             .map_err(|e| SafeQuantaError::Crypto(format!("Invalid peer public key: {}", e)))?;
         
-        let shared_secret = kyber1024::encapsulate(&peer_pk)
-            .map_err(|e| SafeQuantaError::Crypto(format!("Encapsulation failed: {}", e)))?;
+        let (shared_secret, _ciphertext) = kyber1024::encapsulate(&peer_pk);
         
         Ok(shared_secret.to_bytes().to_vec())
     }
 
+    async fn kyber768_decapsulate(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if let Some(sk) = &self.keys().kem_secret_key {
+            let sk = kyber768::SecretKey::from_bytes(sk.to_bytes())
+                .map_err(|e| SafeQuantaError::Crypto(format!("Invalid KEM secret key: {}", e)))?;
+            let ct = kyber768::Ciphertext::from_bytes(ciphertext)
+                .map_err(|e| SafeQuantaError::Crypto(format!("Invalid ciphertext: {}", e)))?;
+            Ok(kyber768::decapsulate(&ct, &sk).to_bytes().to_vec())
+        } else {
+            Err(SafeQuantaError::Crypto("No KEM secret key available".into()))
+        }
+    }
+
+    async fn kyber1024_decapsulate(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if let Some(sk) = &self.keys().kem_secret_key {
+            let sk = kyber1024::SecretKey::from_bytes(sk.to_bytes())
+                .map_err(|e| SafeQuantaError::Crypto(format!("Invalid KEM secret key: {}", e)))?;
+            let ct = kyber1024::Ciphertext::from_bytes(ciphertext)
+                .map_err(|e| SafeQuantaError::Crypto(format!("Invalid ciphertext: {}", e)))?;
+            Ok(kyber1024::decapsulate(&ct, &sk).to_bytes().to_vec())
+        } else {
+            Err(SafeQuantaError::Crypto("No KEM secret key available".into()))
+        }
+    }
+
     // Dilithium3 implementation
     async fn dilithium3_sign(&self, data: &[u8]) -> Result<Vec<u8>> {
-        if let Some(sk) = &self.sign_secret_key {
-            let signature = dilithium3::sign(data, sk.as_ref())
-                .map_err(|e| SafeQuantaError::Crypto(format!("Signing failed: {}", e)))?;
-            Ok(signature.to_bytes().to_vec())
+        if let Some(sk) = &self.keys().sign_secret_key {
+            let sk = dilithium3::SecretKey::from_bytes(sk.as_bytes())
+                .map_err(|e| SafeQuantaError::Crypto(format!("Invalid signing key: {}", e)))?;
+            Ok(dilithium3::detached_sign(data, &sk).as_bytes().to_vec())
         } else {
             Err(SafeQuantaError::Crypto("No signing key available".into()))
         }
     }
 
     async fn dilithium3_verify(&self, data: &[u8], signature: &[u8]) -> Result<bool> {
-        if let Some(pk) = &self.sign_public_key {
-            let sig = dilithium3::Signature::from_bytes(signature)
-                .map_err(|e| SafeQuantaError::Crypto(format!("Invalid signature: {}", e)))?;
-            
-            Ok(dilithium3::verify(&sig, data, pk.as_ref())
-                .map_err(|e| SafeQuantaError::Crypto(format!("Verification failed: {}", e)))?)
-        } else {
-            Err(SafeQuantaError::Crypto("No verification key available".into()))
+        match self.sign_public_key() {
+            Some(pk) => self.verify_with_key(&pk, data, signature).await,
+            None => Err(SafeQuantaError::Crypto("No verification key available".into())),
         }
     }
 
     // RSA-3072 implementation
     async fn rsa3072_sign(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut signer = openssl::sign::Signer::new_without_digest(&self.private_key)?;
+        let mut signer = Signer::new_without_digest(&self.private_key)?;
         Ok(signer.sign_oneshot_to_vec(data)?)
     }
 
     async fn rsa3072_verify(&self, data: &[u8], signature: &[u8]) -> Result<bool> {
-        let mut verifier = openssl::sign::Verifier::new_without_digest(&self.public_key)?;
+        let mut verifier = Verifier::new_without_digest(&self.public_key)?;
         Ok(verifier.verify_oneshot(signature, data)?)
     }
 }
 
+/// KEM and signature keypairs; the signature pair is absent for RSA
+#[derive(Clone)]
+struct Keypairs {
+    kem_secret_key: Option<Arc<dyn KemSecretKey>>,
+    kem_public_key: Option<Arc<dyn KemPublicKey>>,
+    sign_secret_key: Option<Arc<dyn SignSecretKey>>,
+    sign_public_key: Option<Arc<dyn SignPublicKey>>,
+}
+
+/// Generate PQC keypairs for the configured algorithms
+fn generate_keypairs(
+    kem_algorithm: KemAlgorithm,
+    signature_algorithm: SignatureAlgorithm,
+) -> Keypairs {
+    let (kem_secret_key, kem_public_key) = match kem_algorithm {
+        KemAlgorithm::Kyber768 => {
+            let (sk, pk) = kyber768::keypair();
+            (Some(Arc::new(sk) as Arc<dyn KemSecretKey>), Some(Arc::new(pk) as Arc<dyn KemPublicKey>))
+        }
+        KemAlgorithm::Kyber1024 => {
+            let (sk, pk) = kyber1024::keypair();
+            (Some(Arc::new(sk) as Arc<dyn KemSecretKey>), Some(Arc::new(pk) as Arc<dyn KemPublicKey>))
+        }
+    };
+
+    let (sign_secret_key, sign_public_key) = match signature_algorithm {
+        SignatureAlgorithm::Dilithium3 => {
+            let (sk, pk) = dilithium3::keypair();
+            (Some(Arc::new(sk) as Arc<dyn SignSecretKey>), Some(Arc::new(pk) as Arc<dyn SignPublicKey>))
+        }
+        SignatureAlgorithm::Rsa3072 => (None, None),
+    };
+
+    Keypairs {
+        kem_secret_key,
+        kem_public_key,
+        sign_secret_key,
+        sign_public_key,
+    }
+}
+
+/// Machine-readable description of a provider's algorithms
+///
+/// Sizes are in bytes. For RSA-3072 the signature sizes are those of the
+/// certificate key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AlgorithmInfo {
+    pub kem: KemAlgorithm,
+    pub kem_nist_level: u8,
+    pub kem_public_key_bytes: usize,
+    pub kem_ciphertext_bytes: usize,
+    pub kem_shared_secret_bytes: usize,
+    pub signature: SignatureAlgorithm,
+    pub signature_nist_level: u8,
+    pub signature_public_key_bytes: usize,
+    pub signature_bytes: usize,
+    /// A PQC signing key is used alongside the classical certificate key
+    pub hybrid: bool,
+}
+
+/// PQC public keys published out of band, signed by the certificate key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedKeyBundle {
+    pub kem_algorithm: KemAlgorithm,
+    pub signature_algorithm: SignatureAlgorithm,
+    pub kem_public_key: Vec<u8>,
+    /// `None` when signatures use the classical certificate key
+    pub sign_public_key: Option<Vec<u8>>,
+    /// SHA-256 signature over the fields above
+    pub signature: Vec<u8>,
+}
+
+impl SignedKeyBundle {
+    /// Check the bundle signature against the pinned `certificate`
+    pub fn verify(&self, certificate: &X509) -> Result<bool> {
+        let public_key = certificate.public_key()?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+        Ok(verifier.verify_oneshot(&self.signature, &self.signed_payload())?)
+    }
+
+    /// Length-prefixed encoding of everything covered by the signature
+    fn signed_payload(&self) -> Vec<u8> {
+        let mut payload = b"safequanta-key-bundle-v1".to_vec();
+        let mut push = |field: &[u8]| {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        };
+        push(format!("{:?}", self.kem_algorithm).as_bytes());
+        push(format!("{:?}", self.signature_algorithm).as_bytes());
+        push(&self.kem_public_key);
+        push(self.sign_public_key.as_deref().unwrap_or_default());
+        payload
+    }
+}
+
+/// Length of each derived traffic key
+pub const SESSION_KEY_LEN: usize = 32;
+
+/// HKDF-Expand labels, one per traffic direction
+///
+/// Distinct labels keep the two directions' keys independent; protocol
+/// variants should pick their own labels so keys never collide across them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionKeyLabels {
+    pub client_to_server: &'static str,
+    pub server_to_client: &'static str,
+}
+
+impl Default for SessionKeyLabels {
+    fn default() -> Self {
+        Self {
+            client_to_server: "safequanta v1 c2s traffic",
+            server_to_client: "safequanta v1 s2c traffic",
+        }
+    }
+}
+
+/// Whether two secret-derived byte strings are equal, in time independent of
+/// where they differ
+///
+/// Only the lengths are compared in variable time; they are not secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Traffic keys derived from a KEM shared secret
+#[derive(Clone, Eq)]
+pub struct DirectionalKeys {
+    pub client_to_server: [u8; SESSION_KEY_LEN],
+    pub server_to_client: [u8; SESSION_KEY_LEN],
+}
+
+impl PartialEq for DirectionalKeys {
+    fn eq(&self, other: &Self) -> bool {
+        let ours = [self.client_to_server, self.server_to_client].concat();
+        let theirs = [other.client_to_server, other.server_to_client].concat();
+        constant_time_eq(&ours, &theirs)
+    }
+}
+
+impl std::fmt::Debug for DirectionalKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DirectionalKeys { .. }")
+    }
+}
+
+/// Derive per-direction session keys with the default labels
+pub fn derive_session_keys(shared_secret: &[u8], transcript: &[u8]) -> DirectionalKeys {
+    derive_session_keys_with_labels(shared_secret, transcript, &SessionKeyLabels::default())
+}
+
+/// Derive per-direction session keys with HKDF-SHA256
+///
+/// The SHA-256 hash of the handshake transcript is the HKDF-Extract salt and
+/// is appended to each label to form the HKDF-Expand info, binding the keys
+/// to this exact handshake.
+pub fn derive_session_keys_with_labels(
+    shared_secret: &[u8],
+    transcript: &[u8],
+    labels: &SessionKeyLabels,
+) -> DirectionalKeys {
+    let transcript_hash = ring::digest::digest(&ring::digest::SHA256, transcript);
+    let prk = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, transcript_hash.as_ref())
+        .extract(shared_secret);
+
+    let expand = |label: &str| {
+        let mut key = [0u8; SESSION_KEY_LEN];
+        let info = [label.as_bytes(), transcript_hash.as_ref()];
+        prk.expand(&info, KeyLen)
+            .and_then(|okm| okm.fill(&mut key))
+            .expect("SESSION_KEY_LEN is within the HKDF-SHA256 output limit");
+        key
+    };
+
+    DirectionalKeys {
+        client_to_server: expand(labels.client_to_server),
+        server_to_client: expand(labels.server_to_client),
+    }
+}
+
+struct KeyLen;
+
+impl ring::hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        SESSION_KEY_LEN
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -177,10 +578,35 @@ mod tests {
             key.path().to_str().unwrap(),
         ).unwrap();
 
-        assert!(provider.kem_secret_key.is_some());
-        assert!(provider.kem_public_key.is_some());
-        assert!(provider.sign_secret_key.is_some());
-        assert!(provider.sign_public_key.is_some());
+        assert!(provider.keys().kem_secret_key.is_some());
+        assert!(provider.keys().kem_public_key.is_some());
+        assert!(provider.keys().sign_secret_key.is_some());
+        assert!(provider.keys().sign_public_key.is_some());
+    }
+
+    #[test]
+    fn test_algorithm_info_matches_config() {
+        let (cert, key) = create_test_cert_and_key();
+
+        let new = |kem, signature| CryptoProvider::new(kem, signature, cert.path(), key.path()).unwrap();
+
+        let provider = new(KemAlgorithm::Kyber1024, SignatureAlgorithm::Dilithium3);
+        let info = provider.algorithm_info();
+        assert_eq!(info.kem, KemAlgorithm::Kyber1024);
+        assert_eq!(info.kem_nist_level, 5);
+        assert_eq!(info.kem_public_key_bytes, kyber1024::public_key_bytes());
+        assert_eq!(info.kem_ciphertext_bytes, kyber1024::ciphertext_bytes());
+        assert_eq!(info.signature, SignatureAlgorithm::Dilithium3);
+        assert_eq!(info.signature_nist_level, 3);
+        assert_eq!(info.signature_bytes, dilithium3::signature_bytes());
+        assert_eq!(provider.sign_public_key().unwrap().len(), info.signature_public_key_bytes);
+        assert!(info.hybrid);
+
+        let provider = new(KemAlgorithm::Kyber768, SignatureAlgorithm::Rsa3072);
+        let info = provider.algorithm_info();
+        assert_eq!(info.kem_public_key_bytes, kyber768::public_key_bytes());
+        assert_eq!((info.signature, info.signature_nist_level), (SignatureAlgorithm::Rsa3072, 1));
+        assert!(!info.hybrid);
     }
 
     #[tokio::test]
@@ -202,8 +628,8 @@ mod tests {
         ).unwrap();
 
         // Get public keys
-        let pk1 = provider1.kem_public_key.as_ref().unwrap().to_bytes();
-        let pk2 = provider2.kem_public_key.as_ref().unwrap().to_bytes();
+        let pk1 = provider1.keys().kem_public_key.as_ref().unwrap().to_bytes().to_vec();
+        let pk2 = provider2.keys().kem_public_key.as_ref().unwrap().to_bytes().to_vec();
 
         // Perform key exchange
         let shared1 = provider1.key_exchange(&pk2).await.unwrap();
@@ -229,4 +655,185 @@ mod tests {
 
         assert!(verified);
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_verify_with_key_rejects_tampered_message() {
+        let (cert, key) = create_test_cert_and_key();
+        let new = || {
+            let (kem, signature) = (KemAlgorithm::Kyber768, SignatureAlgorithm::Dilithium3);
+            CryptoProvider::new(kem, signature, cert.path(), key.path()).unwrap()
+        };
+        let (verifier, signer) = (new(), new());
+        let public_key = signer.sign_public_key().unwrap();
+        let signature = signer.sign(b"challenge").await.unwrap();
+
+        assert!(verifier.verify_with_key(&public_key, b"challenge", &signature).await.unwrap());
+        assert!(!verifier.verify_with_key(&public_key, b"challengE", &signature).await.unwrap());
+        let other_key = new().sign_public_key().unwrap();
+        assert!(!verifier.verify_with_key(&other_key, b"challenge", &signature).await.unwrap());
+    }
+
+    #[test]
+    fn test_mismatched_certificate_and_key_rejected() {
+        let (cert, _) = create_test_cert_and_key();
+        let mut other_key = NamedTempFile::new().unwrap();
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let pem = PKey::from_rsa(rsa).unwrap().private_key_to_pem_pkcs8().unwrap();
+        other_key.write_all(&pem).unwrap();
+
+        let result = CryptoProvider::new(
+            KemAlgorithm::Kyber768,
+            SignatureAlgorithm::Dilithium3,
+            cert.path(),
+            other_key.path(),
+        );
+        match result {
+            Err(SafeQuantaError::Crypto(message)) => {
+                assert_eq!(message, "certificate and private key do not match")
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("mismatched key was accepted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_certificate_chain_pem_roundtrip() {
+        let (cert, key) = create_test_cert_and_key();
+
+        let provider = CryptoProvider::new(
+            KemAlgorithm::Kyber768,
+            SignatureAlgorithm::Rsa3072,
+            cert.path().to_str().unwrap(),
+            key.path().to_str().unwrap(),
+        ).unwrap();
+
+        let pem = provider.certificate_chain_pem().unwrap();
+        let parsed = X509::stack_from_pem(pem.as_bytes()).unwrap();
+
+        assert_eq!(parsed.len(), 1 + provider.intermediates.len());
+        assert_eq!(parsed[0].to_der().unwrap(), provider.certificate.to_der().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rotated_bundle_verifies_with_new_keys() {
+        let (cert, key) = create_test_cert_and_key();
+        // Held the way listeners share it
+        let provider = Arc::new(
+            CryptoProvider::new(
+                KemAlgorithm::Kyber768,
+                SignatureAlgorithm::Dilithium3,
+                cert.path(),
+                key.path(),
+            )
+            .unwrap(),
+        );
+        let old_kem_pk = provider.keys().kem_public_key.as_ref().unwrap().to_bytes().to_vec();
+        let old_sign_pk = provider.keys().sign_public_key.as_ref().unwrap().to_bytes().to_vec();
+
+        let bundle = provider.rotate_and_sign_bundle().unwrap();
+
+        assert!(bundle.verify(&provider.certificate).unwrap());
+        assert_ne!(bundle.kem_public_key, old_kem_pk);
+        assert_ne!(bundle.sign_public_key.as_deref(), Some(old_sign_pk.as_slice()));
+        let kem_pk = provider.keys().kem_public_key.as_ref().unwrap().to_bytes().to_vec();
+        assert_eq!(bundle.kem_public_key, kem_pk);
+
+        // The rotated signing key is the one now in use
+        let signature = provider.sign(b"after rotation").await.unwrap();
+        assert!(provider.verify(b"after rotation", &signature).await.unwrap());
+
+        let mut tampered = bundle.clone();
+        tampered.kem_public_key[0] ^= 1;
+        assert!(!tampered.verify(&provider.certificate).unwrap());
+    }
+
+    #[test]
+    fn test_session_key_derivation_is_deterministic() {
+        let secret = [7u8; 32];
+        let keys = derive_session_keys(&secret, b"client hello|server hello");
+
+        assert_eq!(keys, derive_session_keys(&secret, b"client hello|server hello"));
+        assert_ne!(keys.client_to_server, keys.server_to_client);
+        assert_ne!(&keys.client_to_server[..], &secret[..]);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secret", b"Bearer secreT"));
+        assert!(!constant_time_eq(b"Bearer secret", b"Bearer secret!"));
+
+        // Session keys compare through it
+        let keys = derive_session_keys(&[9u8; 32], b"transcript");
+        assert_eq!(keys, keys.clone());
+        assert_ne!(keys, derive_session_keys(&[9u8; 32], b"other"));
+    }
+
+    #[test]
+    fn test_session_keys_depend_on_transcript_and_labels() {
+        let secret = [7u8; 32];
+        let keys = derive_session_keys(&secret, b"transcript a");
+
+        assert_ne!(keys, derive_session_keys(&secret, b"transcript b"));
+        assert_ne!(keys, derive_session_keys(&[8u8; 32], b"transcript a"));
+
+        let labels = SessionKeyLabels {
+            client_to_server: "other c2s",
+            server_to_client: "other s2c",
+        };
+        assert_ne!(keys, derive_session_keys_with_labels(&secret, b"transcript a", &labels));
+    }
+
+    #[tokio::test]
+    async fn test_decapsulate_without_kem_secret_key() {
+        let (cert, key) = create_test_cert_and_key();
+        let provider = CryptoProvider::new(
+            KemAlgorithm::Kyber768,
+            SignatureAlgorithm::Dilithium3,
+            cert.path().to_str().unwrap(),
+            key.path().to_str().unwrap(),
+        ).unwrap();
+        provider.keys.write().kem_secret_key = None;
+
+        let err = provider.decapsulate(&[0u8; 1088]).await.unwrap_err();
+        assert!(matches!(err, SafeQuantaError::Crypto(ref msg) if msg == "No KEM secret key available"));
+    }
+
+    #[tokio::test]
+    async fn test_decapsulate_checks_ciphertext_length() {
+        let (cert, key) = create_test_cert_and_key();
+        let new = |kem| CryptoProvider::new(kem, SignatureAlgorithm::Dilithium3, cert.path(), key.path()).unwrap();
+
+        let provider = new(KemAlgorithm::Kyber768);
+        assert!(provider.decapsulate(&[0u8; 1088]).await.is_ok());
+        let err = provider.decapsulate(&[0u8; 1000]).await.unwrap_err();
+        assert!(matches!(err, SafeQuantaError::Crypto(ref msg)
+            if msg == "ciphertext length 1000, expected 1088 for kyber768"));
+
+        let provider = new(KemAlgorithm::Kyber1024);
+        let err = provider.decapsulate(&[0u8; 1088]).await.unwrap_err();
+        assert!(matches!(err, SafeQuantaError::Crypto(ref msg)
+            if msg == "ciphertext length 1088, expected 1568 for kyber1024"));
+    }
+
+    #[tokio::test]
+    async fn test_decapsulate_and_derive_matches_encapsulating_side() {
+        let (cert, key) = create_test_cert_and_key();
+        let new = || {
+            let (kem, signature) = (KemAlgorithm::Kyber768, SignatureAlgorithm::Dilithium3);
+            CryptoProvider::new(kem, signature, cert.path(), key.path()).unwrap()
+        };
+        let (server, client) = (new(), new());
+        let server_pk = server.keys().kem_public_key.as_ref().unwrap().to_bytes().to_vec();
+
+        let (ciphertext, client_keys) =
+            client.encapsulate_and_derive(&server_pk, b"transcript").await.unwrap();
+        let server_keys = server.decapsulate_and_derive(&ciphertext, b"transcript").await.unwrap();
+        assert_eq!(server_keys, client_keys);
+        assert_ne!(server_keys.client_to_server, server_keys.server_to_client);
+
+        let other = server.decapsulate_and_derive(&ciphertext, b"other transcript").await.unwrap();
+        assert_ne!(other, client_keys);
+    }
+}
@@ -29,6 +29,24 @@ pub enum SafeQuantaError {
     #[error("Handshake error: {0}")]
     Handshake(String),
 
+    #[error("Handshake framing error: {0}")]
+    Framing(#[from] crate::handshake::FramingError),
+
+    #[error("Algorithm disabled: {0}")]
+    AlgorithmDisabled(String),
+
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    #[error("Idle timeout: {0}")]
+    IdleTimeout(String),
+
+    #[error("Connection byte quota exceeded")]
+    QuotaExceeded,
+
+    #[error("Connection relay buffer limit exceeded")]
+    BufferLimitExceeded,
+
     #[error("Fallback error: {0}")]
     Fallback(String),
 
@@ -0,0 +1,70 @@
+//! Connection lifecycle events for applications embedding the proxy
+
+use crate::proxy::{CloseReason, ConnectionStats};
+use std::net::SocketAddr;
+
+/// Events buffered per subscriber; one that falls further behind misses the
+/// oldest and sees `RecvError::Lagged`
+pub const EVENT_CAPACITY: usize = 1024;
+
+/// Something that happened to a client connection
+///
+/// Received through `ProxyServer::subscribe`. `id` matches the one in the
+/// connection's log lines and `connection_summary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A client connection was accepted
+    Opened { id: u64, peer: SocketAddr },
+    /// The client TLS handshake (and client auth, if enabled) completed
+    HandshakeCompleted {
+        id: u64,
+        peer: SocketAddr,
+        /// Key exchange group and cipher suite, as in `connection_summary`
+        algorithms: String,
+    },
+    /// The connection ended
+    Closed {
+        id: u64,
+        peer: SocketAddr,
+        reason: CloseReason,
+        client_to_target_bytes: u64,
+        target_to_client_bytes: u64,
+        /// The error that ended the connection, if any
+        error: Option<String>,
+    },
+}
+
+impl Event {
+    pub(crate) fn handshake_completed(stats: &ConnectionStats, peer: SocketAddr) -> Self {
+        Event::HandshakeCompleted {
+            id: stats.id,
+            peer,
+            algorithms: stats.negotiated().unwrap_or("none").to_string(),
+        }
+    }
+
+    pub(crate) fn closed(
+        stats: &ConnectionStats,
+        peer: SocketAddr,
+        reason: CloseReason,
+        error: Option<String>,
+    ) -> Self {
+        Event::Closed {
+            id: stats.id,
+            peer,
+            reason,
+            client_to_target_bytes: stats.client_to_target(),
+            target_to_client_bytes: stats.target_to_client(),
+            error,
+        }
+    }
+
+    /// Id of the connection the event is about
+    pub fn id(&self) -> u64 {
+        match self {
+            Event::Opened { id, .. }
+            | Event::HandshakeCompleted { id, .. }
+            | Event::Closed { id, .. } => *id,
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use openssl::hash::{hash, MessageDigest};
+use tokio::net::TcpStream;
+
+/// Largest ClientHello record peeked for fingerprinting (one TLS record)
+const MAX_CLIENT_HELLO: usize = 5 + 16 * 1024;
+
+/// Attempts at peeking a ClientHello split over several TCP segments
+const PEEK_ATTEMPTS: u32 = 50;
+const PEEK_RETRY: std::time::Duration = std::time::Duration::from_millis(10);
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+
+/// Parameters a client offered in its ClientHello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    pub version: u16,
+    pub cipher_suites: Vec<u16>,
+    /// Extension types in the order sent
+    pub extensions: Vec<u16>,
+    /// `supported_groups`: key exchange groups, in preference order
+    pub groups: Vec<u16>,
+    pub point_formats: Vec<u16>,
+    /// `signature_algorithms`: signature schemes, in preference order
+    pub signature_schemes: Vec<u16>,
+    /// `server_name`: the SNI host name
+    pub server_name: Option<String>,
+}
+
+impl ClientHello {
+    /// Parse a TLS record containing a ClientHello
+    ///
+    /// Returns `None` if the record is not a complete ClientHello.
+    pub fn parse(record: &[u8]) -> Option<Self> {
+        let mut r = Reader(record);
+        // TLS record header: handshake content type, version, length
+        if r.u8()? != 22 {
+            return None;
+        }
+        r.u16()?;
+        let mut r = Reader(r.bytes(r.u16()? as usize)?);
+
+        // Handshake header: ClientHello type and 24-bit length
+        if r.u8()? != 1 {
+            return None;
+        }
+        let len = u32::from_be_bytes([0, r.u8()?, r.u8()?, r.u8()?]) as usize;
+        let mut r = Reader(r.bytes(len)?);
+
+        let version = r.u16()?;
+        r.bytes(32)?;
+        let session_id_len = r.u8()? as usize;
+        r.bytes(session_id_len)?;
+        let ciphers_len = r.u16()? as usize;
+        let cipher_suites = Reader(r.bytes(ciphers_len)?).u16_list();
+        let compression_len = r.u8()? as usize;
+        r.bytes(compression_len)?;
+
+        let mut hello = Self {
+            version,
+            cipher_suites,
+            extensions: Vec::new(),
+            groups: Vec::new(),
+            point_formats: Vec::new(),
+            signature_schemes: Vec::new(),
+            server_name: None,
+        };
+        if !r.0.is_empty() {
+            let extensions_len = r.u16()? as usize;
+            let mut r = Reader(r.bytes(extensions_len)?);
+            while !r.0.is_empty() {
+                let kind = r.u16()?;
+                let ext_len = r.u16()? as usize;
+                let mut data = Reader(r.bytes(ext_len)?);
+                hello.extensions.push(kind);
+                match kind {
+                    EXT_SERVER_NAME => {
+                        let list_len = data.u16()? as usize;
+                        let mut names = Reader(data.bytes(list_len)?);
+                        while !names.0.is_empty() {
+                            let name_type = names.u8()?;
+                            let name_len = names.u16()? as usize;
+                            let name = names.bytes(name_len)?;
+                            // Only host_name (0) is defined
+                            if name_type == 0 {
+                                hello.server_name = std::str::from_utf8(name).ok().map(str::to_owned);
+                            }
+                        }
+                    }
+                    EXT_SUPPORTED_GROUPS => {
+                        let list_len = data.u16()? as usize;
+                        hello.groups = Reader(data.bytes(list_len)?).u16_list();
+                    }
+                    EXT_EC_POINT_FORMATS => {
+                        let list_len = data.u8()? as usize;
+                        hello.point_formats =
+                            data.bytes(list_len)?.iter().map(|&f| f as u16).collect();
+                    }
+                    EXT_SIGNATURE_ALGORITHMS => {
+                        let list_len = data.u16()? as usize;
+                        hello.signature_schemes = Reader(data.bytes(list_len)?).u16_list();
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Some(hello)
+    }
+
+    /// JA3 fingerprint of this ClientHello
+    pub fn ja3(&self) -> Option<Ja3> {
+        let string = format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(&self.cipher_suites),
+            join(&self.extensions),
+            join(&self.groups),
+            join(&self.point_formats)
+        );
+        let digest = hash(MessageDigest::md5(), string.as_bytes()).ok()?;
+        let hash = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        Some(Ja3 { string, hash })
+    }
+}
+
+/// JA3 fingerprint of a ClientHello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ja3 {
+    /// `version,ciphers,extensions,groups,point_formats`
+    pub string: String,
+    /// Hex MD5 of `string`
+    pub hash: String,
+}
+
+impl Ja3 {
+    /// Fingerprint a TLS record containing a ClientHello
+    ///
+    /// Returns `None` if the record is not a complete ClientHello.
+    pub fn from_client_hello(record: &[u8]) -> Option<Self> {
+        ClientHello::parse(record)?.ja3()
+    }
+
+    /// Low-cardinality bucket for metrics: the first two hex digits of the hash
+    pub fn bucket(&self) -> &str {
+        &self.hash[..2]
+    }
+}
+
+/// Parse the ClientHello waiting on `stream` without consuming it
+///
+/// A ClientHello record that has only partly arrived is peeked again for a
+/// short while, as large (e.g. PQC) key shares can span several segments.
+pub async fn peek_client_hello(stream: &TcpStream) -> Option<ClientHello> {
+    let mut buf = vec![0u8; MAX_CLIENT_HELLO];
+    for _ in 0..PEEK_ATTEMPTS {
+        let n = stream.peek(&mut buf).await.ok()?;
+        if let Some(hello) = ClientHello::parse(&buf[..n]) {
+            return Some(hello);
+        }
+        // Wait only while a handshake record is still arriving
+        let record_len = |buf: &[u8]| 5 + u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        let arriving = n > 0
+            && buf[0] == 22
+            && (n < 5 || n < record_len(&buf).min(MAX_CLIENT_HELLO));
+        if !arriving {
+            return None;
+        }
+        tokio::time::sleep(PEEK_RETRY).await;
+    }
+    None
+}
+
+/// Bytes a client must send for its first handshake message, from the record
+/// and handshake headers at the start of `prefix`
+///
+/// The larger of the record length and the handshake message length is
+/// returned, as a message may be fragmented over several records. Returns
+/// `None` if `prefix` is too short or is not a handshake record.
+pub fn announced_handshake_len(prefix: &[u8]) -> Option<usize> {
+    let mut r = Reader(prefix);
+    if r.u8()? != 22 {
+        return None;
+    }
+    r.u16()?;
+    let record_len = r.u16()? as usize;
+    r.u8()?;
+    let message_len = u32::from_be_bytes([0, r.u8()?, r.u8()?, r.u8()?]) as usize;
+    Some(record_len.max(4 + message_len))
+}
+
+/// Peek the headers waiting on `stream` for `announced_handshake_len`
+///
+/// Headers split over several segments are peeked again until all nine bytes
+/// have arrived, the client closes or the bytes can't be a handshake record.
+/// This does not time out by itself; callers bound it with the handshake
+/// timeout.
+pub async fn peek_handshake_len(stream: &TcpStream) -> Option<usize> {
+    let mut buf = [0u8; 9];
+    loop {
+        let n = stream.peek(&mut buf).await.ok()?;
+        if n == buf.len() || n == 0 || !starts_tls_handshake(&buf[..n.min(2)]) {
+            return announced_handshake_len(&buf[..n]);
+        }
+        tokio::time::sleep(PEEK_RETRY).await;
+    }
+}
+
+/// Whether `prefix`, the first bytes a client sent, can open a TLS connection
+///
+/// Only a handshake record (content type 22, major version 3) can; empty
+/// input, plaintext protocols and scanner probes cannot.
+pub fn starts_tls_handshake(prefix: &[u8]) -> bool {
+    matches!(prefix, [22] | [22, 3, ..])
+}
+
+/// Peek the first bytes waiting on `stream` for `starts_tls_handshake`
+///
+/// `None` if the client sends nothing within `wait`.
+pub async fn peek_starts_tls(stream: &TcpStream, wait: std::time::Duration) -> Option<bool> {
+    let mut buf = [0u8; 2];
+    let n = tokio::time::timeout(wait, stream.peek(&mut buf)).await.ok()?.ok()?;
+    Some(starts_tls_handshake(&buf[..n]))
+}
+
+/// Fingerprint the ClientHello waiting on `stream` without consuming it
+pub async fn peek_ja3(stream: &TcpStream) -> Option<Ja3> {
+    peek_client_hello(stream).await?.ja3()
+}
+
+/// Dash-separated values, skipping GREASE (RFC 8701) placeholders
+fn join(values: &[u16]) -> String {
+    values
+        .iter()
+        .filter(|&&v| !is_grease(v))
+        .map(u16::to_string)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u16_list(mut self) -> Vec<u16> {
+        std::iter::from_fn(|| self.u16()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{
+        ClientConfig, ClientConnection, NamedGroup, RootCertStore, SignatureScheme,
+    };
+
+    fn client_hello(config: ClientConfig) -> Vec<u8> {
+        let mut conn = ClientConnection::new(Arc::new(config), "example.com".try_into().unwrap()).unwrap();
+        let mut record = Vec::new();
+        conn.write_tls(&mut record).unwrap();
+        record
+    }
+
+    fn base_config() -> ClientConfig {
+        ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth()
+    }
+
+    #[test]
+    fn test_different_tls_stacks_have_different_fingerprints() {
+        let modern = base_config();
+        let mut legacy = ClientConfig::builder_with_protocol_versions(&[&tokio_rustls::rustls::version::TLS12])
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        legacy.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let a = Ja3::from_client_hello(&client_hello(modern)).unwrap();
+        let b = Ja3::from_client_hello(&client_hello(legacy)).unwrap();
+        let again = Ja3::from_client_hello(&client_hello(base_config())).unwrap();
+
+        assert_ne!(a.hash, b.hash);
+        assert_eq!(a, again);
+        assert_eq!(a.hash.len(), 32);
+    }
+
+    #[test]
+    fn test_ja3_string_skips_grease() {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        hello.push(0); // session id
+        hello.extend_from_slice(&[0, 6, 0x0a, 0x0a, 0x13, 0x01, 0x13, 0x02]);
+        hello.extend_from_slice(&[1, 0]); // compression
+        let extensions = [
+            0x00, 0x0a, 0, 6, 0, 4, 0x1a, 0x1a, 0x00, 0x1d, // supported_groups
+            0x00, 0x0b, 0, 2, 1, 0, // ec_point_formats
+        ];
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&((hello.len() + 4) as u16).to_be_bytes());
+        record.push(1);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+
+        let ja3 = Ja3::from_client_hello(&record).unwrap();
+        assert_eq!(ja3.string, "771,4865-4866,10-11,29,0");
+        assert!(Ja3::from_client_hello(&record[..record.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_client_hello_lists_offered_parameters() {
+        let hello = ClientHello::parse(&client_hello(base_config())).unwrap();
+        assert!(hello.groups.contains(&u16::from(NamedGroup::X25519)));
+        assert!(hello
+            .signature_schemes
+            .contains(&u16::from(SignatureScheme::ECDSA_NISTP256_SHA256)));
+        assert!(!hello.cipher_suites.is_empty());
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_announced_handshake_len() {
+        let record = client_hello(base_config());
+        assert_eq!(announced_handshake_len(&record), Some(record.len() - 5));
+
+        // A 1 MiB ClientHello announced in a 16 KiB record
+        let oversized = [22, 3, 1, 0x40, 0x00, 1, 0x10, 0x00, 0x00];
+        assert_eq!(announced_handshake_len(&oversized), Some(4 + 0x10_0000));
+        assert_eq!(announced_handshake_len(&oversized[..8]), None);
+        assert_eq!(announced_handshake_len(b"GET / HTTP/1.1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_peek_handshake_len_waits_for_split_headers() {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.set_nodelay(true).unwrap();
+
+        // The record header arrives before the handshake header
+        client.write_all(&[22, 3, 1]).await.unwrap();
+        let sender = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            client.write_all(&[0x40, 0x00, 1, 0x01, 0x00, 0x00]).await.unwrap();
+            client
+        });
+        assert_eq!(peek_handshake_len(&server).await, Some(4 + 0x010000));
+        drop(sender.await.unwrap());
+    }
+
+    #[test]
+    fn test_starts_tls_handshake() {
+        assert!(starts_tls_handshake(&client_hello(base_config())));
+        assert!(starts_tls_handshake(&[22]));
+        assert!(!starts_tls_handshake(b"GET / HTTP/1.1\r\n"));
+        assert!(!starts_tls_handshake(b"SSH-2.0-OpenSSH_9.6"));
+        assert!(!starts_tls_handshake(&[22, 0x47]));
+        assert!(!starts_tls_handshake(&[]));
+    }
+}
@@ -0,0 +1,434 @@
+//! Framing for an application-layer PQC handshake
+//!
+//! Nothing in the proxy speaks this protocol yet: TLS handshakes are done by
+//! rustls, and client authentication (`TlsManager::authenticate_client`) has
+//! its own challenge format. The codec and `read_framed` are scaffolding for
+//! that handshake, tested on their own; only `MAX_FIELD_LEN` is used
+//! elsewhere.
+
+use crate::error::SafeQuantaError;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Current handshake framing version
+pub const FRAMING_VERSION: u8 = 1;
+
+/// Size of the frame header: version, message type and `u32` body length
+pub const HEADER_LEN: usize = 6;
+
+/// Upper bound on any single length-prefixed field in a handshake message
+pub const MAX_FIELD_LEN: usize = 8192;
+
+/// Upper bound on the declared body length of a handshake message
+pub const MAX_BODY_LEN: usize = 3 * (2 + MAX_FIELD_LEN);
+
+/// Reasons a handshake frame is rejected
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum FramingError {
+    #[error("Unsupported framing version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Unknown handshake message type {0}")]
+    UnknownMessageType(u8),
+
+    #[error("Length {len} for {field} exceeds maximum of {max}")]
+    LengthExceedsMax { field: &'static str, len: usize, max: usize },
+
+    #[error("Truncated {field}: expected {expected} bytes, got {actual}")]
+    Truncated { field: &'static str, expected: usize, actual: usize },
+
+    #[error("{0} trailing bytes after {1}")]
+    TrailingBytes(usize, &'static str),
+}
+
+/// Kind of PQC handshake message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// Client KEM public key
+    ClientHello = 1,
+    /// Server KEM ciphertext and signature
+    ServerHello = 2,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = FramingError;
+
+    fn try_from(tag: u8) -> Result<Self, FramingError> {
+        match tag {
+            1 => Ok(MessageType::ClientHello),
+            2 => Ok(MessageType::ServerHello),
+            _ => Err(FramingError::UnknownMessageType(tag)),
+        }
+    }
+}
+
+/// PQC handshake message carried alongside the TLS handshake
+///
+/// Wire format: a header of version (`u8`), message type (`u8`) and
+/// big-endian `u32` body length, then a body of three fields, each a
+/// big-endian `u16` length followed by that many bytes, in the order KEM
+/// public key, KEM ciphertext, signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeMessage {
+    pub message_type: MessageType,
+    pub kem_public_key: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl HandshakeMessage {
+    /// Encode the message into its wire format
+    pub fn encode(&self) -> Result<Vec<u8>, FramingError> {
+        let mut out = self.signed_payload()?;
+        push_field(&mut out, &self.signature, "signature")?;
+        Ok(out)
+    }
+
+    /// Bytes covered by the signature: the header and every field before it
+    ///
+    /// Signing these authenticates the declared lengths as well as the keys.
+    pub fn signed_payload(&self) -> Result<Vec<u8>, FramingError> {
+        let body_len = 6 + self.kem_public_key.len() + self.ciphertext.len() + self.signature.len();
+        let mut out = Vec::with_capacity(HEADER_LEN + body_len);
+        out.push(FRAMING_VERSION);
+        out.push(self.message_type as u8);
+        out.extend_from_slice(&(body_len as u32).to_be_bytes());
+        push_field(&mut out, &self.kem_public_key, "KEM public key")?;
+        push_field(&mut out, &self.ciphertext, "ciphertext")?;
+        Ok(out)
+    }
+
+    /// Parse a handshake frame, rejecting unknown versions and types,
+    /// oversized declared lengths, truncation and trailing data
+    pub fn decode(input: &[u8]) -> Result<Self, FramingError> {
+        if input.len() < HEADER_LEN {
+            return Err(FramingError::Truncated {
+                field: "header",
+                expected: HEADER_LEN,
+                actual: input.len(),
+            });
+        }
+        let (message_type, body_len) = parse_header(&input[..HEADER_LEN])?;
+
+        let body = &input[HEADER_LEN..];
+        if body.len() < body_len {
+            return Err(FramingError::Truncated {
+                field: "message body",
+                expected: body_len,
+                actual: body.len(),
+            });
+        }
+        if body.len() > body_len {
+            return Err(FramingError::TrailingBytes(body.len() - body_len, "handshake message"));
+        }
+
+        let mut rest = body;
+        let kem_public_key = read_field(&mut rest, "KEM public key")?;
+        let ciphertext = read_field(&mut rest, "ciphertext")?;
+        let signature = read_field(&mut rest, "signature")?;
+        if !rest.is_empty() {
+            return Err(FramingError::TrailingBytes(rest.len(), "signature"));
+        }
+
+        Ok(HandshakeMessage {
+            message_type,
+            kem_public_key,
+            ciphertext,
+            signature,
+        })
+    }
+}
+
+/// Message type and declared body length from a frame header
+fn parse_header(header: &[u8]) -> Result<(MessageType, usize), FramingError> {
+    if header[0] != FRAMING_VERSION {
+        return Err(FramingError::UnsupportedVersion(header[0]));
+    }
+    let message_type = MessageType::try_from(header[1])?;
+    let body_len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if body_len > MAX_BODY_LEN {
+        return Err(FramingError::LengthExceedsMax {
+            field: "message body",
+            len: body_len,
+            max: MAX_BODY_LEN,
+        });
+    }
+    Ok((message_type, body_len))
+}
+
+/// Read one complete handshake frame from `stream` within `limit`
+///
+/// Not called by the proxy yet; see the module documentation.
+///
+/// A frame may arrive split across any number of reads; bytes are
+/// accumulated until the header and the body length it declares are in.
+/// The header is checked before the body is read, so an oversized length is
+/// refused without buffering it. EOF before the frame is complete is
+/// `FramingError::Truncated`.
+pub async fn read_framed<R>(stream: &mut R, limit: Duration) -> crate::error::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let read = async {
+        let mut frame = Vec::with_capacity(HEADER_LEN);
+        fill(stream, &mut frame, HEADER_LEN, "header").await?;
+        let (_, body_len) = parse_header(&frame)?;
+        fill(stream, &mut frame, HEADER_LEN + body_len, "message body").await?;
+        Ok(frame)
+    };
+    tokio::time::timeout(limit, read).await.map_err(|_| {
+        SafeQuantaError::Timeout(format!("No complete handshake frame within {:?}", limit))
+    })?
+}
+
+/// Read and decode one handshake message, as `read_framed`
+pub async fn read_message<R>(
+    stream: &mut R,
+    limit: Duration,
+) -> crate::error::Result<HandshakeMessage>
+where
+    R: AsyncRead + Unpin,
+{
+    let frame = read_framed(stream, limit).await?;
+    Ok(HandshakeMessage::decode(&frame)?)
+}
+
+/// Read from `stream` until `buf` holds `len` bytes
+async fn fill<R>(
+    stream: &mut R,
+    buf: &mut Vec<u8>,
+    len: usize,
+    field: &'static str,
+) -> crate::error::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let start = buf.len();
+    buf.resize(len, 0);
+    let mut filled = start;
+    while filled < len {
+        let n = stream.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            return Err(FramingError::Truncated {
+                field,
+                expected: len - start,
+                actual: filled - start,
+            }
+            .into());
+        }
+        filled += n;
+    }
+    Ok(())
+}
+
+fn push_field(out: &mut Vec<u8>, field: &[u8], name: &'static str) -> Result<(), FramingError> {
+    if field.len() > MAX_FIELD_LEN {
+        return Err(FramingError::LengthExceedsMax {
+            field: name,
+            len: field.len(),
+            max: MAX_FIELD_LEN,
+        });
+    }
+    out.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    out.extend_from_slice(field);
+    Ok(())
+}
+
+fn read_field(rest: &mut &[u8], name: &'static str) -> Result<Vec<u8>, FramingError> {
+    if rest.len() < 2 {
+        return Err(FramingError::Truncated {
+            field: name,
+            expected: 2,
+            actual: rest.len(),
+        });
+    }
+    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+    if len > MAX_FIELD_LEN {
+        return Err(FramingError::LengthExceedsMax {
+            field: name,
+            len,
+            max: MAX_FIELD_LEN,
+        });
+    }
+    let body = &rest[2..];
+    if body.len() < len {
+        return Err(FramingError::Truncated {
+            field: name,
+            expected: len,
+            actual: body.len(),
+        });
+    }
+    let (field, tail) = body.split_at(len);
+    *rest = tail;
+    Ok(field.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> HandshakeMessage {
+        HandshakeMessage {
+            message_type: MessageType::ServerHello,
+            kem_public_key: vec![1; 32],
+            ciphertext: vec![2; 16],
+            signature: vec![3; 8],
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let msg = sample();
+        let encoded = msg.encode().unwrap();
+        assert_eq!(&encoded[..2], &[FRAMING_VERSION, MessageType::ServerHello as u8]);
+        assert_eq!(HandshakeMessage::decode(&encoded).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_roundtrip_empty_fields() {
+        let msg = HandshakeMessage {
+            message_type: MessageType::ClientHello,
+            kem_public_key: vec![],
+            ciphertext: vec![],
+            signature: vec![],
+        };
+        let encoded = msg.encode().unwrap();
+        assert_eq!(encoded.len(), HEADER_LEN + 6);
+        assert_eq!(HandshakeMessage::decode(&encoded).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_signed_payload_is_encoding_prefix() {
+        let msg = sample();
+        let encoded = msg.encode().unwrap();
+        let signed = msg.signed_payload().unwrap();
+        assert_eq!(&encoded[..signed.len()], &signed[..]);
+        assert_eq!(encoded.len() - signed.len(), 2 + msg.signature.len());
+    }
+
+    #[test]
+    fn test_empty_input() {
+        assert!(matches!(
+            HandshakeMessage::decode(&[]),
+            Err(FramingError::Truncated { field: "header", .. })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_body() {
+        let encoded = sample().encode().unwrap();
+        for len in 0..encoded.len() {
+            assert!(matches!(
+                HandshakeMessage::decode(&encoded[..len]),
+                Err(FramingError::Truncated { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut encoded = sample().encode().unwrap();
+        encoded[0] = 2;
+        assert_eq!(HandshakeMessage::decode(&encoded), Err(FramingError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn test_unknown_message_type() {
+        let mut encoded = sample().encode().unwrap();
+        encoded[1] = 0xff;
+        assert_eq!(HandshakeMessage::decode(&encoded), Err(FramingError::UnknownMessageType(0xff)));
+    }
+
+    #[test]
+    fn test_declared_body_length_exceeds_max() {
+        let mut encoded = sample().encode().unwrap();
+        encoded[2..6].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            HandshakeMessage::decode(&encoded),
+            Err(FramingError::LengthExceedsMax { field: "message body", .. })
+        ));
+    }
+
+    #[test]
+    fn test_overlong_field_length_prefix() {
+        let mut encoded = sample().encode().unwrap();
+        encoded[HEADER_LEN..HEADER_LEN + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(matches!(
+            HandshakeMessage::decode(&encoded),
+            Err(FramingError::LengthExceedsMax { field: "KEM public key", .. })
+        ));
+    }
+
+    #[test]
+    fn test_field_lengths_disagree_with_body_length() {
+        let mut encoded = sample().encode().unwrap();
+        // Shrink the KEM public key so the fields no longer fill the body
+        encoded[HEADER_LEN..HEADER_LEN + 2].copy_from_slice(&31u16.to_be_bytes());
+        assert!(HandshakeMessage::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_trailing_garbage() {
+        let mut encoded = sample().encode().unwrap();
+        encoded.push(0);
+        assert_eq!(
+            HandshakeMessage::decode(&encoded),
+            Err(FramingError::TrailingBytes(1, "handshake message"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_framed_reassembles_small_chunks() {
+        let frame = sample().encode().unwrap();
+        let (mut reader, mut writer) = tokio::io::duplex(4);
+        let chunks = frame.clone();
+        let sender = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for chunk in chunks.chunks(3) {
+                writer.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            // A second frame right behind the first is left unread
+            writer.write_all(&[FRAMING_VERSION]).await.unwrap();
+            writer
+        });
+
+        let limit = Duration::from_secs(5);
+        let received = read_framed(&mut reader, limit).await.unwrap();
+        assert_eq!(received, frame);
+        assert_eq!(HandshakeMessage::decode(&received).unwrap(), sample());
+
+        // EOF partway through a frame is a truncation, not a short frame
+        drop(sender.await.unwrap());
+        let err = read_framed(&mut reader, limit).await.unwrap_err();
+        assert!(matches!(
+            err,
+            SafeQuantaError::Framing(FramingError::Truncated { field: "header", actual: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_framed_rejects_oversized_length_before_body() {
+        let mut header = vec![FRAMING_VERSION, MessageType::ClientHello as u8];
+        header.extend_from_slice(&(MAX_BODY_LEN as u32 + 1).to_be_bytes());
+        let err = read_framed(&mut header.as_slice(), Duration::from_secs(5)).await.unwrap_err();
+        assert!(matches!(err, SafeQuantaError::Framing(FramingError::LengthExceedsMax { .. })));
+
+        // A peer that stops mid-frame runs into the deadline
+        let (mut reader, _writer) = tokio::io::duplex(64);
+        let err = read_framed(&mut reader, Duration::from_millis(50)).await.unwrap_err();
+        assert!(matches!(err, SafeQuantaError::Timeout(_)));
+    }
+
+    #[test]
+    fn test_encode_rejects_oversized_field() {
+        let mut msg = sample();
+        msg.signature = vec![0; MAX_FIELD_LEN + 1];
+        assert!(matches!(
+            msg.encode(),
+            Err(FramingError::LengthExceedsMax { field: "signature", .. })
+        ));
+    }
+}
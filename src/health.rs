@@ -0,0 +1,219 @@
+use crate::balancer::UpstreamSet;
+use crate::config::{HealthCheckConfig, ProxyConfig};
+use crate::error::{Result, SafeQuantaError};
+use crate::l7::upstream_authority;
+use crate::pool::connect_upstream;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+/// Response bytes searched for `expect` before the check gives up
+const MAX_RESPONSE_LEN: usize = 16 * 1024;
+
+/// Upstreams of routes with a `health_check`, each with its check and the
+/// local address of the route defining the check
+///
+/// The first route with a check wins when several share an upstream.
+pub fn checked_upstreams(
+    config: &ProxyConfig,
+) -> Vec<(String, HealthCheckConfig, Option<SocketAddr>)> {
+    let mut checked: Vec<(String, HealthCheckConfig, Option<SocketAddr>)> = vec![];
+    for route in &config.routes {
+        if let Some(check) = &route.health_check {
+            if !checked.iter().any(|(upstream, _, _)| *upstream == route.upstream) {
+                let bind_addr = config.bind_addr_for(Some(route));
+                checked.push((route.upstream.clone(), check.clone(), bind_addr));
+            }
+        }
+    }
+    checked
+}
+
+/// Run one check: connect to `upstream`, write `send` and wait for `expect`
+///
+/// The whole check must finish within the check interval.
+pub async fn check_upstream(
+    upstream: &str,
+    check: &HealthCheckConfig,
+    bind_addr: Option<SocketAddr>,
+) -> Result<()> {
+    let addr = upstream_authority(upstream);
+    let limit = Duration::from_secs(check.interval.max(1));
+    let exchange = async {
+        let mut stream = connect_upstream(&addr, bind_addr).await?;
+        if !check.send.is_empty() {
+            stream.write_all(check.send.as_bytes()).await?;
+        }
+        if check.expect.is_empty() {
+            return Ok(());
+        }
+
+        let expect = check.expect.as_bytes();
+        let mut response = Vec::new();
+        let mut buf = [0u8; 4096];
+        while response.len() < MAX_RESPONSE_LEN {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..read]);
+            if response.windows(expect.len()).any(|window| window == expect) {
+                return Ok(());
+            }
+        }
+        Err(SafeQuantaError::Proxy(format!(
+            "Health check response from {} does not contain {:?}",
+            addr, check.expect
+        )))
+    };
+    tokio::time::timeout(limit, exchange)
+        .await
+        .map_err(|_| SafeQuantaError::Timeout(format!("Health check of {} timed out", addr)))?
+}
+
+/// Consecutive check results, deciding when an upstream changes state
+#[derive(Debug, Default)]
+struct HealthState {
+    ejected: bool,
+    /// Consecutive results disagreeing with the current state
+    streak: u32,
+}
+
+impl HealthState {
+    /// Record a check result, returning the new ejected state on a change
+    fn observe(&mut self, passed: bool, check: &HealthCheckConfig) -> Option<bool> {
+        if passed != self.ejected {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        let needed = if self.ejected { check.rise } else { check.fall };
+        if self.streak < needed.max(1) {
+            return None;
+        }
+        self.ejected = !self.ejected;
+        self.streak = 0;
+        Some(self.ejected)
+    }
+}
+
+/// Check `upstream` every `interval` seconds until `shutdown`, ejecting it
+/// from `set` after `fall` failed checks in a row and restoring it after
+/// `rise` passed ones
+pub async fn monitor(
+    set: Arc<UpstreamSet>,
+    upstream: String,
+    check: HealthCheckConfig,
+    bind_addr: Option<SocketAddr>,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(check.interval.max(1)));
+    let mut state = HealthState::default();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let result = check_upstream(&upstream, &check, bind_addr).await;
+        if let Err(e) = &result {
+            log::debug!("Health check of {} failed: {}", upstream, e);
+        }
+        crate::metrics::record_health_check(&upstream, result.is_ok());
+        if let Some(ejected) = state.observe(result.is_ok(), &check) {
+            set.set_ejected(&upstream, ejected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Upstream answering every connection with `response`
+    async fn stub_upstream(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn http_check(expect: &str) -> HealthCheckConfig {
+        HealthCheckConfig {
+            interval: 2,
+            send: "GET /health HTTP/1.0\r\n\r\n".to_string(),
+            expect: expect.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expect_match_passes() {
+        let upstream = stub_upstream("HTTP/1.0 200 OK\r\n\r\nhealthy").await;
+        check_upstream(&upstream, &http_check("200 OK"), None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expect_mismatch_fails() {
+        let upstream = stub_upstream("HTTP/1.0 503 Service Unavailable\r\n\r\n").await;
+        let err = check_upstream(&upstream, &http_check("200 OK"), None).await.unwrap_err();
+        assert!(matches!(err, SafeQuantaError::Proxy(ref msg) if msg.contains("200 OK")));
+    }
+
+    #[test]
+    fn test_check_bound_like_its_route() {
+        use crate::config::RouteConfig;
+
+        let route = |bind_addr: &str, health_check: Option<HealthCheckConfig>| RouteConfig {
+            server_name: "a.example".to_string(),
+            upstream: "http://10.0.0.1:80".to_string(),
+            timeouts: Default::default(),
+            allow_early_data: false,
+            bind_addr: Some(bind_addr.parse().unwrap()),
+            dscp: None,
+            maintenance_page: None,
+            health_check,
+            header_rules: Default::default(),
+            forward_sni: false,
+        };
+        let config = ProxyConfig {
+            routes: vec![
+                route("127.0.0.2:0", None),
+                route("127.0.0.3:0", Some(http_check("200 OK"))),
+                route("127.0.0.4:0", Some(http_check("ok"))),
+            ],
+            ..Default::default()
+        };
+
+        let checked = checked_upstreams(&config);
+        assert_eq!(checked.len(), 1);
+        let (upstream, check, bind_addr) = &checked[0];
+        assert_eq!(upstream, "http://10.0.0.1:80");
+        assert_eq!(check.expect, "200 OK");
+        assert_eq!(*bind_addr, Some("127.0.0.3:0".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_fall_and_rise_thresholds() {
+        let check = HealthCheckConfig {
+            fall: 2,
+            rise: 2,
+            ..Default::default()
+        };
+        let mut state = HealthState::default();
+        assert_eq!(state.observe(false, &check), None);
+        assert_eq!(state.observe(true, &check), None);
+        assert_eq!(state.observe(false, &check), None);
+        assert_eq!(state.observe(false, &check), Some(true));
+        assert_eq!(state.observe(true, &check), None);
+        assert_eq!(state.observe(true, &check), Some(false));
+    }
+}
@@ -0,0 +1,123 @@
+//! Append-only JSON lines files written from a dedicated thread
+//!
+//! Records are serialized by the caller and queued; a thread per file does
+//! the blocking writes, so connection tasks never wait on the disk.
+
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// Lines waiting for the writer thread before further records are dropped
+const QUEUE_DEPTH: usize = 1024;
+
+enum Command {
+    Line(Vec<u8>),
+    /// Answered once every line queued before it is written
+    Flush(SyncSender<()>),
+}
+
+/// Queue of lines for one file, drained by its writer thread
+///
+/// The thread exits once the writer is dropped and the queue is empty.
+pub struct JsonLinesWriter {
+    path: PathBuf,
+    queue: SyncSender<Command>,
+}
+
+impl JsonLinesWriter {
+    /// Start a writer thread appending to `path`
+    ///
+    /// With `max_bytes`, lines that would grow the file beyond it are dropped.
+    pub fn spawn(path: impl Into<PathBuf>, max_bytes: Option<u64>) -> Self {
+        let path = path.into();
+        let (queue, commands) = mpsc::sync_channel(QUEUE_DEPTH);
+        let thread_path = path.clone();
+        std::thread::spawn(move || write_lines(&thread_path, max_bytes, commands));
+        Self { path, queue }
+    }
+
+    /// Queue `record` as a line
+    ///
+    /// Dropped with a warning if the writer thread has fallen behind.
+    pub fn append<T: Serialize>(&self, record: &T) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("Failed to serialize record for {:?}: {}", self.path, e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(TrySendError::Full(_)) = self.queue.try_send(Command::Line(line)) {
+            log::warn!("Writer for {:?} is behind, dropping a record", self.path);
+        }
+    }
+
+    /// Wait until every line queued so far is written
+    pub fn flush(&self) {
+        let (done, written) = mpsc::sync_channel(1);
+        if self.queue.send(Command::Flush(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+fn write_lines(path: &Path, max_bytes: Option<u64>, commands: Receiver<Command>) {
+    let mut file: Option<(File, u64)> = None;
+    for command in commands {
+        let line = match command {
+            Command::Line(line) => line,
+            Command::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        if file.is_none() {
+            let opened = OpenOptions::new().create(true).append(true).open(path);
+            match opened.and_then(|f| f.metadata().map(|m| (f, m.len()))) {
+                Ok(opened) => file = Some(opened),
+                Err(e) => {
+                    log::warn!("Failed to open {:?}: {}", path, e);
+                    continue;
+                }
+            }
+        }
+        let Some((f, len)) = file.as_mut() else {
+            continue;
+        };
+        if max_bytes.is_some_and(|max| *len + line.len() as u64 > max) {
+            log::debug!("{:?} is full, dropping a record", path);
+            continue;
+        }
+        match f.write_all(&line) {
+            Ok(()) => *len += line.len() as u64,
+            Err(e) => {
+                log::warn!("Failed to write to {:?}: {}", path, e);
+                // Reopen for the next line
+                file = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_written_in_order_and_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("records.jsonl");
+        let writer = JsonLinesWriter::spawn(&path, Some(40));
+
+        for n in 0..10 {
+            writer.append(&serde_json::json!({ "n": n }));
+        }
+        writer.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "{\"n\":0}\n{\"n\":1}\n{\"n\":2}\n{\"n\":3}\n{\"n\":4}\n");
+    }
+}
@@ -0,0 +1,2551 @@
+use crate::balancer::UpstreamSet;
+use crate::config::{ErrorResponse, ForwardedConfig, HeaderRules, ProxyConfig, RouteConfig};
+use crate::error::{Result, SafeQuantaError};
+use crate::metrics::Metrics;
+use crate::pool::{
+    connect_upstream, connect_upstream_within, set_dscp, PoolKey, PooledSender, UpstreamPool,
+    UpstreamProtocol,
+};
+use crate::proxy::{ByteBudget, CloseReason, ProxyServer};
+use crate::recorder::RequestRecorder;
+use crate::tls::EarlyDataState;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+pub(crate) type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// Frames buffered for a shadow request before it is abandoned
+const SHADOW_BUFFER_FRAMES: usize = 64;
+
+/// `Retry-After` sent with a route's maintenance page
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 30;
+
+/// gRPC request deadline, e.g. `250m`; honored on every L7 request
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// Response header carrying the client connection's id when request ids are on
+const CONNECTION_ID_HEADER: &str = "x-connection-id";
+
+/// Response header naming the client connection's key exchange
+const KEM_HEADER: &str = "x-safequanta-kem";
+/// Response header naming the server certificate's signature algorithm
+const SIG_HEADER: &str = "x-safequanta-sig";
+
+/// Id given to an L7 request by `tag_request`, and what its access log line needs
+struct RequestId {
+    name: HeaderName,
+    value: HeaderValue,
+    method: Method,
+    path: String,
+    started: Instant,
+}
+
+/// Layer 7 (HTTP) proxy
+#[derive(Clone)]
+pub struct L7Proxy {
+    config: Arc<ProxyConfig>,
+    metrics: Arc<Metrics>,
+    pool: Arc<UpstreamPool>,
+    client_addr: Option<SocketAddr>,
+    /// Whether requests may still come from the connection's TLS early data
+    early_data: Option<EarlyDataState>,
+    /// KEM and signature header values added to responses
+    algorithm_headers: Option<(HeaderValue, HeaderValue)>,
+    /// Route upstreams taken out of service by their health checks, and the
+    /// weighted targets requests matching no route are spread over
+    upstream_health: Option<Arc<UpstreamSet>>,
+    recorder: Option<Arc<RequestRecorder>>,
+    /// Id of the client connection, as in its log lines and events
+    connection_id: Option<u64>,
+    /// Cancelled when the proxy shuts down, to wind down client connections
+    shutdown: CancellationToken,
+    /// Tracks `CONNECT` tunnels, which outlive the request that opened them
+    tunnels: TaskTracker,
+    /// When the client connection reaches `max_connection_lifetime_secs`
+    deadline: Option<tokio::time::Instant>,
+}
+
+impl L7Proxy {
+    /// Create a new L7 proxy with its own upstream connection pool
+    pub fn new(config: Arc<ProxyConfig>, metrics: Arc<Metrics>) -> Self {
+        let pool = Arc::new(UpstreamPool::from_config(&config));
+        Self::with_pool(config, metrics, pool)
+    }
+
+    /// Create a new L7 proxy sharing an existing upstream connection pool
+    pub fn with_pool(config: Arc<ProxyConfig>, metrics: Arc<Metrics>, pool: Arc<UpstreamPool>) -> Self {
+        Self {
+            config,
+            metrics,
+            pool,
+            client_addr: None,
+            early_data: None,
+            algorithm_headers: None,
+            upstream_health: None,
+            recorder: None,
+            connection_id: None,
+            shutdown: CancellationToken::new(),
+            tunnels: TaskTracker::new(),
+            deadline: None,
+        }
+    }
+
+    /// Set the client address reported to upstreams in forwarding headers
+    pub fn with_client_addr(mut self, client_addr: SocketAddr) -> Self {
+        self.client_addr = Some(client_addr);
+        self
+    }
+
+    /// Treat requests as TLS early (0-RTT) data while `state` is pending
+    pub fn with_early_data(mut self, state: EarlyDataState) -> Self {
+        self.early_data = Some(state);
+        self
+    }
+
+    /// Record the algorithms negotiated for the client connection
+    ///
+    /// They are added to responses as `X-SafeQuanta-KEM` and `X-SafeQuanta-Sig`
+    /// when `expose_negotiated_algorithms` is enabled.
+    pub fn with_negotiated_algorithms(mut self, kem: &str, signature: &str) -> Self {
+        if let (Ok(kem), Ok(signature)) = (HeaderValue::from_str(kem), HeaderValue::from_str(signature)) {
+            self.algorithm_headers = Some((kem, signature));
+        }
+        self
+    }
+
+    /// Fail requests for route upstreams that `health` reports as ejected
+    pub fn with_upstream_health(mut self, health: Arc<UpstreamSet>) -> Self {
+        self.upstream_health = Some(health);
+        self
+    }
+
+    /// Record every forwarded request with `recorder`
+    pub fn with_request_recorder(mut self, recorder: Arc<RequestRecorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Id of the client connection, sent as `X-Connection-Id` when
+    /// `request_id_header` is set
+    pub fn with_connection_id(mut self, id: u64) -> Self {
+        self.connection_id = Some(id);
+        self
+    }
+
+    /// Once `shutdown` is cancelled, close client connections after their
+    /// in-flight requests: HTTP/1.1 responses carry `Connection: close` and
+    /// HTTP/2 clients receive `GOAWAY`, so they reconnect elsewhere
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Spawn `CONNECT` tunnels on `tracker`, so waiting on it at shutdown
+    /// covers them too; they are closed once `shutdown` is cancelled
+    pub fn with_task_tracker(mut self, tracker: TaskTracker) -> Self {
+        self.tunnels = tracker;
+        self
+    }
+
+    /// Wind the connection down at `deadline`, like at shutdown
+    ///
+    /// Without it, `max_connection_lifetime_secs` counts from when serving starts.
+    pub fn with_deadline(mut self, deadline: tokio::time::Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// When the connection must close under `max_connection_lifetime_secs`
+    fn lifetime_deadline(&self) -> Option<tokio::time::Instant> {
+        self.deadline.or_else(|| {
+            self.config
+                .max_connection_lifetime_secs
+                .map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs))
+        })
+    }
+
+    /// Resolves once the connection should close after its in-flight
+    /// requests: at shutdown, or at `deadline`
+    async fn wind_down(&self, deadline: Option<tokio::time::Instant>) -> CloseReason {
+        match deadline {
+            Some(deadline) => tokio::select! {
+                _ = self.shutdown.cancelled() => CloseReason::Shutdown,
+                _ = tokio::time::sleep_until(deadline) => {
+                    log::debug!("Closing L7 connection: max_connection_lifetime_secs reached");
+                    CloseReason::Lifetime
+                }
+            },
+            None => {
+                self.shutdown.cancelled().await;
+                CloseReason::Shutdown
+            }
+        }
+    }
+
+    /// Whether responses should carry `Connection: close`
+    fn winding_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+            || self.deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+    }
+
+    /// Serve HTTP/2 streams from an accepted client connection
+    pub async fn serve_http2<S>(&self, stream: S) -> Result<CloseReason>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let deadline = self.lifetime_deadline();
+        let proxy = L7Proxy { deadline, ..self.clone() };
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            let proxy = proxy.clone();
+            async move {
+                let stream = ActiveStream::open();
+                let request_id = proxy.tag_request(&mut req);
+                let result = proxy.forward_http2(req).await;
+                let response = result.unwrap_or_else(|e| proxy.error_response(&e));
+                // The stream stays active until its response body is done
+                let response = response.map(|body| ActiveStreamBody { inner: body, _stream: stream }.boxed());
+                let response = proxy.add_request_ids(response, request_id);
+                Ok::<_, Infallible>(proxy.add_algorithm_headers(response))
+            }
+        });
+
+        let connection = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+            .max_concurrent_streams(self.config.max_concurrent_streams)
+            .serve_connection(TokioIo::new(stream), service);
+        tokio::pin!(connection);
+        let (served, reason) = tokio::select! {
+            served = connection.as_mut() => (served, CloseReason::ClientEof),
+            reason = self.wind_down(deadline) => {
+                // Sends GOAWAY and finishes the streams already open
+                connection.as_mut().graceful_shutdown();
+                (connection.await, reason)
+            }
+        };
+        served
+            .map(|()| reason)
+            .map_err(|e| SafeQuantaError::Proxy(format!("HTTP/2 connection error: {}", e)))
+    }
+
+    /// Serve HTTP/1.1 requests from an accepted client connection
+    ///
+    /// `CONNECT` requests open a tunnel when forward proxying is enabled; all
+    /// other requests are forwarded to the upstream selected by `Host`. After
+    /// `max_requests_per_connection` requests, or once the connection reaches
+    /// `max_connection_lifetime_secs`, the connection is closed.
+    pub async fn serve_http1<S>(&self, stream: S) -> Result<CloseReason>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let deadline = self.lifetime_deadline();
+        let proxy = L7Proxy { deadline, ..self.clone() };
+        let requests = Arc::new(AtomicUsize::new(0));
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            let proxy = proxy.clone();
+            let requests = requests.clone();
+            async move {
+                if req.method() == Method::CONNECT {
+                    // Never allowed as early data, so this only ever rejects
+                    if let Some(too_early) = proxy.check_early_data(&mut req, None) {
+                        return Ok::<_, Infallible>(too_early);
+                    }
+                    return Ok(proxy.connect_tunnel(req).await);
+                }
+                let count = requests.fetch_add(1, Ordering::Relaxed) + 1;
+                let request_id = proxy.tag_request(&mut req);
+                let result = proxy.forward_http1(req).await;
+                let response = result.unwrap_or_else(|e| proxy.error_response(&e));
+                let mut response = proxy.add_request_ids(response, request_id);
+                let last = proxy.config.max_requests_per_connection.is_some_and(|max| count >= max);
+                if last || proxy.winding_down() {
+                    response
+                        .headers_mut()
+                        .insert(http::header::CONNECTION, HeaderValue::from_static("close"));
+                }
+                Ok(proxy.add_algorithm_headers(response))
+            }
+        });
+
+        let connection = hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .with_upgrades();
+        tokio::pin!(connection);
+        let (served, reason) = tokio::select! {
+            served = connection.as_mut() => (served, CloseReason::ClientEof),
+            reason = self.wind_down(deadline) => {
+                // Closes an idle connection now, a busy one after its response
+                connection.as_mut().graceful_shutdown();
+                (connection.await, reason)
+            }
+        };
+        served
+            .map(|()| reason)
+            .map_err(|e| SafeQuantaError::Proxy(format!("HTTP/1.1 connection error: {}", e)))
+    }
+
+    /// Answer every request on `stream` with the configured over-capacity response
+    pub async fn reject_over_capacity<S>(&self, stream: S, http2: bool) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let capacity = self.config.error_responses.capacity.clone();
+        let service = service_fn(move |_req: Request<Incoming>| {
+            let response = configured_response(&capacity);
+            async move { Ok::<_, Infallible>(response) }
+        });
+        let io = TokioIo::new(stream);
+        // Don't let a rejected client hold its connection open indefinitely
+        let idle = self.config.timeouts_for(None).idle;
+
+        let served = if http2 {
+            let connection = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(io, service);
+            timeout(idle, connection).await.unwrap_or(Ok(()))
+        } else {
+            let connection = hyper::server::conn::http1::Builder::new()
+                .keep_alive(false)
+                .serve_connection(io, service);
+            timeout(idle, connection).await.unwrap_or(Ok(()))
+        };
+        served.map_err(|e| SafeQuantaError::Proxy(format!("Rejected connection error: {}", e)))
+    }
+
+    /// Map a proxying failure to the configured error response
+    fn error_response(&self, err: &SafeQuantaError) -> Response<ProxyBody> {
+        log::warn!("L7 request failed: {}", err);
+        let responses = &self.config.error_responses;
+        match err {
+            SafeQuantaError::Timeout(_) => configured_response(&responses.timeout),
+            _ => configured_response(&responses.upstream_error),
+        }
+    }
+
+    /// Give the request an id when `request_id_header` is set, keeping one
+    /// the client sent, so the upstream sees the id the client gets back
+    ///
+    /// Returns the id with its access log context; `None` when disabled.
+    fn tag_request<B>(&self, req: &mut Request<B>) -> Option<RequestId> {
+        let name = HeaderName::try_from(self.config.request_id_header.as_deref()?).ok()?;
+        let value = match req.headers().get(&name) {
+            Some(value) => value.clone(),
+            None => {
+                let generated = format!("{:032x}", rand::random::<u128>());
+                let value = HeaderValue::try_from(generated).expect("hex is a valid header value");
+                req.headers_mut().insert(name.clone(), value.clone());
+                value
+            }
+        };
+        Some(RequestId {
+            name,
+            value,
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Add the request and connection ids to the response and write the
+    /// request's access log line
+    fn add_request_ids(
+        &self,
+        mut response: Response<ProxyBody>,
+        request_id: Option<RequestId>,
+    ) -> Response<ProxyBody> {
+        let Some(request_id) = request_id else {
+            return response;
+        };
+        log::info!(
+            "Request {} on connection {}: {} {} -> {} in {:?}",
+            request_id.value.to_str().unwrap_or("-"),
+            self.connection_id.map_or_else(|| "-".to_string(), |id| id.to_string()),
+            request_id.method,
+            request_id.path,
+            response.status().as_u16(),
+            request_id.started.elapsed()
+        );
+        let headers = response.headers_mut();
+        if let Some(id) = self.connection_id {
+            headers.insert(CONNECTION_ID_HEADER, HeaderValue::from(id));
+        }
+        headers.insert(request_id.name, request_id.value);
+        response
+    }
+
+    fn add_algorithm_headers(&self, mut response: Response<ProxyBody>) -> Response<ProxyBody> {
+        if !self.config.expose_negotiated_algorithms {
+            return response;
+        }
+        if let Some((kem, signature)) = &self.algorithm_headers {
+            let headers = response.headers_mut();
+            headers.insert(KEM_HEADER, kem.clone());
+            headers.insert(SIG_HEADER, signature.clone());
+        }
+        response
+    }
+
+    /// Establish a `CONNECT` tunnel and relay raw bytes once the client upgrades
+    async fn connect_tunnel(&self, req: Request<Incoming>) -> Response<ProxyBody> {
+        let config = self.config.clone();
+        let metrics = self.metrics.clone();
+        if !config.forward_proxy {
+            return empty_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        let authority = match req.uri().authority() {
+            Some(authority) if authority.port().is_some() => authority.to_string(),
+            _ => return empty_response(StatusCode::BAD_REQUEST),
+        };
+        if !config.connect_allowed(&authority) {
+            log::warn!("CONNECT to {} rejected: not on the allow-list", authority);
+            return empty_response(StatusCode::FORBIDDEN);
+        }
+
+        let timeouts = config.timeouts_for(None);
+        let target = match timeout(timeouts.connect, connect_upstream(&authority, config.bind_addr)).await {
+            Ok(Ok(target)) => {
+                set_dscp(&target, config.dscp);
+                target
+            }
+            Ok(Err(e)) => {
+                log::warn!("CONNECT to {} failed: {}", authority, e);
+                return configured_response(&config.error_responses.upstream_error);
+            }
+            Err(_) => {
+                log::warn!("CONNECT to {} timed out", authority);
+                return configured_response(&config.error_responses.timeout);
+            }
+        };
+
+        let deadline = self.lifetime_deadline();
+        let upstream_connections = self.pool.upstream_connections().clone();
+        let shutdown = self.shutdown.clone();
+        self.tunnels.spawn(async move {
+            match hyper::upgrade::on(req).await {
+                Ok(upgraded) => {
+                    let budget = Arc::new(
+                        ByteBudget::new(config.max_total_bytes)
+                            .with_buffer_limit(config.max_relay_buffer_bytes),
+                    );
+                    let client = TokioIo::new(upgraded);
+                    let _upstream_connection = upstream_connections.open(&authority);
+                    let relay = ProxyServer::relay_using(
+                        config.relay_strategy,
+                        client,
+                        target,
+                        metrics,
+                        budget,
+                        timeouts.idle,
+                        deadline,
+                    );
+                    let reason = tokio::select! {
+                        reason = relay => reason,
+                        _ = shutdown.cancelled() => CloseReason::Shutdown,
+                    };
+                    log::debug!("CONNECT tunnel to {} closed: {}", authority, reason.as_str());
+                }
+                Err(e) => log::warn!("CONNECT upgrade to {} failed: {}", authority, e),
+            }
+        });
+
+        empty_response(StatusCode::OK)
+    }
+
+    /// Forward an HTTP/1.1 request to the upstream selected by `Host`
+    async fn forward_http1(&self, mut req: Request<Incoming>) -> Result<Response<ProxyBody>> {
+        let config = &self.config;
+        let deadline = client_deadline(req.headers(), config.deadline_header.as_deref())
+            .map(|deadline| Instant::now() + deadline);
+        apply_forwarded_headers(req.headers_mut(), self.client_addr, &config.forwarded);
+        let host = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .map(|host| host.rsplit_once(':').map_or(host, |(host, _)| host).to_string());
+        let route = config.route_for(host.as_deref());
+        if config.rejects_unmatched(route) {
+            crate::metrics::record_unmatched_route_rejection();
+            return Ok(configured_response(&config.error_responses.no_route));
+        }
+        let timeouts = config.timeouts_for(route);
+        if let Some(route) = route {
+            apply_header_rules(req.headers_mut(), &route.header_rules);
+        }
+        if let Some(too_early) = self.check_early_data(&mut req, route) {
+            return Ok(too_early);
+        }
+
+        if let Err(e) = self.check_upstream_health(route) {
+            return maintenance_or(route, e).await;
+        }
+        let mut target = match self.upstream_for(route) {
+            Ok(target) => target,
+            Err(e) => return maintenance_or(route, e).await,
+        };
+        let mut key = PoolKey {
+            upstream: upstream_authority(&target),
+            protocol: UpstreamProtocol::Http1,
+            bind_addr: config.bind_addr_for(route),
+            dscp: config.dscp_for(route),
+        };
+        let warm = config.warm_upstream.as_ref();
+        let mut retries = match warm {
+            Some(warm) => warm.retries,
+            None => u32::from(config.retry_on_upstream_reset),
+        };
+        let replay = (retries > 0).then(|| replayable(&req)).flatten();
+        let no_response =
+            |upstream: &str| SafeQuantaError::Timeout(format!("No response from {}", upstream));
+        let failed =
+            |e: hyper::Error| SafeQuantaError::Proxy(format!("Upstream request failed: {}", e));
+        let mut request = self.outbound(req);
+        let mut checkout = self.pool.checkout(&key, timeouts.connect).await;
+        let (response, sender) = loop {
+            // Whether the failure may be retried, and whether it was the connect
+            let (retryable, connecting, e) = match checkout {
+                Ok(mut sender) => {
+                    let sent = timeout(response_wait(deadline, timeouts.idle), sender.send(request))
+                        .await
+                        .map_err(|_| no_response(&key.upstream))?;
+                    match sent {
+                        Ok(response) => break (response, sender),
+                        // Warm mode retries any failure but an oversized
+                        // header block, which the upstream would send again
+                        Err(e) => {
+                            let retryable = match warm {
+                                Some(_) => !e.is_parse_too_large(),
+                                None => upstream_reset(&e),
+                            };
+                            (retryable, false, failed(e))
+                        }
+                    }
+                }
+                Err(e) => (warm.is_some(), true, e),
+            };
+            // Nothing has reached the client yet, so the request can go again:
+            // to another upstream, or in warm mode to the same one
+            let retry = replay
+                .as_ref()
+                .filter(|_| retries > 0 && retryable)
+                .and_then(replayable)
+                .and_then(|retry| {
+                    let other = self.other_upstream(route, &target);
+                    Some((retry, other.or_else(|| warm.map(|_| target.clone()))?))
+                });
+            let Some((retry, other)) = retry else {
+                return if connecting { maintenance_or(route, e).await } else { Err(e) };
+            };
+            retries -= 1;
+            log::warn!(
+                "Upstream {} failed before responding ({}), retrying on {}",
+                target,
+                e,
+                other
+            );
+            crate::metrics::record_upstream_reset_retry();
+            target = other;
+            key.upstream = upstream_authority(&target);
+            request = retry;
+            checkout = self.pool.connect(&key, timeouts.connect).await;
+        };
+
+        // Hand the connection back to the pool once the response body completes
+        let pool = self.pool.clone();
+        Ok(response.map(|body| {
+            PoolReturnBody {
+                inner: body,
+                checkin: Some((pool, key, sender)),
+            }
+            .boxed()
+        }))
+    }
+
+    /// Forward a single HTTP/2 stream to the upstream selected by `:authority`
+    async fn forward_http2(&self, mut req: Request<Incoming>) -> Result<Response<ProxyBody>> {
+        let config = &self.config;
+        apply_forwarded_headers(req.headers_mut(), self.client_addr, &config.forwarded);
+        let start = Instant::now();
+        let deadline = client_deadline(req.headers(), config.deadline_header.as_deref())
+            .map(|deadline| start + deadline);
+        let method = grpc_method_label(req.uri().path(), &config.grpc_methods);
+        let authority = req.uri().authority().map(|a| a.host().to_string());
+        let route = config.route_for(authority.as_deref());
+        if config.rejects_unmatched(route) {
+            crate::metrics::record_unmatched_route_rejection();
+            return Ok(configured_response(&config.error_responses.no_route));
+        }
+        let timeouts = config.timeouts_for(route);
+        if let Some(route) = route {
+            apply_header_rules(req.headers_mut(), &route.header_rules);
+        }
+        if let Some(too_early) = self.check_early_data(&mut req, route) {
+            return Ok(too_early);
+        }
+
+        if let Err(e) = self.check_upstream_health(route) {
+            return maintenance_or(route, e).await;
+        }
+        let upstream = match self.upstream_for(route) {
+            Ok(target) => upstream_authority(&target),
+            Err(e) => return maintenance_or(route, e).await,
+        };
+        // gRPC backends behind the proxy speak HTTP/2 with prior knowledge
+        let key = PoolKey {
+            upstream: upstream.clone(),
+            protocol: UpstreamProtocol::Http2,
+            bind_addr: config.bind_addr_for(route),
+            dscp: config.dscp_for(route),
+        };
+        let checkout = self.pool.checkout(&key, timeouts.connect).await;
+        let mut sender = match checkout {
+            Ok(sender) => sender,
+            Err(e) => return maintenance_or(route, e).await,
+        };
+        let sent = sender.send_request(self.outbound(req));
+        let no_response = || SafeQuantaError::Timeout(format!("No response from {}", upstream));
+        let response = timeout(response_wait(deadline, timeouts.idle), sent)
+            .await
+            .map_err(|_| no_response())??;
+
+        let (parts, body) = response.into_parts();
+        let body = GrpcMetricsBody {
+            inner: body,
+            status: grpc_status(&parts.headers),
+            method,
+            start,
+            metrics: self.metrics.clone(),
+            recorded: false,
+        };
+        Ok(Response::from_parts(parts, body.boxed()))
+    }
+}
+
+impl L7Proxy {
+    /// Request as sent upstream, copied to the shadow upstream and recorded
+    fn outbound(&self, req: Request<Incoming>) -> Request<ProxyBody> {
+        let req = mirror(req, &self.config);
+        match &self.recorder {
+            Some(recorder) => recorder.record(req),
+            None => req,
+        }
+    }
+
+    /// Upstream for a request matching `route`, or for one matching none the
+    /// next weighted target, if there are any, otherwise `upstream`
+    fn upstream_for(&self, route: Option<&RouteConfig>) -> Result<String> {
+        if let Some(route) = route {
+            return Ok(route.upstream.clone());
+        }
+        match &self.upstream_health {
+            Some(health) if !self.config.weighted_targets.is_empty() => {
+                let target = health.select().map(str::to_owned);
+                target.ok_or_else(|| SafeQuantaError::Proxy("No weighted target is available".to_string()))
+            }
+            _ => Ok(self.config.upstream.clone()),
+        }
+    }
+
+    /// Another weighted target to retry a request on after `failed`
+    ///
+    /// `None` for a request matching `route`, which has no other upstream.
+    fn other_upstream(&self, route: Option<&RouteConfig>, failed: &str) -> Option<String> {
+        if route.is_some() || self.config.weighted_targets.is_empty() {
+            return None;
+        }
+        let health = self.upstream_health.as_ref()?;
+        health.select_excluding(failed).map(str::to_owned)
+    }
+
+    /// Refuse a route whose upstream is ejected by its health check
+    fn check_upstream_health(&self, route: Option<&RouteConfig>) -> Result<()> {
+        match (&self.upstream_health, route) {
+            (Some(health), Some(route)) if !health.is_available(&route.upstream) => Err(
+                SafeQuantaError::Proxy(format!("Upstream {} is failing health checks", route.upstream)),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decide whether a request sent as TLS early data may be forwarded
+    ///
+    /// Accepted requests are marked with `Early-Data: 1` (RFC 8470); others
+    /// get `425 Too Early` so the client retries after the handshake.
+    fn check_early_data<B>(
+        &self,
+        req: &mut Request<B>,
+        route: Option<&RouteConfig>,
+    ) -> Option<Response<ProxyBody>> {
+        if !self.early_data.as_ref().is_some_and(EarlyDataState::pending) {
+            return None;
+        }
+        if early_data_allowed(req.method(), route) {
+            crate::metrics::record_early_data_accepted();
+            req.headers_mut().insert("early-data", HeaderValue::from_static("1"));
+            None
+        } else {
+            log::debug!("Rejecting {} {} sent as early data", req.method(), req.uri());
+            crate::metrics::record_early_data_rejected();
+            Some(empty_response(StatusCode::TOO_EARLY))
+        }
+    }
+}
+
+/// How long a client allows for its request: the shorter of `grpc-timeout`
+/// and the configured `deadline_header` (milliseconds), if either is valid
+fn client_deadline(headers: &HeaderMap, deadline_header: Option<&str>) -> Option<Duration> {
+    let value = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let grpc = value(GRPC_TIMEOUT_HEADER).and_then(parse_grpc_timeout);
+    let custom = deadline_header
+        .and_then(value)
+        .and_then(|millis| millis.trim().parse().ok())
+        .map(Duration::from_millis);
+    grpc.into_iter().chain(custom).min()
+}
+
+/// Parse a `grpc-timeout` value: at most 8 digits followed by a unit of
+/// `H`, `M`, `S`, `m`, `u` or `n`
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    match unit {
+        'H' => Some(Duration::from_secs(amount * 3600)),
+        'M' => Some(Duration::from_secs(amount * 60)),
+        'S' => Some(Duration::from_secs(amount)),
+        'm' => Some(Duration::from_millis(amount)),
+        'u' => Some(Duration::from_micros(amount)),
+        'n' => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Time left to wait for an upstream response: what remains of the client's
+/// `deadline`, if it set one, but never more than `idle`
+fn response_wait(deadline: Option<Instant>, idle: Duration) -> Duration {
+    deadline.map_or(idle, |deadline| deadline.saturating_duration_since(Instant::now()).min(idle))
+}
+
+/// A copy of `req` to resend if the upstream fails it, when that is safe:
+/// an idempotent method and no request body to replay
+fn replayable<B: Body>(req: &Request<B>) -> Option<Request<ProxyBody>> {
+    if !req.method().is_idempotent() || !req.body().is_end_stream() {
+        return None;
+    }
+    let mut copy = Request::new(Empty::new().map_err(|never| match never {}).boxed());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    Some(copy)
+}
+
+/// Whether the upstream connection was reset or closed before a response
+/// arrived, as opposed to a malformed response or a local failure
+fn upstream_reset(err: &hyper::Error) -> bool {
+    if err.is_incomplete_message() || err.is_canceled() {
+        return true;
+    }
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+            );
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// Only idempotent, side-effect free methods on opted-in routes may be replayed
+fn early_data_allowed(method: &Method, route: Option<&RouteConfig>) -> bool {
+    route.map_or(false, |route| route.allow_early_data)
+        && matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Accept either `host:port` or a URL for an upstream address
+pub(crate) fn upstream_authority(upstream: &str) -> String {
+    upstream
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|a| a.to_string()))
+        .unwrap_or_else(|| upstream.to_string())
+}
+
+/// Add the client to `X-Forwarded-For`/`Forwarded` and set `X-Forwarded-Proto`
+///
+/// Only the last `trusted_hops` inbound entries are kept; anything earlier
+/// could have been set by the client itself. `X-Forwarded-Proto` is set even
+/// when `enabled` is off, which only stops the client address being added.
+fn apply_forwarded_headers(
+    headers: &mut HeaderMap,
+    client: Option<SocketAddr>,
+    config: &ForwardedConfig,
+) {
+    const X_FORWARDED_FOR: &str = "x-forwarded-for";
+    const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+    let trusted = |name| -> Vec<String> {
+        let entries: Vec<String> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        let skip = entries.len().saturating_sub(config.trusted_hops);
+        entries.into_iter().skip(skip).collect()
+    };
+    let mut xff = trusted(X_FORWARDED_FOR);
+    let mut forwarded = trusted(http::header::FORWARDED.as_str());
+    let proto = if config.trusted_hops > 0 {
+        headers.get(X_FORWARDED_PROTO).cloned()
+    } else {
+        None
+    };
+
+    headers.remove(X_FORWARDED_FOR);
+    headers.remove(http::header::FORWARDED);
+    headers.remove(X_FORWARDED_PROTO);
+
+    if config.enabled {
+        if let Some(client) = client {
+            let ip = client.ip();
+            xff.push(ip.to_string());
+            forwarded.push(match ip {
+                std::net::IpAddr::V4(ip) => format!("for={};proto=https", ip),
+                std::net::IpAddr::V6(ip) => format!("for=\"[{}]\";proto=https", ip),
+            });
+        }
+    }
+
+    let mut set = |name, values: Vec<String>| {
+        if let Ok(value) = HeaderValue::from_str(&values.join(", ")) {
+            if !values.is_empty() {
+                headers.insert(name, value);
+            }
+        }
+    };
+    set(http::HeaderName::from_static(X_FORWARDED_FOR), xff);
+    set(http::header::FORWARDED, forwarded);
+    headers.insert(
+        X_FORWARDED_PROTO,
+        proto.unwrap_or_else(|| HeaderValue::from_static("https")),
+    );
+}
+
+/// Apply a route's `header_rules`: removals, then renames, then sets
+///
+/// Invalid names and values are refused when the configuration is loaded
+/// (see `ProxyConfig::ensure_valid_header_rules`); any that still fail to
+/// parse are skipped.
+fn apply_header_rules(headers: &mut HeaderMap, rules: &HeaderRules) {
+    let name = |name: &str| HeaderName::from_bytes(name.as_bytes()).ok();
+
+    for remove in rules.remove.iter().filter_map(|remove| name(remove)) {
+        headers.remove(remove);
+    }
+    for rename in &rules.rename {
+        let (Some(from), Some(to)) = (name(&rename.from), name(&rename.to)) else {
+            continue;
+        };
+        let values: Vec<HeaderValue> = headers.get_all(&from).iter().cloned().collect();
+        if values.is_empty() {
+            continue;
+        }
+        headers.remove(&from);
+        headers.remove(&to);
+        for value in values {
+            headers.append(to.clone(), value);
+        }
+    }
+    for set in &rules.set {
+        let Some(header) = name(&set.name) else {
+            continue;
+        };
+        if let Ok(value) = HeaderValue::from_str(&set.value) {
+            headers.insert(header, value);
+        }
+    }
+}
+
+/// Copy `req` to the shadow upstream, if configured, and return the primary request
+///
+/// The shadow copy is fed from the primary body without ever blocking it: if
+/// the shadow falls behind its body is cut short and the copy is abandoned.
+fn mirror(req: Request<Incoming>, config: &ProxyConfig) -> Request<ProxyBody> {
+    let Some(shadow_upstream) = config.shadow_upstream.as_deref() else {
+        return req.map(|body| body.boxed());
+    };
+
+    let (tx, rx) = mpsc::channel(SHADOW_BUFFER_FRAMES);
+    let frames = futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|frame| (frame, rx)) });
+    let mut shadow = Request::new(http_body_util::StreamBody::new(frames).boxed());
+    *shadow.method_mut() = req.method().clone();
+    *shadow.uri_mut() = req.uri().clone();
+    *shadow.version_mut() = req.version();
+    *shadow.headers_mut() = req.headers().clone();
+
+    let upstream = upstream_authority(shadow_upstream);
+    let connect_timeout = config.timeouts_for(None).connect;
+    let bind_addr = config.bind_addr;
+    tokio::spawn(async move {
+        if let Err(e) = send_shadow(shadow, &upstream, connect_timeout, bind_addr).await {
+            log::debug!("Shadow request to {} failed: {}", upstream, e);
+        }
+    });
+
+    req.map(|body| TeeBody { inner: body, shadow: Some(tx) }.boxed())
+}
+
+/// Send a shadow request and discard the response
+async fn send_shadow(
+    req: Request<BoxBody<Bytes, Infallible>>,
+    upstream: &str,
+    connect_timeout: std::time::Duration,
+    bind_addr: Option<SocketAddr>,
+) -> Result<()> {
+    let stream = connect_upstream_within(upstream, bind_addr, connect_timeout).await?;
+    let io = TokioIo::new(stream);
+    let response = if req.version() == http::Version::HTTP_2 {
+        let (mut sender, connection) = hyper::client::conn::http2::handshake(TokioExecutor::new(), io)
+            .await
+            .map_err(|e| SafeQuantaError::Proxy(e.to_string()))?;
+        tokio::spawn(connection);
+        sender.send_request(req).await
+    } else {
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(io)
+            .await
+            .map_err(|e| SafeQuantaError::Proxy(e.to_string()))?;
+        tokio::spawn(connection);
+        sender.send_request(req).await
+    }
+    .map_err(|e| SafeQuantaError::Proxy(e.to_string()))?;
+
+    response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| SafeQuantaError::Proxy(e.to_string()))?;
+    Ok(())
+}
+
+/// Request body that copies every frame to a shadow request
+struct TeeBody {
+    inner: Incoming,
+    shadow: Option<mpsc::Sender<std::result::Result<Frame<Bytes>, Infallible>>>,
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(shadow) = &this.shadow {
+                    let copy = match (frame.data_ref(), frame.trailers_ref()) {
+                        (Some(data), _) => Some(Frame::data(data.clone())),
+                        (_, Some(trailers)) => Some(Frame::trailers(trailers.clone())),
+                        _ => None,
+                    };
+                    if let Some(copy) = copy {
+                        if shadow.try_send(Ok(copy)).is_err() {
+                            this.shadow = None;
+                        }
+                    }
+                }
+            }
+            Some(Err(_)) | None => this.shadow = None,
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Response body that returns its upstream connection to the pool on clean completion
+struct PoolReturnBody {
+    inner: Incoming,
+    checkin: Option<(Arc<UpstreamPool>, PoolKey, PooledSender)>,
+}
+
+impl Body for PoolReturnBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(_)) if !this.inner.is_end_stream() => {}
+            Some(Ok(_)) | None => {
+                if let Some((pool, key, sender)) = this.checkin.take() {
+                    pool.checkin(key, sender);
+                }
+            }
+            // A failed response leaves the connection in an unknown state
+            Some(Err(_)) => this.checkin = None,
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// An open HTTP/2 stream, counted in `http2_active_streams` until dropped
+struct ActiveStream;
+
+impl ActiveStream {
+    fn open() -> Self {
+        crate::metrics::record_http2_stream_opened();
+        ActiveStream
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        crate::metrics::record_http2_stream_closed();
+    }
+}
+
+/// Response body that keeps its HTTP/2 stream counted as active
+struct ActiveStreamBody {
+    inner: ProxyBody,
+    _stream: ActiveStream,
+}
+
+impl Body for ActiveStreamBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// The route's maintenance page as a `503`, or `err` if it has none
+///
+/// Used when the upstream cannot be reached; the page is read on each use so
+/// it can be updated during an outage.
+async fn maintenance_or(route: Option<&RouteConfig>, err: SafeQuantaError) -> Result<Response<ProxyBody>> {
+    let Some(path) = route.and_then(|r| r.maintenance_page.as_ref()) else {
+        return Err(err);
+    };
+    let page = match tokio::fs::read(path).await {
+        Ok(page) => page,
+        Err(e) => {
+            log::warn!("Failed to read maintenance page {}: {}", path.display(), e);
+            return Err(err);
+        }
+    };
+    log::warn!("Serving maintenance page: {}", err);
+
+    let body = Full::new(Bytes::from(page)).map_err(|never| match never {});
+    let mut response = Response::new(body.boxed());
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    let headers = response.headers_mut();
+    headers.insert(http::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    headers.insert(http::header::RETRY_AFTER, MAINTENANCE_RETRY_AFTER_SECS.into());
+    Ok(response)
+}
+
+fn configured_response(config: &ErrorResponse) -> Response<ProxyBody> {
+    let body = Full::new(Bytes::from(config.body.clone())).map_err(|never| match never {});
+    let mut response = Response::new(body.boxed());
+    *response.status_mut() = StatusCode::from_u16(config.status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+    response
+        .headers_mut()
+        .insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("text/plain"));
+    if let Some(retry_after) = config.retry_after {
+        response.headers_mut().insert(http::header::RETRY_AFTER, retry_after.into());
+    }
+    response
+}
+
+fn empty_response(status: StatusCode) -> Response<ProxyBody> {
+    let mut response = Response::new(Empty::new().map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// `grpc_requests_total` method label for a request to `path`
+fn grpc_method_label(path: &str, methods: &[String]) -> String {
+    if methods.iter().any(|method| method == path) {
+        path.to_string()
+    } else {
+        "other".to_string()
+    }
+}
+
+/// Response body that records `grpc_requests_total` once the stream ends
+///
+/// The status comes from the `grpc-status` trailer, or from the headers for
+/// trailers-only responses.
+struct GrpcMetricsBody {
+    inner: Incoming,
+    method: String,
+    status: Option<String>,
+    start: Instant,
+    metrics: Arc<Metrics>,
+    recorded: bool,
+}
+
+impl GrpcMetricsBody {
+    fn record(&mut self) {
+        if self.recorded {
+            return;
+        }
+        self.recorded = true;
+        let code = self.status.as_deref().unwrap_or("unknown");
+        self.metrics.record_grpc_request(&self.method, code, self.start.elapsed());
+    }
+}
+
+impl Body for GrpcMetricsBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    if let Some(status) = grpc_status(trailers) {
+                        this.status = Some(status);
+                    }
+                    this.record();
+                }
+            }
+            Some(Err(_)) | None => this.record(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for GrpcMetricsBody {
+    fn drop(&mut self) {
+        // Streams cancelled by the client still count
+        self.record();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        HeaderRename, HeaderValueRule, ProxyMode, RouteConfig, UnmatchedRoute, WarmUpstreamConfig,
+    };
+    use crate::metrics::testing::{counters, record};
+    use crate::tls::EarlyDataStream;
+    use http_body_util::StreamBody;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn spawn_grpc_upstream() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|_req: Request<Incoming>| async {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                let frames: Vec<std::result::Result<Frame<Bytes>, Infallible>> = vec![
+                    Ok(Frame::data(Bytes::from_static(b"\0\0\0\0\0"))),
+                    Ok(Frame::trailers(trailers)),
+                ];
+                let response = Response::builder()
+                    .header("content-type", "application/grpc")
+                    .body(StreamBody::new(futures::stream::iter(frames)))
+                    .unwrap();
+                Ok::<_, Infallible>(response)
+            });
+            hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_unary_grpc_call_records_status() {
+        let recorded = record(async {
+            let upstream_addr = spawn_grpc_upstream().await;
+            let config = ProxyConfig {
+                mode: ProxyMode::Layer7,
+                routes: vec![RouteConfig {
+                    server_name: "grpc.test".to_string(),
+                    upstream: upstream_addr.to_string(),
+                    timeouts: Default::default(),
+                    allow_early_data: false,
+                    bind_addr: None,
+                    maintenance_page: None,
+                    dscp: None,
+                    health_check: None,
+                    header_rules: Default::default(),
+                    forward_sni: false,
+                }],
+                grpc_methods: vec!["/test.Echo/Say".to_string()],
+                ..Default::default()
+            };
+
+            let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+            let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+            tokio::spawn(async move { proxy.serve_http2(proxy_io).await });
+
+            let (mut sender, connection) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(client_io))
+                    .await
+                    .unwrap();
+            tokio::spawn(connection);
+
+            // A method not configured is counted without its name
+            for path in ["/test.Echo/Say", "/test.Echo/Unlisted"] {
+                let request = Request::post(format!("http://grpc.test{}", path))
+                    .header("content-type", "application/grpc")
+                    .header("te", "trailers")
+                    .body(Full::new(Bytes::from_static(b"\0\0\0\0\0")))
+                    .unwrap();
+                let response = sender.send_request(request).await.unwrap();
+                let body = response.into_body().collect().await.unwrap();
+
+                assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+            }
+        });
+
+        let labels = |method: &str| vec![format!("method={}", method), "code=0".to_string()];
+        assert_eq!(
+            counters(&recorded, "grpc_requests_total"),
+            vec![(labels("/test.Echo/Say"), 1), (labels("other"), 1)]
+        );
+    }
+
+    /// Raw HTTP/2 frame: 24-bit length, type, flags and stream id
+    fn h2_frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_streams_beyond_limit_refused() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        const SETTINGS: u8 = 4;
+        const RST_STREAM: u8 = 3;
+        const REFUSED_STREAM: u32 = 7;
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async {
+                // An upstream that accepts but never answers keeps the first stream open
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let upstream = listener.local_addr().unwrap();
+                tokio::spawn(async move {
+                    let (_stream, _) = listener.accept().await.unwrap();
+                    std::future::pending::<()>().await;
+                });
+                let config = ProxyConfig {
+                    mode: ProxyMode::Layer7,
+                    upstream: upstream.to_string(),
+                    max_concurrent_streams: 1,
+                    ..Default::default()
+                };
+                let (mut client, proxy_io) = tokio::io::duplex(64 * 1024);
+                let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+                tokio::spawn(async move { proxy.serve_http2(proxy_io).await });
+
+                // Written by hand because a conforming client would queue the
+                // second request instead of exceeding the advertised limit
+                client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+                client.write_all(&h2_frame(SETTINGS, 0, 0, &[])).await.unwrap();
+
+                // GET http://app.test/ as indexed and literal HPACK fields
+                let mut headers = vec![0x82, 0x86, 0x84, 0x41, 8];
+                headers.extend_from_slice(b"app.test");
+                let mut sent = false;
+                let refused = loop {
+                    let mut header = [0u8; 9];
+                    client.read_exact(&mut header).await.unwrap();
+                    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                    let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+                    let mut payload = vec![0u8; len];
+                    client.read_exact(&mut payload).await.unwrap();
+
+                    if header[3] == SETTINGS && header[4] & 1 == 0 && !sent {
+                        client.write_all(&h2_frame(SETTINGS, 1, 0, &[])).await.unwrap();
+                        // END_STREAM | END_HEADERS on streams 1 and 3
+                        client.write_all(&h2_frame(1, 0x5, 1, &headers)).await.unwrap();
+                        client.write_all(&h2_frame(1, 0x5, 3, &headers)).await.unwrap();
+                        sent = true;
+                    }
+                    if header[3] == RST_STREAM {
+                        let code = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                        break (stream_id, code);
+                    }
+                };
+                assert_eq!(refused, (3, REFUSED_STREAM));
+
+                // Only the first stream is still open
+                let active = snapshotter.snapshot().into_vec().into_iter().any(|(key, _, _, value)| {
+                    key.key().name() == "http2_active_streams"
+                        && matches!(value, DebugValue::Gauge(v) if v.into_inner() == 1.0)
+                });
+                assert!(active);
+            });
+        });
+    }
+
+    async fn spawn_echo_target() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+        });
+        addr
+    }
+
+    async fn connect_request(proxy: Arc<L7Proxy>, target: &str) -> (tokio::io::DuplexStream, String) {
+        let (mut client, proxy_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+
+        let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        let status_line = String::from_utf8_lossy(&buf[..n]).lines().next().unwrap().to_string();
+        (client, status_line)
+    }
+
+    #[tokio::test]
+    async fn test_connect_allowed_and_disallowed() {
+        let target = spawn_echo_target().await;
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            forward_proxy: true,
+            connect_allow_list: vec![target.to_string()],
+            ..Default::default()
+        };
+        let proxy = Arc::new(L7Proxy::new(Arc::new(config), Arc::new(Metrics::new())));
+
+        let (mut tunnel, status) = connect_request(proxy.clone(), &target.to_string()).await;
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+
+        tunnel.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        tunnel.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let (_, status) = connect_request(proxy, "blocked.example:443").await;
+        assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel_closed_at_shutdown() {
+        // A target that keeps the tunnel open until the proxy closes it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        });
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            forward_proxy: true,
+            connect_allow_list: vec![target.to_string()],
+            ..Default::default()
+        };
+        let (shutdown, tunnels) = (CancellationToken::new(), TaskTracker::new());
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()))
+            .with_shutdown(shutdown.clone())
+            .with_task_tracker(tunnels.clone());
+
+        let (mut tunnel, status) = connect_request(Arc::new(proxy), &target.to_string()).await;
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        tunnel.write_all(b"ping").await.unwrap();
+
+        // Shutdown waits for the tunnel, which closes instead of idling on
+        shutdown.cancel();
+        tunnels.close();
+        timeout(Duration::from_secs(5), tunnels.wait()).await.unwrap();
+        let mut buf = [0u8; 16];
+        let closed = timeout(Duration::from_secs(5), tunnel.read(&mut buf)).await.unwrap();
+        assert_eq!(closed.unwrap(), 0);
+    }
+
+    async fn spawn_http1_upstream(
+        reply: &'static str,
+    ) -> (std::net::SocketAddr, mpsc::UnboundedReceiver<(String, Bytes)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |req: Request<Incoming>| {
+                let tx = tx.clone();
+                async move {
+                    let path = req.uri().path().to_string();
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    tx.send((path, body)).unwrap();
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(reply.as_bytes()))))
+                }
+            });
+            hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_shadow_upstream_receives_copy() {
+        let (primary, mut primary_rx) = spawn_http1_upstream("primary").await;
+        let (shadow, mut shadow_rx) = spawn_http1_upstream("shadow").await;
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: format!("http://{}", primary),
+            shadow_upstream: Some(shadow.to_string()),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let (mut client, proxy_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+
+        client
+            .write_all(b"POST /orders HTTP/1.1\r\nHost: app.test\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]);
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("primary"));
+
+        assert_eq!(primary_rx.recv().await.unwrap(), ("/orders".to_string(), Bytes::from_static(b"hello")));
+        assert_eq!(shadow_rx.recv().await.unwrap(), ("/orders".to_string(), Bytes::from_static(b"hello")));
+    }
+
+    #[tokio::test]
+    async fn test_pooled_connection_reused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        {
+            let accepted = accepted.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let service = service_fn(|_req: Request<Incoming>| async {
+                            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"ok"))))
+                        });
+                        let _ = hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            });
+        }
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+        let pool = proxy.pool.clone();
+        let key = PoolKey {
+            upstream: upstream.to_string(),
+            protocol: UpstreamProtocol::Http1,
+            bind_addr: None,
+            dscp: None,
+        };
+
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        for _ in 0..2 {
+            let request = Request::get("/").header("host", "app.test").body(Empty::<Bytes>::new()).unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, Bytes::from_static(b"ok"));
+
+            // Wait for the upstream connection to be returned to the pool
+            for _ in 0..100 {
+                if pool.idle_count(&key) == 1 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_after_max_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let service = service_fn(|_req: Request<Incoming>| async {
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"ok"))))
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            max_requests_per_connection: Some(3),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        for n in 1..=3 {
+            let request = Request::get("/").header("host", "app.test").body(Empty::<Bytes>::new()).unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let close = response.headers().get(http::header::CONNECTION);
+            assert_eq!(close.is_some_and(|value| value == "close"), n == 3, "request {}", n);
+            response.into_body().collect().await.unwrap();
+        }
+
+        // The proxy closes the connection after the third response
+        served.await.unwrap().unwrap();
+        assert!(sender.ready().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_responses_carry_connection_and_request_ids() {
+        // The upstream replies with the request id it received
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let id = req.headers().get("x-request-id").cloned();
+                        let body = id.map_or_else(Bytes::new, |id| Bytes::copy_from_slice(id.as_bytes()));
+                        Ok::<_, Infallible>(Response::new(Full::new(body)))
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            request_id_header: Some("X-Request-Id".to_string()),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new())).with_connection_id(42);
+
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        // Without an inbound id one is generated, and the upstream sees it too
+        let request = Request::get("/").header("host", "app.test").body(Empty::<Bytes>::new()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.headers()["x-connection-id"], "42");
+        let generated = response.headers()["x-request-id"].clone();
+        assert_eq!(generated.len(), 32);
+        assert!(generated.as_bytes().iter().all(u8::is_ascii_hexdigit));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, generated.as_bytes());
+
+        // An inbound id is passed through and echoed
+        let request = Request::get("/")
+            .header("host", "app.test")
+            .header("x-request-id", "abc-123")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.headers()["x-connection-id"], "42");
+        assert_eq!(response.headers()["x-request-id"], "abc-123");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "abc-123");
+    }
+
+    /// Upstream that drops its first connection after reading the request,
+    /// having sent `first_reply`: with a reset if that is empty, with a FIN
+    /// partway through the response otherwise. Later connections get `ok`.
+    async fn spawn_failing_upstream(
+        first_reply: &'static [u8],
+    ) -> (std::net::SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let first = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0;
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8; 1];
+                    if stream.read(&mut byte).await.unwrap() == 0 {
+                        break;
+                    }
+                    request.push(byte[0]);
+                }
+                if !first {
+                    let ok = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                    stream.write_all(ok).await.unwrap();
+                } else if first_reply.is_empty() {
+                    socket2::SockRef::from(&stream).set_linger(Some(std::time::Duration::ZERO)).unwrap();
+                } else {
+                    stream.write_all(first_reply).await.unwrap();
+                }
+            }
+        });
+        (addr, accepted)
+    }
+
+    /// L7 config spreading unrouted requests over `targets`, equally weighted
+    fn weighted_config(targets: &[std::net::SocketAddr]) -> ProxyConfig {
+        let weighted_targets = targets
+            .iter()
+            .map(|addr| crate::config::WeightedTarget {
+                addr: addr.to_string(),
+                weight: 1,
+            })
+            .collect();
+        ProxyConfig {
+            mode: ProxyMode::Layer7,
+            weighted_targets,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_only_before_response_bytes() {
+        let partial = b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nabc";
+        for (first_reply, retried) in [(&b""[..], true), (&partial[..], false)] {
+            let (failing, accepted) = spawn_failing_upstream(first_reply).await;
+            let (other, mut other_rx) = spawn_http1_upstream("ok").await;
+            let config = ProxyConfig {
+                retry_on_upstream_reset: true,
+                ..weighted_config(&[failing, other])
+            };
+            let health = Arc::new(crate::proxy::upstream_set(&config));
+            let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new())).with_upstream_health(health);
+
+            let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+            let (mut sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(client_io)).await.unwrap();
+            tokio::spawn(connection);
+
+            // Equal weights: the first request goes to the first target
+            let request = Request::get("/").header("host", "app.test").body(Empty::<Bytes>::new()).unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await;
+            assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+            if retried {
+                // Reset before any response: the request went to the other
+                // upstream and succeeded
+                assert_eq!(body.unwrap().to_bytes(), Bytes::from_static(b"ok"));
+                assert_eq!(other_rx.try_recv().unwrap().0, "/");
+            } else {
+                // Part of the response was already relayed, so it is not retried
+                assert!(body.is_err());
+                assert!(other_rx.try_recv().is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_reset_without_other_upstream_fails() {
+        // A routed request has no other upstream to go to
+        let (upstream, accepted) = spawn_failing_upstream(b"").await;
+        let (other, mut other_rx) = spawn_http1_upstream("ok").await;
+        let config = ProxyConfig {
+            routes: vec![RouteConfig {
+                server_name: "app.test".to_string(),
+                upstream: upstream.to_string(),
+                timeouts: Default::default(),
+                allow_early_data: false,
+                bind_addr: None,
+                maintenance_page: None,
+                dscp: None,
+                health_check: None,
+                header_rules: Default::default(),
+                forward_sni: false,
+            }],
+            retry_on_upstream_reset: true,
+            error_responses: error_responses(),
+            ..weighted_config(&[other])
+        };
+        let health = Arc::new(crate::proxy::upstream_set(&config));
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new())).with_upstream_health(health);
+
+        let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.serve_http1(io).await })).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(other_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_warm_upstream_retries_failure_before_headers() {
+        for warm_upstream in [Some(WarmUpstreamConfig::default()), None] {
+            // Accepts, reads the request, then resets before any headers
+            let (upstream, accepted) = spawn_failing_upstream(b"").await;
+            let config = ProxyConfig {
+                mode: ProxyMode::Layer7,
+                upstream: upstream.to_string(),
+                warm_upstream: warm_upstream.clone(),
+                // With no other upstream this alone cannot help
+                retry_on_upstream_reset: true,
+                error_responses: error_responses(),
+                ..Default::default()
+            };
+            let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+            let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.serve_http1(io).await })).await;
+            if warm_upstream.is_some() {
+                // Retried on a new connection without the client noticing
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.body(), &Bytes::from_static(b"ok"));
+                assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 2);
+            } else {
+                assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+                assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warm_upstream_caps_buffered_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let filler = "a".repeat(16 * 1024);
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let reply = format!("HTTP/1.1 200 OK\r\nx-filler: {}\r\ncontent-length: 2\r\n\r\nok", filler);
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+
+        for (max_header_bytes, status) in [(64 * 1024, StatusCode::OK), (8 * 1024, StatusCode::BAD_GATEWAY)] {
+            accepted.store(0, std::sync::atomic::Ordering::SeqCst);
+            let config = ProxyConfig {
+                mode: ProxyMode::Layer7,
+                upstream: upstream.to_string(),
+                warm_upstream: Some(WarmUpstreamConfig {
+                    max_header_bytes,
+                    ..Default::default()
+                }),
+                error_responses: error_responses(),
+                ..Default::default()
+            };
+            let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+            let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.serve_http1(io).await })).await;
+            assert_eq!(response.status(), status);
+            // An oversized header block would come back the same, so it is not retried
+            assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn test_only_idempotent_bodiless_requests_are_replayable() {
+        let get = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+        assert!(replayable(&get).is_some());
+        let post = Request::post("/").body(Empty::<Bytes>::new()).unwrap();
+        assert!(replayable(&post).is_none());
+        let put = Request::put("/").body(Full::new(Bytes::from_static(b"data"))).unwrap();
+        assert!(replayable(&put).is_none());
+    }
+
+    async fn http1_roundtrip<T>(
+        serve: impl FnOnce(tokio::io::DuplexStream) -> tokio::task::JoinHandle<Result<T>>,
+    ) -> Response<Bytes> {
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        serve(proxy_io);
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::get("/").header("host", "app.test").body(Empty::<Bytes>::new()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    fn error_responses() -> crate::config::ErrorResponses {
+        let response = |status, retry_after, body: &str| ErrorResponse {
+            status,
+            retry_after,
+            body: body.to_string(),
+        };
+        crate::config::ErrorResponses {
+            capacity: response(503, Some(30), "busy"),
+            upstream_error: response(502, None, "upstream down"),
+            timeout: response(504, None, "upstream slow"),
+            no_route: response(421, None, "no route"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_over_capacity_response() {
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.reject_over_capacity(io, false).await })).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "30");
+        assert_eq!(response.body(), &Bytes::from_static(b"busy"));
+    }
+
+    #[tokio::test]
+    async fn test_over_capacity_idle_http1_client_closed() {
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            timeout: 1,
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        // A rejected client that never sends its request is dropped after the idle timeout
+        let (_client_io, proxy_io) = tokio::io::duplex(1024);
+        let served = timeout(Duration::from_secs(5), proxy.reject_over_capacity(proxy_io, false)).await;
+        assert!(served.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_error_response() {
+        // Reserve a port and close it so connecting is refused
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: closed.to_string(),
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.serve_http1(io).await })).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(response.headers().get(http::header::RETRY_AFTER).is_none());
+        assert_eq!(response.body(), &Bytes::from_static(b"upstream down"));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_page_when_upstream_down() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let page = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(page.path(), "<h1>Back soon</h1>").unwrap();
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: closed.to_string(),
+            routes: vec![RouteConfig {
+                server_name: "app.test".to_string(),
+                upstream: closed.to_string(),
+                timeouts: Default::default(),
+                allow_early_data: false,
+                bind_addr: None,
+                maintenance_page: Some(page.path().to_path_buf()),
+                dscp: None,
+                health_check: None,
+                header_rules: Default::default(),
+                forward_sni: false,
+            }],
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.serve_http1(io).await })).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "30");
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(response.body(), &Bytes::from_static(b"<h1>Back soon</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_host_goes_to_default_or_is_rejected() {
+        async fn get(config: &ProxyConfig, host: &str) -> (StatusCode, Bytes) {
+            let proxy = L7Proxy::new(Arc::new(config.clone()), Arc::new(Metrics::new()));
+            let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+            let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            let request = Request::get("/").header("host", host).body(Empty::<Bytes>::new()).unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let status = response.status();
+            (status, response.into_body().collect().await.unwrap().to_bytes())
+        }
+
+        let (routed, _routed_requests) = spawn_http1_upstream("routed").await;
+        let (default, _default_requests) = spawn_http1_upstream("default").await;
+        let mut config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: default.to_string(),
+            routes: vec![RouteConfig {
+                server_name: "app.test".to_string(),
+                upstream: routed.to_string(),
+                timeouts: Default::default(),
+                allow_early_data: false,
+                bind_addr: None,
+                maintenance_page: None,
+                dscp: None,
+                health_check: None,
+                header_rules: Default::default(),
+                forward_sni: false,
+            }],
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+
+        assert_eq!(get(&config, "app.test").await, (StatusCode::OK, Bytes::from_static(b"routed")));
+        assert_eq!(get(&config, "other.test").await, (StatusCode::OK, Bytes::from_static(b"default")));
+
+        config.unmatched_route = UnmatchedRoute::Reject;
+        let rejected = get(&config, "other.test").await;
+        assert_eq!(rejected, (StatusCode::MISDIRECTED_REQUEST, Bytes::from_static(b"no route")));
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_algorithm_headers() {
+        for enabled in [true, false] {
+            let (upstream, _requests) = spawn_http1_upstream("ok").await;
+            let config = ProxyConfig {
+                mode: ProxyMode::Layer7,
+                upstream: upstream.to_string(),
+                expose_negotiated_algorithms: enabled,
+                ..Default::default()
+            };
+            let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()))
+                .with_negotiated_algorithms("kyber768", "dilithium3");
+
+            let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.serve_http1(io).await })).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            if enabled {
+                assert_eq!(response.headers()["x-safequanta-kem"], "kyber768");
+                assert_eq!(response.headers()["x-safequanta-sig"], "dilithium3");
+            } else {
+                assert!(response.headers().get("x-safequanta-kem").is_none());
+                assert!(response.headers().get("x-safequanta-sig").is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_close_during_shutdown() {
+        // Upstream that holds its response until released
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let (arrived_tx, mut arrived) = mpsc::unbounded_channel();
+        let release = Arc::new(tokio::sync::Notify::new());
+        {
+            let release = release.clone();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(move |_req: Request<Incoming>| {
+                    let (arrived_tx, release) = (arrived_tx.clone(), release.clone());
+                    async move {
+                        arrived_tx.send(()).unwrap();
+                        release.notified().await;
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"ok"))))
+                    }
+                });
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            ..Default::default()
+        };
+        let shutdown = CancellationToken::new();
+        let proxy =
+            L7Proxy::new(Arc::new(config), Arc::new(Metrics::new())).with_shutdown(shutdown.clone());
+
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::get("/").header("host", "app.test").body(Empty::<Bytes>::new()).unwrap();
+        let response = tokio::spawn(sender.send_request(request));
+        arrived.recv().await.unwrap();
+        shutdown.cancel();
+        release.notify_one();
+
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONNECTION], "close");
+        // The proxy closes the connection once the response is written
+        response.into_body().collect().await.unwrap();
+        served.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_at_lifetime() {
+        let (upstream, mut requests) = spawn_http1_upstream("ok").await;
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            ..Default::default()
+        };
+        let lifetime = Duration::from_millis(200);
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()))
+            .with_deadline(tokio::time::Instant::now() + lifetime);
+
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        // A request before the deadline keeps the connection open
+        let request = Request::get("/").header("host", "app.test").body(Empty::<Bytes>::new()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert!(response.headers().get(http::header::CONNECTION).is_none());
+        response.into_body().collect().await.unwrap();
+        requests.recv().await.unwrap();
+
+        // At the deadline the idle keep-alive connection is closed
+        let reason = timeout(lifetime * 5, served).await.unwrap().unwrap().unwrap();
+        assert_eq!(reason, CloseReason::Lifetime);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_response() {
+        // An upstream that accepts but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            timeout: 1,
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let response = http1_roundtrip(|io| tokio::spawn(async move { proxy.serve_http1(io).await })).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(response.body(), &Bytes::from_static(b"upstream slow"));
+    }
+
+    #[tokio::test]
+    async fn test_http2_timeout_response() {
+        // An HTTP/2 upstream that accepts streams but never answers them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|_req: Request<Incoming>| async {
+                std::future::pending::<std::result::Result<Response<Empty<Bytes>>, Infallible>>()
+                    .await
+            });
+            let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            timeout: 1,
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { proxy.serve_http2(proxy_io).await });
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(client_io))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::get("http://app.test/").body(Empty::<Bytes>::new()).unwrap();
+        let response = timeout(Duration::from_secs(5), sender.send_request(request))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"upstream slow"));
+    }
+
+    #[tokio::test]
+    async fn test_client_deadline_bounds_upstream_wait() {
+        // An upstream that accepts but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        // The idle timeout alone would keep the client waiting 30 seconds
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            timeout: 30,
+            deadline_header: Some("x-request-deadline-ms".to_string()),
+            error_responses: error_responses(),
+            ..Default::default()
+        };
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()));
+
+        let (client_io, proxy_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let start = Instant::now();
+        let request = Request::get("/")
+            .header("host", "app.test")
+            .header("x-request-deadline-ms", "200")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_client_deadline_parsing() {
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("123456789m"), None);
+        assert_eq!(parse_grpc_timeout("m"), None);
+        assert_eq!(parse_grpc_timeout("10x"), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(GRPC_TIMEOUT_HEADER, "5S".parse().unwrap());
+        headers.insert("x-deadline", "800".parse().unwrap());
+        assert_eq!(client_deadline(&headers, None), Some(Duration::from_secs(5)));
+        assert_eq!(client_deadline(&headers, Some("x-deadline")), Some(Duration::from_millis(800)));
+        assert_eq!(client_deadline(&HeaderMap::new(), Some("x-deadline")), None);
+    }
+
+    #[test]
+    fn test_forwarded_appends_to_trusted_xff() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.2".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        let config = ForwardedConfig {
+            enabled: true,
+            trusted_hops: 2,
+        };
+
+        let client = Some("192.0.2.10:5555".parse().unwrap());
+        apply_forwarded_headers(&mut headers, client, &config);
+
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7, 10.0.0.2, 192.0.2.10");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers[http::header::FORWARDED], "for=192.0.2.10;proto=https");
+    }
+
+    #[test]
+    fn test_forwarded_strips_untrusted_inbound_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 10.0.0.2".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert(http::header::FORWARDED, "for=1.2.3.4".parse().unwrap());
+
+        let client = Some("[2001:db8::1]:443".parse().unwrap());
+        apply_forwarded_headers(&mut headers, client, &ForwardedConfig::default());
+
+        assert_eq!(headers["x-forwarded-for"], "2001:db8::1");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers[http::header::FORWARDED], "for=\"[2001:db8::1]\";proto=https");
+    }
+
+    #[test]
+    fn test_forwarded_partially_trusted_chain() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "6.6.6.6, 10.0.0.2".parse().unwrap());
+        let config = ForwardedConfig {
+            enabled: true,
+            trusted_hops: 1,
+        };
+
+        let client = Some("10.0.0.3:1234".parse().unwrap());
+        apply_forwarded_headers(&mut headers, client, &config);
+
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.2, 10.0.0.3");
+    }
+
+    #[test]
+    fn test_forwarded_disabled_still_sets_proto() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        let config = ForwardedConfig {
+            enabled: false,
+            trusted_hops: 0,
+        };
+
+        let client = Some("192.0.2.10:5555".parse().unwrap());
+        apply_forwarded_headers(&mut headers, client, &config);
+
+        // No client address, but the upstream still learns the scheme
+        assert!(!headers.contains_key("x-forwarded-for"));
+        assert!(!headers.contains_key(http::header::FORWARDED));
+        assert_eq!(headers["x-forwarded-proto"], "https");
+    }
+
+    fn header_rules() -> HeaderRules {
+        serde_yaml::from_str(
+            r#"
+remove: [x-debug, x-absent]
+rename:
+  - { from: x-user, to: x-remote-user }
+  - { from: x-missing, to: x-other }
+set:
+  - { name: x-route, value: app }
+  - { name: x-env, value: prod }
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_header_rules_on_existing_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug", "1".parse().unwrap());
+        headers.append("x-user", "alice".parse().unwrap());
+        headers.append("x-user", "bob".parse().unwrap());
+        headers.insert("x-remote-user", "spoofed".parse().unwrap());
+        headers.insert("x-env", "dev".parse().unwrap());
+
+        apply_header_rules(&mut headers, &header_rules());
+
+        assert!(!headers.contains_key("x-debug"));
+        assert!(!headers.contains_key("x-user"));
+        let users: Vec<_> = headers.get_all("x-remote-user").iter().collect();
+        assert_eq!(users, ["alice", "bob"]);
+        assert_eq!(headers["x-env"], "prod");
+        assert_eq!(headers["x-route"], "app");
+    }
+
+    #[test]
+    fn test_header_rules_on_missing_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-other", "kept".parse().unwrap());
+
+        apply_header_rules(&mut headers, &header_rules());
+
+        assert_eq!(headers["x-other"], "kept");
+        assert!(!headers.contains_key("x-remote-user"));
+        assert_eq!(headers["x-route"], "app");
+        assert_eq!(headers["x-env"], "prod");
+        assert_eq!(headers.len(), 3);
+    }
+
+    #[test]
+    fn test_header_rules_order() {
+        // A header set by the rules survives a removal of the same name
+        let rules = HeaderRules {
+            remove: vec!["x-tag".to_string()],
+            rename: vec![HeaderRename {
+                from: "x-tag".to_string(),
+                to: "x-old-tag".to_string(),
+            }],
+            set: vec![HeaderValueRule {
+                name: "x-tag".to_string(),
+                value: "new".to_string(),
+            }],
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tag", "old".parse().unwrap());
+
+        apply_header_rules(&mut headers, &rules);
+
+        assert_eq!(headers["x-tag"], "new");
+        assert!(!headers.contains_key("x-old-tag"));
+    }
+
+    fn early_data_config(upstream: std::net::SocketAddr) -> ProxyConfig {
+        ProxyConfig {
+            mode: ProxyMode::Layer7,
+            upstream: upstream.to_string(),
+            forward_proxy: true,
+            routes: vec![RouteConfig {
+                server_name: "app.test".to_string(),
+                upstream: upstream.to_string(),
+                timeouts: Default::default(),
+                allow_early_data: true,
+                bind_addr: None,
+                maintenance_page: None,
+                dscp: None,
+                health_check: None,
+                header_rules: Default::default(),
+                forward_sni: false,
+            }],
+            ..Default::default()
+        }
+    }
+
+    async fn early_data_request(request: &'static [u8]) -> (String, Option<(String, Bytes)>) {
+        let (upstream, mut upstream_rx) = spawn_http1_upstream("ok").await;
+        let config = early_data_config(upstream);
+
+        // The request arrives as early data
+        let (mut client, proxy_io) = tokio::io::duplex(64 * 1024);
+        let proxy_io = EarlyDataStream::new(Some(Bytes::from_static(request)), proxy_io);
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()))
+            .with_early_data(proxy_io.state().unwrap());
+        tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+
+        let mut buf = [0u8; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        (String::from_utf8_lossy(&buf[..n]).into_owned(), upstream_rx.try_recv().ok())
+    }
+
+    #[tokio::test]
+    async fn test_early_data_get_accepted_post_rejected() {
+        let (response, forwarded) = early_data_request(b"GET /items HTTP/1.1\r\nHost: app.test\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(forwarded.unwrap().0, "/items");
+
+        let (response, forwarded) = early_data_request(
+            b"POST /orders HTTP/1.1\r\nHost: app.test\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 425"));
+        assert!(forwarded.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_every_early_data_request_checked_until_confirmed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let (tx, mut forwarded) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let early = req.headers().contains_key("early-data");
+                    tx.send((req.uri().path().to_string(), early)).unwrap();
+                    async { Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"ok")))) }
+                });
+                let connection =
+                    hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                tokio::spawn(connection);
+            }
+        });
+        let config = early_data_config(upstream);
+
+        // Three requests pipelined in the early data: the GET goes through
+        // marked, the POST and the CONNECT behind it are too early
+        let early = concat!(
+            "GET /first HTTP/1.1\r\nHost: app.test\r\n\r\n",
+            "POST /second HTTP/1.1\r\nHost: app.test\r\nContent-Length: 0\r\n\r\n",
+            "CONNECT app.test:443 HTTP/1.1\r\nHost: app.test:443\r\n\r\n",
+        );
+        let (client, proxy_io) = tokio::io::duplex(64 * 1024);
+        let proxy_io = EarlyDataStream::new(Some(Bytes::from_static(early.as_bytes())), proxy_io);
+        let proxy = L7Proxy::new(Arc::new(config), Arc::new(Metrics::new()))
+            .with_early_data(proxy_io.state().unwrap());
+        tokio::spawn(async move { proxy.serve_http1(proxy_io).await });
+
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let mut responses = tokio::io::BufReader::new(client_read);
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            let mut status = String::new();
+            responses.read_line(&mut status).await.unwrap();
+            statuses.push(status);
+            let mut line = String::new();
+            let mut length = 0;
+            while line != "\r\n" {
+                line.clear();
+                responses.read_line(&mut line).await.unwrap();
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; length];
+            responses.read_exact(&mut body).await.unwrap();
+        }
+        assert!(statuses[0].starts_with("HTTP/1.1 200"), "{}", statuses[0]);
+        assert!(statuses[1].starts_with("HTTP/1.1 425"), "{}", statuses[1]);
+        assert!(statuses[2].starts_with("HTTP/1.1 425"), "{}", statuses[2]);
+        assert_eq!(forwarded.recv().await.unwrap(), ("/first".to_string(), true));
+
+        // Sent after the handshake, the same POST is forwarded unmarked
+        client_write
+            .write_all(b"POST /third HTTP/1.1\r\nHost: app.test\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut status = String::new();
+        responses.read_line(&mut status).await.unwrap();
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(forwarded.recv().await.unwrap(), ("/third".to_string(), false));
+    }
+
+    #[test]
+    fn test_early_data_requires_route_opt_in() {
+        let route = RouteConfig {
+            server_name: "app.test".to_string(),
+            upstream: "127.0.0.1:8080".to_string(),
+            timeouts: Default::default(),
+            allow_early_data: false,
+            bind_addr: None,
+            maintenance_page: None,
+            dscp: None,
+            health_check: None,
+            header_rules: Default::default(),
+            forward_sni: false,
+        };
+        assert!(!early_data_allowed(&Method::GET, Some(&route)));
+        assert!(!early_data_allowed(&Method::GET, None));
+    }
+}
@@ -0,0 +1,24 @@
+pub mod activation;
+pub mod balancer;
+pub mod config;
+pub mod crypto;
+pub mod error;
+pub mod events;
+pub mod fingerprint;
+pub mod handshake;
+pub mod health;
+pub mod jsonl;
+pub mod l7;
+pub mod logfile;
+pub mod metrics;
+pub mod padding;
+pub mod policy;
+pub mod pool;
+pub mod proxy;
+pub mod recorder;
+pub mod sampler;
+pub mod tls;
+pub mod trace;
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
@@ -0,0 +1,192 @@
+//! Log file with daily or size-based rotation
+//!
+//! On rotation the current file becomes `<path>.1`, older ones move up by one
+//! and the oldest beyond `KEPT_LOG_FILES` is deleted, so the log takes a
+//! bounded amount of disk.
+
+use crate::config::LogRotation;
+use parking_lot::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rotated files kept next to the current one
+pub const KEPT_LOG_FILES: usize = 7;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Days since the Unix epoch, in UTC
+fn day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() / SECS_PER_DAY)
+}
+
+/// Writer that appends to a log file and rotates it
+///
+/// Meant to sit behind `tracing_appender::non_blocking`, which moves the
+/// file writes off the logging threads and flushes them when its guard is
+/// dropped at shutdown.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: File,
+    /// Bytes in the current file
+    len: u64,
+    /// Day the current file was started
+    opened_day: u64,
+}
+
+impl RotatingFile {
+    /// Append to `path`, creating it if needed
+    pub fn open(path: impl Into<PathBuf>, rotation: LogRotation) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // An existing file counts from when it was last written, so a restart
+        // the next day still rotates yesterday's log
+        let opened_day = day(metadata.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            len: metadata.len(),
+            opened_day,
+        })
+    }
+
+    /// Write `buf` as of `now`, rotating first if a boundary has been reached
+    fn write_at(&mut self, buf: &[u8], now: SystemTime) -> io::Result<usize> {
+        let due = match self.rotation {
+            LogRotation::Daily => day(now) != self.opened_day,
+            LogRotation::Size(limit) => self.len > 0 && self.len + buf.len() as u64 > limit,
+        };
+        if due {
+            self.rotate(now)?;
+        }
+        let n = self.file.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        self.file.flush()?;
+        let _ = fs::remove_file(rotated(&self.path, KEPT_LOG_FILES));
+        for n in (1..KEPT_LOG_FILES).rev() {
+            let from = rotated(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.len = 0;
+        self.opened_day = day(now);
+        Ok(())
+    }
+}
+
+/// Path of the `n`th most recent rotated file
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, SystemTime::now())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Log output that goes to stderr until `redirect` sends it elsewhere
+///
+/// Lets logging start before the configuration naming the log file is
+/// loaded, so load warnings and errors are not lost.
+#[derive(Clone, Default)]
+pub struct LogTarget {
+    redirected: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+}
+
+impl LogTarget {
+    /// Write everything logged from now on to `writer`
+    pub fn redirect(&self, writer: impl Write + Send + 'static) {
+        *self.redirected.lock() = Some(Box::new(writer));
+    }
+}
+
+impl Write for LogTarget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.redirected.lock().as_mut() {
+            Some(writer) => writer.write(buf),
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.redirected.lock().as_mut() {
+            Some(writer) => writer.flush(),
+            None => io::stderr().flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rotation_starts_new_file_at_boundary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proxy.log");
+
+        // Size: the write that would cross the limit goes to a new file
+        let mut log = RotatingFile::open(&path, LogRotation::Size(100)).unwrap();
+        let now = SystemTime::now();
+        log.write_at(&[b'a'; 60], now).unwrap();
+        log.write_at(&[b'b'; 30], now).unwrap();
+        assert!(!rotated(&path, 1).exists());
+        log.write_at(&[b'c'; 30], now).unwrap();
+        assert_eq!(fs::read(rotated(&path, 1)).unwrap().len(), 90);
+        assert_eq!(fs::read(&path).unwrap(), [b'c'; 30]);
+
+        // Daily: the first write after midnight UTC rotates, shifting the older file
+        let mut log = RotatingFile::open(&path, LogRotation::Daily).unwrap();
+        let midnight = UNIX_EPOCH + Duration::from_secs((day(now) + 1) * SECS_PER_DAY);
+        log.write_at(b"before\n", midnight - Duration::from_secs(1)).unwrap();
+        assert!(!rotated(&path, 2).exists());
+        log.write_at(b"after\n", midnight).unwrap();
+        assert_eq!(fs::read(rotated(&path, 2)).unwrap().len(), 90);
+        assert!(fs::read(rotated(&path, 1)).unwrap().ends_with(b"before\n"));
+        assert_eq!(fs::read(&path).unwrap(), b"after\n");
+    }
+
+    #[test]
+    fn test_log_target_redirected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proxy.log");
+        let mut target = LogTarget::default();
+
+        target.write_all(b"to stderr\n").unwrap();
+        target.clone().redirect(File::create(&path).unwrap());
+        target.write_all(b"to the file\n").unwrap();
+        target.flush().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"to the file\n");
+    }
+
+    #[test]
+    fn test_log_rotation_parsing() {
+        let parse = |rotation: &str| LogRotation::try_from(rotation.to_string());
+        assert_eq!(parse("daily"), Ok(LogRotation::Daily));
+        assert_eq!(parse("size:10mb"), Ok(LogRotation::Size(10 * 1024 * 1024)));
+        assert_eq!(String::from(LogRotation::Size(10 * 1024 * 1024)), "size:10mb");
+        assert!(parse("size:0mb").is_err());
+        assert!(parse("hourly").is_err());
+    }
+}
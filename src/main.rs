@@ -1,60 +1,104 @@
-mod config;
-mod crypto;
-mod error;
-mod metrics;
-mod proxy;
-mod tls;
-
-use crate::config::Config;
-use crate::crypto::CryptoProvider;
-use crate::error::Result;
-use crate::metrics::Metrics;
-use crate::proxy::ProxyServer;
-use crate::tls::TlsManager;
+use safequanta_tls::activation::ActivationEnv;
+use safequanta_tls::config::{AgeDecryptor, Config};
+use safequanta_tls::crypto::CryptoProvider;
+use safequanta_tls::error::Result;
+use safequanta_tls::logfile::{LogTarget, RotatingFile};
+use safequanta_tls::metrics::{AdminEndpoints, Metrics};
+use safequanta_tls::proxy::ProxyServer;
+use safequanta_tls::tls::TlsManager;
 use std::sync::Arc;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    env_logger::init();
+fn main() -> Result<()> {
+    // Taking the socket activation variables changes the environment, which
+    // is only sound before the runtime starts its threads
+    let activation = ActivationEnv::take();
+    tokio::runtime::Runtime::new()?.block_on(run(activation))
+}
+
+async fn run(activation: ActivationEnv) -> Result<()> {
+    if std::env::args().skip(1).any(|arg| arg == "--generate-config") {
+        print!("{}", Config::annotated_default()?);
+        return Ok(());
+    }
+
+    // Initialize logging to stderr, so configuration problems are reported
+    let log_target = LogTarget::default();
+    env_logger::Builder::from_default_env()
+        .target(env_logger::Target::Pipe(Box::new(log_target.clone())))
+        .init();
     log::info!("Starting SafeQuanta TLS Proxy...");
 
     // Load configuration
-    let config = Arc::new(Config::load()?);
+    let loaded = AgeDecryptor::from_env().and_then(|decryptor| Config::load_with_decryptor(&decryptor));
+    let config = match loaded {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            log::error!("Failed to load configuration: {:#}", e);
+            return Err(e.into());
+        }
+    };
     log::info!("Configuration loaded successfully");
 
-    // Initialize metrics
-    let metrics = Arc::new(Metrics::new());
-    log::info!("Metrics initialized");
+    // Move logging to the log file; the guard flushes it when main returns.
+    // Lines wait for the writer thread rather than being dropped when it
+    // falls behind.
+    let log_guard = match &config.server.log_file {
+        Some(path) => {
+            let file = RotatingFile::open(path, config.server.log_rotation)?;
+            let (writer, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
+                .lossy(false)
+                .finish(file);
+            log_target.redirect(writer);
+            Some(guard)
+        }
+        None => None,
+    };
 
     // Initialize crypto provider
-    let crypto_provider = Arc::new(CryptoProvider::new(
-        config.tls.kem_algorithm,
-        config.tls.signature_algorithm,
-        &config.tls.cert_path,
-        &config.tls.key_path,
-    )?);
+    let crypto_provider = Arc::new(CryptoProvider::from_config(&config.tls)?);
     log::info!("Crypto provider initialized");
 
-    // Initialize TLS manager
-    let tls_manager = Arc::new(TlsManager::new(
-        Arc::new(config.tls.clone()),
-        crypto_provider.clone(),
-        metrics.clone(),
-    )?);
-    log::info!("TLS manager initialized");
+    // Adopt sockets passed by systemd socket activation
+    let inherited = activation.adopt()?;
+
+    // Initialize metrics
+    let endpoints = AdminEndpoints {
+        config: config.metrics.auth.is_some().then(|| config.clone()),
+        pqc_keys: config.metrics.serve_pqc_keys.then(|| crypto_provider.clone()),
+        ..Default::default()
+    };
+    let upstreams = endpoints.upstreams.clone();
+    let tracers = endpoints.tracers.clone();
+    let metrics = Arc::new(Metrics::install_on(&config.metrics, endpoints, inherited.metrics)?);
+    log::info!("Metrics initialized");
+
+    // Initialize a crypto provider and TLS manager per listener, so a
+    // listener's own `tls` algorithms are the ones it negotiates. The
+    // top-level listener keeps the provider whose keys `/pqc-keys` serves.
+    let mut listeners = Vec::new();
+    for (i, (proxy_config, tls_config)) in config.listener_configs().into_iter().enumerate() {
+        let listener_crypto = if i == 0 {
+            crypto_provider.clone()
+        } else {
+            Arc::new(CryptoProvider::from_config(&tls_config)?)
+        };
+        let connect_timeout = std::time::Duration::from_millis(proxy_config.connect_timeout_ms);
+        let tls_manager = Arc::new(
+            TlsManager::new(Arc::new(tls_config), listener_crypto, metrics.clone())?
+                .with_connect_timeout(connect_timeout),
+        );
+        listeners.push((Arc::new(proxy_config), tls_manager));
+    }
+    log::info!("TLS managers initialized for {} listener(s)", listeners.len());
 
     // Create and start proxy server
-    let proxy_server = ProxyServer::new(
-        Arc::new(config.proxy.clone()),
-        tls_manager,
-        crypto_provider,
-        metrics,
-    );
+    let proxy_server = ProxyServer::with_listeners(listeners, metrics);
+    let _ = upstreams.set(proxy_server.upstream_sets());
+    let _ = tracers.set(proxy_server.connection_tracers());
     log::info!("Proxy server created");
 
     // Start the server
-    proxy_server.start().await?;
-
-    Ok(())
+    let result = proxy_server.start(inherited.listeners).await;
+    drop(log_guard);
+    result
 } 
\ No newline at end of file
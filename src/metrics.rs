@@ -1,28 +1,222 @@
-use crate::config::MetricsConfig;
-use crate::error::Result;
-use metrics_exporter_prometheus::PrometheusBuilder;
+use crate::balancer::UpstreamSet;
+use crate::config::{
+    Config, KemAlgorithm, MetricsAuth, MetricsConfig, MetricsExporter, SignatureAlgorithm,
+};
+use crate::crypto::CryptoProvider;
+use crate::error::{Result, SafeQuantaError};
+use crate::trace::ConnectionTracer;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_exporter_statsd::StatsdBuilder;
+use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
 
-pub fn init(config: &MetricsConfig) -> Result<()> {
-    if !config.enabled {
-        return Ok(());
+/// Endpoints served next to `/metrics` when configured
+#[derive(Clone, Default)]
+pub struct AdminEndpoints {
+    /// Redacted JSON of the configuration loaded at startup on `/config`,
+    /// only served when `auth` is set
+    pub config: Option<Arc<Config>>,
+    /// Signed PQC public-key bundle (see `SignedKeyBundle`) on `/pqc-keys`
+    pub pqc_keys: Option<Arc<CryptoProvider>>,
+    /// Every listener's upstreams, drained with `POST /upstreams/<address>/drain`
+    /// and brought back with `POST /upstreams/<address>/undrain`
+    ///
+    /// Set once the listeners are built, after metrics are installed. Only
+    /// served when `auth` is set.
+    pub upstreams: Arc<OnceLock<Vec<Arc<UpstreamSet>>>>,
+    /// Every listener's connection trace, started with `POST /trace/enable`
+    /// and stopped with `POST /trace/disable`
+    ///
+    /// Set with `upstreams`, and likewise only served when `auth` is set.
+    pub tracers: Arc<OnceLock<Vec<Arc<ConnectionTracer>>>>,
+}
+
+impl AdminEndpoints {
+    fn is_empty(&self) -> bool {
+        self.config.is_none() && self.pqc_keys.is_none()
+    }
+}
+
+/// Handle for recording proxy metrics
+///
+/// Recording goes through the `metrics` facade, so the instance methods behave
+/// the same whichever exporter was installed.
+pub struct Metrics {
+    exporter: MetricsExporter,
+}
+
+impl Metrics {
+    /// Create a handle without installing any exporter
+    pub fn new() -> Self {
+        Self {
+            exporter: MetricsExporter::Noop,
+        }
+    }
+
+    /// Install the exporter selected in `config` and return a handle
+    ///
+    /// With `auth` set, the Prometheus endpoint is served by a task on the
+    /// current Tokio runtime that checks credentials before rendering.
+    pub fn install(config: &MetricsConfig) -> Result<Self> {
+        Self::install_with_endpoints(config, AdminEndpoints::default())
+    }
+
+    /// Like `install`, also serving `endpoints` next to the Prometheus
+    /// `/metrics` endpoint, behind the same credentials
+    pub fn install_with_endpoints(config: &MetricsConfig, endpoints: AdminEndpoints) -> Result<Self> {
+        Self::install_on(config, endpoints, None)
+    }
+
+    /// Like `install_with_endpoints`, serving Prometheus on `inherited`, e.g.
+    /// a socket-activated listener, instead of binding `host:port`
+    pub fn install_on(
+        config: &MetricsConfig,
+        endpoints: AdminEndpoints,
+        inherited: Option<std::net::TcpListener>,
+    ) -> Result<Self> {
+        let exporter = if config.enabled {
+            config.exporter
+        } else {
+            MetricsExporter::Noop
+        };
+
+        match exporter {
+            MetricsExporter::Prometheus => {
+                let addr = format!("{}:{}", config.host, config.port)
+                    .parse::<SocketAddr>()
+                    .map_err(|e| SafeQuantaError::Metrics(e.to_string()))?;
+
+                match (&config.auth, endpoints, inherited) {
+                    (None, endpoints, None) if endpoints.is_empty() => PrometheusBuilder::new()
+                        .with_http_listener(addr)
+                        .install()
+                        .map_err(|e| SafeQuantaError::Metrics(e.to_string()))?,
+                    (auth, endpoints, inherited) => {
+                        let listener = match inherited {
+                            Some(listener) => listener,
+                            None => std::net::TcpListener::bind(addr)?,
+                        };
+                        listener.set_nonblocking(true)?;
+                        let listener = TcpListener::from_std(listener)?;
+                        let handle = PrometheusBuilder::new()
+                            .install_recorder()
+                            .map_err(|e| SafeQuantaError::Metrics(e.to_string()))?;
+                        tokio::spawn(run_upkeep(handle.clone()));
+                        tokio::spawn(serve_scrapes(listener, handle, auth.clone(), endpoints));
+                    }
+                }
+            }
+            MetricsExporter::Statsd => {
+                let recorder = StatsdBuilder::from(config.host.as_str(), config.port)
+                    .build(config.prefix.as_deref())
+                    .map_err(|e| SafeQuantaError::Metrics(e.to_string()))?;
+
+                metrics::set_global_recorder(recorder)
+                    .map_err(|e| SafeQuantaError::Metrics(e.to_string()))?;
+            }
+            MetricsExporter::Noop => {}
+        }
+
+        record_build_info();
+        Ok(Self { exporter })
+    }
+
+    /// The exporter backing this handle
+    pub fn exporter(&self) -> MetricsExporter {
+        self.exporter
+    }
+
+    /// Record how long a handshake took, by whether it `succeeded`
+    pub fn record_tls_handshake_time(&self, duration: Duration, succeeded: bool) {
+        let outcome = if succeeded { "success" } else { "failure" };
+        record_handshake_duration(duration.as_millis() as u64, outcome);
+    }
+
+    /// Count a completed or refused handshake by its PQC `path`: `pqc`,
+    /// `classic`, `reject` or `redirect`. `side` is `client` for handshakes
+    /// clients make with the proxy and `upstream` for the proxy's own.
+    pub fn increment_tls_connections(&self, side: &'static str, path: &str) {
+        metrics::counter!("tls_connections_total", "path" => path.to_string(), "side" => side)
+            .increment(1);
+    }
+
+    pub fn record_bytes_transferred(&self, bytes: usize) {
+        metrics::counter!("proxy_bytes_transferred_total").increment(bytes as u64);
     }
 
-    let addr = format!("{}:{}", config.host, config.port)
-        .parse::<SocketAddr>()
-        .map_err(|e| crate::error::SafeQuantaError::Metrics(e.to_string()))?;
+    pub fn record_permit_wait(&self, duration: Duration) {
+        metrics::histogram!("connection_permit_wait_ms").record(duration.as_secs_f64() * 1000.0);
+    }
 
-    PrometheusBuilder::new()
-        .with_http_listener(addr)
-        .install()
-        .map_err(|e| crate::error::SafeQuantaError::Metrics(e.to_string()))?;
+    pub fn record_grpc_request(&self, method: &str, code: &str, duration: Duration) {
+        metrics::counter!(
+            "grpc_requests_total",
+            "method" => method.to_string(),
+            "code" => code.to_string()
+        )
+        .increment(1);
+        metrics::histogram!("grpc_request_duration_ms", "method" => method.to_string())
+            .record(duration.as_secs_f64() * 1000.0);
+    }
+}
 
-    Ok(())
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `safequanta_build_info` gauge, always 1, labelled with the version, the
+/// Cargo features and the algorithms this binary was built with
+pub fn record_build_info() {
+    let algorithms: Vec<String> = KemAlgorithm::ALL
+        .iter()
+        .map(|kem| format!("{:?}", kem))
+        .chain(SignatureAlgorithm::ALL.iter().map(|signature| format!("{:?}", signature)))
+        .collect();
+    metrics::gauge!(
+        "safequanta_build_info",
+        "version" => env!("CARGO_PKG_VERSION"),
+        "features" => enabled_features().join(","),
+        "algorithms" => algorithms.join(",")
+    )
+    .set(1.0);
+}
+
+/// Cargo features enabled for this build
+fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "test-util") {
+        features.push("test-util");
+    }
+    features
 }
 
 // Handshake metrics
-pub fn record_handshake_duration(duration_ms: u64) {
-    metrics::histogram!("handshake_duration_ms", duration_ms as f64, "type" => "handshake");
+/// Handshake latency, split by `outcome` (`success` or `failure`) since fast
+/// rejections and slow timeouts would otherwise skew the successful series
+pub fn record_handshake_duration(duration_ms: u64, outcome: &'static str) {
+    metrics::histogram!("handshake_duration_ms", "type" => "handshake", "outcome" => outcome)
+        .record(duration_ms as f64);
+}
+
+/// Bytes of one part of a TLS handshake (a key share or the peer's
+/// certificate chain), labeled by the algorithm behind it, to weigh PQC
+/// payloads against classical ones
+pub fn record_handshake_payload_bytes(field: &'static str, algorithm: String, bytes: usize) {
+    metrics::histogram!("handshake_payload_bytes", "field" => field, "algorithm" => algorithm)
+        .record(bytes as f64);
 }
 
 pub fn record_handshake_error() {
@@ -34,6 +228,67 @@ pub fn record_tls_alert(alert_type: &str) {
     metrics::counter!("tls_alerts_total", "type" => alert_type.to_string()).increment(1);
 }
 
+// TLS session resumption metrics
+pub fn record_session_resumption_hit() {
+    metrics::counter!("tls_session_resumption_total", "result" => "hit").increment(1);
+}
+
+pub fn record_session_resumption_miss() {
+    metrics::counter!("tls_session_resumption_total", "result" => "miss").increment(1);
+}
+
+/// Count a ClientHello fingerprint, bucketed to keep label cardinality low
+pub fn record_client_fingerprint(bucket: &str) {
+    metrics::counter!("tls_client_fingerprints_total", "bucket" => bucket.to_string()).increment(1);
+}
+
+pub fn record_disabled_algorithm_rejection(algorithm: &str) {
+    metrics::counter!("disabled_algorithm_rejections_total", "algorithm" => algorithm.to_string()).increment(1);
+}
+
+pub fn record_handshake_negotiation_failure(reason: &str) {
+    metrics::counter!("handshake_negotiation_failures_total", "reason" => reason.to_string()).increment(1);
+}
+
+pub fn record_client_cert_rejection(reason: &str) {
+    metrics::counter!("client_cert_rejections_total", "reason" => reason.to_string()).increment(1);
+}
+
+/// Handshakes waiting for a `max_concurrent_handshakes` slot
+pub fn record_handshake_queue_depth(depth: u64) {
+    metrics::gauge!("tls_handshake_queue_depth").set(depth as f64);
+}
+
+/// Time a connection waited for a `max_concurrent_handshakes` slot, kept
+/// apart from `handshake_duration_ms` so queueing shows up separately from
+/// the cost of the handshake itself
+pub fn record_handshake_queue_wait(duration: Duration) {
+    metrics::histogram!("handshake_queue_wait_ms").record(duration.as_secs_f64() * 1000.0);
+}
+
+/// Count a connection or L7 request refused because no route matched it
+pub fn record_unmatched_route_rejection() {
+    metrics::counter!("unmatched_route_rejections_total").increment(1);
+}
+
+/// Connections waiting for a `max_connections` slot
+pub fn record_queue_depth(depth: u64) {
+    metrics::gauge!("queue_depth").set(depth as f64);
+}
+
+/// Count a connection rejected after waiting `queue_timeout_ms` for a slot
+pub fn record_queue_timeout() {
+    metrics::counter!("queue_timeouts_total").increment(1);
+}
+
+pub fn record_client_auth_failure() {
+    metrics::counter!("client_auth_failures_total").increment(1);
+}
+
+pub fn record_post_handshake_violation(reason: &'static str) {
+    metrics::counter!("tls_post_handshake_violations_total", "reason" => reason).increment(1);
+}
+
 // CPU metrics
 pub fn record_cpu_cycles(cycles: u64) {
     metrics::gauge!("cpu_cycles_total", cycles as f64, "type" => "cpu");
@@ -48,6 +303,293 @@ pub fn record_connection_error() {
     metrics::counter!("connection_errors_total").increment(1);
 }
 
+pub fn record_connection_truncated() {
+    metrics::counter!("connections_truncated_total").increment(1);
+}
+
+pub fn record_connection_quota_exceeded() {
+    metrics::counter!("connections_quota_exceeded_total").increment(1);
+}
+
+pub fn record_early_data_accepted() {
+    metrics::counter!("early_data_accepted_total").increment(1);
+}
+
+pub fn record_early_data_rejected() {
+    metrics::counter!("early_data_rejected_total").increment(1);
+}
+
+/// Count an L7 request resent after its upstream failed before responding
+pub fn record_upstream_reset_retry() {
+    metrics::counter!("upstream_reset_retries_total").increment(1);
+}
+
+pub fn record_accept_rate_limited() {
+    metrics::counter!("accepts_rate_limited_total").increment(1);
+}
+
+pub fn record_connection_closed(reason: &'static str) {
+    metrics::counter!("connections_closed_total", "reason" => reason).increment(1);
+}
+
+pub fn record_connection_buffer_limit_exceeded() {
+    metrics::counter!("connections_buffer_limit_exceeded_total").increment(1);
+}
+
+/// Count a client connection reaching `rekey_after_bytes`, by the `action`
+/// taken: `key_update` or `close`
+pub fn record_key_rotation(action: &str) {
+    metrics::counter!("tls_key_rotations_total", "action" => action.to_string()).increment(1);
+}
+
+pub fn record_connection_aged_out() {
+    metrics::counter!("connections_aged_out_total").increment(1);
+}
+
+pub fn record_upstream_chain_too_deep() {
+    metrics::counter!("upstream_cert_chain_too_deep_total").increment(1);
+}
+
+/// Count a client closed before any handshake because its first bytes were
+/// not TLS, e.g. a port scanner or plaintext HTTP
+pub fn record_non_tls_connection() {
+    metrics::counter!("non_tls_connections_total").increment(1);
+}
+
+/// Count a handshake refused by the algorithm policy, by `kind` (`kem`,
+/// `signature`, or `config` for a rule whose server config failed to build)
+pub fn record_policy_rejection(kind: &'static str) {
+    metrics::counter!("tls_policy_rejections_total", "kind" => kind).increment(1);
+}
+
+pub fn record_oversized_handshake() {
+    metrics::counter!("tls_handshake_too_large_total").increment(1);
+}
+
+/// 1 while `upstream` is draining, 0 while it takes new connections
+pub fn record_upstream_draining(upstream: &str, draining: bool) {
+    metrics::gauge!("upstream_draining", "upstream" => upstream.to_string())
+        .set(if draining { 1.0 } else { 0.0 });
+}
+
+/// 1 while `upstream` is ejected by its failing health check
+pub fn record_upstream_ejected(upstream: &str, ejected: bool) {
+    metrics::gauge!("upstream_ejected", "upstream" => upstream.to_string())
+        .set(if ejected { 1.0 } else { 0.0 });
+}
+
+/// Count a connection that found `upstream` at its connection limit
+pub fn record_upstream_saturated(upstream: &str) {
+    metrics::counter!("upstream_saturated_total", "upstream" => upstream.to_string()).increment(1);
+}
+
+pub fn record_health_check(upstream: &str, passed: bool) {
+    let result = if passed { "pass" } else { "fail" };
+    let upstream = upstream.to_string();
+    metrics::counter!("upstream_health_checks_total", "upstream" => upstream, "result" => result)
+        .increment(1);
+}
+
+pub fn record_http2_stream_opened() {
+    metrics::gauge!("http2_active_streams").increment(1.0);
+}
+
+pub fn record_http2_stream_closed() {
+    metrics::gauge!("http2_active_streams").decrement(1.0);
+}
+
+/// Pause before accepting again after a failed accept
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often histogram samples are folded into the recorder between scrapes
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Periodically fold recorded histogram samples into their distributions
+///
+/// Nothing else drains them between scrapes, so samples would pile up
+/// unbounded while nobody scrapes.
+async fn run_upkeep(handle: PrometheusHandle) {
+    let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // Rendering is the only way this exporter version drains samples
+        let _ = handle.render();
+    }
+}
+
+/// Serve `/metrics` from `handle` to clients presenting `auth` credentials
+async fn serve_scrapes(
+    listener: TcpListener,
+    handle: PrometheusHandle,
+    auth: Option<MetricsAuth>,
+    endpoints: AdminEndpoints,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // Errors such as EMFILE persist until connections close, so
+                // retrying at once would spin
+                log::warn!("Failed to accept metrics connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        let auth = auth.clone();
+        let endpoints = endpoints.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let response = scrape_response(&req, &handle, auth.as_ref(), &endpoints);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+fn scrape_response(
+    req: &Request<Incoming>,
+    handle: &PrometheusHandle,
+    auth: Option<&MetricsAuth>,
+    endpoints: &AdminEndpoints,
+) -> Response<Full<Bytes>> {
+    let allowed = auth.map_or(true, |auth| authorized(req.headers(), auth));
+    let (status, content_type, body) = if !allowed {
+        (StatusCode::UNAUTHORIZED, None, String::new())
+    } else {
+        match (req.uri().path(), &endpoints.config, &endpoints.pqc_keys) {
+            ("/metrics", _, _) => (StatusCode::OK, None, handle.render()),
+            // Even redacted, the configuration maps out the deployment
+            ("/config", Some(config), _) if auth.is_some() => json_body("config", config.redacted()),
+            ("/pqc-keys", _, Some(crypto)) => json_body("PQC key bundle", pqc_keys_json(crypto)),
+            (path, _, _) if auth.is_some() && path.starts_with("/upstreams/") => {
+                drain_response(req.method(), path, endpoints)
+            }
+            (path, _, _) if auth.is_some() && path.starts_with("/trace/") => {
+                trace_response(req.method(), path, endpoints)
+            }
+            _ => (StatusCode::NOT_FOUND, None, String::new()),
+        }
+    };
+
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    if let Some(content_type) = content_type {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    if let Some(auth) = auth.filter(|_| status == StatusCode::UNAUTHORIZED) {
+        let challenge = match auth {
+            MetricsAuth::Bearer { .. } => "Bearer realm=\"metrics\"",
+            MetricsAuth::Basic { .. } => "Basic realm=\"metrics\"",
+        };
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+    }
+    response
+}
+
+/// Drain or undrain the upstream named in `path` on every listener using it
+fn drain_response(
+    method: &Method,
+    path: &str,
+    endpoints: &AdminEndpoints,
+) -> (StatusCode, Option<&'static str>, String) {
+    let not_found = (StatusCode::NOT_FOUND, None, String::new());
+    let Some((upstream, action)) =
+        path.strip_prefix("/upstreams/").and_then(|rest| rest.rsplit_once('/'))
+    else {
+        return not_found;
+    };
+    let draining = match action {
+        "drain" => true,
+        "undrain" => false,
+        _ => return not_found,
+    };
+    if method != Method::POST {
+        return (StatusCode::METHOD_NOT_ALLOWED, None, String::new());
+    }
+    let sets = endpoints.upstreams.get().map_or(&[][..], Vec::as_slice);
+    let mut found = false;
+    for set in sets {
+        found |= if draining {
+            set.drain_upstream(upstream)
+        } else {
+            set.undrain_upstream(upstream)
+        };
+    }
+    if found {
+        (StatusCode::NO_CONTENT, None, String::new())
+    } else {
+        not_found
+    }
+}
+
+/// Enable or disable the connection trace on every listener that has one
+fn trace_response(
+    method: &Method,
+    path: &str,
+    endpoints: &AdminEndpoints,
+) -> (StatusCode, Option<&'static str>, String) {
+    let not_found = (StatusCode::NOT_FOUND, None, String::new());
+    let enabled = match path {
+        "/trace/enable" => true,
+        "/trace/disable" => false,
+        _ => return not_found,
+    };
+    if method != Method::POST {
+        return (StatusCode::METHOD_NOT_ALLOWED, None, String::new());
+    }
+    let tracers = endpoints.tracers.get().map_or(&[][..], Vec::as_slice);
+    if tracers.is_empty() {
+        return not_found;
+    }
+    for tracer in tracers {
+        tracer.set_enabled(enabled);
+    }
+    (StatusCode::NO_CONTENT, None, String::new())
+}
+
+/// The current PQC public keys, signed by the certificate key
+fn pqc_keys_json(crypto: &CryptoProvider) -> anyhow::Result<serde_json::Value> {
+    Ok(serde_json::to_value(crypto.signed_bundle()?)?)
+}
+
+/// A JSON response body, or a 500 if `what` could not be rendered
+fn json_body(
+    what: &str,
+    json: anyhow::Result<serde_json::Value>,
+) -> (StatusCode, Option<&'static str>, String) {
+    match json {
+        Ok(json) => (StatusCode::OK, Some("application/json"), json.to_string()),
+        Err(e) => {
+            log::warn!("Failed to render {}: {}", what, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, None, String::new())
+        }
+    }
+}
+
+/// Whether the `Authorization` header carries the configured credentials
+fn authorized(headers: &HeaderMap, auth: &MetricsAuth) -> bool {
+    let expected = match auth {
+        MetricsAuth::Bearer { token } => format!("Bearer {}", token),
+        MetricsAuth::Basic { username, password } => {
+            format!("Basic {}", BASE64.encode(format!("{}:{}", username, password)))
+        }
+    };
+    let Some(presented) = headers.get(header::AUTHORIZATION) else {
+        return false;
+    };
+    let presented = presented.as_bytes();
+    crate::crypto::constant_time_eq(presented, expected.as_bytes())
+}
+
 // Proxy metrics
 pub fn record_proxy_request_duration(duration_ms: u64) {
     metrics::histogram!("proxy_request_duration_ms", duration_ms as f64, "type" => "request");
@@ -59,4 +601,376 @@ pub fn record_proxy_bytes_sent(bytes: u64) {
 
 pub fn record_proxy_bytes_received(bytes: u64) {
     metrics::counter!("proxy_bytes_received_total").increment(bytes);
-} 
\ No newline at end of file
+}
+
+/// Helpers for tests asserting on recorded metrics
+#[cfg(test)]
+pub(crate) mod testing {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::future::Future;
+
+    /// A recorded metric: its name, `key=value` labels and value
+    pub(crate) type Recorded = (String, Vec<String>, DebugValue);
+
+    /// Run `f` on a current-thread runtime and return the metrics it recorded
+    ///
+    /// The recorder is local to the test thread, so tests running in
+    /// parallel do not see each other's metrics.
+    pub(crate) fn record<F: Future>(f: F) -> Vec<Recorded> {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(f);
+        });
+
+        snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels = key.labels().map(|l| format!("{}={}", l.key(), l.value())).collect();
+                (key.name().to_string(), labels, value)
+            })
+            .collect()
+    }
+
+    /// Labels and values of the metrics called `name`
+    pub(crate) fn named<'a>(
+        recorded: &'a [Recorded],
+        name: &'a str,
+    ) -> impl Iterator<Item = (&'a Vec<String>, &'a DebugValue)> {
+        recorded
+            .iter()
+            .filter(move |(recorded_name, _, _)| recorded_name == name)
+            .map(|(_, labels, value)| (labels, value))
+    }
+
+    /// Counter `name` by labels, sorted
+    pub(crate) fn counters(recorded: &[Recorded], name: &str) -> Vec<(Vec<String>, u64)> {
+        let mut counters: Vec<_> = named(recorded, name)
+            .filter_map(|(labels, value)| match value {
+                DebugValue::Counter(count) => Some((labels.clone(), *count)),
+                _ => None,
+            })
+            .collect();
+        counters.sort();
+        counters
+    }
+
+    /// Samples of histogram `name` by labels, sorted by labels
+    pub(crate) fn histograms(recorded: &[Recorded], name: &str) -> Vec<(Vec<String>, Vec<f64>)> {
+        let mut histograms: Vec<(Vec<String>, Vec<f64>)> = named(recorded, name)
+            .filter_map(|(labels, value)| match value {
+                DebugValue::Histogram(samples) => {
+                    Some((labels.clone(), samples.iter().map(|v| v.into_inner()).collect()))
+                }
+                _ => None,
+            })
+            .collect();
+        histograms.sort_by(|a, b| a.0.cmp(&b.0));
+        histograms
+    }
+
+    /// Samples recorded to histogram `name` while running `f`, across labels
+    pub(crate) fn histogram_samples<F: Future>(name: &str, f: F) -> Vec<f64> {
+        histograms(&record(f), name).into_iter().flat_map(|(_, samples)| samples).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statsd_exporter_does_not_bind_http_listener() {
+        // Occupy the port so a Prometheus HTTP listener could not bind it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let config = MetricsConfig {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port,
+            exporter: MetricsExporter::Statsd,
+            prefix: Some("safequanta".to_string()),
+            auth: None,
+            serve_pqc_keys: false,
+        };
+
+        let metrics = Metrics::install(&config).unwrap();
+        assert_eq!(metrics.exporter(), MetricsExporter::Statsd);
+        metrics.increment_tls_connections("client", "pqc");
+    }
+
+    #[test]
+    fn test_build_info_rendered_with_labels() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, record_build_info);
+
+        let rendered = handle.render();
+        let line = rendered
+            .lines()
+            .find(|line| line.starts_with("safequanta_build_info{"))
+            .unwrap();
+        assert!(line.ends_with(" 1"));
+        assert!(line.contains(&format!("version=\"{}\"", env!("CARGO_PKG_VERSION"))));
+        assert!(line.contains(&format!("features=\"{}\"", enabled_features().join(","))));
+        assert!(line.contains("algorithms=\"Kyber768,Kyber1024,Dilithium3,Rsa3072\""));
+    }
+
+    #[test]
+    fn test_disabled_metrics_is_noop() {
+        let config = MetricsConfig {
+            enabled: false,
+            host: "not an address".to_string(),
+            ..Default::default()
+        };
+
+        let metrics = Metrics::install(&config).unwrap();
+        assert_eq!(metrics.exporter(), MetricsExporter::Noop);
+    }
+
+    async fn scrape(addr: SocketAddr, path: &str, authorization: Option<&str>) -> Response<Bytes> {
+        admin_request(addr, Method::GET, path, authorization).await
+    }
+
+    async fn admin_request(
+        addr: SocketAddr,
+        method: Method,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> Response<Bytes> {
+        use http_body_util::{BodyExt, Empty};
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, "metrics.test");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let response = sender
+            .send_request(request.body(Empty::<Bytes>::new()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_requires_auth() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("scrape_test_total").increment(1);
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth = MetricsAuth::Bearer {
+            token: "s3cret".to_string(),
+        };
+        tokio::spawn(serve_scrapes(listener, handle, Some(auth), AdminEndpoints::default()));
+
+        let response = scrape(addr, "/metrics", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"metrics\"");
+        assert!(response.body().is_empty());
+
+        let response = scrape(addr, "/metrics", Some("Bearer wrong")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = scrape(addr, "/metrics", Some("Bearer s3cret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("scrape_test_total 1"));
+    }
+
+    #[tokio::test]
+    async fn test_config_endpoint_serves_redacted_config_behind_auth() {
+        let mut config = crate::config::Config::default();
+        config.metrics.auth = Some(MetricsAuth::Bearer {
+            token: "s3cret".to_string(),
+        });
+        config.proxy.timeout = 7;
+        let config = Arc::new(config);
+        let serve = |auth: Option<MetricsAuth>| {
+            let handle = PrometheusBuilder::new().build_recorder().handle();
+            let endpoints = AdminEndpoints {
+                config: Some(config.clone()),
+                ..Default::default()
+            };
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let listener = TcpListener::from_std(listener).unwrap();
+            tokio::spawn(serve_scrapes(listener, handle, auth, endpoints));
+            addr
+        };
+
+        let addr = serve(config.metrics.auth.clone());
+        let response = scrape(addr, "/config", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = scrape(addr, "/config", Some("Bearer s3cret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["metrics"]["auth"]["Bearer"]["token"], "<redacted>");
+        assert_eq!(body["proxy"]["timeout"], 7);
+
+        // Never served to unauthenticated clients
+        let addr = serve(None);
+        assert_eq!(scrape(addr, "/config", None).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_drained_through_admin_endpoint() {
+        let set = Arc::new(UpstreamSet::new(["a:80", "b:80"].map(String::from)));
+        let endpoints = AdminEndpoints::default();
+        assert!(endpoints.upstreams.set(vec![set.clone()]).is_ok());
+        let auth = MetricsAuth::Bearer {
+            token: "s3cret".to_string(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        tokio::spawn(serve_scrapes(listener, handle, Some(auth), endpoints));
+        let post =
+            |path: &'static str| admin_request(addr, Method::POST, path, Some("Bearer s3cret"));
+
+        let unauthorized = admin_request(addr, Method::POST, "/upstreams/a:80/drain", None).await;
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        assert!(!set.is_draining("a:80"));
+
+        assert_eq!(post("/upstreams/a:80/drain").await.status(), StatusCode::NO_CONTENT);
+        assert!(!set.is_available("a:80"));
+        assert!(set.is_available("b:80"));
+
+        assert_eq!(post("/upstreams/a:80/undrain").await.status(), StatusCode::NO_CONTENT);
+        assert!(set.is_available("a:80"));
+
+        assert_eq!(post("/upstreams/c:80/drain").await.status(), StatusCode::NOT_FOUND);
+        let get = scrape(addr, "/upstreams/a:80/drain", Some("Bearer s3cret")).await;
+        assert_eq!(get.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_connection_trace_toggled_through_admin_endpoint() {
+        use crate::config::ConnectionTraceConfig;
+        use crate::proxy::{CloseReason, ConnectionStats};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        let tracer = Arc::new(ConnectionTracer::new(ConnectionTraceConfig {
+            enabled: false,
+            path: path.clone(),
+            source_ips: vec!["127.0.0.1".parse().unwrap()],
+            ..Default::default()
+        }));
+        let endpoints = AdminEndpoints::default();
+        assert!(endpoints.tracers.set(vec![tracer.clone()]).is_ok());
+        let auth = MetricsAuth::Bearer {
+            token: "s3cret".to_string(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        tokio::spawn(serve_scrapes(listener, handle, Some(auth), endpoints));
+        let post = |path: &'static str| admin_request(addr, Method::POST, path, Some("Bearer s3cret"));
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let trace_lines = || {
+            tracer.flush();
+            std::fs::read_to_string(&path).map_or(0, |contents| contents.lines().count())
+        };
+
+        tracer.record(&ConnectionStats::new(), peer, CloseReason::ClientEof, None);
+        assert_eq!(trace_lines(), 0);
+
+        let unauthorized = admin_request(addr, Method::POST, "/trace/enable", None).await;
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(post("/trace/enable").await.status(), StatusCode::NO_CONTENT);
+        tracer.record(&ConnectionStats::new(), peer, CloseReason::ClientEof, None);
+        assert_eq!(trace_lines(), 1);
+
+        assert_eq!(post("/trace/disable").await.status(), StatusCode::NO_CONTENT);
+        tracer.record(&ConnectionStats::new(), peer, CloseReason::ClientEof, None);
+        assert_eq!(trace_lines(), 1);
+
+        let get = scrape(addr, "/trace/enable", Some("Bearer s3cret")).await;
+        assert_eq!(get.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_pqc_keys_endpoint_serves_verifiable_bundle() {
+        use crate::config::{KemAlgorithm, SignatureAlgorithm};
+        use crate::crypto::SignedKeyBundle;
+
+        let handle = PrometheusBuilder::new().build_recorder().handle();
+        let crypto = Arc::new(
+            CryptoProvider::new(
+                KemAlgorithm::Kyber768,
+                SignatureAlgorithm::Dilithium3,
+                "tests/fixtures/test.crt",
+                "tests/fixtures/test.key",
+            )
+            .unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let endpoints = AdminEndpoints {
+            pqc_keys: Some(crypto.clone()),
+            ..Default::default()
+        };
+        tokio::spawn(serve_scrapes(listener, handle, None, endpoints));
+
+        let response = scrape(addr, "/pqc-keys", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let bundle: SignedKeyBundle = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(bundle.sign_public_key, crypto.sign_public_key());
+
+        let certificate =
+            openssl::x509::X509::from_pem(&std::fs::read("tests/fixtures/test.crt").unwrap()).unwrap();
+        assert!(bundle.verify(&certificate).unwrap());
+        let mut tampered = bundle;
+        tampered.kem_public_key[0] ^= 1;
+        assert!(!tampered.verify(&certificate).unwrap());
+
+        // Not served unless configured
+        assert_eq!(scrape(addr, "/config", None).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_basic_auth_credentials() {
+        let auth = MetricsAuth::Basic {
+            username: "Aladdin".to_string(),
+            password: "open sesame".to_string(),
+        };
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, &auth));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="),
+        );
+        assert!(authorized(&headers, &auth));
+        // Same length, last byte differs
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic QWxhZGRpbjpvcGVuIHNlc2FtZR=="),
+        );
+        assert!(!authorized(&headers, &auth));
+    }
+}
@@ -0,0 +1,270 @@
+//! Application-layer record padding between two SafeQuanta proxies
+//!
+//! rustls has no per-record padding, so with `record_padding` set the proxied
+//! bytes are framed inside TLS instead: an 8-byte header (big-endian `u32`
+//! payload length, then `u32` padding length), the payload, and zeros up to
+//! the next multiple of the block size. Both ends must agree, which they do by
+//! negotiating `PADDING_ALPN`.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// ALPN protocol offered and accepted when `record_padding` is set
+pub const PADDING_ALPN: &[u8] = b"safequanta-pad/1";
+
+const HEADER_LEN: usize = 8;
+
+/// Largest payload put in one frame
+const MAX_PAYLOAD: usize = 16 * 1024;
+
+/// Frame length for `payload` bytes padded to a multiple of `block`
+fn padded_len(payload: usize, block: usize) -> usize {
+    (HEADER_LEN + payload).div_ceil(block) * block
+}
+
+/// Stream that pads what it writes and strips the padding of what it reads
+///
+/// Without a block size it passes everything through unchanged, so callers
+/// can wrap a stream whether or not padding was negotiated.
+pub struct PaddedStream<S> {
+    inner: S,
+    block: Option<usize>,
+    /// Encoded frame being written, and how far
+    frame: Vec<u8>,
+    frame_written: usize,
+    /// Header of the frame being read
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    payload_left: usize,
+    padding_left: usize,
+}
+
+impl<S> PaddedStream<S> {
+    /// Pad to multiples of `block` bytes; `None` (or zero) disables padding
+    pub fn new(inner: S, block: Option<usize>) -> Self {
+        Self {
+            inner,
+            block: block.filter(|block| *block > 0),
+            frame: Vec::new(),
+            frame_written: 0,
+            header: [0; HEADER_LEN],
+            header_read: 0,
+            payload_left: 0,
+            padding_left: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// Whether a negotiated ALPN protocol turns padding on
+pub fn negotiated(alpn: Option<&[u8]>) -> bool {
+    alpn == Some(PADDING_ALPN)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PaddedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(block) = this.block else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated padded frame");
+
+        loop {
+            if this.payload_left > 0 {
+                let limit = this.payload_left.min(buf.remaining());
+                let mut payload = ReadBuf::new(buf.initialize_unfilled_to(limit));
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut payload))?;
+                let n = payload.filled().len();
+                if n == 0 && limit > 0 {
+                    return Poll::Ready(Err(truncated()));
+                }
+                buf.advance(n);
+                this.payload_left -= n;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.padding_left > 0 {
+                let mut scratch = [0u8; 512];
+                let limit = this.padding_left.min(scratch.len());
+                let mut padding = ReadBuf::new(&mut scratch[..limit]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut padding))?;
+                if padding.filled().is_empty() {
+                    return Poll::Ready(Err(truncated()));
+                }
+                this.padding_left -= padding.filled().len();
+                continue;
+            }
+
+            let mut header = ReadBuf::new(&mut this.header[this.header_read..]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut header))?;
+            let n = header.filled().len();
+            if n == 0 {
+                // EOF between frames is the end of the stream
+                return match this.header_read {
+                    0 => Poll::Ready(Ok(())),
+                    _ => Poll::Ready(Err(truncated())),
+                };
+            }
+            this.header_read += n;
+            if this.header_read == HEADER_LEN {
+                let (payload, padding) = this.header.split_at(4);
+                let payload = u32::from_be_bytes(payload.try_into().unwrap()) as usize;
+                let padding = u32::from_be_bytes(padding.try_into().unwrap()) as usize;
+                // The peer pads exactly as `poll_write` does, so any other
+                // lengths are a broken or hostile peer
+                if payload > MAX_PAYLOAD || padding != padded_len(payload, block) - HEADER_LEN - payload {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid padded frame header ({} + {} bytes)", payload, padding),
+                    )));
+                }
+                this.payload_left = payload;
+                this.padding_left = padding;
+                this.header_read = 0;
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> PaddedStream<S> {
+    /// Write out the rest of the pending frame
+    fn poll_write_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.frame_written < self.frame.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.frame[self.frame_written..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.frame_written += n;
+        }
+        self.frame.clear();
+        self.frame_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PaddedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(block) = this.block else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        // Only one frame is buffered at a time
+        ready!(this.poll_write_frame(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let payload = &buf[..buf.len().min(MAX_PAYLOAD)];
+        let len = padded_len(payload.len(), block);
+        let padding = len - HEADER_LEN - payload.len();
+        this.frame.reserve(len);
+        this.frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        this.frame.extend_from_slice(&(padding as u32).to_be_bytes());
+        this.frame.extend_from_slice(payload);
+        this.frame.resize(len, 0);
+
+        // Like a `BufWriter`, the bytes count as written once they are in the
+        // frame; whatever the inner stream can't take yet goes out on the next
+        // write, flush or shutdown
+        if let Poll::Ready(Err(e)) = this.poll_write_frame(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(payload.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_padded_transfer_round_trips() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let chunks = [&data[..1], &data[1..64], &data[64..70_000], &data[70_000..]];
+
+        let mut padded = PaddedStream::new(Vec::new(), Some(256));
+        for chunk in chunks {
+            padded.write_all(chunk).await.unwrap();
+        }
+        padded.shutdown().await.unwrap();
+        let wire = padded.inner;
+        // Only whole blocks go on the wire
+        assert_eq!(wire.len() % 256, 0);
+        assert!(wire.len() > data.len());
+
+        let mut unpadded = PaddedStream::new(wire.as_slice(), Some(256));
+        let mut received = Vec::new();
+        unpadded.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        // A frame cut short is an error, not a silently shorter stream
+        let mut truncated = PaddedStream::new(&wire[..wire.len() - 1], Some(256));
+        assert!(truncated.read_to_end(&mut Vec::new()).await.is_err());
+
+        // Lengths the writer would never produce are rejected before any
+        // payload or padding is read
+        for (payload, padding) in [(u32::MAX, 0u32), (1, 1 << 20), (1, 0)] {
+            let mut header = payload.to_be_bytes().to_vec();
+            header.extend_from_slice(&padding.to_be_bytes());
+            let mut hostile = PaddedStream::new(header.as_slice(), Some(256));
+            let err = hostile.read_to_end(&mut Vec::new()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_reports_bytes_once_framed() {
+        // The pipe holds less than one frame, yet the write completes, and the
+        // next one waits for the reader to drain the first frame
+        let (writer, mut reader) = tokio::io::duplex(16);
+        let mut padded = PaddedStream::new(writer, Some(64));
+        assert_eq!(padded.write(&[7u8; 40]).await.unwrap(), 40);
+        let next = tokio::time::timeout(std::time::Duration::from_millis(50), padded.write(&[8u8; 40]));
+        assert!(next.await.is_err());
+
+        let mut wire = [0u8; 64];
+        let (flushed, read) = tokio::join!(padded.flush(), reader.read_exact(&mut wire));
+        flushed.unwrap();
+        read.unwrap();
+        assert_eq!(&wire[..4], &40u32.to_be_bytes());
+        assert_eq!(&wire[HEADER_LEN..HEADER_LEN + 40], &[7u8; 40]);
+    }
+
+    #[test]
+    fn test_padded_len() {
+        assert_eq!(padded_len(1, 64), 64);
+        assert_eq!(padded_len(56, 64), 64);
+        assert_eq!(padded_len(57, 64), 128);
+        assert!(negotiated(Some(PADDING_ALPN)));
+        assert!(!negotiated(Some(b"h2")));
+    }
+}
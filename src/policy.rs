@@ -0,0 +1,275 @@
+//! Per-connection algorithm policy loaded from `tls.policy_path`
+
+use crate::config::{KemAlgorithm, SignatureAlgorithm};
+use crate::error::{Result, SafeQuantaError};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+
+/// How often the policy file is checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Algorithms a connection may negotiate, most preferred first
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct AlgorithmPolicy {
+    pub kem_algorithms: Vec<KemAlgorithm>,
+    pub signature_algorithms: Vec<SignatureAlgorithm>,
+}
+
+impl AlgorithmPolicy {
+    /// Just the globally configured algorithms
+    pub fn global(kem: KemAlgorithm, signature: SignatureAlgorithm) -> Self {
+        Self {
+            kem_algorithms: vec![kem],
+            signature_algorithms: vec![signature],
+        }
+    }
+}
+
+/// Address range such as `10.0.0.0/8`; a bare address is a single host
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (bits, network, addr) = match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                (32, u128::from(u32::from(network)), u128::from(u32::from(addr)))
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (128, u128::from(network), u128::from(addr)),
+            _ => return false,
+        };
+        let host_bits = bits - u32::from(self.prefix);
+        host_bits >= 128 || network >> host_bits == addr >> host_bits
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(range: String) -> std::result::Result<Self, String> {
+        let (network, prefix) = range.split_once('/').unwrap_or((&range, ""));
+        let network: IpAddr =
+            network.parse().map_err(|e| format!("invalid address in {:?}: {}", range, e))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", range))?,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+/// Algorithms for connections matching every condition given
+#[derive(Debug, Deserialize, Clone)]
+pub struct PolicyRule {
+    /// Client SNI values, exact or `*.example.com`; empty matches any
+    #[serde(default)]
+    pub server_names: Vec<String>,
+    /// Client address ranges; empty matches any
+    #[serde(default)]
+    pub sources: Vec<IpRange>,
+    #[serde(flatten)]
+    pub algorithms: AlgorithmPolicy,
+}
+
+impl PolicyRule {
+    fn matches(&self, server_name: Option<&str>, client_ip: Option<IpAddr>) -> bool {
+        let name_matches = self.server_names.is_empty()
+            || server_name.is_some_and(|name| {
+                self.server_names.iter().any(|pattern| server_name_matches(pattern, name))
+            });
+        let source_matches = self.sources.is_empty()
+            || client_ip.is_some_and(|ip| self.sources.iter().any(|range| range.contains(ip)));
+        name_matches && source_matches
+    }
+}
+
+fn server_name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name
+            .len()
+            .checked_sub(suffix.len() + 1)
+            .is_some_and(|dot| {
+                name.as_bytes()[dot] == b'.' && name[dot + 1..].eq_ignore_ascii_case(suffix)
+            }),
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Contents of a policy file: rules tried in order, the first match wins
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+impl Policy {
+    /// Parse a YAML policy file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        serde_yaml::from_str(&contents).map_err(|e| {
+            SafeQuantaError::InvalidConfig(format!("Invalid policy {}: {}", path.display(), e))
+        })
+    }
+
+    /// Index of the first rule matching a connection
+    pub fn matching_rule(&self, server_name: Option<&str>, client_ip: Option<IpAddr>) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.matches(server_name, client_ip))
+    }
+
+    /// Algorithms for a connection, `default` if no rule matches
+    pub fn evaluate<'a>(
+        &'a self,
+        server_name: Option<&str>,
+        client_ip: Option<IpAddr>,
+        default: &'a AlgorithmPolicy,
+    ) -> &'a AlgorithmPolicy {
+        self.matching_rule(server_name, client_ip)
+            .map_or(default, |rule| &self.rules[rule].algorithms)
+    }
+}
+
+/// A policy file, reloaded when it changes on disk
+///
+/// The file is checked from a background thread, never from the accept path,
+/// which stops once the store is dropped. A changed file that fails to parse
+/// is logged and the previous policy kept.
+pub struct PolicyStore {
+    path: PathBuf,
+    policy: RwLock<Arc<Policy>>,
+}
+
+impl PolicyStore {
+    /// Load `path` and watch it for changes
+    ///
+    /// `on_change` is called with the loaded policy before this returns, then
+    /// from the watcher thread with each reloaded one.
+    pub fn watch(
+        path: PathBuf,
+        on_change: impl Fn(&Arc<Policy>) + Send + 'static,
+    ) -> Result<Arc<Self>> {
+        let mut loaded_modified = modified(&path);
+        let policy = Arc::new(Policy::from_file(&path)?);
+        log::info!("Loaded {} algorithm policy rules from {}", policy.rules.len(), path.display());
+        on_change(&policy);
+        let store = Arc::new(Self {
+            path,
+            policy: RwLock::new(policy),
+        });
+
+        let watched = Arc::downgrade(&store);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(RELOAD_CHECK_INTERVAL);
+            let Some(store) = Weak::upgrade(&watched) else {
+                return;
+            };
+            let modified = modified(&store.path);
+            if modified == loaded_modified {
+                continue;
+            }
+            loaded_modified = modified;
+            match Policy::from_file(&store.path) {
+                Ok(policy) => {
+                    log::info!("Reloaded algorithm policy from {}", store.path.display());
+                    let policy = Arc::new(policy);
+                    *store.policy.write() = policy.clone();
+                    on_change(&policy);
+                }
+                Err(e) => log::warn!("Keeping previous algorithm policy: {}", e),
+            }
+        });
+        Ok(store)
+    }
+
+    /// The policy in effect
+    pub fn current(&self) -> Arc<Policy> {
+        self.policy.read().clone()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_names_resolve_to_different_policies() {
+        let policy: Policy = serde_yaml::from_str(
+            r#"
+rules:
+  - server_names: ["secure.example.com"]
+    kem_algorithms: [Kyber1024]
+    signature_algorithms: [Dilithium3]
+  - server_names: ["*.legacy.example.com"]
+    sources: ["10.0.0.0/8"]
+    kem_algorithms: [Kyber768]
+    signature_algorithms: [Rsa3072, Dilithium3]
+"#,
+        )
+        .unwrap();
+        let default = AlgorithmPolicy::global(KemAlgorithm::Kyber768, SignatureAlgorithm::Dilithium3);
+        let internal: IpAddr = "10.1.2.3".parse().unwrap();
+
+        let secure = policy.evaluate(Some("Secure.Example.com"), Some(internal), &default);
+        assert_eq!(secure.kem_algorithms, vec![KemAlgorithm::Kyber1024]);
+
+        let legacy = policy.evaluate(Some("app.legacy.example.com"), Some(internal), &default);
+        assert_eq!(legacy.signature_algorithms[0], SignatureAlgorithm::Rsa3072);
+
+        // Outside the rule's source range, or without SNI, the global policy applies
+        let external: IpAddr = "192.0.2.1".parse().unwrap();
+        let outside = policy.evaluate(Some("app.legacy.example.com"), Some(external), &default);
+        assert_eq!(outside, &default);
+        assert_eq!(policy.evaluate(None, Some(internal), &default), &default);
+    }
+
+    #[test]
+    fn test_policy_reloaded_in_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.yaml");
+        let rule = |kem: &str| {
+            format!("rules:\n  - kem_algorithms: [{}]\n    signature_algorithms: [Dilithium3]\n", kem)
+        };
+        std::fs::write(&path, rule("Kyber768")).unwrap();
+
+        let (changed, changes) = std::sync::mpsc::channel();
+        let store = PolicyStore::watch(path.clone(), move |policy| {
+            let _ = changed.send(policy.rules[0].algorithms.kem_algorithms.clone());
+        })
+        .unwrap();
+        assert_eq!(changes.recv().unwrap(), vec![KemAlgorithm::Kyber768]);
+
+        // Keep the modification time from landing in the same tick
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(&path, rule("Kyber1024")).unwrap();
+        let reloaded = changes.recv_timeout(RELOAD_CHECK_INTERVAL * 5).unwrap();
+        assert_eq!(reloaded, vec![KemAlgorithm::Kyber1024]);
+        assert_eq!(store.current().rules[0].algorithms.kem_algorithms, reloaded);
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let range = IpRange::try_from("10.0.0.0/8".to_string()).unwrap();
+        assert!(range.contains("10.255.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+        let any = IpRange::try_from("::/0".to_string()).unwrap();
+        assert!(any.contains("2001:db8::1".parse().unwrap()));
+        assert!(IpRange::try_from("10.0.0.0/33".to_string()).is_err());
+    }
+}
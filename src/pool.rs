@@ -0,0 +1,458 @@
+use crate::config::ProxyConfig;
+use crate::error::{Result, SafeQuantaError};
+use crate::l7::ProxyBody;
+use crate::proxy::UpstreamConnections;
+use http::{Request, Response};
+use hyper::body::Incoming;
+use hyper::client::conn::{http1, http2};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::timeout;
+
+/// Smallest read buffer hyper accepts for an HTTP/1.1 connection
+const MIN_HEADER_BUFFER: usize = 8192;
+
+/// Protocol spoken to an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpstreamProtocol {
+    Http1,
+    /// HTTP/2 with prior knowledge
+    Http2,
+}
+
+/// Request sender for a single upstream connection
+pub enum PooledSender {
+    Http1(http1::SendRequest<ProxyBody>),
+    Http2(http2::SendRequest<ProxyBody>),
+}
+
+impl PooledSender {
+    fn is_usable(&self) -> bool {
+        match self {
+            PooledSender::Http1(sender) => sender.is_ready(),
+            PooledSender::Http2(sender) => sender.is_ready(),
+        }
+    }
+
+    pub async fn send_request(&mut self, req: Request<ProxyBody>) -> Result<Response<Incoming>> {
+        self.send(req)
+            .await
+            .map_err(|e| SafeQuantaError::Proxy(format!("Upstream request failed: {}", e)))
+    }
+
+    /// As `send_request`, keeping hyper's error for callers that classify it
+    pub async fn send(&mut self, req: Request<ProxyBody>) -> hyper::Result<Response<Incoming>> {
+        match self {
+            PooledSender::Http1(sender) => sender.send_request(req).await,
+            PooledSender::Http2(sender) => sender.send_request(req).await,
+        }
+    }
+}
+
+struct IdleSender {
+    sender: PooledSender,
+    /// When the connection was pooled or, for shared HTTP/2, last handed out
+    last_used: Instant,
+}
+
+/// Upstream connection and how it is opened
+///
+/// Pooled connections are only reused under the same key, so a connection
+/// bound to one route's source address or marked with its DSCP never
+/// carries another route's requests.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    /// `host:port`
+    pub upstream: String,
+    pub protocol: UpstreamProtocol,
+    pub bind_addr: Option<SocketAddr>,
+    pub dscp: Option<u8>,
+}
+
+/// Keep-alive connection pool for L7 upstreams, keyed by `PoolKey`
+///
+/// HTTP/1.1 connections are checked out exclusively and returned once their
+/// response completes; HTTP/2 connections are shared between streams.
+pub struct UpstreamPool {
+    idle: Mutex<HashMap<PoolKey, Vec<IdleSender>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+    /// Cap on the response headers buffered from HTTP/1.1 upstreams
+    max_header_bytes: Option<usize>,
+    /// Counts each connection while it is open, idle or not
+    connections: UpstreamConnections,
+}
+
+impl UpstreamPool {
+    /// Create a pool keeping at most `max_idle` idle connections per key
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+            max_idle,
+            idle_timeout,
+            max_header_bytes: None,
+            connections: UpstreamConnections::default(),
+        }
+    }
+
+    /// Count the pool's connections in `connections`, e.g. the listener's
+    pub fn with_upstream_connections(mut self, connections: UpstreamConnections) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Where the pool's open connections are counted
+    pub fn upstream_connections(&self) -> &UpstreamConnections {
+        &self.connections
+    }
+
+    pub fn from_config(config: &ProxyConfig) -> Self {
+        let mut pool = Self::new(config.pool_max_idle, Duration::from_secs(config.pool_idle_timeout));
+        pool.max_header_bytes = config.warm_upstream.as_ref().map(|warm| warm.max_header_bytes);
+        pool
+    }
+
+    /// Reuse a validated idle connection for `key`, or open a new one
+    pub async fn checkout(&self, key: &PoolKey, connect_timeout: Duration) -> Result<PooledSender> {
+        if let Some(sender) = self.take_idle(key) {
+            log::trace!("Reusing pooled {:?} connection to {}", key.protocol, key.upstream);
+            return Ok(sender);
+        }
+
+        let sender = self.connect(key, connect_timeout).await?;
+        if let PooledSender::Http2(shared) = &sender {
+            self.insert(key.clone(), PooledSender::Http2(shared.clone()));
+        }
+        Ok(sender)
+    }
+
+    /// Return an HTTP/1.1 connection once its response has completed
+    pub fn checkin(self: &Arc<Self>, key: PoolKey, sender: PooledSender) {
+        let PooledSender::Http1(mut sender) = sender else {
+            // HTTP/2 connections stay in the pool while in use
+            return;
+        };
+
+        let pool = self.clone();
+        tokio::spawn(async move {
+            // The connection task may need a moment to finish the response
+            if timeout(pool.idle_timeout, sender.ready()).await.map_or(false, |r| r.is_ok()) {
+                pool.insert(key, PooledSender::Http1(sender));
+            }
+        });
+    }
+
+    /// Number of idle connections held for `key`
+    pub fn idle_count(&self, key: &PoolKey) -> usize {
+        self.idle.lock().get(key).map_or(0, Vec::len)
+    }
+
+    fn take_idle(&self, key: &PoolKey) -> Option<PooledSender> {
+        let mut idle = self.idle.lock();
+        let entries = idle.get_mut(key)?;
+        entries.retain(|entry| {
+            entry.last_used.elapsed() < self.idle_timeout && entry.sender.is_usable()
+        });
+
+        match key.protocol {
+            UpstreamProtocol::Http1 => entries.pop().map(|entry| entry.sender),
+            UpstreamProtocol::Http2 => {
+                let entry = entries.last_mut()?;
+                entry.last_used = Instant::now();
+                match &entry.sender {
+                    PooledSender::Http2(shared) => Some(PooledSender::Http2(shared.clone())),
+                    PooledSender::Http1(_) => None,
+                }
+            }
+        }
+    }
+
+    fn insert(&self, key: PoolKey, sender: PooledSender) {
+        let mut idle = self.idle.lock();
+        let entries = idle.entry(key).or_default();
+        if entries.len() < self.max_idle {
+            entries.push(IdleSender {
+                sender,
+                last_used: Instant::now(),
+            });
+        }
+    }
+
+    /// Open a new connection for `key`, bypassing the idle connections
+    pub async fn connect(&self, key: &PoolKey, connect_timeout: Duration) -> Result<PooledSender> {
+        let upstream = &key.upstream;
+        let stream = connect_upstream_within(upstream, key.bind_addr, connect_timeout).await?;
+        set_dscp(&stream, key.dscp);
+        let io = TokioIo::new(stream);
+        let open = self.connections.open(upstream);
+
+        match key.protocol {
+            UpstreamProtocol::Http1 => {
+                let mut builder = http1::Builder::new();
+                if let Some(max) = self.max_header_bytes {
+                    builder.max_buf_size(max.max(MIN_HEADER_BUFFER));
+                }
+                let (sender, connection) = builder.handshake(io).await.map_err(|e| {
+                    SafeQuantaError::Proxy(format!("HTTP/1.1 handshake with {} failed: {}", upstream, e))
+                })?;
+                tokio::spawn(async move {
+                    let _open = open;
+                    if let Err(e) = connection.await {
+                        log::debug!("Upstream HTTP/1.1 connection closed: {}", e);
+                    }
+                });
+                Ok(PooledSender::Http1(sender))
+            }
+            UpstreamProtocol::Http2 => {
+                let (sender, connection) = http2::handshake(TokioExecutor::new(), io).await.map_err(|e| {
+                    SafeQuantaError::Proxy(format!("HTTP/2 handshake with {} failed: {}", upstream, e))
+                })?;
+                tokio::spawn(async move {
+                    let _open = open;
+                    if let Err(e) = connection.await {
+                        log::debug!("Upstream HTTP/2 connection closed: {}", e);
+                    }
+                });
+                Ok(PooledSender::Http2(sender))
+            }
+        }
+    }
+}
+
+/// Mark the packets of `stream` with the `dscp` code point, if set
+///
+/// Sets the IPv4 TOS byte or the IPv6 traffic class, by the stream's
+/// family. A failure only costs the marking, so it is logged rather than
+/// returned.
+pub fn set_dscp(stream: &TcpStream, dscp: Option<u8>) {
+    let Some(dscp) = dscp else {
+        return;
+    };
+    let socket = SockRef::from(stream);
+    let class = u32::from(dscp) << 2;
+    let marked = match stream.local_addr() {
+        Ok(SocketAddr::V6(_)) => socket.set_tclass_v6(class),
+        _ => socket.set_tos(class),
+    };
+    if let Err(e) = marked {
+        log::warn!("Cannot set DSCP {} on connection: {}", dscp, e);
+    }
+}
+
+/// `connect_upstream`, failing with a `Timeout` error after `connect_timeout`
+pub async fn connect_upstream_within(
+    upstream: &str,
+    bind_addr: Option<SocketAddr>,
+    connect_timeout: Duration,
+) -> Result<TcpStream> {
+    timeout(connect_timeout, connect_upstream(upstream, bind_addr))
+        .await
+        .map_err(|_| {
+            SafeQuantaError::Timeout(format!("Connect to {} timed out after {:?}", upstream, connect_timeout))
+        })?
+}
+
+/// Connect to `upstream` (`host:port`), binding the local end to `bind_addr` if set
+pub async fn connect_upstream(upstream: &str, bind_addr: Option<SocketAddr>) -> Result<TcpStream> {
+    let Some(bind_addr) = bind_addr else {
+        return Ok(TcpStream::connect(upstream).await?);
+    };
+
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(upstream).await? {
+        if addr.is_ipv4() != bind_addr.is_ipv4() {
+            continue;
+        }
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&bind_addr.into()).map_err(|e| {
+            SafeQuantaError::Proxy(format!("Cannot bind upstream connection to {}: {}", bind_addr, e))
+        })?;
+        let socket = TcpSocket::from_std_stream(std::net::TcpStream::from(socket));
+
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(match last_error {
+        Some(e) => e.into(),
+        None => SafeQuantaError::Proxy(format!(
+            "{} has no address in the same family as bind address {}",
+            upstream, bind_addr
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_upstream_connection_uses_bind_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        let bind_addr: SocketAddr = "127.0.0.2:0".parse().unwrap();
+
+        let (stream, accepted) =
+            tokio::join!(connect_upstream(&upstream, Some(bind_addr)), listener.accept());
+        let (_, peer) = accepted.unwrap();
+
+        assert_eq!(peer.ip(), bind_addr.ip());
+        assert_eq!(stream.unwrap().local_addr().unwrap().ip(), bind_addr.ip());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_bind_addr_fails_at_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        // TEST-NET-1 is never assigned to a local interface
+        let bind_addr: SocketAddr = "192.0.2.1:0".parse().unwrap();
+
+        let err = connect_upstream(&upstream, Some(bind_addr)).await.unwrap_err();
+        assert!(matches!(err, SafeQuantaError::Proxy(ref msg) if msg.contains("192.0.2.1")));
+    }
+
+    #[tokio::test]
+    async fn test_blackholed_upstream_times_out_fast() {
+        // A listener whose accept queue is full drops further SYNs unanswered
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+        socket.listen(0).unwrap();
+        let upstream = socket.local_addr().unwrap().as_socket().unwrap().to_string();
+        let mut queued = Vec::new();
+        loop {
+            match connect_upstream_within(&upstream, None, Duration::from_millis(200)).await {
+                Ok(stream) => queued.push(stream),
+                Err(_) => break,
+            }
+            assert!(queued.len() < 16, "accept queue never filled");
+        }
+
+        let start = Instant::now();
+        let result = connect_upstream_within(&upstream, None, Duration::from_millis(200)).await;
+
+        assert!(start.elapsed() < Duration::from_secs(2));
+        match result {
+            Err(SafeQuantaError::Timeout(msg)) => assert!(msg.contains("timed out after 200ms")),
+            other => panic!("unexpected connect result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dscp_sets_tos_byte() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        let stream = connect_upstream(&upstream, None).await.unwrap();
+
+        // EF, for interactive traffic
+        set_dscp(&stream, Some(46));
+        assert_eq!(SockRef::from(&stream).tos().unwrap(), 46 << 2);
+
+        set_dscp(&stream, None);
+        assert_eq!(SockRef::from(&stream).tos().unwrap(), 46 << 2);
+
+        // IPv6 connections carry it in the traffic class
+        let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+            return;
+        };
+        let upstream = listener.local_addr().unwrap().to_string();
+        let stream = connect_upstream(&upstream, None).await.unwrap();
+        set_dscp(&stream, Some(46));
+        assert_eq!(SockRef::from(&stream).tclass_v6().unwrap(), 46 << 2);
+    }
+
+    #[tokio::test]
+    async fn test_pooled_connection_reused_only_for_same_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(http2_server(stream));
+            }
+        });
+
+        let connections = UpstreamConnections::default();
+        let pool = UpstreamPool::new(8, Duration::from_secs(60))
+            .with_upstream_connections(connections.clone());
+        let key = PoolKey {
+            upstream: upstream.clone(),
+            protocol: UpstreamProtocol::Http2,
+            bind_addr: None,
+            dscp: Some(46),
+        };
+        pool.checkout(&key, Duration::from_secs(1)).await.unwrap();
+        pool.checkout(&key, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(pool.idle_count(&key), 1);
+
+        // A route with another marking or source address opens its own
+        let unmarked = PoolKey { dscp: None, ..key.clone() };
+        let bound = PoolKey {
+            bind_addr: Some("127.0.0.2:0".parse().unwrap()),
+            ..key.clone()
+        };
+        for other in [&unmarked, &bound] {
+            assert_eq!(pool.idle_count(other), 0);
+            pool.checkout(other, Duration::from_secs(1)).await.unwrap();
+            assert_eq!(pool.idle_count(other), 1);
+        }
+        assert_eq!(pool.idle_count(&key), 1);
+        assert_eq!(connections.snapshot()[&upstream], 3);
+    }
+
+    #[tokio::test]
+    async fn test_shared_http2_connection_expires_after_last_use() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(http2_server(stream));
+            }
+        });
+
+        let pool = UpstreamPool::new(8, Duration::from_millis(300));
+        let key = PoolKey {
+            upstream,
+            protocol: UpstreamProtocol::Http2,
+            bind_addr: None,
+            dscp: None,
+        };
+
+        // Kept in use, the connection outlives the idle timeout
+        for _ in 0..3 {
+            pool.checkout(&key, Duration::from_secs(1)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // Left idle, it is replaced
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        pool.checkout(&key, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(pool.idle_count(&key), 1);
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    async fn http2_server(stream: TcpStream) {
+        let service = hyper::service::service_fn(|_req: Request<Incoming>| async {
+            Ok::<_, std::convert::Infallible>(Response::new(http_body_util::Empty::<bytes::Bytes>::new()))
+        });
+        let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    }
+}
@@ -1,269 +1,3096 @@
-use crate::config::ProxyConfig;
-use crate::crypto::CryptoProvider;
+use crate::balancer::{UpstreamPermit, UpstreamSet};
+use crate::config::{
+    AcceptRateMode, ProbeAction, ProxyConfig, ProxyMode, RelayStrategy, RouteConfig,
+    SaturatedAction, Timeouts, UnmatchedRoute, UpstreamTls,
+};
 use crate::error::{Result, SafeQuantaError};
+use crate::events::{Event, EVENT_CAPACITY};
+use crate::fingerprint::peek_client_hello;
+use crate::l7::L7Proxy;
 use crate::metrics::Metrics;
-use crate::tls::TlsManager;
-use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use crate::padding::PaddedStream;
+use crate::pool::{connect_upstream_within, set_dscp, UpstreamPool};
+use crate::recorder::RequestRecorder;
+use crate::sampler::LogSampler;
+use crate::tls::{ClientTlsStream, EarlyDataStream, KeyRotationStream, TlsManager, UpstreamTlsDetector};
+use crate::trace::ConnectionTracer;
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::time::timeout;
+use tokio::sync::{broadcast, oneshot, Semaphore, SemaphorePermit};
+use tokio::time::{timeout, Instant};
+use tokio_util::either::Either;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
-/// Proxy server implementation
-pub struct ProxyServer {
+/// Total byte budget shared by both directions of a connection, plus a cap
+/// on the relay buffers the connection holds
+///
+/// Only the relay's own copy buffers are counted; TLS, socket and HTTP
+/// buffers are not, so the buffer limit is not a bound on a connection's
+/// total memory.
+pub struct ByteBudget {
+    limit: Option<u64>,
+    used: AtomicU64,
+    buffer_limit: Option<usize>,
+    buffered: AtomicUsize,
+}
+
+impl ByteBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+            buffer_limit: None,
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    /// Cap the relay buffers held for the connection at `limit` bytes
+    pub fn with_buffer_limit(mut self, limit: Option<usize>) -> Self {
+        self.buffer_limit = limit;
+        self
+    }
+
+    /// Account for `bytes`, returning false if the budget would be exceeded
+    fn consume(&self, bytes: u64) -> bool {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.limit.map_or(true, |limit| used <= limit)
+    }
+
+    /// Account for a `bytes` buffer held until the reservation is dropped
+    ///
+    /// `None` if the connection would go over its buffer limit.
+    fn reserve_buffer(&self, bytes: usize) -> Option<BufferReservation<'_>> {
+        let used = self.buffered.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let reservation = BufferReservation { budget: self, bytes };
+        self.buffer_limit.map_or(true, |limit| used <= limit).then_some(reservation)
+    }
+
+    /// Relay buffer bytes currently held for the connection
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+}
+
+/// Relay buffer accounted to a connection, released on drop
+struct BufferReservation<'a> {
+    budget: &'a ByteBudget,
+    bytes: usize,
+}
+
+impl Drop for BufferReservation<'_> {
+    fn drop(&mut self) {
+        self.budget.buffered.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Next id handed to an accepted connection, used to correlate its logs
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Connections currently open to each upstream of a listener, relayed or
+/// pooled for L7 requests
+#[derive(Clone, Default)]
+pub struct UpstreamConnections {
+    counts: Arc<Mutex<BTreeMap<String, usize>>>,
+}
+
+impl UpstreamConnections {
+    /// Count a connection to `upstream` until the returned guard is dropped
+    pub fn open(&self, upstream: &str) -> UpstreamConnection {
+        *self.counts.lock().entry(upstream.to_string()).or_default() += 1;
+        UpstreamConnection {
+            counts: self.counts.clone(),
+            upstream: upstream.to_string(),
+        }
+    }
+
+    /// Open connections by upstream
+    pub fn snapshot(&self) -> BTreeMap<String, usize> {
+        self.counts.lock().clone()
+    }
+}
+
+/// Counts a connection to an upstream while held
+pub struct UpstreamConnection {
+    counts: Arc<Mutex<BTreeMap<String, usize>>>,
+    upstream: String,
+}
+
+impl Drop for UpstreamConnection {
+    fn drop(&mut self) {
+        let mut connections = self.counts.lock();
+        if let Some(count) = connections.get_mut(&self.upstream) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.upstream);
+            }
+        }
+    }
+}
+
+/// Per-connection totals, logged as a single summary line at close
+pub struct ConnectionStats {
+    pub id: u64,
+    started: Instant,
+    started_at: SystemTime,
+    client_to_target: AtomicU64,
+    target_to_client: AtomicU64,
+    negotiated: OnceLock<String>,
+    /// Time from accept until the TLS handshake completed
+    handshake_time: OnceLock<Duration>,
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            client_to_target: AtomicU64::new(0),
+            target_to_client: AtomicU64::new(0),
+            negotiated: OnceLock::new(),
+            handshake_time: OnceLock::new(),
+        }
+    }
+
+    /// Record the algorithms the TLS handshake settled on
+    pub fn set_negotiated(&self, algorithms: String) {
+        let _ = self.handshake_time.set(self.started.elapsed());
+        let _ = self.negotiated.set(algorithms);
+    }
+
+    pub fn negotiated(&self) -> Option<&str> {
+        self.negotiated.get().map(String::as_str)
+    }
+
+    pub fn handshake_time(&self) -> Option<Duration> {
+        self.handshake_time.get().copied()
+    }
+
+    /// Wall clock time the connection was accepted
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn client_to_target(&self) -> u64 {
+        self.client_to_target.load(Ordering::Relaxed)
+    }
+
+    pub fn target_to_client(&self) -> u64 {
+        self.target_to_client.load(Ordering::Relaxed)
+    }
+
+    /// Summary line for billing and audit, written once the connection closes
+    pub fn summary(&self, peer: std::net::SocketAddr, reason: CloseReason) -> String {
+        format!(
+            "connection_summary id={} peer={} client_to_target_bytes={} \
+             target_to_client_bytes={} duration_ms={} algorithms={} reason={}",
+            self.id,
+            peer,
+            self.client_to_target(),
+            self.target_to_client(),
+            self.started.elapsed().as_millis(),
+            self.negotiated().unwrap_or("none"),
+            reason.as_str()
+        )
+    }
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Client stream wrapper counting the bytes read from and written to the client
+pub struct CountingStream<S> {
+    inner: S,
+    stats: Arc<ConnectionStats>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S, stats: Arc<ConnectionStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.stats.client_to_target.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &result {
+            self.stats.target_to_client.fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Token bucket pacing new connections, allowing bursts of up to one second
+pub struct AcceptRateLimiter {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl AcceptRateLimiter {
+    pub fn new(per_sec: u32) -> Self {
+        let rate = per_sec.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Take a token if one is available
+    fn try_acquire(&self) -> bool {
+        self.take().is_none()
+    }
+
+    /// Wait until a token is available, sleeping rather than spinning
+    async fn acquire(&self) {
+        while let Some(wait) = self.take() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token, or return how long until the next one is available
+    fn take(&self) -> Option<std::time::Duration> {
+        let mut state = self.state.lock();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            None
+        } else {
+            Some(std::time::Duration::from_secs_f64((1.0 - *tokens) / self.rate))
+        }
+    }
+}
+
+/// Fatal `unrecognized_name` alert record
+const UNRECOGNIZED_NAME_ALERT: [u8; 7] = [21, 3, 3, 0, 2, 2, 112];
+
+/// Turn away a client whose SNI matches no route with an `unrecognized_name`
+/// alert, returning the error the connection ends with
+async fn reject_unmatched(
+    client_stream: &mut TcpStream,
+    server_name: Option<&str>,
+) -> SafeQuantaError {
+    // Discard the unread ClientHello first; closing with it still queued
+    // would reset the connection and could lose the alert
+    let mut discard = [0u8; 4096];
+    while matches!(client_stream.try_read(&mut discard), Ok(n) if n > 0) {}
+    let _ = client_stream.write_all(&UNRECOGNIZED_NAME_ALERT).await;
+    let _ = client_stream.shutdown().await;
+    crate::metrics::record_unmatched_route_rejection();
+    SafeQuantaError::Proxy(format!("No route for server name {:?}", server_name))
+}
+
+/// Caps TLS handshakes in progress at once, independently of `max_connections`
+///
+/// PQC handshakes are CPU-heavy, so a burst of them is queued rather than run
+/// all at once.
+pub struct HandshakeLimiter {
+    permits: Semaphore,
+    queued: AtomicU64,
+}
+
+impl HandshakeLimiter {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max_concurrent.max(1)),
+            queued: AtomicU64::new(0),
+        }
+    }
+
+    /// Run `handshake` once fewer than the configured number are in progress
+    ///
+    /// The queue wait is recorded from `accepted_at`, when the connection
+    /// was accepted, until the handshake holds a permit.
+    pub async fn run<F: std::future::Future>(
+        &self,
+        accepted_at: Instant,
+        handshake: F,
+    ) -> Result<F::Output> {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        crate::metrics::record_handshake_queue_depth(queued);
+        let permit = self.permits.acquire().await;
+        crate::metrics::record_handshake_queue_wait(accepted_at.elapsed());
+        let queued = self.queued.fetch_sub(1, Ordering::Relaxed) - 1;
+        crate::metrics::record_handshake_queue_depth(queued);
+
+        let _permit =
+            permit.map_err(|e| SafeQuantaError::Proxy(format!("Handshake limiter closed: {}", e)))?;
+        Ok(handshake.await)
+    }
+}
+
+/// Waiting room for connections beyond `max_connections`
+///
+/// A connection waits for a permit for at most the queue timeout, and is
+/// turned away at once when the queue already holds its maximum depth.
+#[derive(Default)]
+pub struct ConnectionQueue {
+    waiting: AtomicU64,
+    max_depth: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl ConnectionQueue {
+    pub fn new(max_depth: Option<usize>, timeout: Option<Duration>) -> Self {
+        Self {
+            waiting: AtomicU64::new(0),
+            max_depth,
+            timeout,
+        }
+    }
+
+    /// Whether waiting connections give up after a deadline
+    pub fn has_timeout(&self) -> bool {
+        self.timeout.is_some()
+    }
+
+    /// Take a permit from `limit`, queueing for one if none is free
+    pub async fn acquire<'a>(&self, limit: &'a Semaphore) -> Result<SemaphorePermit<'a>> {
+        if let Ok(permit) = limit.try_acquire() {
+            return Ok(permit);
+        }
+        let queued = self.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.max_depth.is_some_and(|max| queued > max as u64) {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return Err(SafeQuantaError::Proxy(format!(
+                "Connection queue full at {} waiting",
+                queued - 1
+            )));
+        }
+        crate::metrics::record_queue_depth(queued);
+        let permit = match self.timeout {
+            Some(wait) => timeout(wait, limit.acquire()).await.map_err(|_| {
+                crate::metrics::record_queue_timeout();
+                SafeQuantaError::Timeout(format!("No connection slot free within {:?}", wait))
+            }),
+            None => Ok(limit.acquire().await),
+        };
+        let queued = self.waiting.fetch_sub(1, Ordering::Relaxed) - 1;
+        crate::metrics::record_queue_depth(queued);
+
+        permit?.map_err(|e| SafeQuantaError::Proxy(format!("Connection limiter closed: {}", e)))
+    }
+}
+
+/// How the reading peer ended its side of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerClose {
+    /// EOF after a TLS `close_notify` (or a plain TCP FIN)
+    Clean,
+    /// The TCP stream ended without a `close_notify`, a possible truncation attack
+    Truncated,
+}
+
+/// Why a proxied connection ended, exported as `connections_closed_total{reason}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client finished sending
+    ClientEof,
+    /// The upstream finished sending
+    UpstreamEof,
+    /// Neither side sent data within the idle timeout
+    IdleTimeout,
+    /// The connection exceeded its total timeout
+    TotalTimeout,
+    /// The connection reached `max_connection_lifetime_secs`
+    Lifetime,
+    /// The proxy shut down while the connection was open
+    Shutdown,
+    /// The connection was answered with the over-capacity response
+    OverCapacity,
+    /// The connection exceeded `max_total_bytes`
+    Quota,
+    /// The connection's relay buffers would exceed `max_relay_buffer_bytes`
+    BufferLimit,
+    /// The client's first bytes were not a TLS handshake
+    NotTls,
+    /// TLS, upstream or I/O failure
+    Error,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::UpstreamEof => "upstream_eof",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::TotalTimeout => "total_timeout",
+            CloseReason::Lifetime => "lifetime",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::OverCapacity => "over_capacity",
+            CloseReason::Quota => "quota",
+            CloseReason::BufferLimit => "buffer_limit",
+            CloseReason::NotTls => "not_tls",
+            CloseReason::Error => "error",
+        }
+    }
+
+    /// Reason for a direction of `relay` ending with `result`
+    fn from_transfer(result: &Result<PeerClose>, eof: CloseReason) -> Self {
+        match result {
+            Ok(_) => eof,
+            Err(SafeQuantaError::IdleTimeout(_)) => CloseReason::IdleTimeout,
+            Err(SafeQuantaError::QuotaExceeded) => CloseReason::Quota,
+            Err(SafeQuantaError::BufferLimitExceeded) => CloseReason::BufferLimit,
+            Err(_) => CloseReason::Error,
+        }
+    }
+}
+
+/// Every upstream of `config`, selecting among its weighted targets
+pub(crate) fn upstream_set(config: &ProxyConfig) -> UpstreamSet {
+    if config.weighted_targets.is_empty() {
+        return UpstreamSet::new(config.upstreams());
+    }
+    // Routed upstreams weigh 0, so only weighted targets are selected
+    let weight = |addr: &str| {
+        config
+            .weighted_targets
+            .iter()
+            .find(|target| target.addr == addr)
+            .map_or(0, |target| target.weight)
+    };
+    UpstreamSet::weighted(config.upstreams().into_iter().map(|addr| {
+        let weight = weight(&addr);
+        (addr, weight)
+    }))
+}
+
+/// Upstream for an L4 or passthrough connection that matches no route
+fn default_target(config: &ProxyConfig, upstream_health: &UpstreamSet) -> Result<String> {
+    if config.weighted_targets.is_empty() {
+        return Ok(config.target_addr.to_string());
+    }
+    upstream_health
+        .select()
+        .map(str::to_owned)
+        .ok_or_else(|| SafeQuantaError::Proxy("No weighted target is available".to_string()))
+}
+
+/// The client's SNI to send upstream, refused unless it is a legal DNS name
+///
+/// `None` when the client sent no SNI, leaving the configured name in place.
+fn forwarded_server_name(sni: Option<&str>) -> Result<Option<String>> {
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    let Some(sni) = sni else {
+        return Ok(None);
+    };
+    match ServerName::try_from(sni) {
+        Ok(ServerName::DnsName(_)) => Ok(Some(sni.to_string())),
+        _ => Err(SafeQuantaError::Handshake(format!(
+            "client SNI {:?} is not a valid DNS name to forward",
+            sni
+        ))),
+    }
+}
+
+/// Read buffer held by each direction of a relayed connection
+const RELAY_BUFFER_SIZE: usize = 8192;
+
+/// One listen address with its own proxy and TLS settings
+struct Listener {
+    config: Arc<ProxyConfig>,
+    tls_manager: Arc<TlsManager>,
+    connection_limit: Arc<Semaphore>,
+    connection_queue: Arc<ConnectionQueue>,
+    upstream_pool: Arc<UpstreamPool>,
+    accept_limiter: Option<AcceptRateLimiter>,
+    handshake_limiter: Option<Arc<HandshakeLimiter>>,
+    tracer: Option<Arc<ConnectionTracer>>,
+    /// Upstreams, ejected while their health check fails and limited to
+    /// `max_connections_per_upstream`
+    upstream_health: Arc<UpstreamSet>,
+    /// What each upstream speaks under `upstream_tls: Auto`
+    upstream_tls: Arc<UpstreamTlsDetector>,
+    recorder: Option<Arc<RequestRecorder>>,
+    /// Rate limit on connection error log lines
+    error_log_sampler: Option<Arc<LogSampler>>,
+    /// Open upstream connections, shared with `upstream_pool`
+    upstream_connections: UpstreamConnections,
+}
+
+impl Listener {
+    fn new(config: Arc<ProxyConfig>, tls_manager: Arc<TlsManager>) -> Self {
+        let upstream_connections = UpstreamConnections::default();
+        Self {
+            connection_limit: Arc::new(Semaphore::new(config.max_connections)),
+            connection_queue: Arc::new(ConnectionQueue::new(
+                config.max_queue_depth,
+                config.queue_timeout_ms.map(Duration::from_millis),
+            )),
+            upstream_pool: Arc::new(
+                UpstreamPool::from_config(&config)
+                    .with_upstream_connections(upstream_connections.clone()),
+            ),
+            accept_limiter: config.max_accepts_per_sec.map(AcceptRateLimiter::new),
+            handshake_limiter: config
+                .max_concurrent_handshakes
+                .map(|max| Arc::new(HandshakeLimiter::new(max))),
+            upstream_health: Arc::new(
+                upstream_set(&config).with_connection_limit(config.max_connections_per_upstream),
+            ),
+            upstream_tls: Arc::new(UpstreamTlsDetector::default()),
+            recorder: config.record_requests.as_ref().map(|path| {
+                Arc::new(RequestRecorder::new(path, config.record_request_bodies))
+            }),
+            error_log_sampler: config
+                .max_error_logs_per_sec
+                .map(|per_sec| Arc::new(LogSampler::new("connection error", per_sec))),
+            tracer: config
+                .connection_trace
+                .clone()
+                .map(|trace| Arc::new(ConnectionTracer::new(trace))),
+            upstream_connections,
+            config,
+            tls_manager,
+        }
+    }
+}
+
+/// What a connection handler shares with its listener and the server
+struct ConnectionContext {
     config: Arc<ProxyConfig>,
     tls_manager: Arc<TlsManager>,
-    crypto_provider: Arc<CryptoProvider>,
     metrics: Arc<Metrics>,
     connection_limit: Arc<Semaphore>,
+    connection_queue: Arc<ConnectionQueue>,
+    upstream_pool: Arc<UpstreamPool>,
+    handshake_limiter: Option<Arc<HandshakeLimiter>>,
+    upstream_health: Arc<UpstreamSet>,
+    upstream_tls: Arc<UpstreamTlsDetector>,
+    upstream_connections: UpstreamConnections,
+    recorder: Option<Arc<RequestRecorder>>,
+    shutdown: CancellationToken,
+    /// The server's connection tasks, which tasks spawned by a connection join
+    connections: TaskTracker,
+    events: broadcast::Sender<Event>,
+}
+
+/// Proxy server implementation
+///
+/// Serves one or more listeners sharing the metrics; each listener negotiates
+/// with the crypto provider of its own TLS manager.
+pub struct ProxyServer {
+    listeners: Vec<Listener>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    connections: TaskTracker,
+    /// Tasks such as health monitors that run until `shutdown`
+    background: TaskTracker,
+    started: Instant,
+    ready: Mutex<Option<oneshot::Sender<Vec<SocketAddr>>>>,
+    events: broadcast::Sender<Event>,
 }
 
-impl ProxyServer {
-    /// Create a new proxy server
-    pub fn new(
-        config: Arc<ProxyConfig>,
-        tls_manager: Arc<TlsManager>,
-        crypto_provider: Arc<CryptoProvider>,
-        metrics: Arc<Metrics>,
-    ) -> Self {
-        Self {
-            config: config.clone(),
-            tls_manager,
-            crypto_provider,
-            metrics,
-            connection_limit: Arc::new(Semaphore::new(config.max_connections)),
+impl ProxyServer {
+    /// Create a new proxy server with a single listener
+    pub fn new(
+        config: Arc<ProxyConfig>,
+        tls_manager: Arc<TlsManager>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self::with_listeners(vec![(config, tls_manager)], metrics)
+    }
+
+    /// Create a proxy server with a listener per `(proxy settings, TLS manager)` pair
+    pub fn with_listeners(
+        listeners: Vec<(Arc<ProxyConfig>, Arc<TlsManager>)>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            listeners: listeners
+                .into_iter()
+                .map(|(config, tls_manager)| Listener::new(config, tls_manager))
+                .collect(),
+            metrics,
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            background: TaskTracker::new(),
+            started: Instant::now(),
+            ready: Mutex::new(None),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Each listener's connection trace, for enabling it at runtime
+    pub fn connection_tracers(&self) -> Vec<Arc<ConnectionTracer>> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.tracer.clone())
+            .collect()
+    }
+
+    /// Each listener's upstreams, for draining them at runtime
+    pub fn upstream_sets(&self) -> Vec<Arc<UpstreamSet>> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.upstream_health.clone())
+            .collect()
+    }
+
+    /// Receive an `Event` as each connection opens, completes its handshake
+    /// and closes
+    ///
+    /// Only events after the call are seen. Events are dropped while nobody
+    /// is subscribed.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Resolves with the listeners' bound addresses once they are all
+    /// accepting
+    ///
+    /// Fired at most once, alongside the systemd `READY=1` notification; a
+    /// later call replaces the receiver of an earlier one.
+    pub fn ready(&self) -> oneshot::Receiver<Vec<SocketAddr>> {
+        let (sender, receiver) = oneshot::channel();
+        *self.ready.lock() = Some(sender);
+        receiver
+    }
+
+    /// Start the proxy server
+    ///
+    /// `inherited` sockets, e.g. from systemd socket activation (see
+    /// `ActivationEnv`), are used for the listeners in order; any listeners
+    /// left over bind their `listen_addr`.
+    pub async fn start(&self, inherited: Vec<std::net::TcpListener>) -> Result<()> {
+        if inherited.len() > self.listeners.len() {
+            log::warn!(
+                "Ignoring {} inherited sockets beyond the {} configured listeners",
+                inherited.len() - self.listeners.len(),
+                self.listeners.len()
+            );
+        }
+        let mut inherited = inherited.into_iter();
+        let mut sockets = Vec::with_capacity(self.listeners.len());
+        for listener in &self.listeners {
+            let socket = match inherited.next() {
+                Some(socket) => TcpListener::from_std(socket)?,
+                None => Self::bind(listener.config.listen_addr, listener.config.ipv6_only)?,
+            };
+            log::info!("Proxy server listening on {}", socket.local_addr()?);
+            sockets.push(socket);
+        }
+        self.serve_all(sockets).await
+    }
+
+    /// Bind a listening socket, applying `ipv6_only` before the bind so
+    /// dual-stack behavior does not depend on the platform default
+    fn bind(addr: SocketAddr, ipv6_only: Option<bool>) -> Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let (true, Some(only_v6)) = (addr.is_ipv6(), ipv6_only) {
+            socket.set_only_v6(only_v6)?;
+        }
+        // As tokio's TcpListener::bind, so restarts can rebind straight away
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+
+    /// Handshake with the TLS upstreams of listeners with `probe_upstreams_on_start`
+    ///
+    /// An upstream that is unreachable, does not finish the handshake within
+    /// the handshake timeout or negotiates a classical group is logged, or
+    /// fails startup with `upstream_probe_action: Fail`. Under `require_pqc`,
+    /// plaintext upstreams fail startup too.
+    pub async fn probe_upstreams(&self) -> Result<()> {
+        for listener in &self.listeners {
+            let config = &listener.config;
+            config.ensure_pqc_upstreams()?;
+            if !config.probe_upstreams_on_start {
+                continue;
+            }
+            let limit = config.timeouts_for(None).handshake;
+            for (addr, server_name) in config.tls_upstreams() {
+                let probe = timeout(limit, listener.tls_manager.probe_upstream(&addr, &server_name))
+                    .await
+                    .unwrap_or_else(|_| {
+                        let message = format!("Handshake not completed within {:?}", limit);
+                        Err(SafeQuantaError::Timeout(message))
+                    });
+                let problem = match probe {
+                    Ok(probe) if probe.quantum_safe() => {
+                        log::info!(
+                            "Upstream {} negotiated quantum-safe group {:?}",
+                            addr,
+                            probe.group
+                        );
+                        continue;
+                    }
+                    Ok(probe) => format!("negotiated classical group {:?}", probe.group),
+                    Err(e) => format!("probe handshake failed: {}", e),
+                };
+                if config.upstream_probe_action == ProbeAction::Fail {
+                    let message = format!("Upstream {} {}", addr, problem);
+                    return Err(SafeQuantaError::Handshake(message));
+                }
+                log::warn!("Upstream {} {}", addr, problem);
+            }
+        }
+        Ok(())
+    }
+
+    /// Accept and proxy connections from an already bound listener
+    ///
+    /// Lets embedders pick the socket, e.g. an ephemeral port in tests. Only
+    /// valid for a server with a single listener.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.serve_all(vec![listener]).await
+    }
+
+    /// Run an accept loop per listener on the matching already bound socket
+    ///
+    /// Upstreams are probed first (see `probe_upstreams`). Returns once every
+    /// loop has stopped and in-flight connections have finished.
+    pub async fn serve_all(&self, sockets: Vec<TcpListener>) -> Result<()> {
+        if sockets.len() != self.listeners.len() {
+            return Err(SafeQuantaError::InvalidConfig(format!(
+                "{} sockets given for {} listeners",
+                sockets.len(),
+                self.listeners.len()
+            )));
+        }
+        self.probe_upstreams().await?;
+
+        for listener in &self.listeners {
+            for (upstream, check, bind_addr) in crate::health::checked_upstreams(&listener.config) {
+                self.background.spawn(crate::health::monitor(
+                    listener.upstream_health.clone(),
+                    upstream,
+                    check,
+                    bind_addr,
+                    self.shutdown.clone(),
+                ));
+            }
+            if let Some(sampler) = listener.error_log_sampler.clone() {
+                let shutdown = self.shutdown.clone();
+                self.background.spawn(async move { sampler.report_suppressed(shutdown).await });
+            }
+        }
+
+        let addrs = sockets
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let accept_loops = self
+            .listeners
+            .iter()
+            .zip(sockets)
+            .map(|(listener, socket)| self.accept_loop(listener, socket));
+        self.signal_ready(addrs);
+        let result = tokio::select! {
+            result = futures::future::try_join_all(accept_loops) => result,
+            _ = self.dump_stats_on_sigusr1() => unreachable!("stats dump loop never ends"),
+        };
+
+        // One failed loop stops the others, then drain what is in flight
+        self.shutdown.cancel();
+        self.connections.close();
+        self.background.close();
+        tokio::join!(self.connections.wait(), self.background.wait());
+        result.map(|_| ())
+    }
+
+    /// Report that every listener is bound and accepting
+    fn signal_ready(&self, addrs: Vec<SocketAddr>) {
+        if let Err(e) = crate::activation::notify_ready() {
+            log::warn!("Failed to notify systemd of readiness: {}", e);
+        }
+        if let Some(ready) = self.ready.lock().take() {
+            // The embedder may have stopped waiting
+            let _ = ready.send(addrs);
+        }
+    }
+
+    /// One-line JSON summary of the server's state
+    ///
+    /// Covers uptime, open client connections, relayed connections per
+    /// upstream and upstreams ejected by their health checks.
+    pub fn stats_dump(&self) -> String {
+        let mut upstreams = BTreeMap::new();
+        for listener in &self.listeners {
+            for (upstream, count) in listener.upstream_connections.snapshot() {
+                *upstreams.entry(upstream).or_insert(0) += count;
+            }
+        }
+        let ejected: Vec<_> = self
+            .listeners
+            .iter()
+            .flat_map(|listener| listener.upstream_health.ejected())
+            .collect();
+        let dump = serde_json::json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "active_connections": self.connections.len(),
+            "upstream_connections": upstreams,
+            "ejected_upstreams": ejected,
+        });
+        format!("stats_dump {}", dump)
+    }
+
+    /// Log `stats_dump` every time the process receives `SIGUSR1`
+    ///
+    /// Never returns.
+    async fn dump_stats_on_sigusr1(&self) {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::user_defined1()) {
+                Ok(signal) => self.dump_stats_on(signal, |dump| log::info!("{}", dump)).await,
+                Err(e) => log::warn!("Cannot listen for SIGUSR1, stats dumps disabled: {}", e),
+            }
+        }
+        std::future::pending().await
+    }
+
+    #[cfg(unix)]
+    async fn dump_stats_on(
+        &self,
+        mut signal: tokio::signal::unix::Signal,
+        mut emit: impl FnMut(String),
+    ) {
+        while signal.recv().await.is_some() {
+            emit(self.stats_dump());
+        }
+    }
+
+    /// Stop accepting on every listener
+    ///
+    /// L7 client connections are closed after their in-flight requests
+    /// (`Connection: close` or `GOAWAY`); `start`/`serve` return once
+    /// in-flight connections have finished.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    async fn accept_loop(&self, listener: &Listener, socket: TcpListener) -> Result<()> {
+        loop {
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => return Ok(()),
+                accepted = async {
+                    // In delay mode, excess connections wait in the listen backlog
+                    if let Some(limiter) = &listener.accept_limiter {
+                        if listener.config.accept_rate_mode == AcceptRateMode::Delay {
+                            limiter.acquire().await;
+                        }
+                    }
+                    socket.accept().await
+                } => accepted,
+            };
+
+            // Accept new connection
+            let (client_stream, client_addr) = accepted?;
+            let stats = Arc::new(ConnectionStats::new());
+            log::debug!("New connection {} from {}", stats.id, client_addr);
+
+            if let Some(limiter) = &listener.accept_limiter {
+                if listener.config.accept_rate_mode == AcceptRateMode::Reject
+                    && !limiter.try_acquire()
+                {
+                    log::warn!("Rejecting {}: accept rate limit reached", client_addr);
+                    crate::metrics::record_accept_rate_limited();
+                    continue;
+                }
+            }
+
+            // Clone necessary components for the connection handler
+            let context = self.connection_context(listener);
+            let tracer = listener.tracer.clone();
+            let error_log_sampler = listener.error_log_sampler.clone();
+            let events = self.events.clone();
+
+            // Spawn connection handler
+            self.connections.spawn(async move {
+                let handled =
+                    Self::handle_connection(client_stream, client_addr, stats.clone(), context);
+                let (reason, error) = match handled.await {
+                    Ok(reason) => (reason, None),
+                    Err(e) => {
+                        let log_error = || log::error!("Connection {} error: {}", stats.id, e);
+                        match &error_log_sampler {
+                            Some(sampler) => sampler.log(log_error),
+                            None => log_error(),
+                        }
+                        (CloseReason::Error, Some(e.to_string()))
+                    }
+                };
+                log::info!("{}", stats.summary(client_addr, reason));
+                if let Some(tracer) = &tracer {
+                    tracer.record(&stats, client_addr, reason, error.as_deref());
+                }
+                crate::metrics::record_connection_closed(reason.as_str());
+                // No subscribers is not an error
+                let _ = events.send(Event::closed(&stats, client_addr, reason, error));
+            });
+        }
+    }
+
+    /// Shared state for a connection accepted on `listener`
+    fn connection_context(&self, listener: &Listener) -> ConnectionContext {
+        ConnectionContext {
+            config: listener.config.clone(),
+            tls_manager: listener.tls_manager.clone(),
+            metrics: self.metrics.clone(),
+            connection_limit: listener.connection_limit.clone(),
+            connection_queue: listener.connection_queue.clone(),
+            upstream_pool: listener.upstream_pool.clone(),
+            handshake_limiter: listener.handshake_limiter.clone(),
+            upstream_health: listener.upstream_health.clone(),
+            upstream_tls: listener.upstream_tls.clone(),
+            upstream_connections: listener.upstream_connections.clone(),
+            recorder: listener.recorder.clone(),
+            shutdown: self.shutdown.clone(),
+            connections: self.connections.clone(),
+            events: self.events.clone(),
+        }
+    }
+
+    /// Handle a single client connection
+    async fn handle_connection(
+        mut client_stream: TcpStream,
+        client_addr: std::net::SocketAddr,
+        stats: Arc<ConnectionStats>,
+        context: ConnectionContext,
+    ) -> Result<CloseReason> {
+        let ConnectionContext {
+            config,
+            tls_manager,
+            metrics,
+            connection_limit,
+            connection_queue,
+            upstream_pool,
+            handshake_limiter,
+            upstream_health,
+            upstream_tls,
+            upstream_connections,
+            recorder,
+            shutdown,
+            connections,
+            events,
+        } = context;
+        let _ = events.send(Event::Opened { id: stats.id, peer: client_addr });
+        let deadline = config
+            .max_connection_lifetime_secs
+            .map(|secs| Instant::now() + std::time::Duration::from_secs(secs));
+
+        // Port scanners and plaintext clients are closed quietly instead of
+        // failing a handshake; a client still silent after the handshake
+        // timeout is left to the handshake itself
+        let wait = config.timeouts_for(None).handshake;
+        if crate::fingerprint::peek_starts_tls(&client_stream, wait).await == Some(false) {
+            log::debug!("Closing {}: first bytes are not TLS", client_addr);
+            crate::metrics::record_non_tls_connection();
+            return Ok(CloseReason::NotTls);
+        }
+
+        // L7 mode proxies per request: HTTP/2 (e.g. gRPC) when negotiated via
+        // ALPN, HTTP/1.1 otherwise. Over capacity, L7 clients get the configured
+        // error response instead of waiting for a permit.
+        if matches!(config.mode, ProxyMode::Layer7) {
+            let permit = if connection_queue.has_timeout() {
+                Self::acquire_permit(&connection_limit, &connection_queue, &metrics).await.ok()
+            } else {
+                connection_limit.try_acquire().ok()
+            };
+            // Which certificate the client is shown, and so its signature
+            // algorithm, follows from the schemes in its ClientHello
+            let offered_schemes = if config.expose_negotiated_algorithms {
+                let wait = config.timeouts_for(None).handshake;
+                let hello = timeout(wait, peek_client_hello(&client_stream)).await.ok().flatten();
+                hello.map(|hello| hello.signature_schemes)
+            } else {
+                None
+            };
+            let mut client_tls = Self::accept_tls(
+                &tls_manager,
+                handshake_limiter.as_deref(),
+                client_stream,
+                &stats,
+                config.timeouts_for(None).handshake,
+            )
+            .await?;
+            let _ = events.send(Event::handshake_completed(&stats, client_addr));
+            let http2 = client_tls.get_ref().1.alpn_protocol() == Some(b"h2");
+            let padding = tls_manager.padding_for(client_tls.get_ref().1.alpn_protocol());
+            let early_data = crate::tls::take_early_data(&mut client_tls);
+            let expose_algorithms = config.expose_negotiated_algorithms;
+            let (rekey_after_bytes, rekey_action) = (config.rekey_after_bytes, config.rekey_action);
+            let max_key_updates = config.max_key_updates;
+            let mut l7 = L7Proxy::with_pool(config, metrics, upstream_pool)
+                .with_client_addr(client_addr)
+                .with_upstream_health(upstream_health)
+                .with_connection_id(stats.id)
+                .with_shutdown(shutdown)
+                .with_task_tracker(connections);
+            if let Some(recorder) = recorder {
+                l7 = l7.with_request_recorder(recorder);
+            }
+            if let Some(deadline) = deadline {
+                l7 = l7.with_deadline(deadline);
+            }
+            if expose_algorithms {
+                let kem = tls_manager
+                    .peer_negotiated_group(&client_tls)
+                    .map_or_else(|| "none".to_string(), |group| format!("{:?}", group));
+                let signature = offered_schemes
+                    .and_then(|offered| tls_manager.served_signature_algorithm(&offered))
+                    .map_or_else(|| "none".to_string(), |algorithm| format!("{:?}", algorithm));
+                l7 = l7.with_negotiated_algorithms(
+                    &kem.to_ascii_lowercase(),
+                    &signature.to_ascii_lowercase(),
+                );
+            }
+            let client_tls = KeyRotationStream::new(client_tls, rekey_after_bytes, rekey_action)
+                .with_max_key_updates(max_key_updates);
+            let client_tls = EarlyDataStream::new(early_data, client_tls);
+            if let Some(state) = client_tls.state() {
+                l7 = l7.with_early_data(state);
+            }
+            let client_tls = PaddedStream::new(client_tls, padding);
+            let client_tls = CountingStream::new(client_tls, stats);
+
+            let Some(_permit) = permit else {
+                log::warn!("Rejecting {}: connection limit reached", client_addr);
+                l7.reject_over_capacity(client_tls, http2).await?;
+                return Ok(CloseReason::OverCapacity);
+            };
+            return if http2 {
+                l7.serve_http2(client_tls).await
+            } else {
+                l7.serve_http1(client_tls).await
+            };
+        }
+
+        // Acquire connection permit
+        let _permit = Self::acquire_permit(&connection_limit, &connection_queue, &metrics).await?;
+
+        // Passthrough routes on the SNI of the ClientHello and relays the TLS
+        // stream as is; the upstream terminates TLS itself
+        if matches!(config.mode, ProxyMode::Passthrough) {
+            let hello = timeout(config.timeouts_for(None).handshake, peek_client_hello(&client_stream))
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| {
+                    SafeQuantaError::Handshake(format!("{} sent no TLS ClientHello", client_addr))
+                })?;
+            let route = config.route_for(hello.server_name.as_deref());
+            if config.rejects_unmatched(route) {
+                return Err(reject_unmatched(&mut client_stream, hello.server_name.as_deref()).await);
+            }
+            let timeouts = config.timeouts_for(route);
+            let target_addr = match route {
+                Some(route) => route.upstream.clone(),
+                None => default_target(&config, &upstream_health)?,
+            };
+            log::debug!("{} ({:?}) passed through to {}", client_addr, hello.server_name, target_addr);
+
+            if !upstream_health.is_available(&target_addr) {
+                return Err(SafeQuantaError::Proxy(format!(
+                    "Upstream {} is failing health checks",
+                    target_addr
+                )));
+            }
+            let (target_addr, _upstream_slot) =
+                Self::claim_upstream(&upstream_health, &config, route, target_addr)?;
+            let bind_addr = config.bind_addr_for(route);
+            let target_stream =
+                connect_upstream_within(&target_addr, bind_addr, timeouts.connect).await?;
+            let dscp = config.dscp_for(route);
+            set_dscp(&target_stream, dscp);
+            set_dscp(&client_stream, dscp);
+            let _upstream_connection = upstream_connections.open(&target_addr);
+
+            let budget = Arc::new(
+                ByteBudget::new(config.max_total_bytes).with_buffer_limit(config.max_relay_buffer_bytes),
+            );
+            let client_stream = CountingStream::new(client_stream, stats);
+            let transfer = Self::relay_using(
+                config.relay_strategy,
+                client_stream,
+                target_stream,
+                metrics,
+                budget,
+                timeouts.idle,
+                deadline,
+            );
+            return Self::with_total_timeout(&timeouts, transfer).await;
+        }
+
+        // Refuse a server name without a route before spending a handshake on it
+        if config.unmatched_route == UnmatchedRoute::Reject {
+            let wait = config.timeouts_for(None).handshake;
+            let hello = timeout(wait, peek_client_hello(&client_stream)).await.ok().flatten();
+            let server_name = hello.and_then(|hello| hello.server_name);
+            if config.rejects_unmatched(config.route_for(server_name.as_deref())) {
+                return Err(reject_unmatched(&mut client_stream, server_name.as_deref()).await);
+            }
+        }
+
+        // Accept TLS connection
+        let client_tls = Self::accept_tls(
+            &tls_manager,
+            handshake_limiter.as_deref(),
+            client_stream,
+            &stats,
+            config.timeouts_for(None).handshake,
+        )
+        .await?;
+        let _ = events.send(Event::handshake_completed(&stats, client_addr));
+
+        // Select the upstream and its timeouts from the client's SNI
+        let server_name = client_tls.get_ref().1.server_name().map(str::to_owned);
+        let route = config.route_for(server_name.as_deref());
+        let timeouts = config.timeouts_for(route);
+        let (target_addr, mut target_host) = match route {
+            Some(route) => (route.upstream.clone(), route.server_name.clone()),
+            None => (default_target(&config, &upstream_health)?, config.target_host.clone()),
+        };
+        if config.forward_sni_for(route) {
+            if let Some(server_name) = forwarded_server_name(server_name.as_deref())? {
+                target_host = server_name;
+            }
+        }
+        log::debug!("{} routed to {} with {:?}", client_addr, target_addr, timeouts);
+
+        if !upstream_health.is_available(&target_addr) {
+            return Err(SafeQuantaError::Proxy(format!(
+                "Upstream {} is failing health checks",
+                target_addr
+            )));
+        }
+
+        let (target_addr, _upstream_slot) =
+            Self::claim_upstream(&upstream_health, &config, route, target_addr)?;
+
+        // Connect to target server
+        let bind_addr = config.bind_addr_for(route);
+        let target_stream = connect_upstream_within(&target_addr, bind_addr, timeouts.connect).await?;
+        let dscp = config.dscp_for(route);
+        set_dscp(&target_stream, dscp);
+        set_dscp(client_tls.get_ref().0.get_ref(), dscp);
+        let _upstream_connection = upstream_connections.open(&target_addr);
+        let timed_out =
+            || SafeQuantaError::Timeout(format!("TLS connect to {} timed out", target_host));
+        let target = match (config.upstream_tls, upstream_tls.detected(&target_addr)) {
+            (UpstreamTls::Off, _) | (UpstreamTls::Auto, Some(false)) => Either::Right(target_stream),
+            (UpstreamTls::On, _) | (UpstreamTls::Auto, Some(true)) => {
+                let handshake = tls_manager.handshake_upstream(target_stream, &target_host);
+                let target_tls = timeout(timeouts.handshake, handshake).await.map_err(|_| timed_out())??;
+                Either::Left(target_tls)
+            }
+            (UpstreamTls::Auto, None) => {
+                let detection = upstream_tls.detect(
+                    &tls_manager,
+                    &target_addr,
+                    &target_host,
+                    target_stream,
+                    timeouts.handshake,
+                );
+                match detection.await? {
+                    Some(target_tls) => Either::Left(target_tls),
+                    None => {
+                        // The failed handshake used up the connection
+                        let plain =
+                            connect_upstream_within(&target_addr, bind_addr, timeouts.connect).await?;
+                        set_dscp(&plain, dscp);
+                        Either::Right(plain)
+                    }
+                }
+            }
+        };
+
+        // Start proxying data
+        let budget = Arc::new(
+            ByteBudget::new(config.max_total_bytes).with_buffer_limit(config.max_relay_buffer_bytes),
+        );
+        // Each TLS leg carries padded frames if its peer negotiated record padding
+        let client_padding = tls_manager.padding_for(client_tls.get_ref().1.alpn_protocol());
+        let client_tls = KeyRotationStream::new(client_tls, config.rekey_after_bytes, config.rekey_action)
+            .with_max_key_updates(config.max_key_updates);
+        let client_tls = CountingStream::new(PaddedStream::new(client_tls, client_padding), stats);
+        let target = match target {
+            Either::Left(target_tls) => {
+                let padding = tls_manager.padding_for(target_tls.get_ref().1.alpn_protocol());
+                Either::Left(PaddedStream::new(target_tls, padding))
+            }
+            Either::Right(plain) => Either::Right(plain),
+        };
+        let transfer = Self::relay_using(
+            config.relay_strategy,
+            client_tls,
+            target,
+            metrics,
+            budget,
+            timeouts.idle,
+            deadline,
+        );
+
+        Self::with_total_timeout(&timeouts, transfer).await
+    }
+
+    /// Take a slot on `target_addr` under `max_connections_per_upstream`
+    ///
+    /// With `SaturatedAction::Failover` an unrouted connection to a saturated
+    /// weighted target moves to another one below its limit. Routed upstreams
+    /// weigh 0 in `upstream_set`, so a connection never leaves for another
+    /// route's upstream.
+    fn claim_upstream(
+        upstream_health: &UpstreamSet,
+        config: &ProxyConfig,
+        route: Option<&RouteConfig>,
+        target_addr: String,
+    ) -> Result<(String, UpstreamPermit)> {
+        if let Some(slot) = upstream_health.try_acquire(&target_addr) {
+            return Ok((target_addr, slot));
+        }
+        crate::metrics::record_upstream_saturated(&target_addr);
+        let failover = config.upstream_saturated_action == SaturatedAction::Failover
+            && route.is_none()
+            && !config.weighted_targets.is_empty();
+        if failover {
+            if let Some((other, slot)) = upstream_health.select_unsaturated() {
+                log::debug!("Upstream {} saturated, failing over to {}", target_addr, other);
+                return Ok((other.to_string(), slot));
+            }
+        }
+        Err(SafeQuantaError::Proxy(format!(
+            "Upstream {} is at its connection limit",
+            target_addr
+        )))
+    }
+
+    /// TLS handshake and client authentication, within the handshake limit
+    ///
+    /// The handshake must finish within `limit` once it holds a limiter
+    /// permit, so a client that stalls mid-handshake can't keep the permit.
+    async fn accept_tls(
+        tls_manager: &TlsManager,
+        handshake_limiter: Option<&HandshakeLimiter>,
+        client_stream: TcpStream,
+        stats: &ConnectionStats,
+        limit: Duration,
+    ) -> Result<ClientTlsStream> {
+        // The limit starts once the handshake holds a permit, so time spent
+        // queued behind other handshakes doesn't count against it
+        let handshake = async {
+            timeout(limit, async {
+                let mut client_tls = tls_manager.accept(client_stream).await?;
+                stats.set_negotiated(crate::tls::negotiated_algorithms(&client_tls));
+                if tls_manager.client_auth_enabled() {
+                    tls_manager.authenticate_client(&mut client_tls).await?;
+                }
+                Ok(client_tls)
+            })
+            .await
+        };
+        let accepted = match handshake_limiter {
+            Some(limiter) => limiter.run(stats.started, handshake).await?,
+            None => handshake.await,
+        };
+        accepted.map_err(|_| SafeQuantaError::Timeout(format!("TLS handshake not completed within {:?}", limit)))?
+    }
+
+    /// Wait in `queue` for a connection permit, recording how long the wait took
+    async fn acquire_permit<'a>(
+        connection_limit: &'a Semaphore,
+        queue: &ConnectionQueue,
+        metrics: &Metrics,
+    ) -> Result<SemaphorePermit<'a>> {
+        let start = std::time::Instant::now();
+        let permit = queue.acquire(connection_limit).await?;
+        metrics.record_permit_wait(start.elapsed());
+        Ok(permit)
+    }
+
+    /// Copy data in both directions until either side finishes
+    ///
+    /// Once `deadline` passes, both directions are shut down cleanly
+    /// regardless of activity.
+    pub(crate) async fn relay<C, T>(
+        client: C,
+        target: T,
+        metrics: Arc<Metrics>,
+        budget: Arc<ByteBudget>,
+        idle_timeout: std::time::Duration,
+        deadline: Option<Instant>,
+    ) -> CloseReason
+    where
+        C: AsyncRead + AsyncWrite,
+        T: AsyncRead + AsyncWrite,
+    {
+        let (client_reader, mut client_writer) = tokio::io::split(client);
+        let (target_reader, mut target_writer) = tokio::io::split(target);
+
+        let reason = {
+            // Spawn bidirectional data transfer
+            let client_to_target = Self::proxy_data(
+                client_reader,
+                &mut target_writer,
+                "client -> target",
+                metrics.clone(),
+                budget.clone(),
+                idle_timeout,
+            );
+            let target_to_client = Self::proxy_data(
+                target_reader,
+                &mut client_writer,
+                "target -> client",
+                metrics,
+                budget,
+                idle_timeout,
+            );
+            let lifetime = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            // Wait for either direction to complete or the lifetime to run out
+            tokio::select! {
+                result = client_to_target => {
+                    if let Err(e) = &result {
+                        log::error!("Client to target error: {}", e);
+                    }
+                    CloseReason::from_transfer(&result, CloseReason::ClientEof)
+                }
+                result = target_to_client => {
+                    if let Err(e) = &result {
+                        log::error!("Target to client error: {}", e);
+                    }
+                    CloseReason::from_transfer(&result, CloseReason::UpstreamEof)
+                }
+                _ = lifetime => CloseReason::Lifetime,
+            }
+        };
+
+        if reason == CloseReason::Lifetime {
+            log::info!("Closing connection: maximum connection lifetime reached");
+            crate::metrics::record_connection_aged_out();
+            let _ = tokio::join!(client_writer.shutdown(), target_writer.shutdown());
+        }
+        reason
+    }
+
+    /// `relay` or `relay_single_task`, as selected by `strategy`
+    pub(crate) async fn relay_using<C, T>(
+        strategy: RelayStrategy,
+        client: C,
+        target: T,
+        metrics: Arc<Metrics>,
+        budget: Arc<ByteBudget>,
+        idle_timeout: std::time::Duration,
+        deadline: Option<Instant>,
+    ) -> CloseReason
+    where
+        C: AsyncRead + AsyncWrite,
+        T: AsyncRead + AsyncWrite,
+    {
+        match strategy {
+            RelayStrategy::Split => {
+                Self::relay(client, target, metrics, budget, idle_timeout, deadline).await
+            }
+            RelayStrategy::SingleTask => {
+                let (metrics, budget) = (metrics.as_ref(), budget.as_ref());
+                Self::relay_single_task(client, target, metrics, budget, idle_timeout, deadline).await
+            }
+        }
+    }
+
+    /// Copy data in both directions from one loop selecting over both
+    /// directions
+    ///
+    /// Each direction holds one buffer and is either reading into it or
+    /// writing it out, so a peer that stops reading stalls only the data
+    /// headed to it, and the idle and lifetime timers keep running while
+    /// writes are pending. The connection is idle only once neither direction
+    /// has made progress for `idle_timeout`. When one peer finishes, the write
+    /// side towards the other is shut down (a FIN or `close_notify`) before
+    /// returning.
+    pub(crate) async fn relay_single_task<C, T>(
+        client: C,
+        target: T,
+        metrics: &Metrics,
+        budget: &ByteBudget,
+        idle_timeout: std::time::Duration,
+        deadline: Option<Instant>,
+    ) -> CloseReason
+    where
+        C: AsyncRead + AsyncWrite,
+        T: AsyncRead + AsyncWrite,
+    {
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let (mut target_reader, mut target_writer) = tokio::io::split(target);
+
+        let Some(_reservation) = budget.reserve_buffer(2 * RELAY_BUFFER_SIZE) else {
+            log::warn!(
+                "Closing connection, relay buffers would exceed the buffer limit ({} bytes held)",
+                budget.buffered()
+            );
+            crate::metrics::record_connection_buffer_limit_exceeded();
+            let _ = tokio::join!(client_writer.shutdown(), target_writer.shutdown());
+            return CloseReason::BufferLimit;
+        };
+        let mut to_target = RelayDirection::new("client -> target");
+        let mut to_client = RelayDirection::new("target -> client");
+        let lifetime = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(lifetime);
+
+        let reason = loop {
+            let (step, from_client) = tokio::select! {
+                step = std::future::poll_fn(|cx| {
+                    to_target.poll_step(cx, &mut client_reader, &mut target_writer)
+                }) => (step, true),
+                step = std::future::poll_fn(|cx| {
+                    to_client.poll_step(cx, &mut target_reader, &mut client_writer)
+                }) => (step, false),
+                _ = tokio::time::sleep(idle_timeout) => break CloseReason::IdleTimeout,
+                _ = &mut lifetime => {
+                    log::info!("Closing connection: maximum connection lifetime reached");
+                    crate::metrics::record_connection_aged_out();
+                    let _ = tokio::join!(client_writer.shutdown(), target_writer.shutdown());
+                    break CloseReason::Lifetime;
+                }
+            };
+            let (direction, eof) = if from_client {
+                (&mut to_target, CloseReason::ClientEof)
+            } else {
+                (&mut to_client, CloseReason::UpstreamEof)
+            };
+            let close = match step {
+                Ok(RelayStep::Read(n)) => {
+                    if !budget.consume(n as u64) {
+                        log::warn!("{}: closing connection, byte quota exceeded", direction.name);
+                        crate::metrics::record_connection_quota_exceeded();
+                        break CloseReason::Quota;
+                    }
+                    continue;
+                }
+                Ok(RelayStep::Wrote(n)) => {
+                    direction.total += n as u64;
+                    metrics.record_bytes_transferred(n);
+                    continue;
+                }
+                Ok(RelayStep::Eof) => PeerClose::Clean,
+                // rustls reports EOF without close_notify as UnexpectedEof
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => PeerClose::Truncated,
+                Err(e) => {
+                    report_relay_error(direction.name, &e);
+                    log::error!("{} error: {}", direction.name, e);
+                    break CloseReason::Error;
+                }
+            };
+            report_peer_close(direction.name, close, direction.total);
+            break eof;
+        };
+
+        match reason {
+            CloseReason::ClientEof => {
+                let _ = target_writer.shutdown().await;
+            }
+            CloseReason::UpstreamEof => {
+                let _ = client_writer.shutdown().await;
+            }
+            CloseReason::Quota => {
+                let _ = tokio::join!(client_writer.shutdown(), target_writer.shutdown());
+            }
+            _ => {}
+        }
+        log::debug!(
+            "Relay finished ({}): {} bytes client -> target, {} bytes target -> client",
+            reason.as_str(),
+            to_target.total,
+            to_client.total
+        );
+        reason
+    }
+
+    /// Run `transfer`, closing it once the total connection timeout elapses
+    async fn with_total_timeout<F>(timeouts: &Timeouts, transfer: F) -> Result<CloseReason>
+    where
+        F: std::future::Future<Output = CloseReason>,
+    {
+        match timeouts.total {
+            Some(total) => Ok(timeout(total, transfer).await.unwrap_or_else(|_| {
+                log::info!("Closing connection: total timeout of {:?} exceeded", total);
+                CloseReason::TotalTimeout
+            })),
+            None => Ok(transfer.await),
+        }
+    }
+
+    /// Proxy data between two streams
+    ///
+    /// Each chunk is fully written before the next read, so a stalled writer
+    /// stops this direction from reading: at most one buffer is held per
+    /// direction and backpressure reaches the producing peer. The buffer is
+    /// accounted against the connection's buffer limit.
+    async fn proxy_data<R, W>(
+        mut reader: R,
+        mut writer: W,
+        direction: &str,
+        metrics: Arc<Metrics>,
+        budget: Arc<ByteBudget>,
+        idle_timeout: std::time::Duration,
+    ) -> Result<PeerClose>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let Some(_reservation) = budget.reserve_buffer(RELAY_BUFFER_SIZE) else {
+            log::warn!(
+                "{}: closing connection, relay buffers would exceed the buffer limit ({} bytes held)",
+                direction,
+                budget.buffered()
+            );
+            crate::metrics::record_connection_buffer_limit_exceeded();
+            writer.shutdown().await?;
+            return Err(SafeQuantaError::BufferLimitExceeded);
+        };
+        let mut buffer = vec![0u8; RELAY_BUFFER_SIZE];
+        let mut total_bytes = 0;
+
+        let close = loop {
+            let read = timeout(idle_timeout, reader.read(&mut buffer))
+                .await
+                .map_err(|_| SafeQuantaError::IdleTimeout(direction.to_string()))?;
+
+            // rustls reports EOF without close_notify as UnexpectedEof
+            let n = match read {
+                Ok(0) => break PeerClose::Clean,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break PeerClose::Truncated,
+                Err(e) => {
+                    report_relay_error(direction, &e);
+                    return Err(e.into());
+                }
+            };
+
+            if !budget.consume(n as u64) {
+                log::warn!(
+                    "{}: closing connection after {} bytes, byte quota exceeded",
+                    direction,
+                    total_bytes
+                );
+                crate::metrics::record_connection_quota_exceeded();
+                writer.shutdown().await?;
+                return Err(SafeQuantaError::QuotaExceeded);
+            }
+
+            writer.write_all(&buffer[..n]).await?;
+            total_bytes += n;
+
+            // Record metrics
+            metrics.record_bytes_transferred(n);
+        };
+
+        report_peer_close(direction, close, total_bytes as u64);
+        Ok(close)
+    }
+}
+
+/// Log how a peer ended its side after `total_bytes` were relayed from it
+fn report_peer_close(direction: &str, close: PeerClose, total_bytes: u64) {
+    match close {
+        PeerClose::Clean => {
+            log::debug!("{}: transferred {} bytes, peer closed cleanly", direction, total_bytes);
+        }
+        PeerClose::Truncated => {
+            log::warn!(
+                "{}: transferred {} bytes, peer closed without close_notify (possible truncation)",
+                direction,
+                total_bytes
+            );
+            crate::metrics::record_connection_truncated();
+        }
+    }
+}
+
+/// Log and count `err` if it is a post-handshake protocol violation
+fn report_relay_error(direction: &str, err: &std::io::Error) {
+    if let Some(reason) = crate::tls::post_handshake_violation(err) {
+        log::warn!("{}: closing connection, post-handshake violation: {}", direction, reason);
+        crate::metrics::record_post_handshake_violation(reason);
+    }
+}
+
+/// What a `RelayDirection` step did
+enum RelayStep {
+    /// Read this many bytes, to be written next
+    Read(usize),
+    /// Wrote this many bytes of the last read
+    Wrote(usize),
+    /// The reading peer finished
+    Eof,
+}
+
+/// One direction of `ProxyServer::relay_single_task`: a buffer that is
+/// either filled from the reader or drained into the writer
+struct RelayDirection {
+    name: &'static str,
+    buffer: Box<[u8]>,
+    /// Unwritten part of the buffer
+    pending: std::ops::Range<usize>,
+    /// Written bytes may still sit in the writer's buffers
+    needs_flush: bool,
+    total: u64,
+}
+
+impl RelayDirection {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            buffer: vec![0u8; RELAY_BUFFER_SIZE].into_boxed_slice(),
+            pending: 0..0,
+            needs_flush: false,
+            total: 0,
+        }
+    }
+
+    /// Make one step of progress: write out pending data, or else read more
+    ///
+    /// The writer is flushed whenever the reader has nothing ready.
+    fn poll_step<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Poll<std::io::Result<RelayStep>>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if !self.pending.is_empty() {
+            let n = std::task::ready!(
+                Pin::new(&mut *writer).poll_write(cx, &self.buffer[self.pending.clone()])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.start += n;
+            self.needs_flush = true;
+            return Poll::Ready(Ok(RelayStep::Wrote(n)));
+        }
+
+        let mut buf = ReadBuf::new(&mut self.buffer);
+        match Pin::new(reader).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => {
+                let n = buf.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Ok(RelayStep::Eof));
+                }
+                self.pending = 0..n;
+                Poll::Ready(Ok(RelayStep::Read(n)))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                if self.needs_flush {
+                    std::task::ready!(Pin::new(writer).poll_flush(cx))?;
+                    self.needs_flush = false;
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CryptoProvider;
+    use crate::metrics::testing::{counters, histogram_samples, record};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Duration;
+
+    async fn setup_test_proxy() -> (ProxyServer, SocketAddr, SocketAddr) {
+        let proxy_config = Arc::new(ProxyConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            target_addr: "127.0.0.1:0".parse().unwrap(),
+            max_connections: 10,
+            ..Default::default()
+        });
+
+        let tls_config = Arc::new(crate::config::TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            server_addr: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        });
+
+        let metrics = Arc::new(Metrics::new());
+        let crypto_provider = Arc::new(CryptoProvider::new(
+            tls_config.kem_algorithm,
+            tls_config.signature_algorithm,
+            &tls_config.cert_path,
+            &tls_config.key_path,
+        ).unwrap());
+
+        let tls_manager = Arc::new(TlsManager::new(
+            tls_config,
+            crypto_provider,
+            metrics.clone(),
+        ).unwrap());
+
+        let proxy_server = ProxyServer::new(
+            proxy_config.clone(),
+            tls_manager,
+            metrics,
+        );
+
+        let proxy_listener = TcpListener::bind(proxy_config.listen_addr).await.unwrap();
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+
+        let target_listener = TcpListener::bind(proxy_config.target_addr).await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        (proxy_server, proxy_addr, target_addr)
+    }
+
+    /// Serve `config` with the test certificate on an ephemeral port,
+    /// returning the server, its address and a connector trusting it
+    async fn spawn_tls_proxy(
+        config: ProxyConfig,
+    ) -> (Arc<ProxyServer>, SocketAddr, tokio_rustls::TlsConnector) {
+        use tokio_rustls::rustls::pki_types::CertificateDer;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let tls_config = Arc::new(crate::config::TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            ..Default::default()
+        });
+        let metrics = Arc::new(Metrics::new());
+        let crypto_provider = Arc::new(CryptoProvider::from_config(&tls_config).unwrap());
+        let tls_manager = Arc::new(
+            TlsManager::new(tls_config, crypto_provider, metrics.clone()).unwrap(),
+        );
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = socket.local_addr().unwrap();
+        let server = Arc::new(ProxyServer::new(
+            Arc::new(config),
+            tls_manager,
+            metrics,
+        ));
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve_all(vec![socket]).await }
+        });
+
+        let mut roots = RootCertStore::empty();
+        let cert = std::fs::read("tests/fixtures/test.crt").unwrap();
+        roots.add(CertificateDer::from(cert)).unwrap();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(
+            ClientConfig::builder().with_root_certificates(roots).with_no_client_auth(),
+        ));
+        (server, proxy_addr, connector)
+    }
+
+    /// Route from `server_name` to `upstream`, with every override unset
+    fn route(server_name: &str, upstream: &str) -> crate::config::RouteConfig {
+        crate::config::RouteConfig {
+            server_name: server_name.to_string(),
+            upstream: upstream.to_string(),
+            timeouts: Default::default(),
+            allow_early_data: false,
+            bind_addr: None,
+            maintenance_page: None,
+            dscp: None,
+            health_check: None,
+            header_rules: Default::default(),
+            forward_sni: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_data_transfer() {
+        let (proxy_server, proxy_addr, target_addr) = setup_test_proxy().await;
+
+        // Start target server
+        let target_server = tokio::spawn(async move {
+            let listener = TcpListener::bind(target_addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls_manager = &proxy_server.listeners[0].tls_manager;
+            let mut tls_stream = tls_manager.accept(stream).await.unwrap();
+            
+            let mut buf = [0u8; 1024];
+            let n = tls_stream.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"hello");
+            
+            tls_stream.write_all(b"world").await.unwrap();
+        });
+
+        // Connect client to proxy
+        let client_stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let tls_manager = &proxy_server.listeners[0].tls_manager;
+        let mut client_tls = tls_manager.connect("localhost").await.unwrap();
+        
+        client_tls.write_all(b"hello").await.unwrap();
+        
+        let mut buf = [0u8; 1024];
+        let n = client_tls.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"world");
+
+        target_server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let (proxy_server, proxy_addr, _) = setup_test_proxy().await;
+        let mut handles = vec![];
+
+        // Try to establish more connections than the limit
+        for _ in 0..proxy_server.listeners[0].config.max_connections + 1 {
+            let handle = tokio::spawn(async move {
+                let stream = TcpStream::connect(proxy_addr).await;
+                stream
+            });
+            handles.push(handle);
+        }
+
+        // Wait for all connection attempts
+        let results = futures::future::join_all(handles).await;
+        
+        // Count successful connections
+        let successful = results.iter()
+            .filter(|r| r.as_ref().unwrap().is_ok())
+            .count();
+
+        assert_eq!(successful, proxy_server.listeners[0].config.max_connections);
+    }
+
+    #[tokio::test]
+    async fn test_byte_quota_closes_connection() {
+        let (mut client, proxy_in) = tokio::io::duplex(64);
+        let (proxy_out, mut target) = tokio::io::duplex(64);
+        let budget = Arc::new(ByteBudget::new(Some(10)));
+
+        let transfer = tokio::spawn(ProxyServer::proxy_data(
+            proxy_in,
+            proxy_out,
+            "client -> target",
+            Arc::new(Metrics::new()),
+            budget,
+            Duration::from_secs(5),
+        ));
+
+        client.write_all(b"0123456789").await.unwrap();
+        let mut buf = [0u8; 10];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"0123456789");
+
+        client.write_all(b"x").await.unwrap();
+        let result = transfer.await.unwrap();
+        assert!(matches!(result, Err(SafeQuantaError::Proxy(_))));
+
+        // The extra byte is never forwarded and the target side sees EOF
+        let n = target.read(&mut buf).await.unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sigusr1_dumps_stats() {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let (proxy_server, _, _) = setup_test_proxy().await;
+        let _upstream = proxy_server.listeners[0].upstream_connections.open("dump.test:443");
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let signal = signal(SignalKind::user_defined1()).unwrap();
+        let dumps = proxy_server.dump_stats_on(signal, move |dump| tx.send(dump).unwrap());
+        tokio::pin!(dumps);
+
+        let status = std::process::Command::new("kill")
+            .args(["-USR1", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let dump = tokio::select! {
+            _ = &mut dumps => unreachable!(),
+            dump = rx.recv() => dump.unwrap(),
+        };
+        let json = dump.strip_prefix("stats_dump ").unwrap();
+        let stats: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(stats["active_connections"], 0);
+        assert!(stats["uptime_secs"].is_u64());
+        assert_eq!(stats["upstream_connections"]["dump.test:443"], 1);
+        assert!(stats["ejected_upstreams"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_limit_closes_connection() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (target, mut target_peer) = tokio::io::duplex(64);
+        // Room for one direction's buffer but not both
+        let budget = Arc::new(ByteBudget::new(None).with_buffer_limit(Some(RELAY_BUFFER_SIZE + 1024)));
+
+        let reason = ProxyServer::relay(
+            client,
+            target,
+            Arc::new(Metrics::new()),
+            budget.clone(),
+            Duration::from_secs(5),
+            None,
+        )
+        .await;
+        assert_eq!(reason, CloseReason::BufferLimit);
+        assert_eq!(budget.buffered(), 0);
+
+        // The side whose buffer was refused is shut down
+        let (mut client_buf, mut target_buf) = ([0u8; 1], [0u8; 1]);
+        let closed = tokio::select! {
+            n = client_peer.read(&mut client_buf) => n.unwrap(),
+            n = target_peer.read(&mut target_buf) => n.unwrap(),
+        };
+        assert_eq!(closed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_routes_enforce_their_own_idle_timeouts() {
+        use crate::config::{RouteConfig, TimeoutOverrides};
+
+        let route = |name: &str, idle: u64| RouteConfig {
+            server_name: name.to_string(),
+            upstream: "127.0.0.1:9".to_string(),
+            timeouts: TimeoutOverrides {
+                idle: Some(idle),
+                ..Default::default()
+            },
+            allow_early_data: false,
+            bind_addr: None,
+            maintenance_page: None,
+            dscp: None,
+            health_check: None,
+            header_rules: Default::default(),
+            forward_sni: false,
+        };
+        let (mut proxy_server, _, _) = setup_test_proxy().await;
+        let mut config = (*proxy_server.listeners[0].config).clone();
+        config.routes = vec![route("fast.example", 1), route("slow.example", 30)];
+        proxy_server.listeners[0].config = Arc::new(config);
+
+        let config = &proxy_server.listeners[0].config;
+        let fast = config.timeouts_for(config.route_for(Some("fast.example")));
+        let slow = config.timeouts_for(config.route_for(Some("slow.example")));
+        assert_eq!(fast.idle, Duration::from_secs(1));
+        assert_eq!(slow.idle, Duration::from_secs(30));
+
+        let idle_transfer = |idle| {
+            let (client, proxy_in) = tokio::io::duplex(64);
+            let (proxy_out, target) = tokio::io::duplex(64);
+            tokio::spawn(async move {
+                // Keep the peers alive so only the idle timeout can end the copy
+                let _peers = (client, target);
+                ProxyServer::proxy_data(
+                    proxy_in,
+                    proxy_out,
+                    "client -> target",
+                    Arc::new(Metrics::new()),
+                    Arc::new(ByteBudget::new(None)),
+                    idle,
+                )
+                .await
+            })
+        };
+
+        let fast_transfer = idle_transfer(fast.idle);
+        let slow_transfer = idle_transfer(slow.idle);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(fast_transfer.is_finished());
+        assert!(matches!(fast_transfer.await.unwrap(), Err(SafeQuantaError::Proxy(_))));
+        assert!(!slow_transfer.is_finished());
+        slow_transfer.abort();
+    }
+
+    #[tokio::test]
+    async fn test_truncated_close_is_distinguished_from_clean() {
+        let transfer = |reader| {
+            ProxyServer::proxy_data(
+                reader,
+                tokio::io::sink(),
+                "client -> target",
+                Arc::new(Metrics::new()),
+                Arc::new(ByteBudget::new(None)),
+                Duration::from_secs(5),
+            )
+        };
+
+        let clean = tokio_test::io::Builder::new().read(b"hello").build();
+        assert_eq!(transfer(clean).await.unwrap(), PeerClose::Clean);
+
+        let truncated = tokio_test::io::Builder::new()
+            .read(b"hello")
+            .read_error(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "peer closed connection without sending TLS close_notify",
+            ))
+            .build();
+        assert_eq!(transfer(truncated).await.unwrap(), PeerClose::Truncated);
+    }
+
+    #[test]
+    fn test_permit_wait_recorded_under_contention() {
+        let waits = histogram_samples("connection_permit_wait_ms", async {
+            let connection_limit = Arc::new(Semaphore::new(1));
+            let metrics = Arc::new(Metrics::new());
+            let queue = Arc::new(ConnectionQueue::default());
+            let held =
+                ProxyServer::acquire_permit(&connection_limit, &queue, &metrics).await.unwrap();
+
+            let waiter = {
+                let connection_limit = connection_limit.clone();
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    ProxyServer::acquire_permit(&connection_limit, &queue, &metrics)
+                        .await
+                        .map(|_| ())
+                })
+            };
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(held);
+            waiter.await.unwrap().unwrap();
+        });
+
+        assert_eq!(waits.len(), 2);
+        assert!(waits.iter().any(|&ms| ms >= 50.0));
+    }
+
+    #[tokio::test]
+    async fn test_queued_connection_served_within_deadline_or_rejected() {
+        let connection_limit = Arc::new(Semaphore::new(1));
+        let queue = Arc::new(ConnectionQueue::new(Some(1), Some(Duration::from_millis(200))));
+        let held = queue.acquire(&connection_limit).await.unwrap();
+
+        // A slot freed before the deadline serves the waiting connection
+        let waiter = {
+            let connection_limit = connection_limit.clone();
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.acquire(&connection_limit).await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The queue is full, so another connection is turned away at once
+        let full = queue.acquire(&connection_limit).await;
+        assert!(matches!(full, Err(SafeQuantaError::Proxy(_))));
+        drop(held);
+        waiter.await.unwrap().unwrap();
+
+        // No slot within the deadline: rejected
+        let _held = queue.acquire(&connection_limit).await.unwrap();
+        let start = Instant::now();
+        let late = queue.acquire(&connection_limit).await;
+        assert!(matches!(late, Err(SafeQuantaError::Timeout(_))));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_rustls_key_update_rejection_reported() {
+        use tokio_rustls::rustls::{Error, PeerMisbehaved};
+
+        let flood = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            Error::PeerMisbehaved(PeerMisbehaved::TooManyKeyUpdateRequests),
+        );
+        assert_eq!(crate::tls::post_handshake_violation(&flood), Some("too_many_key_updates"));
+
+        let reader = tokio_test::io::Builder::new().read(b"hello").read_error(flood).build();
+        let result = ProxyServer::proxy_data(
+            reader,
+            tokio::io::sink(),
+            "client -> target",
+            Arc::new(Metrics::new()),
+            Arc::new(ByteBudget::new(None)),
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert!(matches!(result, Err(SafeQuantaError::Io(_))));
+    }
+
+    #[test]
+    fn test_active_connection_closed_at_max_lifetime() {
+        let recorded = record(async {
+            let (mut client, proxy_in) = tokio::io::duplex(64);
+            let (proxy_out, mut target) = tokio::io::duplex(64);
+            let deadline = Instant::now() + Duration::from_millis(200);
+
+            let relay = tokio::spawn(ProxyServer::relay(
+                proxy_in,
+                proxy_out,
+                Arc::new(Metrics::new()),
+                Arc::new(ByteBudget::new(None)),
+                Duration::from_secs(5),
+                Some(deadline),
+            ));
+
+            // Keep the connection busy well past its lifetime
+            let mut buf = [0u8; 64];
+            let closed = loop {
+                if client.write_all(b"ping").await.is_err() {
+                    break true;
+                }
+                match timeout(Duration::from_secs(2), target.read(&mut buf)).await.unwrap() {
+                    Ok(0) | Err(_) => break true,
+                    Ok(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+
+            assert!(closed);
+            assert!(Instant::now() >= deadline);
+            let reason = timeout(Duration::from_secs(1), relay).await.unwrap().unwrap();
+            assert_eq!(reason, CloseReason::Lifetime);
+
+            // The client side sees a clean EOF too
+            let n = client.read(&mut buf).await.unwrap();
+            assert_eq!(n, 0);
+        });
+
+        assert_eq!(counters(&recorded, "connections_aged_out_total"), vec![(vec![], 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_slow_reader_applies_backpressure() {
+        const PIPE: usize = 1024;
+        let (mut client, proxy_in) = tokio::io::duplex(PIPE);
+        let (proxy_out, mut target) = tokio::io::duplex(PIPE);
+
+        tokio::spawn(ProxyServer::proxy_data(
+            proxy_in,
+            proxy_out,
+            "client -> target",
+            Arc::new(Metrics::new()),
+            Arc::new(ByteBudget::new(None)),
+            Duration::from_secs(5),
+        ));
+
+        // The producer tries to push 1 MiB while the target reads nothing
+        let sent = Arc::new(AtomicU64::new(0));
+        let producer = {
+            let sent = sent.clone();
+            tokio::spawn(async move {
+                let chunk = [0u8; 4096];
+                for _ in 0..256 {
+                    client.write_all(&chunk).await.unwrap();
+                    sent.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+                }
+                client
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!producer.is_finished());
+        // Both pipes plus one in-flight proxy buffer and producer chunk
+        let bound = (2 * PIPE + 8192 + 4096) as u64;
+        assert!(sent.load(Ordering::SeqCst) <= bound);
+
+        // Once the target drains, everything flows through
+        let mut received = 0;
+        let mut buf = vec![0u8; 16 * 1024];
+        while received < 256 * 4096 {
+            received += target.read(&mut buf).await.unwrap();
+        }
+        producer.await.unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 256 * 4096);
+    }
+
+    #[tokio::test]
+    async fn test_accept_rate_limiter_paces_acceptance() {
+        let limiter = AcceptRateLimiter::new(10);
+        let start = Instant::now();
+
+        // The first second's worth goes through at once, the rest is paced
+        for _ in 0..10 {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "paced too fast: {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "paced too slow: {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_handshakes_limited_to_configured_parallelism() {
+        let limiter = Arc::new(HandshakeLimiter::new(2));
+        let in_progress = Arc::new(AtomicU64::new(0));
+        let peak = Arc::new(AtomicU64::new(0));
+
+        let handshakes: Vec<_> = (0..8)
+            .map(|_| {
+                let (limiter, in_progress, peak) = (limiter.clone(), in_progress.clone(), peak.clone());
+                tokio::spawn(async move {
+                    limiter
+                        .run(Instant::now(), async {
+                            let now = in_progress.fetch_add(1, Ordering::SeqCst) + 1;
+                            peak.fetch_max(now, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(20)).await;
+                            in_progress.fetch_sub(1, Ordering::SeqCst);
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for handshake in handshakes {
+            handshake.await.unwrap().unwrap();
         }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(in_progress.load(Ordering::SeqCst), 0);
     }
 
-    /// Start the proxy server
-    pub async fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.config.listen_addr).await?;
-        log::info!("Proxy server listening on {}", self.config.listen_addr);
+    #[tokio::test]
+    async fn test_stalled_handshake_times_out_and_frees_its_slot() {
+        let (proxy_server, _, _) = setup_test_proxy().await;
+        let tls_manager = &proxy_server.listeners[0].tls_manager;
+        let limiter = HandshakeLimiter::new(1);
 
-        loop {
-            // Accept new connection
-            let (client_stream, client_addr) = listener.accept().await?;
-            log::debug!("New connection from {}", client_addr);
+        // A client that connects and never sends its ClientHello
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
 
-            // Clone necessary components for the connection handler
-            let tls_manager = self.tls_manager.clone();
-            let crypto_provider = self.crypto_provider.clone();
-            let metrics = self.metrics.clone();
-            let connection_limit = self.connection_limit.clone();
-            let config = self.config.clone();
+        let started = std::time::Instant::now();
+        let result = ProxyServer::accept_tls(
+            tls_manager,
+            Some(&limiter),
+            stream,
+            &ConnectionStats::new(),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(matches!(result, Err(SafeQuantaError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
 
-            // Spawn connection handler
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(
-                    client_stream,
-                    client_addr,
-                    tls_manager,
-                    crypto_provider,
-                    metrics,
-                    connection_limit,
-                    config,
-                )
+        // The permit went back to the limiter
+        let reacquired = limiter.run(Instant::now(), async {});
+        timeout(Duration::from_millis(100), reacquired).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queued_handshake_gets_its_full_timeout() {
+        use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let (proxy_server, _, _) = setup_test_proxy().await;
+        let tls_manager = &proxy_server.listeners[0].tls_manager;
+        let limiter = HandshakeLimiter::new(1);
+        let limit = Duration::from_millis(300);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A slow client holds the only slot until its handshake times out
+        let _slow_client = TcpStream::connect(addr).await.unwrap();
+        let (slow, _) = listener.accept().await.unwrap();
+
+        // A well-behaved client queues behind it
+        let mut roots = RootCertStore::empty();
+        let cert = std::fs::read("tests/fixtures/test.crt").unwrap();
+        roots.add(CertificateDer::from(cert)).unwrap();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(
+            ClientConfig::builder().with_root_certificates(roots).with_no_client_auth(),
+        ));
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            connector.connect(ServerName::try_from("localhost").unwrap(), stream).await
+        });
+        let (queued, _) = listener.accept().await.unwrap();
+
+        let (slow_stats, queued_stats) = (ConnectionStats::new(), ConnectionStats::new());
+        let (slow, queued) = tokio::join!(
+            ProxyServer::accept_tls(tls_manager, Some(&limiter), slow, &slow_stats, limit),
+            ProxyServer::accept_tls(tls_manager, Some(&limiter), queued, &queued_stats, limit),
+        );
+        assert!(matches!(slow, Err(SafeQuantaError::Timeout(_))));
+        // Its wait for the slot didn't use up its own timeout
+        assert!(queued.is_ok());
+        client.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_handshake_queue_wait_recorded_under_contention() {
+        let mut waits = histogram_samples("handshake_queue_wait_ms", async {
+            // One slot, held for 50ms by the first of two connections
+            // accepted together
+            let limiter = Arc::new(HandshakeLimiter::new(1));
+            let accepted_at = Instant::now();
+            let handshakes: Vec<_> = (0..2)
+                .map(|_| {
+                    let limiter = limiter.clone();
+                    tokio::spawn(async move {
+                        let handshake = tokio::time::sleep(Duration::from_millis(50));
+                        limiter.run(accepted_at, handshake).await
+                    })
+                })
+                .collect();
+            for handshake in handshakes {
+                handshake.await.unwrap().unwrap();
+            }
+        });
+
+        // The first handshake starts at once, the second waits for its slot
+        waits.sort_by(f64::total_cmp);
+        assert_eq!(waits.len(), 2);
+        assert!(waits[0] < 50.0);
+        assert!(waits[1] >= 50.0);
+    }
+
+    #[tokio::test]
+    async fn test_accept_rate_limiter_rejects_beyond_rate() {
+        let limiter = AcceptRateLimiter::new(2);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        tokio::time::sleep(Duration::from_millis(550)).await;
+        assert!(limiter.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn test_single_task_relay_transfers_both_ways_and_closes_cleanly() {
+        let (client, proxy_in) = tokio::io::duplex(64);
+        let (proxy_out, mut target) = tokio::io::duplex(64);
+        let budget = Arc::new(ByteBudget::new(None));
+        let relay = tokio::spawn(ProxyServer::relay_using(
+            RelayStrategy::SingleTask,
+            proxy_in,
+            proxy_out,
+            Arc::new(Metrics::new()),
+            budget.clone(),
+            Duration::from_secs(5),
+            None,
+        ));
+
+        // Larger than the pipes, and the target sends its whole response
+        // before reading anything: the relay has to keep reading from the
+        // target while its write towards the target is stuck
+        let request = vec![1u8; 32 * 1024];
+        let response = vec![2u8; 32 * 1024];
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let client_side = async {
+            let send = client_writer.write_all(&request);
+            let mut received = vec![0u8; response.len()];
+            let receive = client_reader.read_exact(&mut received);
+            let (sent, read) = tokio::join!(send, receive);
+            sent.unwrap();
+            read.unwrap();
+            received
+        };
+        let target_side = async {
+            target.write_all(&response).await.unwrap();
+            let mut received = vec![0u8; request.len()];
+            target.read_exact(&mut received).await.unwrap();
+            received
+        };
+        let (to_client, to_target) =
+            timeout(Duration::from_secs(5), async { tokio::join!(client_side, target_side) })
                 .await
-                {
-                    log::error!("Connection error: {}", e);
-                }
-            });
-        }
+                .expect("relay stalled with writes pending in both directions");
+        assert_eq!(to_target, request);
+        assert_eq!(to_client, response);
+
+        // The client's EOF reaches the target, and the relay reports it
+        let mut client = client_reader.unsplit(client_writer);
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(1), target.read_to_end(&mut rest)).await.unwrap().unwrap();
+        assert!(rest.is_empty());
+        let reason = timeout(Duration::from_secs(1), relay).await.unwrap().unwrap();
+        assert_eq!(reason, CloseReason::ClientEof);
+        assert_eq!(budget.buffered(), 0);
     }
 
-    /// Handle a single client connection
-    async fn handle_connection(
-        client_stream: TcpStream,
-        client_addr: std::net::SocketAddr,
-        tls_manager: Arc<TlsManager>,
-        crypto_provider: Arc<CryptoProvider>,
-        metrics: Arc<Metrics>,
-        connection_limit: Arc<Semaphore>,
-        config: Arc<ProxyConfig>,
-    ) -> Result<()> {
-        // Acquire connection permit
-        let _permit = connection_limit.acquire().await?;
+    #[tokio::test]
+    async fn test_close_reason_clean_close() {
+        let (client, proxy_in) = tokio::io::duplex(64);
+        let (proxy_out, _target) = tokio::io::duplex(64);
+        drop(client);
 
-        // Accept TLS connection
-        let client_tls = tls_manager.accept(client_stream).await?;
+        let reason = ProxyServer::relay(
+            proxy_in,
+            proxy_out,
+            Arc::new(Metrics::new()),
+            Arc::new(ByteBudget::new(None)),
+            Duration::from_secs(5),
+            None,
+        )
+        .await;
 
-        // Connect to target server
-        let target_stream = TcpStream::connect(&config.target_addr).await?;
-        let target_tls = tls_manager.connect(&config.target_host).await?;
+        assert_eq!(reason, CloseReason::ClientEof);
+    }
 
-        // Start proxying data
-        let (client_reader, client_writer) = tokio::io::split(client_tls);
-        let (target_reader, target_writer) = tokio::io::split(target_tls);
+    #[test]
+    fn test_close_reason_idle_timeout_recorded() {
+        let recorded = record(async {
+            use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
 
-        // Spawn bidirectional data transfer
-        let client_to_target = Self::proxy_data(
-            client_reader,
-            target_writer,
-            "client -> target",
-            metrics.clone(),
+            let client_config = ClientConfig::builder()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth();
+            let mut connection =
+                ClientConnection::new(Arc::new(client_config), "app.test".try_into().unwrap())
+                    .unwrap();
+            let mut hello = Vec::new();
+            connection.write_tls(&mut hello).unwrap();
+
+            // The upstream accepts and then stays silent
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target_addr = upstream.local_addr().unwrap();
+            let upstream_task = tokio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut received = Vec::new();
+                let _ = stream.read_to_end(&mut received).await;
+            });
+
+            let tls_config = Arc::new(crate::config::TlsConfig {
+                cert_path: "tests/fixtures/test.crt".into(),
+                key_path: "tests/fixtures/test.key".into(),
+                ..Default::default()
+            });
+            let metrics = Arc::new(Metrics::new());
+            let crypto_provider = Arc::new(CryptoProvider::from_config(&tls_config).unwrap());
+            let tls_manager = Arc::new(
+                TlsManager::new(tls_config, crypto_provider, metrics.clone()).unwrap(),
+            );
+            let config = ProxyConfig {
+                mode: ProxyMode::Passthrough,
+                target_addr,
+                timeout: 1,
+                max_connections: 10,
+                ..Default::default()
+            };
+            let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = socket.local_addr().unwrap();
+            let server = Arc::new(ProxyServer::with_listeners(
+                vec![(Arc::new(config), tls_manager)],
+                metrics,
+            ));
+            let serving = tokio::spawn({
+                let server = server.clone();
+                async move { server.serve_all(vec![socket]).await }
+            });
+
+            // After its ClientHello the client goes silent too
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(&hello).await.unwrap();
+            let mut reply = Vec::new();
+            timeout(Duration::from_secs(5), client.read_to_end(&mut reply))
+                .await
+                .expect("the proxy closes the idle connection")
+                .unwrap();
+            upstream_task.await.unwrap();
+
+            server.shutdown();
+            serving.await.unwrap().unwrap();
+        });
+
+        assert_eq!(
+            counters(&recorded, "connections_closed_total"),
+            vec![(vec!["reason=idle_timeout".to_string()], 1)]
         );
-        let target_to_client = Self::proxy_data(
-            target_reader,
-            client_writer,
-            "target -> client",
-            metrics.clone(),
+    }
+
+    #[test]
+    fn test_plaintext_client_closed_quietly() {
+        let recorded = record(async {
+            let (server, _, _) = setup_test_proxy().await;
+            let server = Arc::new(server);
+            let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = socket.local_addr().unwrap();
+            let serving = tokio::spawn({
+                let server = server.clone();
+                async move { server.serve_all(vec![socket]).await }
+            });
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\nHost: scan\r\n\r\n").await.unwrap();
+            let mut reply = Vec::new();
+            timeout(Duration::from_secs(5), client.read_to_end(&mut reply))
+                .await
+                .unwrap()
+                .unwrap();
+            // Closed without a TLS alert or any other response
+            assert!(reply.is_empty());
+
+            // Serving returns once the closed connection has been recorded
+            server.shutdown();
+            serving.await.unwrap().unwrap();
+        });
+
+        let counter = |name: &str| counters(&recorded, name);
+        assert_eq!(counter("non_tls_connections_total"), vec![(vec![], 1)]);
+        assert_eq!(
+            counter("connections_closed_total"),
+            vec![(vec!["reason=not_tls".to_string()], 1)]
         );
+        assert!(counter("handshake_errors_total").is_empty());
+        assert!(counter("handshake_negotiation_failures_total").is_empty());
+    }
 
-        // Wait for either direction to complete
-        tokio::select! {
-            result = client_to_target => {
-                if let Err(e) = result {
-                    log::error!("Client to target error: {}", e);
-                }
-            }
-            result = target_to_client => {
-                if let Err(e) = result {
-                    log::error!("Target to client error: {}", e);
+    #[tokio::test]
+    async fn test_listeners_serve_independently() {
+        use http_body_util::{BodyExt, Empty, Full};
+        use hyper_util::rt::TokioIo;
+        use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        async fn spawn_upstream(reply: &'static str) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let service = hyper::service::service_fn(move |_req| async move {
+                        Ok::<_, std::convert::Infallible>(http::Response::new(Full::new(
+                            bytes::Bytes::from_static(reply.as_bytes()),
+                        )))
+                    });
+                    tokio::spawn(
+                        hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service),
+                    );
                 }
-            }
+            });
+            addr
         }
 
-        Ok(())
-    }
+        async fn get(addr: SocketAddr) -> String {
+            let mut roots = RootCertStore::empty();
+            let cert = std::fs::read("tests/fixtures/test.crt").unwrap();
+            roots.add(CertificateDer::from(cert)).unwrap();
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            let (mut sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+            tokio::spawn(connection);
+            let request = http::Request::get("/")
+                .header("host", "localhost")
+                .body(Empty::<bytes::Bytes>::new())
+                .unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
 
-    /// Proxy data between two streams
-    async fn proxy_data<R, W>(
-        mut reader: R,
-        mut writer: W,
-        direction: &str,
-        metrics: Arc<Metrics>,
-    ) -> Result<()>
-    where
-        R: AsyncRead + Unpin,
-        W: AsyncWrite + Unpin,
-    {
-        let mut buffer = vec![0u8; 8192];
-        let mut total_bytes = 0;
+        let tls_config = Arc::new(crate::config::TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            ..Default::default()
+        });
+        let metrics = Arc::new(Metrics::new());
+        let crypto_provider = Arc::new(CryptoProvider::from_config(&tls_config).unwrap());
+        let tls_manager = Arc::new(
+            TlsManager::new(tls_config, crypto_provider, metrics.clone()).unwrap(),
+        );
 
-        loop {
-            let n = reader.read(&mut buffer).await?;
-            if n == 0 {
-                break;
-            }
+        let mut listeners = Vec::new();
+        let mut sockets = Vec::new();
+        let mut addrs = Vec::new();
+        for reply in ["internal", "external"] {
+            let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(socket.local_addr().unwrap());
+            sockets.push(socket);
+            let config = ProxyConfig {
+                mode: ProxyMode::Layer7,
+                upstream: format!("http://{}", spawn_upstream(reply).await),
+                max_connections: 10,
+                ..Default::default()
+            };
+            listeners.push((Arc::new(config), tls_manager.clone()));
+        }
 
-            writer.write_all(&buffer[..n]).await?;
-            total_bytes += n;
+        let server = Arc::new(ProxyServer::with_listeners(listeners, metrics));
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve_all(sockets).await }
+        });
 
-            // Record metrics
-            metrics.record_bytes_transferred(n);
-        }
+        assert_eq!(get(addrs[0]).await, "internal");
+        assert_eq!(get(addrs[1]).await, "external");
 
-        log::debug!("{}: transferred {} bytes", direction, total_bytes);
-        Ok(())
+        server.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .expect("shutdown drains both listeners")
+            .unwrap()
+            .unwrap();
+        for addr in addrs {
+            assert!(TcpStream::connect(addr).await.is_err());
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::config::{KemAlgorithm, SignatureAlgorithm};
-    use std::net::SocketAddr;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::time::Duration;
+    #[tokio::test]
+    async fn test_passthrough_routes_on_sni_without_terminating() {
+        use crate::config::RouteConfig;
+        use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
 
-    async fn setup_test_proxy() -> (ProxyServer, SocketAddr, SocketAddr) {
-        let proxy_config = Arc::new(ProxyConfig {
-            listen_addr: "127.0.0.1:0".parse().unwrap(),
-            target_addr: "127.0.0.1:0".parse().unwrap(),
-            target_host: "localhost".to_string(),
-            max_connections: 10,
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let mut connection =
+            ClientConnection::new(Arc::new(client_config), "app.test".try_into().unwrap()).unwrap();
+        let mut client_bytes = Vec::new();
+        connection.write_tls(&mut client_bytes).unwrap();
+        client_bytes.extend_from_slice(b"opaque application records");
+
+        // The routed upstream sees exactly what the client sent
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let expected_len = client_bytes.len();
+        let upstream_task = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut received = vec![0u8; expected_len];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(b"server flight").await.unwrap();
+            received
         });
 
         let tls_config = Arc::new(crate::config::TlsConfig {
-            cert_path: "tests/fixtures/test.crt".to_string(),
-            key_path: "tests/fixtures/test.key".to_string(),
-            server_addr: "127.0.0.1:0".parse().unwrap(),
-            kem_algorithm: KemAlgorithm::Kyber768,
-            signature_algorithm: SignatureAlgorithm::Dilithium3,
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            ..Default::default()
         });
-
         let metrics = Arc::new(Metrics::new());
-        let crypto_provider = Arc::new(CryptoProvider::new(
-            tls_config.kem_algorithm,
-            tls_config.signature_algorithm,
-            &tls_config.cert_path,
-            &tls_config.key_path,
-        ).unwrap());
+        let crypto_provider = Arc::new(CryptoProvider::from_config(&tls_config).unwrap());
+        let tls_manager = Arc::new(
+            TlsManager::new(tls_config, crypto_provider, metrics.clone()).unwrap(),
+        );
+        let config = ProxyConfig {
+            mode: ProxyMode::Passthrough,
+            // Nothing listens on the default target, so a misrouted client fails
+            target_addr: "127.0.0.1:9".parse().unwrap(),
+            routes: vec![RouteConfig {
+                server_name: "app.test".to_string(),
+                upstream: upstream_addr.to_string(),
+                timeouts: Default::default(),
+                allow_early_data: false,
+                bind_addr: None,
+                maintenance_page: None,
+                dscp: None,
+                health_check: None,
+                header_rules: Default::default(),
+                forward_sni: false,
+            }],
+            max_connections: 10,
+            ..Default::default()
+        };
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = socket.local_addr().unwrap();
+        let server = Arc::new(ProxyServer::with_listeners(
+            vec![(Arc::new(config), tls_manager)],
+            metrics,
+        ));
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.serve_all(vec![socket]).await }
+        });
 
-        let tls_manager = Arc::new(TlsManager::new(
-            tls_config,
-            crypto_provider.clone(),
-            metrics.clone(),
-        ).unwrap());
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&client_bytes).await.unwrap();
+        let mut reply = [0u8; 13];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"server flight");
+        assert_eq!(upstream_task.await.unwrap(), client_bytes);
 
-        let proxy_server = ProxyServer::new(
-            proxy_config.clone(),
-            tls_manager,
-            crypto_provider,
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_probe_of_blackholed_upstream_times_out() {
+        // Connections are queued in the backlog but never answered
+        let blackhole = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tls_config = Arc::new(crate::config::TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            ..Default::default()
+        });
+        let metrics = Arc::new(Metrics::new());
+        let crypto_provider = Arc::new(CryptoProvider::from_config(&tls_config).unwrap());
+        let tls_manager = Arc::new(
+            TlsManager::new(tls_config, crypto_provider, metrics.clone()).unwrap(),
+        );
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer4,
+            target_addr: blackhole.local_addr().unwrap(),
+            timeout: 1,
+            probe_upstreams_on_start: true,
+            upstream_probe_action: ProbeAction::Fail,
+            ..Default::default()
+        };
+        let server = ProxyServer::with_listeners(
+            vec![(Arc::new(config), tls_manager)],
             metrics,
         );
 
-        let proxy_listener = TcpListener::bind(proxy_config.listen_addr).await.unwrap();
-        let proxy_addr = proxy_listener.local_addr().unwrap();
+        let probed = timeout(Duration::from_secs(5), server.probe_upstreams())
+            .await
+            .expect("the probe gives up at the handshake timeout");
+        match probed {
+            Err(SafeQuantaError::Handshake(message)) => assert!(message.contains("within 1s")),
+            other => panic!("unexpected probe result: {:?}", other),
+        }
+    }
 
-        let target_listener = TcpListener::bind(proxy_config.target_addr).await.unwrap();
-        let target_addr = target_listener.local_addr().unwrap();
+    #[test]
+    fn test_weighted_targets_share_unrouted_connections() {
+        use crate::config::{RouteConfig, WeightedTarget};
 
-        (proxy_server, proxy_addr, target_addr)
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer4,
+            weighted_targets: vec![
+                WeightedTarget {
+                    addr: "big:443".to_string(),
+                    weight: 3,
+                },
+                WeightedTarget {
+                    addr: "small:443".to_string(),
+                    weight: 1,
+                },
+            ],
+            routes: vec![RouteConfig {
+                server_name: "app.test".to_string(),
+                upstream: "routed:443".to_string(),
+                timeouts: Default::default(),
+                allow_early_data: false,
+                bind_addr: None,
+                maintenance_page: None,
+                dscp: None,
+                health_check: None,
+                header_rules: Default::default(),
+                forward_sni: false,
+            }],
+            ..Default::default()
+        };
+        let set = upstream_set(&config);
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..8 {
+            *counts.entry(default_target(&config, &set).unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(counts["big:443"], 6);
+        assert_eq!(counts["small:443"], 2);
+        assert!(!counts.contains_key("routed:443"));
+
+        // A drained target gets no new connections
+        set.drain_upstream("big:443");
+        assert_eq!(default_target(&config, &set).unwrap(), "small:443");
+        set.drain_upstream("small:443");
+        assert!(default_target(&config, &set).is_err());
+    }
+
+    #[test]
+    fn test_saturated_target_fails_over_to_another() {
+        use crate::config::WeightedTarget;
+
+        let target = |addr: &str| WeightedTarget {
+            addr: addr.to_string(),
+            weight: 1,
+        };
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer4,
+            weighted_targets: vec![target("a:443"), target("b:443")],
+            routes: vec![route("app.test", "routed:443")],
+            max_connections_per_upstream: Some(1),
+            upstream_saturated_action: SaturatedAction::Failover,
+            ..Default::default()
+        };
+        let set = upstream_set(&config).with_connection_limit(config.max_connections_per_upstream);
+        let claim = |config: &ProxyConfig, route: Option<&RouteConfig>, addr: &str| {
+            ProxyServer::claim_upstream(&set, config, route, addr.to_string()).map(|(addr, _)| addr)
+        };
+
+        let _a = ProxyServer::claim_upstream(&set, &config, None, "a:443".to_string()).unwrap();
+        assert_eq!(claim(&config, None, "a:443").unwrap(), "b:443");
+
+        // A routed connection stays on its route's upstream
+        let _routed =
+            ProxyServer::claim_upstream(&set, &config, None, "routed:443".to_string()).unwrap();
+        assert!(claim(&config, config.route_for(Some("app.test")), "routed:443").is_err());
+
+        let rejecting = ProxyConfig {
+            upstream_saturated_action: SaturatedAction::Reject,
+            ..config.clone()
+        };
+        assert!(claim(&rejecting, None, "a:443").is_err());
     }
 
     #[tokio::test]
-    async fn test_proxy_data_transfer() {
-        let (proxy_server, proxy_addr, target_addr) = setup_test_proxy().await;
+    async fn test_forward_sni_reaches_upstream() {
+        use tokio_rustls::rustls::pki_types::ServerName;
 
-        // Start target server
-        let target_server = tokio::spawn(async move {
-            let listener = TcpListener::bind(target_addr).await.unwrap();
-            let (stream, _) = listener.accept().await.unwrap();
-            let mut tls_stream = proxy_server.tls_manager.accept(stream).await.unwrap();
-            
-            let mut buf = [0u8; 1024];
-            let n = tls_stream.read(&mut buf).await.unwrap();
-            assert_eq!(&buf[..n], b"hello");
-            
-            tls_stream.write_all(b"world").await.unwrap();
+        assert_eq!(forwarded_server_name(None).unwrap(), None);
+        assert!(forwarded_server_name(Some("10.0.0.1")).is_err());
+        assert!(forwarded_server_name(Some("not a name")).is_err());
+
+        // The routed upstream sees the client's SNI in its ClientHello
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let upstream_sni = tokio::spawn(async move {
+            let (stream, _) = target.accept().await.unwrap();
+            crate::fingerprint::peek_client_hello(&stream).await.unwrap().server_name
         });
 
-        // Connect client to proxy
-        let client_stream = TcpStream::connect(proxy_addr).await.unwrap();
-        let mut client_tls = proxy_server.tls_manager.connect("localhost").await.unwrap();
-        
-        client_tls.write_all(b"hello").await.unwrap();
-        
-        let mut buf = [0u8; 1024];
-        let n = client_tls.read(&mut buf).await.unwrap();
-        assert_eq!(&buf[..n], b"world");
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer4,
+            target_addr,
+            target_host: "backend.internal".to_string(),
+            forward_sni: true,
+            upstream_tls: UpstreamTls::On,
+            max_connections: 10,
+            ..Default::default()
+        };
+        let (server, proxy_addr, connector) = spawn_tls_proxy(config).await;
 
-        target_server.await.unwrap();
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let _client = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        let sni = timeout(Duration::from_secs(5), upstream_sni).await.unwrap().unwrap();
+        assert_eq!(sni.as_deref(), Some("localhost"));
+
+        server.shutdown();
     }
 
     #[tokio::test]
-    async fn test_connection_limit() {
-        let (proxy_server, proxy_addr, _) = setup_test_proxy().await;
-        let mut handles = vec![];
+    async fn test_connection_summary_reports_byte_totals() {
+        let (mut client, proxy_in) = tokio::io::duplex(64);
+        let (proxy_out, mut target) = tokio::io::duplex(64);
+        let stats = Arc::new(ConnectionStats::new());
+        stats.set_negotiated("X25519MLKEM768/TLS13_AES_256_GCM_SHA384".into());
 
-        // Try to establish more connections than the limit
-        for _ in 0..proxy_server.config.max_connections + 1 {
-            let handle = tokio::spawn(async move {
-                let stream = TcpStream::connect(proxy_addr).await;
-                stream
-            });
-            handles.push(handle);
+        let relay = tokio::spawn(ProxyServer::relay(
+            CountingStream::new(proxy_in, stats.clone()),
+            proxy_out,
+            Arc::new(Metrics::new()),
+            Arc::new(ByteBudget::new(None)),
+            Duration::from_secs(5),
+            None,
+        ));
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        target.read_exact(&mut buf).await.unwrap();
+        target.write_all(b"world!").await.unwrap();
+        let mut buf = [0u8; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        let reason = relay.await.unwrap();
+
+        let summary = stats.summary("127.0.0.1:4000".parse().unwrap(), reason);
+        assert!(summary.starts_with(&format!("connection_summary id={} ", stats.id)));
+        assert!(summary.contains("client_to_target_bytes=5 target_to_client_bytes=6 "));
+        assert!(summary.contains("algorithms=X25519MLKEM768/TLS13_AES_256_GCM_SHA384"));
+        assert!(summary.ends_with("reason=client_eof"));
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only_controls_v4_mapped_clients() {
+        for (ipv6_only, v4_accepted) in [(false, true), (true, false)] {
+            // Skip on hosts without IPv6
+            let Ok(listener) = ProxyServer::bind("[::]:0".parse().unwrap(), Some(ipv6_only)) else {
+                return;
+            };
+            let port = listener.local_addr().unwrap().port();
+            let v4 = TcpStream::connect(("127.0.0.1", port)).await;
+            assert_eq!(v4.is_ok(), v4_accepted, "ipv6_only: {}", ipv6_only);
+            if v4_accepted {
+                let (_, peer) = listener.accept().await.unwrap();
+                assert_eq!(peer.ip().to_canonical(), std::net::Ipv4Addr::LOCALHOST);
+            }
         }
+    }
 
-        // Wait for all connection attempts
-        let results = futures::future::join_all(handles).await;
-        
-        // Count successful connections
-        let successful = results.iter()
-            .filter(|r| r.as_ref().unwrap().is_ok())
-            .count();
+    #[tokio::test]
+    async fn test_subscribers_see_connection_lifecycle() {
+        use tokio_rustls::rustls::pki_types::ServerName;
+
+        // Plaintext echo upstream
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer4,
+            target_addr,
+            upstream_tls: UpstreamTls::Off,
+            max_connections: 10,
+            ..Default::default()
+        };
+        let (server, proxy_addr, connector) = spawn_tls_proxy(config).await;
+        let mut events = server.subscribe();
+
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let client_addr = stream.local_addr().unwrap();
+        let mut client = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut echoed = [0u8; 5];
+        client.read_exact(&mut echoed).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        async fn next(events: &mut broadcast::Receiver<Event>) -> Event {
+            timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap()
+        }
+        let opened = next(&mut events).await;
+        assert_eq!(opened, Event::Opened { id: opened.id(), peer: client_addr });
+        assert!(matches!(
+            next(&mut events).await,
+            Event::HandshakeCompleted { id, peer, .. } if id == opened.id() && peer == client_addr
+        ));
+        match next(&mut events).await {
+            Event::Closed {
+                id,
+                client_to_target_bytes,
+                target_to_client_bytes,
+                error,
+                ..
+            } => {
+                assert_eq!(id, opened.id());
+                assert_eq!((client_to_target_bytes, target_to_client_bytes), (5, 5));
+                assert_eq!(error, None);
+            }
+            event => panic!("expected Closed, got {:?}", event),
+        }
+
+        server.shutdown();
+    }
 
-        assert_eq!(successful, proxy_server.config.max_connections);
+    #[tokio::test]
+    async fn test_unmatched_server_name_rejected_with_alert() {
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::AlertDescription;
+
+        // Plaintext echo upstream for the one route
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let route_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let config = ProxyConfig {
+            mode: ProxyMode::Layer4,
+            upstream_tls: UpstreamTls::Off,
+            routes: vec![route("localhost", &route_addr.to_string())],
+            unmatched_route: UnmatchedRoute::Reject,
+            max_connections: 10,
+            ..Default::default()
+        };
+        let (server, proxy_addr, connector) = spawn_tls_proxy(config).await;
+
+        // The routed name reaches its upstream
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut client = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut echoed = [0u8; 5];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+
+        // Any other name is refused before the handshake
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let err = connector
+            .connect(ServerName::try_from("unknown.test").unwrap(), stream)
+            .await
+            .unwrap_err();
+        let alert = err.get_ref().and_then(|e| e.downcast_ref::<tokio_rustls::rustls::Error>());
+        assert!(
+            matches!(
+                alert,
+                Some(tokio_rustls::rustls::Error::AlertReceived(AlertDescription::UnrecognisedName))
+            ),
+            "{}",
+            err
+        );
+
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_ready_fires_once_listening() {
+        let (server, _, _) = setup_test_proxy().await;
+        let server = Arc::new(server);
+        let ready = server.ready();
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move { server.start(Vec::new()).await }
+        });
+
+        let addrs = timeout(Duration::from_secs(5), ready).await.unwrap().unwrap();
+        assert_eq!(addrs.len(), 1);
+        // The configured port 0 resolved to the port actually bound
+        assert_ne!(addrs[0].port(), 0);
+        TcpStream::connect(addrs[0]).await.unwrap();
+
+        server.shutdown();
+        serving.await.unwrap().unwrap();
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,242 @@
+use crate::jsonl::JsonLinesWriter;
+use crate::l7::ProxyBody;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, HeaderName, Request};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Request headers whose values are recorded as `"<redacted>"`
+pub const REDACTED_HEADERS: [HeaderName; 3] =
+    [header::AUTHORIZATION, header::COOKIE, header::PROXY_AUTHORIZATION];
+
+/// Body bytes kept per request with `record_request_bodies`
+pub const MAX_CAPTURED_BODY_BYTES: usize = 64 * 1024;
+
+/// One recorded L7 request, written as a line of JSON
+///
+/// Holds what is needed to replay the request against another backend: the
+/// body itself only when full capture is enabled, its hash otherwise.
+/// Credentials in `REDACTED_HEADERS` are never recorded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub time_unix_ms: u128,
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body_len: u64,
+    /// Lowercase hex SHA-256 of the body
+    pub body_sha256: String,
+    /// Base64 body, with `record_request_bodies`; at most
+    /// `MAX_CAPTURED_BODY_BYTES` of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Whether `body` was cut short at `MAX_CAPTURED_BODY_BYTES`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub body_truncated: bool,
+    /// False if the client or upstream stopped before the body was complete
+    pub complete: bool,
+}
+
+/// Appends a `RecordedRequest` per proxied L7 request to a file
+pub struct RequestRecorder {
+    capture_bodies: bool,
+    writer: JsonLinesWriter,
+}
+
+impl RequestRecorder {
+    pub fn new(path: impl Into<PathBuf>, capture_bodies: bool) -> Self {
+        Self {
+            capture_bodies,
+            writer: JsonLinesWriter::spawn(path, None),
+        }
+    }
+
+    /// Wait until every request recorded so far is in the file
+    pub fn flush(&self) {
+        self.writer.flush();
+    }
+
+    /// Wrap the body of `req` so the request is recorded once its body ends
+    pub fn record(self: &Arc<Self>, req: Request<ProxyBody>) -> Request<ProxyBody> {
+        let entry = RecordedRequest {
+            time_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: format!("{:?}", req.version()),
+            headers: req
+                .headers()
+                .iter()
+                .map(|(name, value)| {
+                    let value = if REDACTED_HEADERS.contains(name) {
+                        "<redacted>".to_string()
+                    } else {
+                        String::from_utf8_lossy(value.as_bytes()).into_owned()
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+            body_len: 0,
+            body_sha256: String::new(),
+            body: None,
+            body_truncated: false,
+            complete: false,
+        };
+        let recorder = self.clone();
+        req.map(|inner| {
+            RecordingBody {
+                inner,
+                hash: ring::digest::Context::new(&ring::digest::SHA256),
+                captured: recorder.capture_bodies.then(Vec::new),
+                entry: Some(entry),
+                recorder,
+            }
+            .boxed()
+        })
+    }
+}
+
+/// Request body hashing (and optionally keeping) what passes through, which
+/// writes the record when it ends or is dropped
+struct RecordingBody {
+    inner: ProxyBody,
+    hash: ring::digest::Context,
+    captured: Option<Vec<u8>>,
+    /// Taken when the record is written
+    entry: Option<RecordedRequest>,
+    recorder: Arc<RequestRecorder>,
+}
+
+impl RecordingBody {
+    fn finish(&mut self, complete: bool) {
+        let Some(mut entry) = self.entry.take() else {
+            return;
+        };
+        let digest = self.hash.clone().finish();
+        entry.body_sha256 = digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        entry.body = self.captured.take().map(|body| BASE64.encode(&body));
+        entry.body_truncated =
+            entry.body.is_some() && entry.body_len > MAX_CAPTURED_BODY_BYTES as u64;
+        entry.complete = complete;
+        self.recorder.writer.append(&entry);
+    }
+}
+
+impl Body for RecordingBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(entry)) = (frame.data_ref(), this.entry.as_mut()) {
+                    this.hash.update(data);
+                    entry.body_len += data.len() as u64;
+                    if let Some(captured) = &mut this.captured {
+                        let room = MAX_CAPTURED_BODY_BYTES.saturating_sub(captured.len());
+                        captured.extend_from_slice(&data[..data.len().min(room)]);
+                    }
+                }
+            }
+            Some(Err(_)) => this.finish(false),
+            None => this.finish(true),
+        }
+        if this.inner.is_end_stream() {
+            this.finish(true);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for RecordingBody {
+    fn drop(&mut self) {
+        // Bodies hyper never polls, e.g. an empty GET, end here
+        let complete = self.inner.is_end_stream();
+        self.finish(complete);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+
+    async fn record_post(capture_bodies: bool, body: Bytes) -> RecordedRequest {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("requests.jsonl");
+        let recorder = Arc::new(RequestRecorder::new(&path, capture_bodies));
+
+        let request = Request::post("/orders?id=7")
+            .header("host", "app.test")
+            .header("content-type", "text/plain")
+            .header("authorization", "Bearer s3cret")
+            .header("cookie", "session=abc")
+            .body(Full::new(body.clone()).map_err(|never| match never {}).boxed())
+            .unwrap();
+        let forwarded = recorder.record(request).into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(forwarded, body);
+        recorder.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        serde_json::from_str(lines[0]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_recorded_with_body_hash() {
+        let recorded = record_post(false, Bytes::from_static(b"hello")).await;
+        assert_eq!(recorded.method, "POST");
+        assert_eq!(recorded.uri, "/orders?id=7");
+        assert_eq!(recorded.version, "HTTP/1.1");
+        assert!(recorded.headers.contains(&("host".to_string(), "app.test".to_string())));
+        assert!(recorded.headers.contains(&("authorization".to_string(), "<redacted>".to_string())));
+        assert!(recorded.headers.contains(&("cookie".to_string(), "<redacted>".to_string())));
+        assert_eq!(recorded.body_len, 5);
+        // sha256("hello")
+        assert_eq!(
+            recorded.body_sha256,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(recorded.body.is_none());
+        assert!(recorded.complete);
+    }
+
+    #[tokio::test]
+    async fn test_full_body_capture_opt_in() {
+        let recorded = record_post(true, Bytes::from_static(b"hello")).await;
+        assert_eq!(recorded.body.as_deref(), Some("aGVsbG8="));
+        assert!(!recorded.body_truncated);
+
+        // Large bodies are forwarded whole but captured only up to the cap
+        let large = Bytes::from(vec![b'x'; MAX_CAPTURED_BODY_BYTES + 1]);
+        let recorded = record_post(true, large).await;
+        assert_eq!(recorded.body_len, MAX_CAPTURED_BODY_BYTES as u64 + 1);
+        let kept = BASE64.encode([b'x'; MAX_CAPTURED_BODY_BYTES]);
+        assert_eq!(recorded.body, Some(kept));
+        assert!(recorded.body_truncated);
+    }
+}
@@ -0,0 +1,144 @@
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Length of a sampling window
+const WINDOW: Duration = Duration::from_secs(1);
+
+struct Window {
+    start: Instant,
+    emitted: u32,
+    suppressed: u64,
+}
+
+/// Caps how many lines of one kind are logged per second
+///
+/// Lines beyond the cap are dropped and counted; the count is reported in a
+/// single "suppressed" line when the next line is let through in a later
+/// window, by `report_suppressed` once the window ends, or when the sampler
+/// is dropped.
+pub struct LogSampler {
+    kind: &'static str,
+    per_sec: u32,
+    window: Mutex<Window>,
+}
+
+impl LogSampler {
+    /// Sampler for lines of `kind`, e.g. `"connection error"`
+    pub fn new(kind: &'static str, per_sec: u32) -> Self {
+        Self {
+            kind,
+            per_sec: per_sec.max(1),
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                emitted: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    /// Run `emit` (which logs the line) unless this second's cap is reached
+    pub fn log(&self, emit: impl FnOnce()) {
+        if let Some(suppressed) = self.admit_at(Instant::now()) {
+            if suppressed > 0 {
+                self.log_suppressed(suppressed);
+            }
+            emit();
+        }
+    }
+
+    /// Report suppressed lines once their window ends, until `shutdown`
+    ///
+    /// Without this a burst's summary waits for the next line, which may
+    /// never come once the errors stop.
+    pub async fn report_suppressed(&self, shutdown: CancellationToken) {
+        let mut interval = tokio::time::interval(WINDOW);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+            if let Some(suppressed) = self.take_suppressed_at(Instant::now()) {
+                self.log_suppressed(suppressed);
+            }
+        }
+    }
+
+    fn log_suppressed(&self, suppressed: u64) {
+        log::warn!("Suppressed {} {} messages", suppressed, self.kind);
+    }
+
+    /// Lines suppressed in a window that has ended by `now`, if any
+    fn take_suppressed_at(&self, now: Instant) -> Option<u64> {
+        let mut window = self.window.lock();
+        if window.suppressed == 0 || now.duration_since(window.start) < WINDOW {
+            return None;
+        }
+        Some(std::mem::take(&mut window.suppressed))
+    }
+
+    /// Whether a line at `now` is logged
+    ///
+    /// `Some` with the number of lines suppressed since the last one let
+    /// through, `None` if this line is suppressed.
+    fn admit_at(&self, now: Instant) -> Option<u64> {
+        let mut window = self.window.lock();
+        if now.duration_since(window.start) >= WINDOW {
+            window.start = now;
+            window.emitted = 0;
+        }
+        if window.emitted >= self.per_sec {
+            window.suppressed += 1;
+            return None;
+        }
+        window.emitted += 1;
+        Some(std::mem::take(&mut window.suppressed))
+    }
+}
+
+impl Drop for LogSampler {
+    fn drop(&mut self) {
+        let suppressed = self.window.get_mut().suppressed;
+        if suppressed > 0 {
+            self.log_suppressed(suppressed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_is_sampled_then_summarized() {
+        let sampler = LogSampler::new("connection error", 3);
+        let start = Instant::now();
+
+        let burst: Vec<_> = (0..100)
+            .map(|i| sampler.admit_at(start + Duration::from_millis(i)))
+            .collect();
+        assert_eq!(burst.iter().filter(|admitted| admitted.is_some()).count(), 3);
+        assert!(burst[..3].iter().all(|admitted| *admitted == Some(0)));
+
+        // The first line of the next second carries the suppression count
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(sampler.admit_at(later), Some(97));
+        assert_eq!(sampler.admit_at(later), Some(0));
+    }
+
+    #[test]
+    fn test_summary_taken_once_window_ends() {
+        let sampler = LogSampler::new("connection error", 1);
+        let start = Instant::now();
+        for i in 0..3 {
+            sampler.admit_at(start + Duration::from_millis(i));
+        }
+
+        // Not before the window ends, and only once after
+        assert_eq!(sampler.take_suppressed_at(start + Duration::from_millis(500)), None);
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(sampler.take_suppressed_at(later), Some(2));
+        assert_eq!(sampler.take_suppressed_at(later), None);
+        assert_eq!(sampler.admit_at(later), Some(0));
+    }
+}
@@ -0,0 +1,195 @@
+//! Loopback proxy harness for integration tests
+//!
+//! Only built with the `test-util` feature.
+
+use crate::config::{Config, ProxyMode};
+use crate::crypto::CryptoProvider;
+use crate::error::{Result, SafeQuantaError};
+use crate::metrics::Metrics;
+use crate::proxy::ProxyServer;
+use crate::tls::TlsManager;
+use bytes::Bytes;
+use http::{Request, Response};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// A proxy running on an ephemeral loopback port in front of an echo upstream
+pub struct TestProxyHandle {
+    /// Address clients connect to
+    pub proxy_addr: SocketAddr,
+    /// Address of the in-process echo upstream
+    pub upstream_addr: SocketAddr,
+    cert_path: PathBuf,
+    shutdown: Option<oneshot::Sender<()>>,
+    proxy_task: JoinHandle<Result<()>>,
+    upstream_task: JoinHandle<()>,
+}
+
+/// Start a proxy for `config` on `127.0.0.1:0` with an echo upstream
+///
+/// The listen and upstream addresses in `config` are overwritten. In L7 mode
+/// the upstream answers every HTTP/1.1 request with its body; in L4 and
+/// passthrough mode it echoes the raw byte stream.
+pub async fn spawn_test_proxy(mut config: Config) -> Result<TestProxyHandle> {
+    let upstream = TcpListener::bind("127.0.0.1:0").await?;
+    let upstream_addr = upstream.local_addr()?;
+    let upstream_task = match config.proxy.mode {
+        ProxyMode::Layer7 => tokio::spawn(http_echo(upstream)),
+        ProxyMode::Layer4 | ProxyMode::Passthrough => tokio::spawn(tcp_echo(upstream)),
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+    config.proxy.listen_addr = proxy_addr;
+    config.proxy.target_addr = upstream_addr;
+    config.proxy.upstream = format!("http://{}", upstream_addr);
+    config.tls.server_addr = upstream_addr;
+
+    let metrics = Arc::new(Metrics::new());
+    let crypto_provider = Arc::new(CryptoProvider::from_config(&config.tls)?);
+    let cert_path = config.tls.cert_path.clone();
+    let connect_timeout = std::time::Duration::from_millis(config.proxy.connect_timeout_ms);
+    let tls_manager = Arc::new(
+        TlsManager::new(Arc::new(config.tls), crypto_provider, metrics.clone())?
+            .with_connect_timeout(connect_timeout),
+    );
+    let server = ProxyServer::new(Arc::new(config.proxy), tls_manager, metrics);
+
+    let (shutdown, shutdown_rx) = oneshot::channel();
+    let proxy_task = tokio::spawn(async move {
+        tokio::select! {
+            result = server.serve(listener) => result,
+            _ = shutdown_rx => Ok(()),
+        }
+    });
+
+    Ok(TestProxyHandle {
+        proxy_addr,
+        upstream_addr,
+        cert_path,
+        shutdown: Some(shutdown),
+        proxy_task,
+        upstream_task,
+    })
+}
+
+impl TestProxyHandle {
+    /// Open a TLS connection to the proxy, trusting its configured certificate
+    pub async fn connect(&self, server_name: &str) -> Result<TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(std::fs::read(&self.cert_path)?))?;
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|e| SafeQuantaError::InvalidConfig(format!("Invalid server name: {}", e)))?;
+
+        let stream = TcpStream::connect(self.proxy_addr).await?;
+        Ok(TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?)
+    }
+
+    /// Stop accepting connections and tear down the echo upstream
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.upstream_task.abort();
+        (&mut self.proxy_task)
+            .await
+            .map_err(|e| SafeQuantaError::Proxy(format!("Proxy task failed: {}", e)))?
+    }
+}
+
+impl Drop for TestProxyHandle {
+    fn drop(&mut self) {
+        self.proxy_task.abort();
+        self.upstream_task.abort();
+    }
+}
+
+async fn http_echo(listener: TcpListener) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let service = service_fn(|req: Request<Incoming>| async move {
+                let body = req.into_body().collect().await?.to_bytes();
+                Ok::<_, hyper::Error>(Response::new(Full::<Bytes>::new(body)))
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+async fn tcp_echo(listener: TcpListener) {
+    while let Ok((mut stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let mut buf = [0u8; 8192];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip_through_proxy() {
+        let mut config = Config::default();
+        config.proxy.mode = ProxyMode::Layer7;
+        config.tls.cert_path = "tests/fixtures/test.crt".into();
+        config.tls.key_path = "tests/fixtures/test.key".into();
+        let proxy = spawn_test_proxy(config).await.unwrap();
+
+        let stream = proxy.connect("localhost").await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::post("/echo")
+            .header("host", "localhost")
+            .body(Full::new(Bytes::from_static(b"ping")))
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert!(response.status().is_success());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"ping");
+
+        proxy.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_accepting() {
+        let mut config = Config::default();
+        config.tls.cert_path = "tests/fixtures/test.crt".into();
+        config.tls.key_path = "tests/fixtures/test.key".into();
+        let proxy = spawn_test_proxy(config).await.unwrap();
+        let proxy_addr = proxy.proxy_addr;
+
+        proxy.shutdown().await.unwrap();
+        assert!(TcpStream::connect(proxy_addr).await.is_err());
+    }
+}
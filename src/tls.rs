@@ -1,14 +1,41 @@
-use crate::config::TlsConfig;
+use crate::config::{
+    ClientAuthConfig, FallbackStrategy, KemAlgorithm, RekeyAction, SignatureAlgorithm, TlsConfig,
+};
 use crate::crypto::CryptoProvider;
 use crate::error::{Result, SafeQuantaError};
+use crate::fingerprint::ClientHello;
+use crate::handshake::MAX_FIELD_LEN;
 use crate::metrics::Metrics;
+use crate::policy::{AlgorithmPolicy, Policy, PolicyStore};
+use bytes::{Bytes, BytesMut};
+use rand::rngs::OsRng;
+use rand_core::RngCore;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::{
+    Acceptor, NoServerSessionStorage, ProducesTickets, ResolvesServerCert,
+    ServerSessionMemoryCache, WebPkiClientVerifier,
+};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::crypto::aws_lc_rs::{self, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES};
 use tokio_rustls::rustls::{
-    Certificate, PrivateKey, ServerConfig, ServerName,
+    CipherSuite, ClientConfig, CommonState, DigitallySignedStruct, NamedGroup, ProtocolVersion,
+    RootCertStore, ServerConfig, SignatureScheme, SupportedCipherSuite,
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::{client, LazyConfigAcceptor, TlsAcceptor, TlsConnector};
 
 /// TLS connection manager
 pub struct TlsManager {
@@ -16,8 +43,22 @@ pub struct TlsManager {
     crypto_provider: Arc<CryptoProvider>,
     metrics: Arc<Metrics>,
     acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    /// Certificates presented to clients, in order of preference
+    identities: Vec<Arc<CertifiedKey>>,
+    allowed_client_keys: Vec<Vec<u8>>,
+    /// Per-connection algorithm policy from `policy_path`
+    policy: Option<PolicyAcceptor>,
+    /// Limit on the TCP connect in `connect`/`connect_to`
+    connect_timeout: Duration,
 }
 
+/// Length of the client authentication nonce
+const CHALLENGE_LEN: usize = 32;
+
+/// TCP connect timeout for upstream connections unless overridden
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl TlsManager {
     /// Create a new TLS manager
     pub fn new(
@@ -25,63 +66,435 @@ impl TlsManager {
         crypto_provider: Arc<CryptoProvider>,
         metrics: Arc<Metrics>,
     ) -> Result<Self> {
-        // Load TLS certificate and private key
-        let cert = Certificate(std::fs::read(&config.cert_path)?);
-        let key = PrivateKey(std::fs::read(&config.key_path)?);
+        // rustls never requests post-handshake client auth and rejects any
+        // unsolicited post-handshake certificate messages
+        if config.post_handshake_auth {
+            return Err(SafeQuantaError::InvalidConfig(
+                "post_handshake_auth is not supported and must be disabled".into(),
+            ));
+        }
 
-        // Configure TLS server
-        let mut server_config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert], key)?;
+        config.ensure_algorithms_enabled()?;
+        config.ensure_matched_security_level()?;
+        config.ensure_pqc_certificate()?;
 
-        // Enable quantum-safe cipher suites
-        server_config.cipher_suites = vec![
-            // TODO: Add quantum-safe cipher suites
-            // This will be implemented when we add the actual crypto implementations
-        ];
+        let allowed_client_keys = if config.client_auth.enabled {
+            if config.client_auth.allowed_client_keys.is_empty() {
+                return Err(SafeQuantaError::InvalidConfig(
+                    "client_auth is enabled but allowed_client_keys is empty".into(),
+                ));
+            }
+            // Early data would arrive before the challenge response
+            if config.max_early_data_size > 0 {
+                return Err(SafeQuantaError::InvalidConfig(
+                    "client_auth cannot be combined with max_early_data_size".into(),
+                ));
+            }
+            config
+                .client_auth
+                .allowed_client_keys
+                .iter()
+                .map(std::fs::read)
+                .collect::<std::io::Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+
+        // Key exchange groups minus any disabled for incident response, and
+        // minus classical ones with require_pqc. Clients offering none of the
+        // remaining groups fail with a handshake_failure alert.
+        let mut provider = aws_lc_rs::default_provider();
+        provider
+            .kx_groups
+            .retain(|group| !config.algorithm_disabled(&format!("{:?}", group.name())));
+        if config.require_pqc {
+            provider.kx_groups.retain(|group| is_quantum_safe_group(group.name()));
+        }
+        if provider.kx_groups.is_empty() {
+            return Err(SafeQuantaError::InvalidConfig(
+                "disabled_algorithms and require_pqc leave no key exchange groups".into(),
+            ));
+        }
+
+        // Upstream connections offer the same groups and trust the system
+        // roots plus any configured upstream CAs
+        let roots = upstream_roots(&config, rustls_native_certs::load_native_certs)?;
+        let provider = Arc::new(provider);
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let mut client_config = if roots.is_empty() {
+            // Nothing to verify against: every upstream handshake fails anyway
+            builder.with_root_certificates(roots)
+        } else {
+            let verifier = ChainDepthVerifier::new(roots, &provider, config.max_chain_depth)?;
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+        }
+        .with_no_client_auth();
+
+        // Record padding is agreed with the peer proxy through ALPN
+        if config.record_padding == Some(0) {
+            return Err(SafeQuantaError::InvalidConfig("record_padding must be positive".into()));
+        }
+        if config.record_padding.is_some() {
+            client_config.alpn_protocols = vec![crate::padding::PADDING_ALPN.to_vec()];
+        }
+
+        // Configure TLS server, with the configured cipher suites in
+        // preference order
+        let mut server_provider = (*provider).clone();
+        server_provider.cipher_suites = resolve_cipher_suites(&config.cipher_suites)?;
+        let provider = Arc::new(server_provider);
+        // Load TLS certificate and private key (DER), then the PEM alternate
+        let mut identities = vec![load_der_identity(&provider, &config.cert_path, &config.key_path)?];
+        if let Some(alternate) = &config.alternate_identity {
+            identities.push(load_identity(&provider, &alternate.cert_path, &alternate.key_path)?);
+        }
+        // The configured signature_algorithm is only a label: check the
+        // certificate actually loaded
+        if config.require_pqc {
+            let leaf = identities[0].cert.first().map(|cert| cert.as_ref());
+            if leaf.and_then(certificate_algorithm) != Some(SignatureAlgorithm::Dilithium3) {
+                return Err(SafeQuantaError::InvalidConfig(format!(
+                    "require_pqc is set but {} is not a Dilithium3 certificate",
+                    config.cert_path.display()
+                )));
+            }
+        }
+        let server_config = server_config(&config, provider.clone(), identities.clone())?;
+        let policy = match &config.policy_path {
+            Some(path) => Some(PolicyAcceptor::watch(
+                path.clone(),
+                config.clone(),
+                provider,
+                identities.clone(),
+            )?),
+            None => None,
+        };
 
         Ok(Self {
             config,
             crypto_provider,
             metrics,
             acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            identities,
+            allowed_client_keys,
+            policy,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
         })
     }
 
+    /// Fail upstream connections whose TCP connect takes longer than `timeout`
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Signature algorithm of the certificate shown to a client whose
+    /// ClientHello offered the signature schemes `offered`
+    ///
+    /// `None` if that certificate's algorithm is not one this proxy knows.
+    pub fn served_signature_algorithm(&self, offered: &[u16]) -> Option<SignatureAlgorithm> {
+        let offered: Vec<SignatureScheme> =
+            offered.iter().map(|&scheme| SignatureScheme::from(scheme)).collect();
+        let identity = select_identity(&self.identities, &offered)?;
+        certificate_algorithm(identity.end_entity_cert().ok()?)
+    }
+
+    /// Padding block size for a connection that negotiated `alpn`
+    pub fn padding_for(&self, alpn: Option<&[u8]>) -> Option<usize> {
+        self.config.record_padding.filter(|_| crate::padding::negotiated(alpn))
+    }
+
+    /// Whether clients must pass `authenticate_client` after the handshake
+    pub fn client_auth_enabled(&self) -> bool {
+        self.config.client_auth.enabled
+    }
+
+    /// Challenge the client to prove possession of an allowed PQC signing key
+    ///
+    /// Runs over the established TLS stream: the proxy sends a random nonce
+    /// and the client answers with a big-endian `u16` length-prefixed
+    /// signature over it (see `answer_client_auth_challenge`).
+    pub async fn authenticate_client<S>(&self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut nonce = [0u8; CHALLENGE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let exchange = async {
+            stream.write_all(&nonce).await?;
+            stream.flush().await?;
+            let len = stream.read_u16().await? as usize;
+            if len > MAX_FIELD_LEN {
+                return Err(SafeQuantaError::Handshake(format!(
+                    "Client auth signature of {} bytes exceeds maximum of {}",
+                    len, MAX_FIELD_LEN
+                )));
+            }
+            let mut signature = vec![0u8; len];
+            stream.read_exact(&mut signature).await?;
+            Ok(signature)
+        };
+        let limit = std::time::Duration::from_secs(self.config.client_auth.timeout);
+        let signature = tokio::time::timeout(limit, exchange)
+            .await
+            .map_err(|_| SafeQuantaError::Timeout("Client auth challenge timed out".into()))??;
+
+        // Every key is tried, so the time taken does not reveal which matched
+        let payload = client_auth_payload(&nonce);
+        let mut matched = subtle::Choice::from(0);
+        for key in &self.allowed_client_keys {
+            let verified = self.crypto_provider.verify_with_key(key, &payload, &signature).await;
+            matched |= subtle::Choice::from(u8::from(matches!(verified, Ok(true))));
+        }
+        if bool::from(matched) {
+            return Ok(());
+        }
+
+        crate::metrics::record_client_auth_failure();
+        Err(SafeQuantaError::Handshake(
+            "Client auth signature does not match any allowed key".into(),
+        ))
+    }
+
     /// Accept a new TLS connection
-    pub async fn accept(&self, stream: TcpStream) -> Result<impl AsyncRead + AsyncWrite> {
+    pub async fn accept(&self, stream: TcpStream) -> Result<ClientTlsStream> {
         let start_time = std::time::Instant::now();
+        let accepted = self.accept_handshake(stream).await;
+        self.metrics.record_tls_handshake_time(start_time.elapsed(), accepted.is_ok());
+        accepted
+    }
+
+    async fn accept_handshake(
+        &self,
+        stream: TcpStream,
+    ) -> Result<ClientTlsStream> {
+        // Capture the ClientHello before rustls consumes it
+        let peer_addr = stream.peer_addr().ok();
+        let peer = peer_addr.map_or_else(|| "unknown".to_string(), |a| a.to_string());
+        if let Some(len) = crate::fingerprint::peek_handshake_len(&stream).await {
+            if len > self.config.max_handshake_bytes {
+                log::warn!("Refused {} byte handshake message from {}", len, peer);
+                crate::metrics::record_oversized_handshake();
+                return Err(SafeQuantaError::Handshake(format!(
+                    "handshake message of {} bytes exceeds max_handshake_bytes {}",
+                    len, self.config.max_handshake_bytes
+                )));
+            }
+        }
+        let offered = crate::fingerprint::peek_client_hello(&stream).await;
+        if let Some(ja3) = offered.as_ref().and_then(ClientHello::ja3) {
+            log::info!("Client {} TLS fingerprint {} ({})", peer, ja3.hash, ja3.string);
+            crate::metrics::record_client_fingerprint(ja3.bucket());
+        }
         
-        // Accept TLS connection
-        let tls_stream = self.acceptor.accept(stream).await?;
+        // Accept TLS connection, with the server config for the client's
+        // policy rule if one matches
+        let stream = RecordReader::new(stream);
+        let (accepted, policy_rule) = match &self.policy {
+            None => (self.acceptor.accept(stream).await, None),
+            Some(policy) => {
+                let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+                let server_name = start.client_hello().server_name().map(str::to_owned);
+                let client_ip = peer_addr.map(|a| a.ip());
+                let (server_config, rule) = policy
+                    .server_config_for(server_name.as_deref(), client_ip, self.acceptor.config())
+                    .map_err(|e| {
+                        log::warn!("Rejected {} ({:?}): {}", peer, server_name, e);
+                        e
+                    })?;
+                (start.into_stream(server_config).await, rule)
+            }
+        };
+        let tls_stream = accepted.map_err(|e| {
+            if let Some(reason) = client_cert_rejection(&e) {
+                log::warn!("Rejected client certificate from {}: {}", peer, reason);
+                crate::metrics::record_client_cert_rejection(reason);
+            }
+            if let Some(failure) = NegotiationFailure::from_error(&e) {
+                self.report_negotiation_failure(failure, &peer, offered.as_ref());
+                if failure == NegotiationFailure::KxGroup {
+                    let path = self.refused_path(&peer);
+                    self.metrics.increment_tls_connections("client", path.as_str());
+                }
+                if failure == NegotiationFailure::KxGroup
+                    && !self.config.disabled_algorithms.is_empty()
+                {
+                    log::warn!("Rejected handshake: client offered only disabled key exchange groups");
+                    crate::metrics::record_disabled_algorithm_rejection("kx_group");
+                }
+                if let (NegotiationFailure::KxGroup, Some(rule)) = (failure, policy_rule) {
+                    log::warn!("Rejected {}: no key exchange group allowed by policy rule {}", peer, rule);
+                    crate::metrics::record_policy_rejection("kem");
+                }
+            }
+            e
+        })?;
         
         // Record metrics
-        self.metrics.record_tls_handshake_time(start_time.elapsed());
-        self.metrics.increment_tls_connections();
+        let group = self.peer_negotiated_group(&tls_stream);
+        self.metrics.increment_tls_connections("client", TlsPath::for_group(group).as_str());
+        record_payload_sizes(tls_stream.get_ref().1);
+
+        match group {
+            Some(group) if is_quantum_safe_group(group) => {
+                log::debug!("Negotiated quantum-safe group {:?}", group)
+            }
+            Some(group) => log::debug!("Negotiated classical group {:?}", group),
+            None => log::debug!("No key exchange group negotiated"),
+        }
 
         Ok(tls_stream)
     }
 
+    /// Log what the client offered against what this server supports
+    fn report_negotiation_failure(
+        &self,
+        failure: NegotiationFailure,
+        peer: &str,
+        offered: Option<&ClientHello>,
+    ) {
+        let provider = self.acceptor.config().crypto_provider();
+        let (offered, supported): (Vec<String>, Vec<String>) = match failure {
+            NegotiationFailure::KxGroup => (
+                offered.map_or_else(Vec::new, |hello| {
+                    hello.groups.iter().map(|&g| format!("{:?}", NamedGroup::from(g))).collect()
+                }),
+                provider.kx_groups.iter().map(|g| format!("{:?}", g.name())).collect(),
+            ),
+            NegotiationFailure::CipherSuite => (
+                offered.map_or_else(Vec::new, |hello| {
+                    hello
+                        .cipher_suites
+                        .iter()
+                        .map(|&c| format!("{:?}", CipherSuite::from(c)))
+                        .collect()
+                }),
+                provider.cipher_suites.iter().map(|c| format!("{:?}", c.suite())).collect(),
+            ),
+            // The usable schemes follow from the certificate key type
+            NegotiationFailure::SignatureScheme => (
+                offered.map_or_else(Vec::new, |hello| {
+                    hello
+                        .signature_schemes
+                        .iter()
+                        .map(|&s| format!("{:?}", SignatureScheme::from(s)))
+                        .collect()
+                }),
+                vec!["schemes for the certificate key".to_string()],
+            ),
+        };
+        log::warn!(
+            "Handshake with {} failed: no {} in common; client offered [{}], server supports [{}]",
+            peer,
+            failure.description(),
+            offered.join(", "),
+            supported.join(", ")
+        );
+        crate::metrics::record_handshake_negotiation_failure(failure.as_str());
+    }
+
+    /// Path of a client refused for offering no acceptable key exchange group
+    ///
+    /// The handshake fails either way; with the `Redirect` fallback the
+    /// client is expected to retry on `non_pqc_port`, so it counts as
+    /// redirected rather than rejected.
+    fn refused_path(&self, peer: &str) -> TlsPath {
+        let fallback = &self.config.fallback_config;
+        match fallback.non_pqc_port {
+            Some(port) if fallback.enabled && fallback.strategy == FallbackStrategy::Redirect => {
+                log::info!("Client {} without PQC support redirected to port {}", peer, port);
+                TlsPath::Redirect
+            }
+            _ => TlsPath::Reject,
+        }
+    }
+
+    /// Key exchange group negotiated by a completed handshake
+    ///
+    /// `None` if the handshake has not completed.
+    pub fn peer_negotiated_group(
+        &self,
+        stream: &ClientTlsStream,
+    ) -> Option<NamedGroup> {
+        let (_, connection) = stream.get_ref();
+        connection
+            .negotiated_key_exchange_group()
+            .map(|group| group.name())
+    }
+
     /// Create a new TLS client connection
-    pub async fn connect(&self, server_name: &str) -> Result<impl AsyncRead + AsyncWrite> {
+    pub async fn connect(&self, server_name: &str) -> Result<client::TlsStream<TcpStream>> {
+        let server_addr = self.config.server_addr.to_string();
+        self.connect_to(&server_addr, server_name).await
+    }
+
+    /// Create a TLS client connection to `addr`, verifying it as `server_name`
+    pub async fn connect_to(
+        &self,
+        addr: &str,
+        server_name: &str,
+    ) -> Result<client::TlsStream<TcpStream>> {
         let start_time = std::time::Instant::now();
-        
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|e| SafeQuantaError::InvalidConfig(format!("Invalid server name: {}", e)))?;
+
         // Create TCP connection
-        let stream = TcpStream::connect(&self.config.server_addr).await?;
-        
+        let stream = tokio::time::timeout(self.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| {
+                let timeout = self.connect_timeout;
+                SafeQuantaError::Timeout(format!("Connect to {} timed out after {:?}", addr, timeout))
+            })??;
+        self.handshake_over(stream, server_name, start_time).await
+    }
+
+    /// TLS client handshake over an established upstream connection
+    pub async fn handshake_upstream(
+        &self,
+        stream: TcpStream,
+        server_name: &str,
+    ) -> Result<client::TlsStream<TcpStream>> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|e| SafeQuantaError::InvalidConfig(format!("Invalid server name: {}", e)))?;
+        self.handshake_over(stream, server_name, std::time::Instant::now()).await
+    }
+
+    async fn handshake_over(
+        &self,
+        stream: TcpStream,
+        server_name: ServerName<'static>,
+        start_time: std::time::Instant,
+    ) -> Result<client::TlsStream<TcpStream>> {
         // Perform TLS handshake
-        let tls_stream = tokio_rustls::TlsConnector::from(self.acceptor.config().clone())
-            .connect(ServerName::try_from(server_name)?, stream)
-            .await?;
+        let connected = self.connector.connect(server_name, stream).await;
         
         // Record metrics
-        self.metrics.record_tls_handshake_time(start_time.elapsed());
-        self.metrics.increment_tls_connections();
+        self.metrics.record_tls_handshake_time(start_time.elapsed(), connected.is_ok());
+        let tls_stream = connected?;
+        let group = tls_stream.get_ref().1.negotiated_key_exchange_group().map(|g| g.name());
+        self.metrics.increment_tls_connections("upstream", TlsPath::for_group(group).as_str());
+        record_payload_sizes(tls_stream.get_ref().1);
 
         Ok(tls_stream)
     }
 
+    /// Test handshake with an upstream, reporting the key exchange group it chose
+    pub async fn probe_upstream(&self, addr: &str, server_name: &str) -> Result<UpstreamProbe> {
+        let stream = self.connect_to(addr, server_name).await?;
+        let group = stream
+            .get_ref()
+            .1
+            .negotiated_key_exchange_group()
+            .map(|group| group.name());
+        Ok(UpstreamProbe {
+            upstream: addr.to_string(),
+            group,
+        })
+    }
+
     /// Perform a quantum-safe key exchange during TLS handshake
     async fn perform_quantum_safe_key_exchange(&self) -> Result<Vec<u8>> {
         // TODO: Implement quantum-safe key exchange during TLS handshake
@@ -89,21 +502,1166 @@ impl TlsManager {
     }
 }
 
+/// Roots trusted for upstream TLS: `upstream_ca_certs`, plus whatever
+/// `load_native` finds in the OS trust store when `native_roots` is set
+///
+/// An unreadable OS store is logged and skipped so the explicit CAs still
+/// apply; an unreadable `upstream_ca_certs` file is an error.
+fn upstream_roots(
+    config: &TlsConfig,
+    load_native: impl FnOnce() -> std::io::Result<Vec<CertificateDer<'static>>>,
+) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    if config.native_roots {
+        match load_native() {
+            Ok(certs) => {
+                let (added, ignored) = roots.add_parsable_certificates(certs);
+                log::debug!("Loaded {} native root certificates ({} ignored)", added, ignored);
+            }
+            Err(e) => log::warn!("Could not read the OS trust store, skipping native roots: {}", e),
+        }
+    }
+    for path in &config.upstream_ca_certs {
+        roots.add(CertificateDer::from(std::fs::read(path)?))?;
+    }
+    Ok(roots)
+}
+
+/// Client side of `TlsManager::authenticate_client`: sign the proxy's nonce
+pub async fn answer_client_auth_challenge<S>(stream: &mut S, signer: &CryptoProvider) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut nonce = [0u8; CHALLENGE_LEN];
+    stream.read_exact(&mut nonce).await?;
+    let signature = signer.sign(&client_auth_payload(&nonce)).await?;
+    stream.write_u16(signature.len() as u16).await?;
+    stream.write_all(&signature).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Domain-separated bytes signed in answer to a client auth nonce
+fn client_auth_payload(nonce: &[u8; CHALLENGE_LEN]) -> Vec<u8> {
+    [b"safequanta client auth v1\0".as_slice(), nonce].concat()
+}
+
+/// Classify an I/O error as a post-handshake protocol violation
+///
+/// rustls closes the connection itself when a peer sends unsolicited
+/// post-handshake messages or floods it with KeyUpdate requests (beyond
+/// rustls' built-in limit); this lets callers log and count those closes.
+pub fn post_handshake_violation(err: &std::io::Error) -> Option<&'static str> {
+    use tokio_rustls::rustls::{Error, PeerMisbehaved};
+
+    match err.get_ref()?.downcast_ref::<Error>()? {
+        Error::PeerMisbehaved(PeerMisbehaved::TooManyKeyUpdateRequests) => Some("too_many_key_updates"),
+        Error::PeerMisbehaved(PeerMisbehaved::KeyEpochWithPendingFragment) => Some("key_update_mid_fragment"),
+        Error::InappropriateHandshakeMessage { .. } => Some("unexpected_post_handshake_message"),
+        _ => None,
+    }
+}
+
+/// Drain the 0-RTT data a resuming client sent ahead of its handshake
+pub fn take_early_data(stream: &mut ClientTlsStream) -> Option<Bytes> {
+    use std::io::Read;
+
+    let (_, connection) = stream.get_mut();
+    let mut data = Vec::new();
+    connection.early_data()?.read_to_end(&mut data).ok()?;
+    (!data.is_empty()).then(|| Bytes::from(data))
+}
+
+/// Whether requests on a connection may still come from its TLS early data
+///
+/// Pending until the connection is first read past its early data. The
+/// HTTP server reads again only once the requests it has buffered are
+/// handled, so every request it parses before then was sent as early data,
+/// however many the client pipelined.
+#[derive(Clone, Default)]
+pub struct EarlyDataState {
+    confirmed: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl EarlyDataState {
+    /// Whether a request parsed now may have been sent as early data
+    pub fn pending(&self) -> bool {
+        !self.confirmed.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    fn confirm(&self) {
+        self.confirmed.store(true, std::sync::atomic::Ordering::Release);
+    }
+}
+
+/// Stream that yields buffered early data before reading from `inner`
+pub struct EarlyDataStream<S> {
+    early: Bytes,
+    inner: S,
+    state: Option<EarlyDataState>,
+}
+
+impl<S> EarlyDataStream<S> {
+    pub fn new(early: Option<Bytes>, inner: S) -> Self {
+        Self {
+            state: early.is_some().then(EarlyDataState::default),
+            early: early.unwrap_or_default(),
+            inner,
+        }
+    }
+
+    /// Early data state shared with the requests parsed from this stream;
+    /// `None` when the client sent no early data
+    pub fn state(&self) -> Option<EarlyDataState> {
+        self.state.clone()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EarlyDataStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.early.is_empty() {
+            let filled = buf.filled().len();
+            let read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+            if let Some(state) = self.state.as_ref().filter(|_| buf.filled().len() > filled) {
+                state.confirm();
+            }
+            return Poll::Ready(read);
+        }
+        let n = self.early.len().min(buf.remaining());
+        buf.put_slice(&self.early.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EarlyDataStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Accepted client TLS connection
+pub type ClientTlsStream = tokio_rustls::server::TlsStream<RecordReader<TcpStream>>;
+
+/// Length of a TLS record header: type, version and length
+const RECORD_HEADER_LEN: usize = 5;
+
+/// Bytes read from the socket at a time
+const RECORD_READ_CHUNK: usize = 8192;
+
+/// Client socket that hands rustls at most one TLS record per read
+///
+/// rustls processes what each read returns before reading again, so the
+/// stream above it can tell from the plaintext rustls yields which records
+/// carried none; `KeyRotationStream` counts those as `KeyUpdate`s.
+pub struct RecordReader<IO> {
+    io: IO,
+    buffered: BytesMut,
+    /// Bytes of the current record not yet handed out; 0 at a record boundary
+    record_left: usize,
+    /// Records handed out in full
+    records: u64,
+}
+
+impl<IO> RecordReader<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            io,
+            buffered: BytesMut::new(),
+            record_left: 0,
+            records: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    /// Records handed to rustls in full so far
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for RecordReader<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.record_left == 0 && this.buffered.len() >= RECORD_HEADER_LEN {
+                let len = u16::from_be_bytes([this.buffered[3], this.buffered[4]]);
+                this.record_left = RECORD_HEADER_LEN + len as usize;
+            }
+            if this.record_left > 0 && !this.buffered.is_empty() {
+                let n = this.record_left.min(this.buffered.len()).min(buf.remaining());
+                buf.put_slice(&this.buffered.split_to(n));
+                this.record_left -= n;
+                if this.record_left == 0 {
+                    this.records += 1;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; RECORD_READ_CHUNK];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // Pass on what is left of a truncated header, then the EOF
+                let n = this.buffered.len().min(buf.remaining());
+                buf.put_slice(&this.buffered.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            this.buffered.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for RecordReader<IO> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Client TLS stream that rotates its traffic keys every `limit` bytes
+///
+/// Bytes read and written both count towards the limit. With
+/// `RekeyAction::KeyUpdate` a TLS 1.3 `KeyUpdate` is sent and the count starts
+/// over; when that is not possible, or with `RekeyAction::Close`, the stream
+/// sends `close_notify` and then reads as EOF.
+///
+/// It also counts the `KeyUpdate`s the client sends, and fails reads once
+/// there are more than `max_key_updates`.
+pub struct KeyRotationStream<IO> {
+    inner: tokio_rustls::server::TlsStream<RecordReader<IO>>,
+    limit: Option<u64>,
+    action: RekeyAction,
+    since_rotation: u64,
+    rotations: u64,
+    max_key_updates: Option<u64>,
+    peer_key_updates: u64,
+    closing: bool,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> KeyRotationStream<IO> {
+    pub fn new(
+        inner: tokio_rustls::server::TlsStream<RecordReader<IO>>,
+        limit: Option<u64>,
+        action: RekeyAction,
+    ) -> Self {
+        Self {
+            inner,
+            limit,
+            action,
+            since_rotation: 0,
+            rotations: 0,
+            max_key_updates: None,
+            peer_key_updates: 0,
+            closing: false,
+        }
+    }
+
+    /// Close the connection once the client sends more than `max` `KeyUpdate`s
+    pub fn with_max_key_updates(mut self, max: Option<u64>) -> Self {
+        self.max_key_updates = max;
+        self
+    }
+
+    /// Key updates sent so far
+    pub fn key_updates(&self) -> u64 {
+        self.rotations
+    }
+
+    /// Key updates received from the client so far
+    pub fn peer_key_updates(&self) -> u64 {
+        self.peer_key_updates
+    }
+
+    /// Count the records rustls took in during a read without yielding
+    /// plaintext from them as `KeyUpdate`s
+    ///
+    /// `RecordReader` hands rustls one record at a time and rustls stops
+    /// reading once it has plaintext, so of the records behind a read that
+    /// returned data only the last carried any. Only TLS 1.3 is counted: with
+    /// post-handshake client auth never requested, a client has nothing else
+    /// to send after the handshake but application data and `KeyUpdate`s.
+    fn count_key_updates(
+        &mut self,
+        records_before: u64,
+        result: &Poll<std::io::Result<()>>,
+        read: usize,
+    ) -> std::io::Result<()> {
+        let (reader, connection) = self.inner.get_ref();
+        let records = reader.records() - records_before;
+        let key_updates = match result {
+            Poll::Pending => records,
+            Poll::Ready(Ok(())) if read > 0 => records.saturating_sub(1),
+            // EOF and errors end the connection anyway
+            Poll::Ready(_) => return Ok(()),
+        };
+        if key_updates == 0 || connection.protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+            return Ok(());
+        }
+        self.peer_key_updates += key_updates;
+        match self.max_key_updates {
+            Some(max) if self.peer_key_updates > max => {
+                log::warn!(
+                    "Closing connection after {} KeyUpdates, more than max_key_updates {}",
+                    self.peer_key_updates,
+                    max
+                );
+                crate::metrics::record_post_handshake_violation("too_many_key_updates");
+                self.closing = true;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "client exceeded max_key_updates",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count `n` transferred bytes, rotating keys or starting to close at the limit
+    fn account(&mut self, n: usize, cx: &mut Context<'_>) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        self.since_rotation += n as u64;
+        if self.since_rotation < limit || self.closing {
+            return;
+        }
+        self.since_rotation = 0;
+
+        let updated = match self.action {
+            RekeyAction::KeyUpdate => match self.inner.get_mut().1.refresh_traffic_keys() {
+                Ok(()) => true,
+                Err(e) => {
+                    log::info!("Closing connection at rekey_after_bytes, key update failed: {}", e);
+                    false
+                }
+            },
+            RekeyAction::Close => false,
+        };
+        if updated {
+            self.rotations += 1;
+            crate::metrics::record_key_rotation("key_update");
+            // Put the KeyUpdate on the wire now rather than with the next write
+            let _ = Pin::new(&mut self.inner).poll_flush(cx);
+        } else {
+            crate::metrics::record_key_rotation("close");
+            self.closing = true;
+        }
+    }
+
+    /// Send `close_notify` and shut down the write side
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.inner).poll_shutdown(cx).map(|_| ())
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for KeyRotationStream<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.closing {
+            return self.poll_close(cx).map(Ok);
+        }
+        let records = self.inner.get_ref().0.records();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.count_key_updates(records, &result, read)?;
+        self.account(read, cx);
+        result
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for KeyRotationStream<IO> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        if self.closing {
+            return self.poll_close(cx).map(|()| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "connection closed at rekey_after_bytes",
+                ))
+            });
+        }
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &result {
+            let written = *written;
+            self.account(written, cx);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Whether a handshake failed because no key exchange group was shared
+/// Verifier requiring client certificates issued by one of `ca_certs`,
+/// rejecting any revoked by the configured CRLs
+fn client_cert_verifier(
+    config: &ClientAuthConfig,
+    provider: &Arc<tokio_rustls::rustls::crypto::CryptoProvider>,
+) -> Result<Option<Arc<dyn ClientCertVerifier>>> {
+    if config.ca_certs.is_empty() {
+        return Ok(None);
+    }
+
+    let mut roots = RootCertStore::empty();
+    for path in &config.ca_certs {
+        for cert in rustls_pemfile::certs(&mut std::fs::read(path)?.as_slice()) {
+            roots.add(cert?)?;
+        }
+    }
+
+    let mut crls = Vec::new();
+    if let Some(path) = &config.crls {
+        let files = if path.is_dir() {
+            let mut files = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.sort();
+            files
+        } else {
+            vec![path.clone()]
+        };
+        for file in files {
+            for crl in rustls_pemfile::crls(&mut std::fs::read(&file)?.as_slice()) {
+                crls.push(crl?);
+            }
+        }
+    }
+
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .with_crls(crls)
+        .build()
+        .map_err(|e| {
+            SafeQuantaError::InvalidConfig(format!("Invalid client CA configuration: {}", e))
+        })?;
+    Ok(Some(verifier))
+}
+
+/// Upstream certificate verifier rejecting chains longer than `max_depth`
+/// before handing them to webpki
+///
+/// Path building over a long chain of attacker-supplied intermediates is
+/// expensive, so the length check comes first.
+#[derive(Debug)]
+struct ChainDepthVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    max_depth: usize,
+}
+
+impl ChainDepthVerifier {
+    fn new(
+        roots: RootCertStore,
+        provider: &Arc<tokio_rustls::rustls::crypto::CryptoProvider>,
+        max_depth: usize,
+    ) -> Result<Self> {
+        let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .map_err(|e| {
+                SafeQuantaError::InvalidConfig(format!("Invalid upstream CA configuration: {}", e))
+            })?;
+        Ok(Self { inner, max_depth })
+    }
+}
+
+impl ServerCertVerifier for ChainDepthVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let depth = 1 + intermediates.len();
+        if depth > self.max_depth {
+            log::warn!(
+                "Rejected upstream certificate chain of {} certificates (max_chain_depth {})",
+                depth,
+                self.max_depth
+            );
+            crate::metrics::record_upstream_chain_too_deep();
+            return Err(tokio_rustls::rustls::Error::General(format!(
+                "certificate chain of {} exceeds max_chain_depth {}",
+                depth, self.max_depth
+            )));
+        }
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Reason label for a handshake that failed on the client's certificate
+fn client_cert_rejection(err: &std::io::Error) -> Option<&'static str> {
+    use tokio_rustls::rustls::{CertificateError, Error};
+
+    match err.get_ref().and_then(|e| e.downcast_ref::<Error>())? {
+        Error::NoCertificatesPresented => Some("missing"),
+        Error::InvalidCertificate(CertificateError::Revoked) => Some("revoked"),
+        Error::InvalidCertificate(CertificateError::Expired) => Some("expired"),
+        Error::InvalidCertificate(CertificateError::UnknownIssuer) => Some("unknown_issuer"),
+        Error::InvalidCertificate(_) => Some("invalid"),
+        _ => None,
+    }
+}
+
+/// Load a PEM certificate chain and private key as a TLS identity
+fn load_identity(
+    provider: &tokio_rustls::rustls::crypto::CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<Arc<CertifiedKey>> {
+    let chain = rustls_pemfile::certs(&mut std::fs::read(cert_path)?.as_slice())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if chain.is_empty() {
+        return Err(SafeQuantaError::InvalidConfig(format!(
+            "No certificate found in {}",
+            cert_path.display()
+        )));
+    }
+    let key = rustls_pemfile::private_key(&mut std::fs::read(key_path)?.as_slice())?
+        .ok_or_else(|| {
+            let message = format!("No private key found in {}", key_path.display());
+            SafeQuantaError::InvalidConfig(message)
+        })?;
+    let key = provider.key_provider.load_private_key(key)?;
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+/// Load a DER certificate and private key as a single-certificate identity
+fn load_der_identity(
+    provider: &tokio_rustls::rustls::crypto::CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<Arc<CertifiedKey>> {
+    let cert = CertificateDer::from(std::fs::read(cert_path)?);
+    let key = PrivateKeyDer::try_from(std::fs::read(key_path)?).map_err(|e| {
+        let message = format!("Invalid private key {}: {}", key_path.display(), e);
+        SafeQuantaError::InvalidConfig(message)
+    })?;
+    let key = provider.key_provider.load_private_key(key)?;
+    Ok(Arc::new(CertifiedKey::new(vec![cert], key)))
+}
+
+/// Server config presenting `identities`, over `provider`'s groups and suites
+fn server_config(
+    config: &TlsConfig,
+    provider: Arc<tokio_rustls::rustls::crypto::CryptoProvider>,
+    identities: Vec<Arc<CertifiedKey>>,
+) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_cert_verifier(&config.client_auth, &provider)? {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(Arc::new(IdentityResolver { identities }));
+
+    server_config.alpn_protocols = config
+        .record_padding
+        .map(|_| crate::padding::PADDING_ALPN.to_vec())
+        .into_iter()
+        .chain(config.alpn_protocols.iter().map(|p| p.as_bytes().to_vec()))
+        .collect();
+
+    // Session resumption. TLS 1.3 resumption in rustls is psk_dhe_ke only,
+    // so a resumed handshake still runs a fresh key exchange over the same
+    // configured groups and cannot downgrade the PQC policy.
+    if config.session_resumption {
+        // Early data can be replayed, so with 0-RTT enabled sessions only
+        // resume from the stateful cache, where each ticket is taken once,
+        // instead of from reusable stateless tickets
+        if config.max_early_data_size > 0 {
+            server_config.max_early_data_size = config.max_early_data_size;
+        } else {
+            server_config.ticketer = Arc::new(MeteredTicketer::new(aws_lc_rs::Ticketer::new()?));
+        }
+        server_config.session_storage = ServerSessionMemoryCache::new(config.session_cache_size);
+    } else if config.max_early_data_size > 0 {
+        return Err(SafeQuantaError::InvalidConfig(
+            "max_early_data_size requires session_resumption".into(),
+        ));
+    } else {
+        server_config.send_tls13_tickets = 0;
+        server_config.session_storage = Arc::new(NoServerSessionStorage {});
+    }
+    Ok(server_config)
+}
+
+/// Presents the first identity whose key can sign with a scheme the client
+/// advertised, so PQC-aware and classic clients each get a certificate they
+/// can verify
+///
+/// A client that can verify none gets the first, and the handshake fails as
+/// a signature scheme mismatch.
+#[derive(Debug)]
+struct IdentityResolver {
+    identities: Vec<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for IdentityResolver {
+    fn resolve(
+        &self,
+        hello: tokio_rustls::rustls::server::ClientHello<'_>,
+    ) -> Option<Arc<CertifiedKey>> {
+        select_identity(&self.identities, hello.signature_schemes()).cloned()
+    }
+}
+
+/// The first of `identities` whose key can sign with an `offered` scheme,
+/// the first one if none can
+fn select_identity<'a>(
+    identities: &'a [Arc<CertifiedKey>],
+    offered: &[SignatureScheme],
+) -> Option<&'a Arc<CertifiedKey>> {
+    identities
+        .iter()
+        .find(|identity| identity.key.choose_scheme(offered).is_some())
+        .or_else(|| identities.first())
+}
+
+/// Server configs for the rules of the `policy_path` policy
+///
+/// They are rebuilt from the policy watcher thread whenever the file changes,
+/// so accepting a connection only looks its rule up.
+struct PolicyAcceptor {
+    /// Keeps the policy file watched
+    _store: Arc<PolicyStore>,
+    compiled: Arc<parking_lot::RwLock<Arc<CompiledPolicy>>>,
+}
+
+struct CompiledPolicy {
+    policy: Arc<Policy>,
+    /// For each rule, its server config, or the `kind` of algorithm
+    /// (`record_policy_rejection`) none of which this server can offer
+    configs: Vec<std::result::Result<Arc<ServerConfig>, &'static str>>,
+}
+
+impl PolicyAcceptor {
+    fn watch(
+        path: std::path::PathBuf,
+        config: Arc<TlsConfig>,
+        provider: Arc<tokio_rustls::rustls::crypto::CryptoProvider>,
+        identities: Vec<Arc<CertifiedKey>>,
+    ) -> Result<Self> {
+        let empty = CompiledPolicy {
+            policy: Arc::default(),
+            configs: Vec::new(),
+        };
+        let compiled = Arc::new(parking_lot::RwLock::new(Arc::new(empty)));
+        let updated = compiled.clone();
+        let store = PolicyStore::watch(path, move |policy| {
+            let configs = policy
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| {
+                    rule_server_config(&config, &provider, &identities, &rule.algorithms).map_err(|kind| {
+                        log::warn!("Algorithm policy rule {} allows no {} this server offers", index, kind);
+                        kind
+                    })
+                })
+                .collect();
+            *updated.write() = Arc::new(CompiledPolicy {
+                policy: policy.clone(),
+                configs,
+            });
+        })?;
+        Ok(Self {
+            _store: store,
+            compiled,
+        })
+    }
+
+    /// Server config for a client, with the index of the policy rule it
+    /// matched; `default` if it matched none
+    fn server_config_for(
+        &self,
+        server_name: Option<&str>,
+        client_ip: Option<IpAddr>,
+        default: &Arc<ServerConfig>,
+    ) -> Result<(Arc<ServerConfig>, Option<usize>)> {
+        let compiled = self.compiled.read().clone();
+        let Some(rule) = compiled.policy.matching_rule(server_name, client_ip) else {
+            return Ok((default.clone(), None));
+        };
+        match &compiled.configs[rule] {
+            Ok(config) => Ok((config.clone(), Some(rule))),
+            Err(kind) => {
+                crate::metrics::record_policy_rejection(*kind);
+                Err(SafeQuantaError::Handshake(format!(
+                    "policy rule {} allows no {} this server offers",
+                    rule, kind
+                )))
+            }
+        }
+    }
+}
+
+/// Server config offering only the key exchange groups and certificates
+/// `algorithms` allows
+///
+/// Fails with the kind of algorithm (`kem` or `signature`) left with nothing
+/// to offer.
+fn rule_server_config(
+    config: &TlsConfig,
+    provider: &tokio_rustls::rustls::crypto::CryptoProvider,
+    identities: &[Arc<CertifiedKey>],
+    algorithms: &AlgorithmPolicy,
+) -> std::result::Result<Arc<ServerConfig>, &'static str> {
+    let mut provider = provider.clone();
+    provider.kx_groups.retain(|group| {
+        kem_for_group(group.name()).is_some_and(|kem| algorithms.kem_algorithms.contains(&kem))
+    });
+    if provider.kx_groups.is_empty() {
+        return Err("kem");
+    }
+    // The certificate a client is shown must itself use an allowed algorithm
+    let identities: Vec<_> = identities
+        .iter()
+        .filter(|identity| {
+            identity
+                .end_entity_cert()
+                .ok()
+                .and_then(|cert| certificate_algorithm(cert))
+                .is_some_and(|algorithm| algorithms.signature_algorithms.contains(&algorithm))
+        })
+        .cloned()
+        .collect();
+    if identities.is_empty() {
+        return Err("signature");
+    }
+    server_config(config, Arc::new(provider), identities).map(Arc::new).map_err(|e| {
+        log::warn!("Cannot build server config for algorithm policy: {}", e);
+        "config"
+    })
+}
+
+/// DER encodings of the Dilithium3 public key algorithm identifiers: the
+/// Open Quantum Safe OID and ML-DSA-65
+const DILITHIUM3_OIDS: [&[u8]; 2] = [
+    &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x02, 0x82, 0x0b, 0x07, 0x06, 0x05],
+    &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x03, 0x12],
+];
+
+/// Signature algorithm of the key in a DER certificate, if it is one this
+/// proxy knows
+///
+/// Any RSA key counts as `Rsa3072`, the one classical algorithm. OpenSSL
+/// without a PQC provider cannot decode Dilithium keys, so those are
+/// recognized by their algorithm identifier.
+pub fn certificate_algorithm(cert: &[u8]) -> Option<SignatureAlgorithm> {
+    let x509 = openssl::x509::X509::from_der(cert).ok()?;
+    if let Ok(key) = x509.public_key() {
+        if key.id() == openssl::pkey::Id::RSA {
+            return Some(SignatureAlgorithm::Rsa3072);
+        }
+    }
+    let spki = x509.public_key().and_then(|key| key.public_key_to_der()).ok();
+    let searched = spki.as_deref().unwrap_or(cert);
+    DILITHIUM3_OIDS
+        .iter()
+        .any(|oid| searched.windows(oid.len()).any(|window| window == *oid))
+        .then_some(SignatureAlgorithm::Dilithium3)
+}
+
+/// How long a plaintext detection is trusted before the upstream is probed again
+const PLAINTEXT_DETECTION_TTL: Duration = Duration::from_secs(300);
+
+/// Protocol each upstream was found to speak, for `upstream_tls: Auto`
+///
+/// An upstream is probed by its first connection. Only a first record that
+/// cannot be TLS marks it plaintext, and that result expires after
+/// `PLAINTEXT_DETECTION_TTL`. A TLS result is never replaced by plaintext, so
+/// a later failed handshake cannot downgrade an upstream known to speak TLS.
+#[derive(Default)]
+pub struct UpstreamTlsDetector {
+    detected: parking_lot::Mutex<HashMap<String, DetectedProtocol>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DetectedProtocol {
+    Tls,
+    Plaintext { at: std::time::Instant },
+}
+
+impl UpstreamTlsDetector {
+    /// Whether `upstream` speaks TLS, if it has been probed
+    pub fn detected(&self, upstream: &str) -> Option<bool> {
+        match self.detected.lock().get(upstream)? {
+            DetectedProtocol::Tls => Some(true),
+            DetectedProtocol::Plaintext { at } => {
+                (at.elapsed() < PLAINTEXT_DETECTION_TTL).then_some(false)
+            }
+        }
+    }
+
+    /// Attempt a TLS handshake with `upstream` over `stream`
+    ///
+    /// `Ok(None)` if the upstream answered with something that is not TLS,
+    /// which marks it plaintext. Any other failure, such as an invalid
+    /// certificate, a reset or taking longer than `limit`, is returned as an
+    /// error and marks nothing. The stream is used up either way.
+    pub async fn detect(
+        &self,
+        tls_manager: &TlsManager,
+        upstream: &str,
+        server_name: &str,
+        stream: TcpStream,
+        limit: Duration,
+    ) -> Result<Option<client::TlsStream<TcpStream>>> {
+        let handshake = tls_manager.handshake_upstream(stream, server_name);
+        let error = match tokio::time::timeout(limit, handshake).await {
+            Ok(Ok(tls)) => {
+                self.detected.lock().insert(upstream.to_string(), DetectedProtocol::Tls);
+                return Ok(Some(tls));
+            }
+            Ok(Err(e)) => e,
+            Err(_) => {
+                return Err(SafeQuantaError::Timeout(format!(
+                    "TLS handshake with upstream {} timed out",
+                    upstream
+                )))
+            }
+        };
+        let mut detected = self.detected.lock();
+        if !not_tls(&error) || matches!(detected.get(upstream), Some(DetectedProtocol::Tls)) {
+            return Err(error);
+        }
+        log::info!("Upstream {} does not speak TLS ({}), using plaintext", upstream, error);
+        let at = std::time::Instant::now();
+        detected.insert(upstream.to_string(), DetectedProtocol::Plaintext { at });
+        Ok(None)
+    }
+}
+
+/// Whether a failed handshake shows the peer does not speak TLS at all
+///
+/// Only a first record that cannot be TLS counts; certificate errors and
+/// closed connections say nothing about the protocol.
+fn not_tls(err: &SafeQuantaError) -> bool {
+    use tokio_rustls::rustls::{Error, InvalidMessage};
+
+    let SafeQuantaError::Io(err) = err else {
+        return false;
+    };
+    matches!(
+        err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+        Some(Error::InvalidMessage(
+            InvalidMessage::InvalidContentType
+                | InvalidMessage::UnknownProtocolVersion
+                | InvalidMessage::MessageTooLarge
+        ))
+    )
+}
+
+/// Outcome of `TlsManager::probe_upstream`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamProbe {
+    pub upstream: String,
+    /// Key exchange group the upstream negotiated
+    pub group: Option<NamedGroup>,
+}
+
+impl UpstreamProbe {
+    /// Whether the upstream agreed to a post-quantum or hybrid group
+    pub fn quantum_safe(&self) -> bool {
+        self.group.map_or(false, is_quantum_safe_group)
+    }
+}
+
+/// `group/cipher_suite` negotiated by a completed handshake, for logging
+pub fn negotiated_algorithms(stream: &ClientTlsStream) -> String {
+    let (_, connection) = stream.get_ref();
+    let group = connection
+        .negotiated_key_exchange_group()
+        .map_or_else(|| "none".to_string(), |group| format!("{:?}", group.name()));
+    let suite = connection
+        .negotiated_cipher_suite()
+        .map_or_else(|| "none".to_string(), |suite| format!("{:?}", suite.suite()));
+    format!("{}/{}", group, suite)
+}
+
+/// Algorithm family a client and this server could not agree on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegotiationFailure {
+    KxGroup,
+    CipherSuite,
+    SignatureScheme,
+}
+
+impl NegotiationFailure {
+    /// Classify a failed handshake, `None` if it was not a negotiation failure
+    pub fn from_error(err: &std::io::Error) -> Option<Self> {
+        use tokio_rustls::rustls::{Error, PeerIncompatible};
+
+        match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(Error::PeerIncompatible(PeerIncompatible::NoKxGroupsInCommon)) => {
+                Some(NegotiationFailure::KxGroup)
+            }
+            Some(Error::PeerIncompatible(PeerIncompatible::NoCipherSuitesInCommon)) => {
+                Some(NegotiationFailure::CipherSuite)
+            }
+            Some(Error::PeerIncompatible(PeerIncompatible::NoSignatureSchemesInCommon)) => {
+                Some(NegotiationFailure::SignatureScheme)
+            }
+            _ => None,
+        }
+    }
+
+    /// Label used for the `reason` of `handshake_negotiation_failures_total`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NegotiationFailure::KxGroup => "kx_group",
+            NegotiationFailure::CipherSuite => "cipher_suite",
+            NegotiationFailure::SignatureScheme => "signature_scheme",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            NegotiationFailure::KxGroup => "key exchange group",
+            NegotiationFailure::CipherSuite => "cipher suite",
+            NegotiationFailure::SignatureScheme => "signature scheme",
+        }
+    }
+}
+
+/// How a handshake went with respect to PQC, the `path` label of
+/// `tls_connections_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsPath {
+    /// A quantum-safe key exchange group was negotiated
+    Pqc,
+    /// The client offered no group this server accepts
+    Reject,
+    /// The client offered no group this server accepts and is expected to
+    /// retry on `non_pqc_port` (`FallbackStrategy::Redirect`)
+    Redirect,
+    /// A classical key exchange group was negotiated
+    Classic,
+}
+
+impl TlsPath {
+    /// Path of a completed handshake that negotiated `group`
+    pub fn for_group(group: Option<NamedGroup>) -> Self {
+        match group {
+            Some(group) if is_quantum_safe_group(group) => TlsPath::Pqc,
+            _ => TlsPath::Classic,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TlsPath::Pqc => "pqc",
+            TlsPath::Reject => "reject",
+            TlsPath::Redirect => "redirect",
+            TlsPath::Classic => "classic",
+        }
+    }
+}
+
+/// Whether `group` is a post-quantum or hybrid PQ key exchange group
+pub fn is_quantum_safe_group(group: NamedGroup) -> bool {
+    matches!(
+        u16::from(group),
+        // MLKEM512, MLKEM768, MLKEM1024
+        0x0200..=0x0202
+        // SecP256r1MLKEM768, X25519MLKEM768, SecP384r1MLKEM1024
+        | 0x11eb..=0x11ed
+        // X25519Kyber768Draft00
+        | 0x6399
+    )
+}
+
+/// Bytes of the key shares the client and the server send for `group`
+pub fn key_share_sizes(group: NamedGroup) -> Option<(usize, usize)> {
+    match u16::from(group) {
+        // secp256r1, secp384r1, secp521r1, X25519
+        0x0017 => Some((65, 65)),
+        0x0018 => Some((97, 97)),
+        0x0019 => Some((133, 133)),
+        0x001d => Some((32, 32)),
+        // MLKEM512, MLKEM768, MLKEM1024: encapsulation key, then ciphertext
+        0x0200 => Some((800, 768)),
+        0x0201 => Some((1184, 1088)),
+        0x0202 => Some((1568, 1568)),
+        // Hybrids carry both shares
+        0x11eb => Some((65 + 1184, 65 + 1088)),
+        0x11ec | 0x6399 => Some((32 + 1184, 32 + 1088)),
+        0x11ed => Some((97 + 1568, 97 + 1568)),
+        _ => None,
+    }
+}
+
+/// Record the handshake bytes an established connection exchanged: both key
+/// shares, labeled by group, and the peer's certificate chain, labeled by its
+/// key's algorithm
+fn record_payload_sizes(conn: &CommonState) {
+    if let Some(group) = conn.negotiated_key_exchange_group().map(|g| g.name()) {
+        if let Some((client, server)) = key_share_sizes(group) {
+            let algorithm = format!("{:?}", group);
+            crate::metrics::record_handshake_payload_bytes("client_key_share", algorithm.clone(), client);
+            crate::metrics::record_handshake_payload_bytes("server_key_share", algorithm, server);
+        }
+    }
+    if let Some(chain) = conn.peer_certificates() {
+        let algorithm = chain
+            .first()
+            .and_then(|leaf| certificate_algorithm(leaf))
+            .map_or_else(|| "other".to_string(), |algorithm| format!("{:?}", algorithm));
+        let bytes = chain.iter().map(|cert| cert.len()).sum();
+        crate::metrics::record_handshake_payload_bytes("peer_certificate_chain", algorithm, bytes);
+    }
+}
+
+/// KEM security level of a post-quantum or hybrid key exchange group
+pub fn kem_for_group(group: NamedGroup) -> Option<KemAlgorithm> {
+    match u16::from(group) {
+        // MLKEM768, SecP256r1MLKEM768, X25519MLKEM768, X25519Kyber768Draft00
+        0x0201 | 0x11eb | 0x11ec | 0x6399 => Some(KemAlgorithm::Kyber768),
+        // MLKEM1024, SecP384r1MLKEM1024
+        0x0202 | 0x11ed => Some(KemAlgorithm::Kyber1024),
+        _ => None,
+    }
+}
+
+/// Map configured suite names to rustls suites, keeping their order
+///
+/// An empty list selects the rustls safe defaults.
+pub fn resolve_cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(DEFAULT_CIPHER_SUITES.to_vec());
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| SafeQuantaError::InvalidConfig(format!("Unknown cipher suite: {}", name)))
+        })
+        .collect()
+}
+
+/// Session ticketer that records resumption hits and misses
+#[derive(Debug)]
+struct MeteredTicketer {
+    inner: Arc<dyn ProducesTickets>,
+}
+
+impl MeteredTicketer {
+    fn new(inner: Arc<dyn ProducesTickets>) -> Self {
+        Self { inner }
+    }
+}
+
+impl ProducesTickets for MeteredTicketer {
+    fn enabled(&self) -> bool {
+        self.inner.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.inner.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.inner.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let plain = self.inner.decrypt(cipher);
+        if plain.is_some() {
+            crate::metrics::record_session_resumption_hit();
+        } else {
+            crate::metrics::record_session_resumption_miss();
+        }
+        plain
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{KemAlgorithm, SignatureAlgorithm};
+    use crate::config::{ClientAuthConfig, FallbackConfig, KemAlgorithm, SignatureAlgorithm};
+    use crate::metrics::testing::{counters, histograms, record};
     use std::net::SocketAddr;
     use tokio::net::TcpListener;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use parking_lot::Mutex;
+    use tokio_rustls::rustls::crypto::{
+        aws_lc_rs, verify_tls12_signature, verify_tls13_signature, SupportedKxGroup,
+    };
 
     async fn setup_test_tls_manager() -> (TlsManager, SocketAddr) {
+        setup_test_tls_manager_with(true).await
+    }
+
+    async fn setup_test_tls_manager_with(session_resumption: bool) -> (TlsManager, SocketAddr) {
         let config = Arc::new(TlsConfig {
-            cert_path: "tests/fixtures/test.crt".to_string(),
-            key_path: "tests/fixtures/test.key".to_string(),
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
             server_addr: "127.0.0.1:0".parse().unwrap(),
-            kem_algorithm: KemAlgorithm::Kyber768,
-            signature_algorithm: SignatureAlgorithm::Dilithium3,
+            session_resumption,
+            session_cache_size: 16,
+            ..Default::default()
         });
 
         let metrics = Arc::new(Metrics::new());
@@ -124,7 +1682,45 @@ mod tests {
     #[tokio::test]
     async fn test_tls_manager_creation() {
         let (tls_manager, _) = setup_test_tls_manager().await;
-        assert!(tls_manager.acceptor.config().cipher_suites.is_empty());
+        let provider = tls_manager.acceptor.config().crypto_provider();
+        assert_eq!(provider.cipher_suites, DEFAULT_CIPHER_SUITES.to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_post_handshake_auth_rejected() {
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            post_handshake_auth: true,
+            ..Default::default()
+        });
+        let crypto_provider = Arc::new(CryptoProvider::new(
+            config.kem_algorithm,
+            config.signature_algorithm,
+            &config.cert_path,
+            &config.key_path,
+        ).unwrap());
+
+        let result = TlsManager::new(config, crypto_provider, Arc::new(Metrics::new()));
+        assert!(matches!(result, Err(SafeQuantaError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_cipher_suites_applied_in_order() {
+        let names = vec![
+            "TLS13_CHACHA20_POLY1305_SHA256".to_string(),
+            "TLS13_AES_256_GCM_SHA384".to_string(),
+        ];
+        let suites = resolve_cipher_suites(&names).unwrap();
+        let resolved: Vec<String> = suites.iter().map(|s| format!("{:?}", s.suite())).collect();
+        assert_eq!(resolved, names);
+    }
+
+    #[test]
+    fn test_unknown_cipher_suite_rejected() {
+        let names = vec!["TLS13_AES_256_GCM_SHA384".to_string(), "TLS_FAKE_SUITE".to_string()];
+        let err = resolve_cipher_suites(&names).unwrap_err();
+        assert!(matches!(err, SafeQuantaError::InvalidConfig(ref msg) if msg.contains("TLS_FAKE_SUITE")));
     }
 
     #[tokio::test]
@@ -156,4 +1752,1067 @@ mod tests {
 
         server.await.unwrap();
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_oversized_handshake_refused() {
+        let (tls_manager, addr) = setup_test_tls_manager().await;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tls_manager.accept(stream).await
+        });
+
+        // Record and ClientHello headers announcing a 1 MiB ClientHello
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[22, 3, 1, 0x40, 0x00, 1, 0x10, 0x00, 0x00]).await.unwrap();
+
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(SafeQuantaError::Handshake(ref msg)) if msg.contains("max_handshake_bytes")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_group_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            server_addr: addr,
+            ..Default::default()
+        });
+        let crypto_provider = Arc::new(CryptoProvider::new(
+            config.kem_algorithm,
+            config.signature_algorithm,
+            &config.cert_path,
+            &config.key_path,
+        ).unwrap());
+        let tls_manager =
+            Arc::new(TlsManager::new(config, crypto_provider, Arc::new(Metrics::new())).unwrap());
+
+        let server_manager = tls_manager.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls_stream = server_manager.accept(stream).await.unwrap();
+            let mut buf = [0u8; 5];
+            tls_stream.read_exact(&mut buf).await.unwrap();
+            server_manager.peer_negotiated_group(&tls_stream)
+        });
+
+        let mut tls_stream = tls_manager.connect("localhost").await.unwrap();
+        tls_stream.write_all(b"hello").await.unwrap();
+
+        let group = server.await.unwrap().expect("full handshake negotiates a group");
+        let offered: Vec<NamedGroup> = tls_manager
+            .acceptor
+            .config()
+            .crypto_provider()
+            .kx_groups
+            .iter()
+            .map(|g| g.name())
+            .collect();
+        assert!(offered.contains(&group));
+    }
+
+    #[tokio::test]
+    async fn test_rekey_after_bytes() {
+        for action in [RekeyAction::KeyUpdate, RekeyAction::Close] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = Arc::new(TlsConfig {
+                cert_path: "tests/fixtures/test.crt".into(),
+                key_path: "tests/fixtures/test.key".into(),
+                server_addr: listener.local_addr().unwrap(),
+                ..Default::default()
+            });
+            let crypto_provider = Arc::new(test_crypto_provider());
+            let tls_manager =
+                Arc::new(TlsManager::new(config, crypto_provider, Arc::new(Metrics::new())).unwrap());
+
+            let server_manager = tls_manager.clone();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tls_stream = server_manager.accept(stream).await.unwrap();
+                let mut stream = KeyRotationStream::new(tls_stream, Some(1024), action);
+                let mut buf = [0u8; 512];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(stream.key_updates(), 0);
+                stream.read_exact(&mut buf).await.unwrap();
+                let written = stream.write_all(b"after").await;
+                (stream.key_updates(), written.is_ok())
+            });
+
+            let mut client = tls_manager.connect("localhost").await.unwrap();
+            client.write_all(&[7u8; 1024]).await.unwrap();
+            let mut reply = Vec::new();
+            let _ = client.read_to_end(&mut reply).await;
+
+            let (key_updates, written) = server.await.unwrap();
+            match action {
+                RekeyAction::KeyUpdate => {
+                    // The client decrypts data sent under the new keys
+                    assert_eq!((key_updates, written), (1, true));
+                    assert_eq!(reply, b"after");
+                }
+                RekeyAction::Close => {
+                    assert_eq!((key_updates, written), (0, false));
+                    assert!(reply.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_update_flood_closes_connection() {
+        let recorded = record(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = Arc::new(TlsConfig {
+                cert_path: "tests/fixtures/test.crt".into(),
+                key_path: "tests/fixtures/test.key".into(),
+                server_addr: listener.local_addr().unwrap(),
+                ..Default::default()
+            });
+            let crypto_provider = Arc::new(test_crypto_provider());
+            let tls_manager =
+                Arc::new(TlsManager::new(config, crypto_provider, Arc::new(Metrics::new())).unwrap());
+
+            // The client sends data, then more KeyUpdates than allowed
+            let client_manager = tls_manager.clone();
+            let client = tokio::spawn(async move {
+                let mut client = client_manager.connect("localhost").await.unwrap();
+                client.write_all(b"hello").await.unwrap();
+                for _ in 0..8 {
+                    client.get_mut().1.refresh_traffic_keys().unwrap();
+                    client.flush().await.unwrap();
+                }
+                let _ = client.write_all(b"world").await;
+                let mut rest = Vec::new();
+                let _ = client.read_to_end(&mut rest).await;
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls_stream = tls_manager.accept(stream).await.unwrap();
+            let mut stream = KeyRotationStream::new(tls_stream, None, RekeyAction::KeyUpdate)
+                .with_max_key_updates(Some(4));
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(stream.peer_key_updates(), 0);
+
+            let err = stream.read_exact(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(stream.peer_key_updates() > 4);
+            drop(stream);
+            client.await.unwrap();
+        });
+
+        assert_eq!(
+            counters(&recorded, "tls_post_handshake_violations_total"),
+            vec![(vec!["reason=too_many_key_updates".to_string()], 1)]
+        );
+    }
+
+    #[test]
+    fn test_quantum_safe_group_classification() {
+        assert!(is_quantum_safe_group(NamedGroup::from(0x11ec)));
+        assert!(is_quantum_safe_group(NamedGroup::from(0x6399)));
+        assert!(!is_quantum_safe_group(NamedGroup::X25519));
+        assert!(!is_quantum_safe_group(NamedGroup::secp256r1));
+    }
+
+    fn manager_with_disabled(disabled: &[&str]) -> Result<TlsManager> {
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            disabled_algorithms: disabled.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        });
+        let crypto_provider = Arc::new(CryptoProvider::new(
+            config.kem_algorithm,
+            config.signature_algorithm,
+            &config.cert_path,
+            &config.key_path,
+        ).unwrap());
+        TlsManager::new(config, crypto_provider, Arc::new(Metrics::new()))
+    }
+
+    #[test]
+    fn test_disabled_algorithm_refused() {
+        // The default KEM is Kyber768
+        let err = manager_with_disabled(&["kyber768"]).err().unwrap();
+        assert!(matches!(err, SafeQuantaError::AlgorithmDisabled(ref name) if name == "Kyber768"));
+
+        // Disabling an unused algorithm leaves the configured ones working
+        assert!(manager_with_disabled(&["Kyber1024"]).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_kx_group_removed() {
+        let manager = manager_with_disabled(&["secp384r1"]).unwrap();
+        let groups: Vec<NamedGroup> = manager
+            .acceptor
+            .config()
+            .crypto_provider()
+            .kx_groups
+            .iter()
+            .map(|g| g.name())
+            .collect();
+        assert!(!groups.contains(&NamedGroup::secp384r1));
+        assert!(groups.contains(&NamedGroup::X25519));
+    }
+
+    fn test_crypto_provider() -> CryptoProvider {
+        CryptoProvider::new(
+            KemAlgorithm::Kyber768,
+            SignatureAlgorithm::Dilithium3,
+            "tests/fixtures/test.crt",
+            "tests/fixtures/test.key",
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_client_auth_challenge() {
+        let client = test_crypto_provider();
+        let stranger = test_crypto_provider();
+        let key_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(key_file.path(), client.sign_public_key().unwrap()).unwrap();
+
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            client_auth: ClientAuthConfig {
+                enabled: true,
+                allowed_client_keys: vec![key_file.path().to_path_buf()],
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let manager =
+            TlsManager::new(config, Arc::new(test_crypto_provider()), Arc::new(Metrics::new())).unwrap();
+
+        let authenticate = |signer: CryptoProvider| {
+            let (mut server_io, mut client_io) = tokio::io::duplex(16 * 1024);
+            let manager = &manager;
+            async move {
+                let client = tokio::spawn(async move {
+                    answer_client_auth_challenge(&mut client_io, &signer).await
+                });
+                let result = manager.authenticate_client(&mut server_io).await;
+                client.await.unwrap().unwrap();
+                result
+            }
+        };
+
+        assert!(authenticate(client).await.is_ok());
+        assert!(matches!(authenticate(stranger).await, Err(SafeQuantaError::Handshake(_))));
+    }
+
+    #[tokio::test]
+    async fn test_session_resumption_configured() {
+        let (tls_manager, _) = setup_test_tls_manager_with(true).await;
+        let server_config = tls_manager.acceptor.config();
+        assert!(server_config.ticketer.enabled());
+        assert!(server_config.send_tls13_tickets > 0);
+
+        // A ticket issued by the server is accepted back on resumption
+        let ticket = server_config.ticketer.encrypt(b"session state").unwrap();
+        assert_eq!(server_config.ticketer.decrypt(&ticket).unwrap(), b"session state");
+        assert!(server_config.ticketer.decrypt(b"forged ticket").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_early_data_stream_replays_early_bytes_first() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = EarlyDataStream::new(Some(Bytes::from_static(b"GET / ")), server);
+        client.write_all(b"HTTP/1.1").await.unwrap();
+        drop(client);
+
+        let state = stream.state().unwrap();
+        let mut early = [0u8; 6];
+        stream.read_exact(&mut early).await.unwrap();
+        assert!(state.pending());
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"HTTP/1.1");
+        assert!(!state.pending());
+
+        let stream = EarlyDataStream::new(None, tokio::io::empty());
+        assert!(stream.state().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_resumption_disabled() {
+        let (tls_manager, _) = setup_test_tls_manager_with(false).await;
+        let server_config = tls_manager.acceptor.config();
+        assert!(!server_config.ticketer.enabled());
+        assert_eq!(server_config.send_tls13_tickets, 0);
+        assert!(!server_config.session_storage.can_cache());
+    }
+
+    #[test]
+    fn test_kx_group_mismatch_records_negotiation_failure() {
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let recorded = record(async {
+            let manager = manager_with_disabled(&["secp384r1"]).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // The client only offers the group the server has disabled
+            let provider = tokio_rustls::rustls::crypto::CryptoProvider {
+                kx_groups: vec![aws_lc_rs::kx_group::SECP384R1],
+                ..aws_lc_rs::default_provider()
+            };
+            let client_config = ClientConfig::builder_with_provider(Arc::new(provider))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth();
+            let client = tokio::spawn(async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                tokio_rustls::TlsConnector::from(Arc::new(client_config))
+                    .connect("localhost".try_into().unwrap(), stream)
+                    .await
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let err = manager.accept(stream).await.err().unwrap();
+            assert!(matches!(err, SafeQuantaError::Io(_)));
+            assert!(client.await.unwrap().is_err());
+        });
+
+        assert_eq!(
+            counters(&recorded, "handshake_negotiation_failures_total"),
+            vec![(vec!["reason=kx_group".to_string()], 1)]
+        );
+    }
+
+    #[test]
+    fn test_handshake_duration_split_by_outcome() {
+        let recorded = record(async {
+            let config = Arc::new(TlsConfig {
+                cert_path: "tests/fixtures/test.crt".into(),
+                key_path: "tests/fixtures/test.key".into(),
+                upstream_ca_certs: vec!["tests/fixtures/test.crt".into()],
+                ..Default::default()
+            });
+            let crypto_provider = Arc::new(CryptoProvider::from_config(&config).unwrap());
+            let manager =
+                TlsManager::new(config, crypto_provider, Arc::new(Metrics::new())).unwrap();
+
+            // A client that speaks plaintext fails the server handshake
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = tokio::spawn(async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+                stream
+            });
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(manager.accept(stream).await.is_err());
+            drop(client.await.unwrap());
+
+            // A handshake with a TLS upstream succeeds
+            let upstream = stub_upstream(vec![aws_lc_rs::kx_group::X25519MLKEM768]).await;
+            manager.connect_to(&upstream.to_string(), "localhost").await.unwrap();
+        });
+
+        let outcomes: Vec<(Vec<String>, usize)> = histograms(&recorded, "handshake_duration_ms")
+            .into_iter()
+            .map(|(labels, samples)| (labels, samples.len()))
+            .collect();
+        let labels = |outcome: &str| {
+            vec!["type=handshake".to_string(), format!("outcome={}", outcome)]
+        };
+        assert_eq!(outcomes, vec![(labels("failure"), 1), (labels("success"), 1)]);
+    }
+
+    #[test]
+    fn test_upstream_roots_from_native_store() {
+        let cert = || CertificateDer::from(std::fs::read("tests/fixtures/test.crt").unwrap());
+        let config = TlsConfig::default();
+        let roots = upstream_roots(&config, || Ok(vec![cert()])).unwrap();
+        assert_eq!(roots.len(), 1);
+
+        // An unreadable OS store leaves just the explicit CAs
+        let config = TlsConfig {
+            upstream_ca_certs: vec!["tests/fixtures/test.crt".into()],
+            ..Default::default()
+        };
+        let unreadable = || Err(std::io::Error::other("no trust store"));
+        assert_eq!(upstream_roots(&config, unreadable).unwrap().len(), 1);
+
+        // With native_roots off the OS store is not consulted
+        let config = TlsConfig { native_roots: false, ..Default::default() };
+        let roots = upstream_roots(&config, || panic!("native roots loaded")).unwrap();
+        assert!(roots.is_empty());
+    }
+
+    /// TLS server for one handshake, offering only `groups`
+    async fn stub_upstream(
+        groups: Vec<&'static dyn tokio_rustls::rustls::crypto::SupportedKxGroup>,
+    ) -> SocketAddr {
+        use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+        let provider = tokio_rustls::rustls::crypto::CryptoProvider {
+            kx_groups: groups,
+            ..aws_lc_rs::default_provider()
+        };
+        let cert = CertificateDer::from(std::fs::read("tests/fixtures/test.crt").unwrap());
+        let key = std::fs::read("tests/fixtures/test.key").unwrap();
+        let key = PrivateKeyDer::try_from(key).unwrap();
+        let config = ServerConfig::builder_with_provider(Arc::new(provider))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = TlsAcceptor::from(Arc::new(config)).accept(stream).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_probe_upstream_reports_negotiated_group() {
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            upstream_ca_certs: vec!["tests/fixtures/test.crt".into()],
+            ..Default::default()
+        });
+        let crypto_provider = Arc::new(CryptoProvider::from_config(&config).unwrap());
+        let manager = TlsManager::new(config, crypto_provider, Arc::new(Metrics::new())).unwrap();
+
+        let pqc = stub_upstream(vec![aws_lc_rs::kx_group::X25519MLKEM768]).await;
+        let probe = manager.probe_upstream(&pqc.to_string(), "localhost").await.unwrap();
+        assert_eq!(probe.group, Some(NamedGroup::X25519MLKEM768));
+        assert!(probe.quantum_safe());
+
+        let classic = stub_upstream(vec![aws_lc_rs::kx_group::X25519]).await;
+        let probe = manager.probe_upstream(&classic.to_string(), "localhost").await.unwrap();
+        assert_eq!(probe.group, Some(NamedGroup::X25519));
+        assert!(!probe.quantum_safe());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_tls_detection() {
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            upstream_ca_certs: vec!["tests/fixtures/test.crt".into()],
+            ..Default::default()
+        });
+        let crypto_provider = Arc::new(CryptoProvider::from_config(&config).unwrap());
+        let manager = TlsManager::new(config, crypto_provider, Arc::new(Metrics::new())).unwrap();
+        let detector = UpstreamTlsDetector::default();
+        let limit = Duration::from_secs(5);
+
+        let tls = stub_upstream(vec![aws_lc_rs::kx_group::X25519MLKEM768]).await.to_string();
+        let stream = TcpStream::connect(&tls).await.unwrap();
+        let detected = detector.detect(&manager, &tls, "localhost", stream, limit).await;
+        assert!(detected.unwrap().is_some());
+        assert_eq!(detector.detected(&tls), Some(true));
+
+        // A TLS upstream failing verification is an error, not plaintext
+        let other = stub_upstream(vec![aws_lc_rs::kx_group::X25519MLKEM768]).await.to_string();
+        let stream = TcpStream::connect(&other).await.unwrap();
+        let detected = detector.detect(&manager, &other, "wrong.example", stream, limit).await;
+        assert!(detected.is_err());
+        assert_eq!(detector.detected(&other), None);
+
+        // A plaintext HTTP backend answers the ClientHello with an error page
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plain = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.read(&mut [0u8; 1024]).await;
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+        assert_eq!(detector.detected(&plain), None);
+        let stream = TcpStream::connect(&plain).await.unwrap();
+        let detected = detector.detect(&manager, &plain, "localhost", stream, limit).await;
+        assert!(detected.unwrap().is_none());
+        assert_eq!(detector.detected(&plain), Some(false));
+        assert_eq!(detector.detected(&tls), Some(true));
+
+        // Plaintext results expire so the upstream is probed again
+        let at = std::time::Instant::now() - PLAINTEXT_DETECTION_TTL;
+        detector.detected.lock().insert(plain.clone(), DetectedProtocol::Plaintext { at });
+        assert_eq!(detector.detected(&plain), None);
+    }
+
+    #[tokio::test]
+    async fn test_require_pqc_rejects_classic_peers() {
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            upstream_ca_certs: vec!["tests/fixtures/test.crt".into()],
+            require_pqc: true,
+            ..Default::default()
+        });
+        let crypto_provider = Arc::new(CryptoProvider::from_config(&config).unwrap());
+        let manager =
+            TlsManager::new(config.clone(), crypto_provider.clone(), Arc::new(Metrics::new())).unwrap();
+        let groups = &manager.acceptor.config().crypto_provider().kx_groups;
+        assert!(!groups.is_empty());
+        assert!(groups.iter().all(|g| is_quantum_safe_group(g.name())));
+
+        // A client offering only a classical group is refused
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let provider = tokio_rustls::rustls::crypto::CryptoProvider {
+            kx_groups: vec![aws_lc_rs::kx_group::X25519],
+            ..aws_lc_rs::default_provider()
+        };
+        let client_config = ClientConfig::builder_with_provider(Arc::new(provider))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            tokio_rustls::TlsConnector::from(Arc::new(client_config))
+                .connect("localhost".try_into().unwrap(), stream)
+                .await
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(manager.accept(stream).await.is_err());
+        assert!(client.await.unwrap().is_err());
+
+        // So is a classical upstream, which fails the startup probe
+        let classic = stub_upstream(vec![aws_lc_rs::kx_group::X25519]).await;
+        assert!(manager.probe_upstream(&classic.to_string(), "localhost").await.is_err());
+
+        // And a classical certificate, configured as RSA or mislabeled as Dilithium3
+        let rsa = TlsConfig {
+            signature_algorithm: SignatureAlgorithm::Rsa3072,
+            ..(*config).clone()
+        };
+        let err = TlsManager::new(Arc::new(rsa), crypto_provider.clone(), Arc::new(Metrics::new())).err();
+        assert!(matches!(err, Some(SafeQuantaError::InvalidConfig(_))));
+
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        let ecdsa = rcgen::Certificate::from_params(params).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cert.der"), ecdsa.serialize_der().unwrap()).unwrap();
+        std::fs::write(dir.path().join("key.der"), ecdsa.serialize_private_key_der()).unwrap();
+        let mislabeled = TlsConfig {
+            cert_path: dir.path().join("cert.der"),
+            key_path: dir.path().join("key.der"),
+            signature_algorithm: SignatureAlgorithm::Dilithium3,
+            ..(*config).clone()
+        };
+        let err = TlsManager::new(Arc::new(mislabeled), crypto_provider, Arc::new(Metrics::new())).err();
+        assert!(matches!(err, Some(SafeQuantaError::InvalidConfig(_))));
+    }
+
+    /// Accepts any certificate but checks the handshake signature with
+    /// only the given schemes, remembering which certificate was presented
+    #[derive(Debug)]
+    struct SchemeVerifier {
+        schemes: Vec<SignatureScheme>,
+        presented: Mutex<Option<Vec<u8>>>,
+    }
+
+    impl ServerCertVerifier for SchemeVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> std::result::Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+            *self.presented.lock() = Some(end_entity.to_vec());
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            let algorithms = aws_lc_rs::default_provider().signature_verification_algorithms;
+            verify_tls12_signature(message, cert, dss, &algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            let algorithms = aws_lc_rs::default_provider().signature_verification_algorithms;
+            verify_tls13_signature(message, cert, dss, &algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.schemes.clone()
+        }
+    }
+
+    #[test]
+    fn test_tls_connections_counted_by_path() {
+        /// Handshake with `manager` offering only `group`, returning whether it accepted
+        async fn handshake(
+            manager: &TlsManager,
+            group: &'static dyn tokio_rustls::rustls::crypto::SupportedKxGroup,
+        ) -> bool {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let provider = tokio_rustls::rustls::crypto::CryptoProvider {
+                kx_groups: vec![group],
+                ..aws_lc_rs::default_provider()
+            };
+            let verifier = Arc::new(SchemeVerifier {
+                schemes: provider.signature_verification_algorithms.supported_schemes(),
+                presented: Mutex::new(None),
+            });
+            let client_config = ClientConfig::builder_with_provider(Arc::new(provider))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .dangerous()
+                .with_custom_certificate_verifier(verifier)
+                .with_no_client_auth();
+            let client = tokio::spawn(async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                TlsConnector::from(Arc::new(client_config))
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await
+                    .map(|_| ())
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let accepted = manager.accept(stream).await.is_ok();
+            let _ = client.await.unwrap();
+            accepted
+        }
+
+        let recorded = record(async {
+            let manager = manager_with_disabled(&["secp384r1"]).unwrap();
+            let redirecting = {
+                let config = Arc::new(TlsConfig {
+                    cert_path: "tests/fixtures/test.crt".into(),
+                    key_path: "tests/fixtures/test.key".into(),
+                    disabled_algorithms: vec!["secp384r1".to_string()],
+                    fallback_config: FallbackConfig {
+                        enabled: true,
+                        strategy: FallbackStrategy::Redirect,
+                        non_pqc_port: Some(8444),
+                    },
+                    ..Default::default()
+                });
+                let crypto_provider = Arc::new(CryptoProvider::new(
+                    config.kem_algorithm,
+                    config.signature_algorithm,
+                    &config.cert_path,
+                    &config.key_path,
+                ).unwrap());
+                TlsManager::new(config, crypto_provider, Arc::new(Metrics::new())).unwrap()
+            };
+            assert!(handshake(&manager, aws_lc_rs::kx_group::X25519MLKEM768).await);
+            assert!(handshake(&manager, aws_lc_rs::kx_group::X25519).await);
+            assert!(handshake(&manager, aws_lc_rs::kx_group::X25519).await);
+            assert!(!handshake(&manager, aws_lc_rs::kx_group::SECP384R1).await);
+            assert!(!handshake(&redirecting, aws_lc_rs::kx_group::SECP384R1).await);
+        });
+
+        let client = |path: &str, count: u64| {
+            (vec![format!("path={}", path), "side=client".to_string()], count)
+        };
+        assert_eq!(
+            counters(&recorded, "tls_connections_total"),
+            vec![client("classic", 2), client("pqc", 1), client("redirect", 1), client("reject", 1)]
+        );
+    }
+
+    #[test]
+    fn test_handshake_payload_sizes_recorded_per_group() {
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            upstream_ca_certs: vec!["tests/fixtures/test.crt".into()],
+            ..Default::default()
+        });
+        let manager =
+            TlsManager::new(config, Arc::new(test_crypto_provider()), Arc::new(Metrics::new())).unwrap();
+
+        let recorded = record(async {
+            for group in [aws_lc_rs::kx_group::X25519MLKEM768, aws_lc_rs::kx_group::X25519] {
+                let upstream = stub_upstream(vec![group]).await;
+                manager.probe_upstream(&upstream.to_string(), "localhost").await.unwrap();
+            }
+        });
+
+        let sizes = histograms(&recorded, "handshake_payload_bytes");
+
+        // The key shares follow from the group; the chain is the stub's certificate
+        let leaf = std::fs::read("tests/fixtures/test.crt").unwrap();
+        let leaf_algorithm = certificate_algorithm(&leaf).map_or("other".to_string(), |a| format!("{:?}", a));
+        let expected = |part: &str, algorithm: &str, sizes: &[usize]| {
+            let labels = vec![format!("field={}", part), format!("algorithm={}", algorithm)];
+            (labels, sizes.iter().map(|&bytes| bytes as f64).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            sizes,
+            vec![
+                expected("client_key_share", "X25519", &[32]),
+                expected("client_key_share", "X25519MLKEM768", &[1216]),
+                expected("peer_certificate_chain", &leaf_algorithm, &[leaf.len(), leaf.len()]),
+                expected("server_key_share", "X25519", &[32]),
+                expected("server_key_share", "X25519MLKEM768", &[1120]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_alternate_identity_selected_by_client_schemes() {
+        fn identity(alg: &'static rcgen::SignatureAlgorithm) -> (tempfile::TempDir, Vec<u8>) {
+            let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+            params.alg = alg;
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+            std::fs::write(dir.path().join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+            std::fs::write(dir.path().join("cert.der"), cert.serialize_der().unwrap()).unwrap();
+            std::fs::write(dir.path().join("key.der"), cert.serialize_private_key_der()).unwrap();
+            (dir, cert.serialize_der().unwrap())
+        }
+
+        // Ed25519 stands in for the PQC identity, ECDSA for the classic one.
+        // The primary is DER like any `cert_path`, the alternate PEM.
+        let (primary_dir, primary_der) = identity(&rcgen::PKCS_ED25519);
+        let (alternate_dir, alternate_der) = identity(&rcgen::PKCS_ECDSA_P256_SHA256);
+        let config = Arc::new(TlsConfig {
+            cert_path: primary_dir.path().join("cert.der"),
+            key_path: primary_dir.path().join("key.der"),
+            alternate_identity: Some(crate::config::CertIdentity {
+                cert_path: alternate_dir.path().join("cert.pem"),
+                key_path: alternate_dir.path().join("key.pem"),
+            }),
+            ..Default::default()
+        });
+        let manager =
+            TlsManager::new(config, Arc::new(test_crypto_provider()), Arc::new(Metrics::new())).unwrap();
+
+        let presented_to = |schemes: Vec<SignatureScheme>| {
+            let manager = &manager;
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let verifier = Arc::new(SchemeVerifier {
+                    schemes,
+                    presented: Mutex::new(None),
+                });
+                let client_config = ClientConfig::builder()
+                    .dangerous()
+                    .with_custom_certificate_verifier(verifier.clone())
+                    .with_no_client_auth();
+                let client = tokio::spawn(async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    TlsConnector::from(Arc::new(client_config))
+                        .connect(ServerName::try_from("localhost").unwrap(), stream)
+                        .await
+                        .map(|_| ())
+                });
+
+                let (stream, _) = listener.accept().await.unwrap();
+                manager.accept(stream).await.unwrap();
+                client.await.unwrap().unwrap();
+                let presented = verifier.presented.lock().take();
+                presented.unwrap()
+            }
+        };
+
+        assert_eq!(presented_to(vec![SignatureScheme::ED25519]).await, primary_der);
+        assert_eq!(
+            presented_to(vec![SignatureScheme::ECDSA_NISTP256_SHA256]).await,
+            alternate_der
+        );
+        // A client verifying both gets the primary
+        let both = vec![SignatureScheme::ECDSA_NISTP256_SHA256, SignatureScheme::ED25519];
+        assert_eq!(presented_to(both).await, primary_der);
+    }
+
+    #[test]
+    fn test_served_signature_algorithm_follows_offered_schemes() {
+        // A Dilithium3 primary and an RSA alternate
+        let rsa = openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.alg = &rcgen::PKCS_RSA_SHA256;
+        params.key_pair = Some(rcgen::KeyPair::from_der(&rsa.private_key_to_pkcs8().unwrap()).unwrap());
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.path().join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            alternate_identity: Some(crate::config::CertIdentity {
+                cert_path: dir.path().join("cert.pem"),
+                key_path: dir.path().join("key.pem"),
+            }),
+            ..Default::default()
+        });
+        let manager =
+            TlsManager::new(config, Arc::new(test_crypto_provider()), Arc::new(Metrics::new())).unwrap();
+
+        // A client offering no scheme the primary can use is shown the alternate
+        let rsa_only = [u16::from(SignatureScheme::RSA_PSS_SHA256)];
+        let served = |offered: &[u16]| manager.served_signature_algorithm(offered);
+        assert_eq!(served(&rsa_only), Some(SignatureAlgorithm::Rsa3072));
+        assert_eq!(served(&[]), Some(SignatureAlgorithm::Dilithium3));
+    }
+
+    #[tokio::test]
+    async fn test_policy_rules_choose_server_config_per_connection() {
+        // An RSA identity, which policies know as Rsa3072
+        let rsa = openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(3072).unwrap()).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.alg = &rcgen::PKCS_RSA_SHA256;
+        params.key_pair = Some(rcgen::KeyPair::from_der(&rsa.private_key_to_pkcs8().unwrap()).unwrap());
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        assert_eq!(
+            certificate_algorithm(&cert.serialize_der().unwrap()),
+            Some(SignatureAlgorithm::Rsa3072)
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cert.der"), cert.serialize_der().unwrap()).unwrap();
+        std::fs::write(dir.path().join("key.der"), cert.serialize_private_key_der()).unwrap();
+        std::fs::write(
+            dir.path().join("policy.yaml"),
+            r#"
+rules:
+  - server_names: ["pq.example"]
+    kem_algorithms: [Kyber768]
+    signature_algorithms: [Rsa3072]
+  - server_names: ["dilithium.example"]
+    kem_algorithms: [Kyber768]
+    signature_algorithms: [Dilithium3]
+"#,
+        )
+        .unwrap();
+        let config = Arc::new(TlsConfig {
+            cert_path: dir.path().join("cert.der"),
+            key_path: dir.path().join("key.der"),
+            policy_path: Some(dir.path().join("policy.yaml")),
+            ..Default::default()
+        });
+        let manager =
+            TlsManager::new(config, Arc::new(test_crypto_provider()), Arc::new(Metrics::new())).unwrap();
+
+        let handshake = |server_name: &'static str, group: &'static dyn SupportedKxGroup| {
+            let manager = &manager;
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let provider = tokio_rustls::rustls::crypto::CryptoProvider {
+                    kx_groups: vec![group],
+                    ..aws_lc_rs::default_provider()
+                };
+                let verifier = Arc::new(SchemeVerifier {
+                    schemes: vec![SignatureScheme::RSA_PSS_SHA256],
+                    presented: Mutex::new(None),
+                });
+                let client_config = ClientConfig::builder_with_provider(Arc::new(provider))
+                    .with_safe_default_protocol_versions()
+                    .unwrap()
+                    .dangerous()
+                    .with_custom_certificate_verifier(verifier)
+                    .with_no_client_auth();
+                let client = tokio::spawn(async move {
+                    let stream = TcpStream::connect(addr).await.unwrap();
+                    TlsConnector::from(Arc::new(client_config))
+                        .connect(ServerName::try_from(server_name).unwrap(), stream)
+                        .await
+                        .map(drop)
+                });
+                let (stream, _) = listener.accept().await.unwrap();
+                let accepted = manager.accept(stream).await.map(drop);
+                let _ = client.await;
+                accepted
+            }
+        };
+
+        // Without a matching rule the global config applies, classical
+        // groups included
+        assert!(handshake("other.example", aws_lc_rs::kx_group::X25519).await.is_ok());
+        // A matching rule narrows the groups offered to that client
+        assert!(handshake("pq.example", aws_lc_rs::kx_group::X25519).await.is_err());
+        assert!(handshake("pq.example", aws_lc_rs::kx_group::X25519MLKEM768).await.is_ok());
+        // A rule no configured certificate satisfies refuses its clients
+        assert!(handshake("dilithium.example", aws_lc_rs::kx_group::X25519MLKEM768).await.is_err());
+    }
+
+    #[test]
+    fn test_upstream_chain_depth_limited() {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+
+        fn ca(name: &str) -> Certificate {
+            let mut params = CertificateParams::new(vec![]);
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            Certificate::from_params(params).unwrap()
+        }
+
+        // root -> intermediate 1 -> intermediate 2 -> leaf: three certificates sent
+        let root = ca("root");
+        let (int1, int2) = (ca("intermediate 1"), ca("intermediate 2"));
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name.push(DnType::CommonName, "leaf");
+        let leaf = Certificate::from_params(params).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(root.serialize_der().unwrap())).unwrap();
+        let leaf = CertificateDer::from(leaf.serialize_der_with_signer(&int2).unwrap());
+        let intermediates = [
+            CertificateDer::from(int2.serialize_der_with_signer(&int1).unwrap()),
+            CertificateDer::from(int1.serialize_der_with_signer(&root).unwrap()),
+        ];
+
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let verify = |max_depth| {
+            ChainDepthVerifier::new(roots.clone(), &provider, max_depth)
+                .unwrap()
+                .verify_server_cert(
+                    &leaf,
+                    &intermediates,
+                    &ServerName::try_from("localhost").unwrap(),
+                    &[],
+                    UnixTime::now(),
+                )
+        };
+        verify(3).unwrap();
+        let err = verify(2).unwrap_err();
+        assert!(matches!(
+            err,
+            tokio_rustls::rustls::Error::General(ref msg) if msg.contains("max_chain_depth 2")
+        ));
+    }
+
+    #[test]
+    fn test_client_certificates_checked_against_cas_and_crls() {
+        use rcgen::{
+            BasicConstraints, Certificate, CertificateParams, CertificateRevocationList,
+            CertificateRevocationListParams, IsCa, KeyIdMethod, KeyUsagePurpose, RevokedCertParams,
+            SerialNumber,
+        };
+        use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+        fn ca() -> Certificate {
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![
+                KeyUsagePurpose::KeyCertSign,
+                KeyUsagePurpose::CrlSign,
+                KeyUsagePurpose::DigitalSignature,
+            ];
+            Certificate::from_params(params).unwrap()
+        }
+
+        fn client(issuer: &Certificate, serial: u64) -> (Vec<u8>, Vec<u8>) {
+            let mut params = CertificateParams::new(vec!["client.example".to_string()]);
+            params.serial_number = Some(SerialNumber::from(serial));
+            let cert = Certificate::from_params(params).unwrap();
+            (cert.serialize_der_with_signer(issuer).unwrap(), cert.serialize_private_key_der())
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (ca1, ca2) = (ca(), ca());
+        std::fs::write(dir.path().join("ca1.pem"), ca1.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.path().join("ca2.pem"), ca2.serialize_pem().unwrap()).unwrap();
+        let trusted = client(&ca2, 2);
+        let revoked = client(&ca1, 7);
+
+        let crl = CertificateRevocationList::from_params(CertificateRevocationListParams {
+            this_update: rcgen::date_time_ymd(2024, 1, 1),
+            next_update: rcgen::date_time_ymd(2099, 1, 1),
+            crl_number: SerialNumber::from(1),
+            issuing_distribution_point: None,
+            revoked_certs: vec![RevokedCertParams {
+                serial_number: SerialNumber::from(7),
+                revocation_time: rcgen::date_time_ymd(2024, 1, 1),
+                reason_code: Some(rcgen::RevocationReason::KeyCompromise),
+                invalidity_date: None,
+            }],
+            alg: &rcgen::PKCS_ECDSA_P256_SHA256,
+            key_identifier_method: KeyIdMethod::Sha256,
+        })
+        .unwrap();
+        let crl_dir = dir.path().join("crls");
+        std::fs::create_dir(&crl_dir).unwrap();
+        let crl_pem = crl.serialize_pem_with_signer(&ca1).unwrap();
+        std::fs::write(crl_dir.join("ca1.pem"), crl_pem).unwrap();
+
+        let config = Arc::new(TlsConfig {
+            cert_path: "tests/fixtures/test.crt".into(),
+            key_path: "tests/fixtures/test.key".into(),
+            client_auth: ClientAuthConfig {
+                ca_certs: vec![dir.path().join("ca1.pem"), dir.path().join("ca2.pem")],
+                crls: Some(crl_dir),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let manager =
+            TlsManager::new(config, Arc::new(test_crypto_provider()), Arc::new(Metrics::new())).unwrap();
+
+        let recorded = record(async {
+            let handshake = |(cert, key): (Vec<u8>, Vec<u8>)| {
+                let manager = &manager;
+                async move {
+                    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                    let addr = listener.local_addr().unwrap();
+                    let verifier = Arc::new(SchemeVerifier {
+                        schemes: aws_lc_rs::default_provider()
+                            .signature_verification_algorithms
+                            .supported_schemes(),
+                        presented: Mutex::new(None),
+                    });
+                    let client_config = ClientConfig::builder()
+                        .dangerous()
+                        .with_custom_certificate_verifier(verifier)
+                        .with_client_auth_cert(
+                            vec![CertificateDer::from(cert)],
+                            PrivateKeyDer::try_from(key).unwrap(),
+                        )
+                        .unwrap();
+                    let client = tokio::spawn(async move {
+                        let stream = TcpStream::connect(addr).await.unwrap();
+                        let server_name = ServerName::try_from("localhost").unwrap();
+                        let mut tls = TlsConnector::from(Arc::new(client_config))
+                            .connect(server_name, stream)
+                            .await?;
+                        // Reading surfaces the server's alert under TLS 1.3
+                        let mut buf = [0u8; 1];
+                        tls.read(&mut buf).await.map(|_| ())
+                    });
+
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let result = manager.accept(stream).await.map(drop);
+                    drop(client);
+                    result
+                }
+            };
+
+            assert!(handshake(trusted).await.is_ok());
+            assert!(handshake(revoked).await.is_err());
+        });
+
+        assert_eq!(
+            counters(&recorded, "client_cert_rejections_total"),
+            vec![(vec!["reason=revoked".to_string()], 1)]
+        );
+    }
+}
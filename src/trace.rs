@@ -0,0 +1,158 @@
+use crate::config::ConnectionTraceConfig;
+use crate::jsonl::JsonLinesWriter;
+use crate::proxy::{CloseReason, ConnectionStats};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+
+/// One line of the trace file
+#[derive(Debug, Serialize)]
+struct TraceRecord<'a> {
+    id: u64,
+    peer: SocketAddr,
+    started_unix_ms: u128,
+    handshake_ms: Option<u128>,
+    duration_ms: u128,
+    client_to_target_bytes: u64,
+    target_to_client_bytes: u64,
+    algorithms: Option<&'a str>,
+    reason: &'static str,
+    error: Option<&'a str>,
+}
+
+/// Appends a JSON record per closed connection from selected client addresses
+///
+/// Meant for chasing down a single misbehaving client without turning on
+/// debug logging everywhere. Once the file reaches `max_bytes` further
+/// records are dropped, so a trace left enabled cannot fill the disk.
+/// Records are written by a `JsonLinesWriter` thread, off the connection tasks.
+pub struct ConnectionTracer {
+    config: ConnectionTraceConfig,
+    enabled: AtomicBool,
+    writer: JsonLinesWriter,
+}
+
+impl ConnectionTracer {
+    pub fn new(config: ConnectionTraceConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(config.enabled),
+            writer: JsonLinesWriter::spawn(&config.path, Some(config.max_bytes)),
+            config,
+        }
+    }
+
+    /// Start or stop tracing, e.g. from the admin endpoint
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        log::info!(
+            "Connection trace to {:?} {}",
+            self.config.path,
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+
+    /// Whether connections from `peer` are traced
+    pub fn matches(&self, peer: SocketAddr) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+        let ip = peer.ip().to_canonical();
+        self.config.source_ips.iter().any(|source| source.to_canonical() == ip)
+    }
+
+    /// Queue the record for a closed connection if `peer` matches the filter
+    ///
+    /// Failures to write are logged rather than affecting the connection.
+    pub fn record(
+        &self,
+        stats: &ConnectionStats,
+        peer: SocketAddr,
+        reason: CloseReason,
+        error: Option<&str>,
+    ) {
+        if !self.matches(peer) {
+            return;
+        }
+        let record = TraceRecord {
+            id: stats.id,
+            peer,
+            started_unix_ms: stats
+                .started_at()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            handshake_ms: stats.handshake_time().map(|time| time.as_millis()),
+            duration_ms: stats.elapsed().as_millis(),
+            client_to_target_bytes: stats.client_to_target(),
+            target_to_client_bytes: stats.target_to_client(),
+            algorithms: stats.negotiated(),
+            reason: reason.as_str(),
+            error,
+        };
+        self.writer.append(&record);
+    }
+
+    /// Wait until every record queued so far is written
+    pub fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_matching_connections_traced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        let tracer = ConnectionTracer::new(ConnectionTraceConfig {
+            path: path.clone(),
+            source_ips: vec!["127.0.0.1".parse().unwrap()],
+            ..Default::default()
+        });
+
+        let traced = ConnectionStats::new();
+        traced.set_negotiated("X25519Kyber768 / TLS13_AES_256_GCM_SHA384".to_string());
+        tracer.record(&traced, "127.0.0.1:50000".parse().unwrap(), CloseReason::ClientEof, None);
+        let other = ConnectionStats::new();
+        tracer.record(&other, "10.0.0.1:50000".parse().unwrap(), CloseReason::Error, Some("boom"));
+
+        // Nothing is traced while disabled
+        tracer.set_enabled(false);
+        tracer.record(&traced, "127.0.0.1:50000".parse().unwrap(), CloseReason::ClientEof, None);
+        tracer.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["id"], traced.id);
+        assert_eq!(record["peer"], "127.0.0.1:50000");
+        assert_eq!(record["reason"], "client_eof");
+        assert!(record["algorithms"].as_str().unwrap().starts_with("X25519Kyber768"));
+        assert!(record["handshake_ms"].is_u64());
+        assert!(record["error"].is_null());
+    }
+
+    #[test]
+    fn test_trace_file_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        let tracer = ConnectionTracer::new(ConnectionTraceConfig {
+            path: path.clone(),
+            source_ips: vec!["::1".parse().unwrap()],
+            max_bytes: 300,
+            ..Default::default()
+        });
+
+        let peer = "[::1]:1".parse().unwrap();
+        for _ in 0..10 {
+            tracer.record(&ConnectionStats::new(), peer, CloseReason::Error, None);
+        }
+        tracer.flush();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.len() <= 300);
+        assert!(contents.lines().count() >= 1);
+    }
+}